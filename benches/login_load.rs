@@ -0,0 +1,128 @@
+//! measures how long an endpoint that has nothing to do with passwords takes
+//! to answer, first on an idle server and then while it's busy handling
+//! logins. password hashes are computed off the async workers, so the two
+//! should be about the same.
+//!
+//! needs the same database and `.env` as the server, run with
+//! `cargo bench --bench login_load`. the number of concurrent logins can be
+//! set with `LOGIN_LOAD_CLIENTS` (defaults to 4 per CPU)
+
+use std::{net::SocketAddr, sync::{Arc, atomic::{AtomicBool, AtomicUsize, Ordering}}, time::{Duration, Instant}};
+
+use actix_web::{App, HttpResponse, HttpServer, web};
+use cyber_bank_rs::{audit::{self, AuditLogger}, db, mail, store};
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream};
+
+const USERNAME: &str = "login_load_bench";
+const PASSWORD: &str = "Bench_Passw0rd!";
+const SAMPLES: usize = 200;
+
+/// sends a request and returns the response's status code
+async fn request(addr: SocketAddr, method: &str, path: &str, body: &str) -> u16 {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let request = format!(
+        "{method} {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\
+        Content-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+    // "HTTP/1.1 200 OK"
+    String::from_utf8_lossy(&response[9..12]).parse().unwrap()
+}
+
+/// latencies of [`SAMPLES`] sequential requests to `/health`, sorted
+async fn sample_health(addr: SocketAddr) -> Vec<Duration> {
+    let mut latencies = Vec::with_capacity(SAMPLES);
+    for _ in 0..SAMPLES {
+        let start = Instant::now();
+        assert_eq!(request(addr, "GET", "/health", "").await, 200);
+        latencies.push(start.elapsed());
+    }
+    latencies.sort();
+    latencies
+}
+
+fn report(label: &str, latencies: &[Duration]) {
+    let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+    println!(
+        "{label:<24} p50 {:>10.2?}  p90 {:>10.2?}  p99 {:>10.2?}  max {:>10.2?}",
+        percentile(50),
+        percentile(90),
+        percentile(99),
+        latencies[latencies.len() - 1]
+    );
+}
+
+#[tokio::main]
+async fn main() {
+    let pool = db::get_db_pool().await;
+    db::set_up_db_tables(&pool).await;
+
+    let mailer = mail::from_env(&pool);
+    let session_store = store::from_env(&pool).await;
+    let audit_logger = AuditLogger::new(pool.clone());
+    audit::chain::spawn_sealing(pool.clone());
+
+    let workers = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let server = HttpServer::new(move || {
+        App::new()
+            .route("/health", web::get().to(HttpResponse::Ok))
+            .service(web::scope("/auth").configure(cyber_bank_rs::auth::config))
+            .app_data(pool.clone())
+            .app_data(mailer.clone())
+            .app_data(session_store.clone())
+            .app_data(audit_logger.clone())
+    })
+        .workers(workers)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+    let addr = server.addrs()[0];
+    tokio::spawn(server.run());
+
+    let credentials = format!(r#"{{"email":"{USERNAME}@example.com","username":"{USERNAME}","password":"{PASSWORD}"}}"#);
+    // 400 if it's already there from an earlier run
+    request(addr, "POST", "/auth/register", &credentials).await;
+
+    report("idle", &sample_health(addr).await);
+
+    let clients = dotenvy::var("LOGIN_LOAD_CLIENTS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(workers * 4);
+    let done = Arc::new(AtomicBool::new(false));
+    let logins = Arc::new(AtomicUsize::new(0));
+    let overloaded = Arc::new(AtomicUsize::new(0));
+    let login = format!(r#"{{"username":"{USERNAME}","password":"{PASSWORD}"}}"#);
+
+    let load: Vec<_> = (0..clients).map(|_| {
+        let (done, logins, overloaded, login) = (done.clone(), logins.clone(), overloaded.clone(), login.clone());
+        tokio::spawn(async move {
+            while !done.load(Ordering::Relaxed) {
+                match request(addr, "POST", "/auth/login", &login).await {
+                    200 => logins.fetch_add(1, Ordering::Relaxed),
+                    503 => overloaded.fetch_add(1, Ordering::Relaxed),
+                    status => panic!("login failed with {status}"),
+                };
+            }
+        })
+    }).collect();
+
+    // lets the logins pile up first
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let start = Instant::now();
+    let latencies = sample_health(addr).await;
+    let elapsed = start.elapsed();
+    done.store(true, Ordering::Relaxed);
+    for client in load {
+        client.await.unwrap();
+    }
+
+    report(&format!("{clients} concurrent logins"), &latencies);
+    println!(
+        "{} logins, {} rejected with 503 while sampling for {elapsed:.2?}",
+        logins.load(Ordering::Relaxed),
+        overloaded.load(Ordering::Relaxed)
+    );
+}
@@ -0,0 +1,3 @@
+DROP INDEX personal_access_tokens_user_id;
+DROP INDEX personal_access_tokens_token_hash;
+DROP TABLE personal_access_tokens;
@@ -0,0 +1,15 @@
+CREATE TABLE if not exists personal_access_tokens (
+    token_id uuid NOT NULL PRIMARY KEY,
+    user_id uuid NOT NULL REFERENCES users (user_id) ON DELETE CASCADE,
+    name text NOT NULL,
+    token_hash bytea NOT NULL UNIQUE,
+    scopes text[] NOT NULL,
+    creation_date timestamp with time zone NOT NULL,
+    expiration_date timestamp with time zone NOT NULL,
+    last_used_date timestamp with time zone,
+    last_used_ip text,
+    revoked boolean NOT NULL DEFAULT false
+);
+
+CREATE INDEX personal_access_tokens_token_hash ON personal_access_tokens USING HASH (token_hash);
+CREATE INDEX personal_access_tokens_user_id ON personal_access_tokens USING BTREE (user_id);
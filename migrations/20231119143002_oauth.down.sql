@@ -0,0 +1,7 @@
+DROP INDEX oauth_authorization_codes_expiration_date;
+DROP TABLE oauth_authorization_codes;
+
+DROP TABLE oauth_consents;
+
+DROP INDEX oauth_clients_owner_id;
+DROP TABLE oauth_clients;
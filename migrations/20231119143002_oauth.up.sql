@@ -0,0 +1,34 @@
+CREATE TABLE if not exists oauth_clients (
+    client_id uuid NOT NULL PRIMARY KEY,
+    owner_id uuid NOT NULL REFERENCES users (user_id) ON DELETE CASCADE,
+    name text NOT NULL,
+    -- public clients (e.g. native or single-page apps) have no secret and must use PKCE
+    client_secret_hash bytea,
+    redirect_uris text[] NOT NULL,
+    scopes text[] NOT NULL,
+    creation_date timestamp with time zone NOT NULL
+);
+
+CREATE INDEX oauth_clients_owner_id ON oauth_clients USING BTREE (owner_id);
+
+CREATE TABLE if not exists oauth_consents (
+    user_id uuid NOT NULL REFERENCES users (user_id) ON DELETE CASCADE,
+    client_id uuid NOT NULL REFERENCES oauth_clients (client_id) ON DELETE CASCADE,
+    scopes text[] NOT NULL,
+    granted_date timestamp with time zone NOT NULL,
+    PRIMARY KEY (user_id, client_id)
+);
+
+CREATE TABLE if not exists oauth_authorization_codes (
+    code_hash bytea NOT NULL PRIMARY KEY,
+    client_id uuid NOT NULL REFERENCES oauth_clients (client_id) ON DELETE CASCADE,
+    user_id uuid NOT NULL REFERENCES users (user_id) ON DELETE CASCADE,
+    redirect_uri text NOT NULL,
+    scopes text[] NOT NULL,
+    code_challenge text,
+    code_challenge_method text,
+    expiration_date timestamp with time zone NOT NULL,
+    used boolean NOT NULL DEFAULT false
+);
+
+CREATE INDEX oauth_authorization_codes_expiration_date ON oauth_authorization_codes USING BTREE (expiration_date);
@@ -0,0 +1,16 @@
+CREATE TABLE if not exists totp_secrets (
+    user_id uuid NOT NULL PRIMARY KEY REFERENCES users (user_id) ON DELETE CASCADE,
+    secret bytea NOT NULL,
+    creation_date timestamp with time zone NOT NULL,
+    -- NULL until the user proves they enrolled it by entering a valid code
+    enabled_date timestamp with time zone,
+    -- time step of the last accepted code, so codes can't be replayed
+    last_used_step bigint
+);
+
+CREATE TABLE if not exists recovery_codes (
+    user_id uuid NOT NULL REFERENCES users (user_id) ON DELETE CASCADE,
+    code_hash bytea NOT NULL,
+    used_date timestamp with time zone,
+    PRIMARY KEY (user_id, code_hash)
+);
@@ -0,0 +1,5 @@
+DROP INDEX webauthn_challenges_expiration_date;
+DROP TABLE webauthn_challenges;
+
+DROP INDEX webauthn_credentials_user_id;
+DROP TABLE webauthn_credentials;
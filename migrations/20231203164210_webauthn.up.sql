@@ -0,0 +1,23 @@
+CREATE TABLE if not exists webauthn_credentials (
+    credential_id bytea NOT NULL PRIMARY KEY,
+    user_id uuid NOT NULL REFERENCES users (user_id) ON DELETE CASCADE,
+    name text NOT NULL,
+    -- uncompressed SEC1 encoding of the P-256 public key
+    public_key bytea NOT NULL,
+    sign_count bigint NOT NULL,
+    creation_date timestamp with time zone NOT NULL,
+    last_used_date timestamp with time zone
+);
+
+CREATE INDEX webauthn_credentials_user_id ON webauthn_credentials USING BTREE (user_id);
+
+CREATE TABLE if not exists webauthn_challenges (
+    challenge_id uuid NOT NULL PRIMARY KEY,
+    -- NULL for logins with discoverable credentials, where the user isn't known yet
+    user_id uuid REFERENCES users (user_id) ON DELETE CASCADE,
+    challenge bytea NOT NULL,
+    ceremony text NOT NULL,
+    expiration_date timestamp with time zone NOT NULL
+);
+
+CREATE INDEX webauthn_challenges_expiration_date ON webauthn_challenges USING BTREE (expiration_date);
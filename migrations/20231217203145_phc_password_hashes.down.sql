@@ -0,0 +1,13 @@
+-- only hashes still using the original parameters can be stored in the old
+-- format, everything else would need a password reset afterwards
+ALTER TABLE users ADD COLUMN raw_password bytea;
+ALTER TABLE users ADD COLUMN salt bytea;
+
+UPDATE users SET
+    salt = decode(rpad(split_part(password, '$', 5), (length(split_part(password, '$', 5)) + 3) / 4 * 4, '='), 'base64'),
+    raw_password = decode(rpad(split_part(password, '$', 6), (length(split_part(password, '$', 6)) + 3) / 4 * 4, '='), 'base64');
+
+ALTER TABLE users DROP COLUMN password;
+ALTER TABLE users RENAME COLUMN raw_password TO password;
+ALTER TABLE users ALTER COLUMN password SET NOT NULL;
+ALTER TABLE users ALTER COLUMN salt SET NOT NULL;
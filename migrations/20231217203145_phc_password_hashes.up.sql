@@ -0,0 +1,14 @@
+-- passwords used to be stored as a raw Argon2id hash with the salt in its own
+-- column and the parameters hardcoded. those parameters are the ones every
+-- existing hash was made with, so they can be turned into PHC strings as-is
+ALTER TABLE users ADD COLUMN password_hash text;
+
+UPDATE users SET password_hash = '$argon2id$v=19$m=32768,t=2,p=1$'
+    || rtrim(replace(encode(salt, 'base64'), E'\n', ''), '=')
+    || '$'
+    || rtrim(replace(encode(password, 'base64'), E'\n', ''), '=');
+
+ALTER TABLE users DROP COLUMN password;
+ALTER TABLE users DROP COLUMN salt;
+ALTER TABLE users RENAME COLUMN password_hash TO password;
+ALTER TABLE users ALTER COLUMN password SET NOT NULL;
@@ -0,0 +1,3 @@
+-- peppered hashes can't be verified without their pepper anymore, so those
+-- users have to reset their password
+ALTER TABLE users DROP COLUMN password_pepper_id;
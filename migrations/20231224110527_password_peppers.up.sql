@@ -0,0 +1,2 @@
+-- which of the server's peppers the password was hashed with, if any
+ALTER TABLE users ADD COLUMN password_pepper_id text;
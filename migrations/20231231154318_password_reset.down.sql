@@ -0,0 +1,5 @@
+DROP INDEX mail_outbox_recipient;
+DROP TABLE mail_outbox;
+DROP INDEX password_reset_tokens_user_id;
+DROP TABLE password_reset_tokens;
+ALTER TABLE users DROP COLUMN sessions_revoked_date;
@@ -0,0 +1,22 @@
+-- tokens issued before this are no longer accepted, e.g. after a password change
+ALTER TABLE users ADD COLUMN sessions_revoked_date timestamp with time zone;
+
+CREATE TABLE if not exists password_reset_tokens (
+    token_hash bytea NOT NULL PRIMARY KEY,
+    user_id uuid NOT NULL REFERENCES users (user_id) ON DELETE CASCADE,
+    creation_date timestamp with time zone NOT NULL,
+    expiration_date timestamp with time zone NOT NULL
+);
+
+CREATE INDEX password_reset_tokens_user_id ON password_reset_tokens USING BTREE (user_id);
+
+-- emails kept instead of being sent, for development and tests
+CREATE TABLE if not exists mail_outbox (
+    mail_id uuid NOT NULL PRIMARY KEY,
+    recipient text NOT NULL,
+    subject text NOT NULL,
+    body text NOT NULL,
+    creation_date timestamp with time zone NOT NULL
+);
+
+CREATE INDEX mail_outbox_recipient ON mail_outbox USING BTREE (recipient);
@@ -0,0 +1,3 @@
+DROP INDEX email_verification_tokens_user_id;
+DROP TABLE email_verification_tokens;
+ALTER TABLE users DROP COLUMN email_verified_at;
@@ -0,0 +1,13 @@
+ALTER TABLE users ADD COLUMN email_verified_at timestamp with time zone;
+
+CREATE TABLE if not exists email_verification_tokens (
+    token_hash bytea NOT NULL PRIMARY KEY,
+    user_id uuid NOT NULL REFERENCES users (user_id) ON DELETE CASCADE,
+    -- the address being verified, which differs from the user's current one
+    -- when they're changing it
+    email text NOT NULL,
+    creation_date timestamp with time zone NOT NULL,
+    expiration_date timestamp with time zone NOT NULL
+);
+
+CREATE INDEX email_verification_tokens_user_id ON email_verification_tokens USING BTREE (user_id);
@@ -0,0 +1,3 @@
+-- the original case of usernames and emails is gone for good
+DROP INDEX users_email_canonical;
+DROP INDEX users_username_canonical;
@@ -0,0 +1,32 @@
+-- usernames and emails are now stored lowercased and, for emails, NFKC
+-- normalized. accounts that would end up with the same one have to be dealt
+-- with by hand first, so the migration stops and lists them
+DO $$
+DECLARE
+    conflicts text;
+BEGIN
+    SELECT string_agg(format('%s %L: %s', field, canonical, user_ids), E'\n') INTO conflicts
+    FROM (
+        SELECT 'username' AS field, lower(trim(username)) AS canonical,
+            string_agg(user_id::text, ', ' ORDER BY creation_date) AS user_ids
+        FROM users GROUP BY 1, 2 HAVING COUNT(*) > 1
+        UNION ALL
+        SELECT 'email' AS field, lower(normalize(trim(email), NFKC)) AS canonical,
+            string_agg(user_id::text, ', ' ORDER BY creation_date) AS user_ids
+        FROM users GROUP BY 1, 2 HAVING COUNT(*) > 1
+    ) AS duplicates;
+
+    IF conflicts IS NOT NULL THEN
+        RAISE EXCEPTION E'users that only differ in case or normalization have to be renamed or merged first:\n%', conflicts;
+    END IF;
+END $$;
+
+UPDATE users SET
+    username = lower(trim(username)),
+    email = lower(normalize(trim(email), NFKC));
+
+UPDATE email_verification_tokens SET email = lower(normalize(trim(email), NFKC));
+
+-- in case anything writes to the table without going through the server
+CREATE UNIQUE INDEX users_username_canonical ON users USING BTREE (lower(username));
+CREATE UNIQUE INDEX users_email_canonical ON users USING BTREE (lower(email));
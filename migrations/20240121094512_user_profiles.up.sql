@@ -0,0 +1,13 @@
+-- preferences users can set for themselves, unset ones are NULL and the
+-- whole row only exists once something was set
+CREATE TABLE if not exists user_profiles (
+    user_id uuid NOT NULL PRIMARY KEY REFERENCES users (user_id) ON DELETE CASCADE,
+    display_name text,
+    -- ISO 4217 code
+    home_currency text,
+    -- BCP 47 language tag
+    locale text,
+    -- IANA time zone name
+    timezone text,
+    update_date timestamp with time zone NOT NULL
+);
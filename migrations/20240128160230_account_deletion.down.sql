@@ -0,0 +1,5 @@
+DROP INDEX users_purge_date;
+
+ALTER TABLE users
+    DROP COLUMN purge_date,
+    DROP COLUMN deletion_date;
@@ -0,0 +1,7 @@
+-- deleted users are pseudonymized right away and only removed for good once
+-- their purge date has passed
+ALTER TABLE users
+    ADD COLUMN deletion_date timestamp with time zone,
+    ADD COLUMN purge_date timestamp with time zone;
+
+CREATE INDEX users_purge_date ON users USING BTREE (purge_date);
@@ -0,0 +1,14 @@
+-- every login, so users can see where they're logged in and log devices out
+CREATE TABLE if not exists sessions (
+    session_id uuid NOT NULL PRIMARY KEY,
+    user_id uuid NOT NULL REFERENCES users (user_id) ON DELETE CASCADE,
+    device_label text,
+    user_agent text,
+    -- the address the session was last seen from
+    ip text,
+    creation_date timestamp with time zone NOT NULL,
+    last_seen_date timestamp with time zone NOT NULL,
+    expiration_date timestamp with time zone NOT NULL
+);
+
+CREATE INDEX sessions_user_id ON sessions USING BTREE (user_id);
@@ -0,0 +1,16 @@
+DROP INDEX idempotency_keys_expiration_date;
+DROP TABLE idempotency_keys;
+DROP INDEX rate_limit_counters_expiration_date;
+DROP TABLE rate_limit_counters;
+DROP INDEX refresh_tokens_expiration_date;
+DROP TABLE refresh_tokens;
+DROP INDEX revoked_tokens_expiration_date;
+DROP TABLE revoked_tokens;
+
+CREATE TABLE if not exists revoked_tokens (
+    token text NOT NULL UNIQUE,
+    expiration_date timestamp with time zone NOT NULL
+);
+
+CREATE INDEX revoked_tokens_token ON revoked_tokens USING HASH (token);
+CREATE INDEX revoked_tokens_expiration_date ON revoked_tokens USING BTREE (expiration_date);
@@ -0,0 +1,42 @@
+-- short-lived state of the Postgres session store. everything in here
+-- expires and is cleaned up periodically
+
+-- replaced by one keyed by the token's id, the whole token was never stored
+DROP TABLE if exists revoked_tokens;
+
+-- tokens that were revoked before they expired
+CREATE TABLE revoked_tokens (
+    jti uuid NOT NULL PRIMARY KEY,
+    expiration_date timestamp with time zone NOT NULL
+);
+
+CREATE INDEX revoked_tokens_expiration_date ON revoked_tokens USING BTREE (expiration_date);
+
+CREATE TABLE if not exists refresh_tokens (
+    token_hash bytea NOT NULL PRIMARY KEY,
+    user_id uuid NOT NULL,
+    session_id uuid NOT NULL,
+    expiration_date timestamp with time zone NOT NULL
+);
+
+CREATE INDEX refresh_tokens_expiration_date ON refresh_tokens USING BTREE (expiration_date);
+
+CREATE TABLE if not exists rate_limit_counters (
+    counter_key text NOT NULL PRIMARY KEY,
+    count bigint NOT NULL,
+    expiration_date timestamp with time zone NOT NULL
+);
+
+CREATE INDEX rate_limit_counters_expiration_date ON rate_limit_counters USING BTREE (expiration_date);
+
+-- the response is empty while the first request is still being handled
+CREATE TABLE if not exists idempotency_keys (
+    idempotency_key text NOT NULL PRIMARY KEY,
+    fingerprint text NOT NULL,
+    response_status smallint,
+    response_content_type text,
+    response_body text,
+    expiration_date timestamp with time zone NOT NULL
+);
+
+CREATE INDEX idempotency_keys_expiration_date ON idempotency_keys USING BTREE (expiration_date);
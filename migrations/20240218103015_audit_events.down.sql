@@ -0,0 +1,7 @@
+DROP TRIGGER audit_events_no_truncate ON audit_events;
+DROP TRIGGER audit_events_no_changes ON audit_events;
+DROP FUNCTION audit_events_append_only;
+DROP INDEX audit_events_occurred_date;
+DROP INDEX audit_events_user_id;
+DROP TABLE audit_events;
+ALTER TABLE users DROP COLUMN is_admin;
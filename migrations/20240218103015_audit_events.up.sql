@@ -0,0 +1,36 @@
+-- who can look at everyone's audit events. only set by hand, there's no way
+-- to become an admin through the API
+ALTER TABLE users ADD COLUMN is_admin boolean NOT NULL DEFAULT false;
+
+-- security relevant things that happened, which can only ever be added to
+CREATE TABLE if not exists audit_events (
+    event_id uuid NOT NULL PRIMARY KEY,
+    occurred_date timestamp with time zone NOT NULL,
+    event_type text NOT NULL,
+    succeeded boolean NOT NULL,
+    failure_reason text,
+    -- the user whose account it concerns, if it's known
+    user_id uuid,
+    -- who did it, which is the user themselves unless an admin acted
+    actor_id uuid,
+    -- the OAuth client that acted on behalf of the actor
+    client_id uuid,
+    ip text,
+    user_agent text,
+    request_id uuid,
+    details jsonb NOT NULL
+);
+
+CREATE INDEX audit_events_user_id ON audit_events USING BTREE (user_id, occurred_date);
+CREATE INDEX audit_events_occurred_date ON audit_events USING BTREE (occurred_date);
+
+CREATE FUNCTION audit_events_append_only() RETURNS trigger AS $$
+BEGIN
+    RAISE EXCEPTION 'audit events can only be added';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER audit_events_no_changes BEFORE UPDATE OR DELETE ON audit_events
+    FOR EACH ROW EXECUTE FUNCTION audit_events_append_only();
+CREATE TRIGGER audit_events_no_truncate BEFORE TRUNCATE ON audit_events
+    FOR EACH STATEMENT EXECUTE FUNCTION audit_events_append_only();
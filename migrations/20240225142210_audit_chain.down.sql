@@ -0,0 +1,8 @@
+DROP TRIGGER audit_checkpoints_no_truncate ON audit_checkpoints;
+DROP TRIGGER audit_checkpoints_no_changes ON audit_checkpoints;
+DROP TABLE audit_checkpoints;
+DROP TABLE audit_chain_head;
+ALTER TABLE audit_events
+    DROP COLUMN hash,
+    DROP COLUMN previous_hash,
+    DROP COLUMN chain_position;
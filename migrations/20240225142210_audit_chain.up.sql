@@ -0,0 +1,32 @@
+-- every event is hashed together with the hash of the one before it, so
+-- changing or removing one breaks the chain from there on. events recorded
+-- before this have no place in the chain
+ALTER TABLE audit_events
+    ADD COLUMN chain_position bigint UNIQUE,
+    ADD COLUMN previous_hash bytea,
+    ADD COLUMN hash bytea;
+
+-- the end of the chain, which new events are added to one at a time
+CREATE TABLE if not exists audit_chain_head (
+    only_row boolean NOT NULL PRIMARY KEY DEFAULT true CHECK (only_row),
+    chain_position bigint NOT NULL,
+    hash bytea NOT NULL
+);
+
+INSERT INTO audit_chain_head (chain_position, hash) VALUES (0, decode(repeat('00', 64), 'hex'));
+
+-- signed statements of how long the chain was and what its hash was, so it
+-- can't be rewritten without the signing key
+CREATE TABLE if not exists audit_checkpoints (
+    chain_position bigint NOT NULL PRIMARY KEY,
+    hash bytea NOT NULL,
+    creation_date timestamp with time zone NOT NULL,
+    -- which signing key made the signature
+    key_id text NOT NULL,
+    signature bytea NOT NULL
+);
+
+CREATE TRIGGER audit_checkpoints_no_changes BEFORE UPDATE OR DELETE ON audit_checkpoints
+    FOR EACH ROW EXECUTE FUNCTION audit_events_append_only();
+CREATE TRIGGER audit_checkpoints_no_truncate BEFORE TRUNCATE ON audit_checkpoints
+    FOR EACH STATEMENT EXECUTE FUNCTION audit_events_append_only();
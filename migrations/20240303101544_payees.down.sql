@@ -0,0 +1,2 @@
+DROP INDEX payees_target_user_id;
+DROP TABLE payees;
@@ -0,0 +1,20 @@
+-- the people and accounts users send money to, saved under a nickname
+CREATE TABLE if not exists payees (
+    payee_id uuid NOT NULL PRIMARY KEY,
+    user_id uuid NOT NULL REFERENCES users (user_id) ON DELETE CASCADE,
+    nickname text NOT NULL,
+    -- the name the user expects the payee to have
+    name text NOT NULL,
+    identifier_kind text NOT NULL CHECK (identifier_kind IN ('username', 'iban')),
+    -- in its canonical form: lowercased usernames, IBANs without spaces
+    identifier text NOT NULL,
+    -- the user behind a username, payees go when they're removed for good
+    target_user_id uuid REFERENCES users (user_id) ON DELETE CASCADE,
+    -- how the name compared to the one the payee has, when it was added
+    name_check text NOT NULL,
+    creation_date timestamp with time zone NOT NULL,
+    CONSTRAINT payees_nickname UNIQUE (user_id, nickname),
+    CONSTRAINT payees_identifier UNIQUE (user_id, identifier_kind, identifier)
+);
+
+CREATE INDEX payees_target_user_id ON payees USING BTREE (target_user_id);
@@ -0,0 +1,5 @@
+DROP INDEX audit_events_unsealed;
+DROP TRIGGER audit_events_no_changes ON audit_events;
+CREATE TRIGGER audit_events_no_changes BEFORE UPDATE OR DELETE ON audit_events
+    FOR EACH ROW EXECUTE FUNCTION audit_events_append_only();
+DROP FUNCTION audit_events_seal_only();
@@ -0,0 +1,22 @@
+-- events are recorded without their place in the chain, which a background
+-- task fills in for everything recorded since it last ran. that's the only
+-- change to an event there can be, and only once. events recorded before the
+-- chain existed are sealed at its end like any other
+CREATE FUNCTION audit_events_seal_only() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP = 'UPDATE' AND OLD.chain_position IS NULL AND NEW.chain_position IS NOT NULL
+        AND to_jsonb(NEW) - 'chain_position' - 'previous_hash' - 'hash'
+            = to_jsonb(OLD) - 'chain_position' - 'previous_hash' - 'hash' THEN
+        RETURN NEW;
+    END IF;
+    RAISE EXCEPTION 'audit events can only be added';
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER audit_events_no_changes ON audit_events;
+CREATE TRIGGER audit_events_no_changes BEFORE UPDATE OR DELETE ON audit_events
+    FOR EACH ROW EXECUTE FUNCTION audit_events_seal_only();
+
+-- what's left to seal, in the order it's sealed in
+CREATE INDEX audit_events_unsealed ON audit_events USING BTREE (occurred_date, event_id)
+    WHERE chain_position IS NULL;
@@ -0,0 +1,13 @@
+-- what a deleted account needs to be restored until its purge date, which
+-- the user gets a token for by email. purging removes it along with the
+-- rest of the user's personal data, leaving only the pseudonymized row
+CREATE TABLE if not exists account_restorations (
+    user_id uuid NOT NULL PRIMARY KEY REFERENCES users(user_id) ON DELETE CASCADE,
+    token_hash bytea NOT NULL UNIQUE,
+    username text NOT NULL,
+    email text NOT NULL,
+    password text NOT NULL,
+    password_pepper_id text,
+    email_verified_at timestamp with time zone,
+    expiration_date timestamp with time zone NOT NULL
+);
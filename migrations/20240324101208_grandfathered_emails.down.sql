@@ -0,0 +1,13 @@
+WITH introduced AS (
+    SELECT installed_on FROM _sqlx_migrations WHERE version = 20240107102254
+)
+UPDATE users SET email_verified_at = NULL
+FROM introduced
+WHERE users.email_verified_at = introduced.installed_on;
+
+WITH introduced AS (
+    SELECT installed_on FROM _sqlx_migrations WHERE version = 20240107102254
+)
+UPDATE account_restorations SET email_verified_at = NULL
+FROM introduced
+WHERE account_restorations.email_verified_at = introduced.installed_on;
@@ -0,0 +1,20 @@
+-- users who registered before email addresses were verified never got the
+-- chance to, and lost the transfers scope along with it. their addresses
+-- count as verified from when verification was introduced, which also tells
+-- them apart from addresses verified since
+WITH introduced AS (
+    SELECT installed_on FROM _sqlx_migrations WHERE version = 20240107102254
+)
+UPDATE users SET email_verified_at = introduced.installed_on
+FROM introduced
+WHERE users.email_verified_at IS NULL AND users.creation_date < introduced.installed_on;
+
+-- including deleted accounts that can still be restored
+WITH introduced AS (
+    SELECT installed_on FROM _sqlx_migrations WHERE version = 20240107102254
+)
+UPDATE account_restorations SET email_verified_at = introduced.installed_on
+FROM introduced, users
+WHERE users.user_id = account_restorations.user_id
+    AND account_restorations.email_verified_at IS NULL
+    AND users.creation_date < introduced.installed_on;
@@ -0,0 +1 @@
+-- the responses that were dropped can't be brought back
@@ -0,0 +1,5 @@
+-- personal access tokens and OAuth client secrets are only shown once, so
+-- whatever was kept of those responses for replays goes
+UPDATE idempotency_keys SET response_content_type = NULL, response_body = NULL
+WHERE idempotency_key LIKE '%:POST:/auth/tokens:%'
+    OR idempotency_key LIKE '%:POST:/auth/oauth/clients:%';
@@ -0,0 +1,12 @@
+DROP INDEX locks_expiration_date;
+DROP TABLE locks;
+
+CREATE TABLE if not exists login_throttles (
+    -- what's being throttled, e.g. `username:alice` or `ip:127.0.0.1`
+    throttle_key text NOT NULL PRIMARY KEY,
+    failed_attempts integer NOT NULL,
+    last_failure_date timestamp with time zone NOT NULL,
+    locked_until timestamp with time zone
+);
+
+CREATE INDEX login_throttles_last_failure_date ON login_throttles USING BTREE (last_failure_date);
@@ -0,0 +1,11 @@
+-- failed logins are throttled through the session store now, with counters
+-- for the failures and locks for the backoff
+DROP TABLE if exists login_throttles;
+
+-- keys that are locked until they expire
+CREATE TABLE if not exists locks (
+    lock_key text NOT NULL PRIMARY KEY,
+    expiration_date timestamp with time zone NOT NULL
+);
+
+CREATE INDEX if not exists locks_expiration_date ON locks USING BTREE (expiration_date);
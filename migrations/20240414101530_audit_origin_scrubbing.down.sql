@@ -0,0 +1,14 @@
+CREATE OR REPLACE FUNCTION audit_events_seal_only() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP = 'UPDATE' AND OLD.chain_position IS NULL AND NEW.chain_position IS NOT NULL
+        AND to_jsonb(NEW) - 'chain_position' - 'previous_hash' - 'hash'
+            = to_jsonb(OLD) - 'chain_position' - 'previous_hash' - 'hash' THEN
+        RETURN NEW;
+    END IF;
+    RAISE EXCEPTION 'audit events can only be added';
+END;
+$$ LANGUAGE plpgsql;
+
+ALTER TABLE audit_events
+    DROP COLUMN origin_salt,
+    DROP COLUMN origin_digest;
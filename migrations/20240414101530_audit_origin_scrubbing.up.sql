@@ -0,0 +1,26 @@
+-- the address and user agent of an event are chained through a salted
+-- digest of them, so they can be scrubbed once the account they're about is
+-- purged without breaking the chain. events recorded before this have them
+-- in the chain as they are and keep them
+ALTER TABLE audit_events
+    ADD COLUMN if not exists origin_salt bytea,
+    ADD COLUMN if not exists origin_digest bytea;
+
+-- besides sealing, the only change there can be is scrubbing the address,
+-- user agent and salt of an event that has a digest of them
+CREATE OR REPLACE FUNCTION audit_events_seal_only() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP = 'UPDATE' AND OLD.chain_position IS NULL AND NEW.chain_position IS NOT NULL
+        AND to_jsonb(NEW) - 'chain_position' - 'previous_hash' - 'hash'
+            = to_jsonb(OLD) - 'chain_position' - 'previous_hash' - 'hash' THEN
+        RETURN NEW;
+    END IF;
+    IF TG_OP = 'UPDATE' AND OLD.origin_digest IS NOT NULL
+        AND NEW.ip IS NULL AND NEW.user_agent IS NULL AND NEW.origin_salt IS NULL
+        AND to_jsonb(NEW) - 'ip' - 'user_agent' - 'origin_salt'
+            = to_jsonb(OLD) - 'ip' - 'user_agent' - 'origin_salt' THEN
+        RETURN NEW;
+    END IF;
+    RAISE EXCEPTION 'audit events can only be added';
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,550 @@
+use std::{fmt::Display, sync::OnceLock, time::Duration as StdDuration};
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+use chrono::{DateTime, SecondsFormat, SubsecRound, Utc};
+use data_encoding::HEXLOWER;
+use log::{error, info};
+use p256::ecdsa::{Signature, SigningKey, VerifyingKey, signature::{Signer, Verifier}};
+use sha2::{Digest, Sha256};
+use sqlx::{PgConnection, PgPool};
+
+use super::query::Event;
+
+/// how often new events are sealed into the chain
+const SEAL_INTERVAL: StdDuration = StdDuration::from_secs(1);
+
+/// how many events are sealed at once
+const SEAL_BATCH_SIZE: i64 = 1000;
+
+/// how often the end of the chain is signed, if anything was added to it
+const CHECKPOINT_INTERVAL: StdDuration = StdDuration::from_secs(60 * 60);
+
+/// how many events are read at once while verifying
+const VERIFY_BATCH_SIZE: i64 = 1000;
+
+/// what the first event in the chain links to
+pub const GENESIS_HASH: [u8; 64] = [0; 64];
+
+/// what an event's address and user agent are chained through, so the chain
+/// doesn't need them anymore once they're scrubbed. salted, since there are
+/// few enough addresses to try them all against it otherwise
+pub(super) fn origin_digest(salt: &[u8], ip: Option<&str>, user_agent: Option<&str>) -> Vec<u8> {
+    let mut data = salt.to_vec();
+    data.extend(serde_json::json!([ip, user_agent]).to_string().into_bytes());
+    crate::auth::hash(&data)
+}
+
+/// the bytes of an event that go into its hash: its position in the chain
+/// and every column, as a JSON array in [`canonical`] form, so the details
+/// hash the same after a trip through jsonb. events with an
+/// [`origin_digest`] have it in place of their address and user agent, as an
+/// object so it can't be mistaken for an address
+fn encode(position: i64, event: &Event, origin_digest: Option<&[u8]>) -> Vec<u8> {
+    let (ip, user_agent) = match origin_digest {
+        Some(digest) => (serde_json::json!({ "origin_digest": HEXLOWER.encode(digest) }), serde_json::Value::Null),
+        None => (serde_json::json!(event.ip), serde_json::json!(event.user_agent)),
+    };
+    let encoded = serde_json::json!([
+        position,
+        event.event_id,
+        event.occurred_date.to_rfc3339_opts(SecondsFormat::Micros, true),
+        event.event_type,
+        event.succeeded,
+        event.failure_reason,
+        event.user_id,
+        event.actor_id,
+        event.client_id,
+        ip,
+        user_agent,
+        event.request_id,
+        event.details,
+    ]);
+    let mut canonical_json = String::new();
+    canonical(&encoded, &mut canonical_json);
+    canonical_json.into_bytes()
+}
+
+/// writes the value as compact JSON with the keys of every object sorted, at
+/// every level, so it always comes out the same however it was put together.
+/// doesn't rely on serde_json's maps happening to be sorted, which they
+/// aren't with its `preserve_order` feature
+fn canonical(value: &serde_json::Value, out: &mut String) {
+    match value {
+        serde_json::Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                canonical(item, out);
+            }
+            out.push(']');
+        },
+        serde_json::Value::Object(map) => {
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_unstable_by_key(|(key, _)| *key);
+            out.push('{');
+            for (i, (key, item)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&serde_json::Value::String(key.clone()).to_string());
+                out.push(':');
+                canonical(item, out);
+            }
+            out.push('}');
+        },
+        // there's only one way to write everything else
+        scalar => out.push_str(&scalar.to_string()),
+    }
+}
+
+/// the hash of the event at the position, which covers the hash of the one
+/// before it
+fn link(previous_hash: &[u8], position: i64, event: &Event, origin_digest: Option<&[u8]>) -> Vec<u8> {
+    let mut data = previous_hash.to_vec();
+    data.extend(encode(position, event, origin_digest));
+    crate::auth::hash(&data)
+}
+
+/// seals the events recorded since the last time, in the order they
+/// happened, by giving each its place at the end of the chain. returns how
+/// many there were. the chain's head stays locked for each batch, so only
+/// one server seals at a time, but recording events never waits for it
+pub async fn seal(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let mut sealed = 0;
+    loop {
+        let mut tx = pool.begin().await?;
+
+        let head = sqlx::query!(r"SELECT chain_position, hash FROM audit_chain_head FOR UPDATE;")
+            .fetch_one(&mut *tx).await?;
+        let batch = sqlx::query!(r"SELECT event_id, occurred_date, event_type, succeeded, failure_reason,
+            user_id, actor_id, client_id, ip, user_agent, request_id, details, origin_digest
+            FROM audit_events
+            WHERE chain_position IS NULL
+            ORDER BY occurred_date, event_id
+            LIMIT $1;",
+            SEAL_BATCH_SIZE
+        ).fetch_all(&mut *tx).await?;
+        if batch.is_empty() {
+            return Ok(sealed);
+        }
+
+        let mut event_ids = Vec::with_capacity(batch.len());
+        let mut positions = Vec::with_capacity(batch.len());
+        let mut previous_hashes = Vec::with_capacity(batch.len());
+        let mut hashes = Vec::with_capacity(batch.len());
+        let (mut position, mut previous_hash) = (head.chain_position, head.hash);
+        for row in &batch {
+            position += 1;
+            let event = Event {
+                event_id: row.event_id,
+                occurred_date: row.occurred_date,
+                event_type: row.event_type.clone(),
+                succeeded: row.succeeded,
+                failure_reason: row.failure_reason.clone(),
+                user_id: row.user_id,
+                actor_id: row.actor_id,
+                client_id: row.client_id,
+                ip: row.ip.clone(),
+                user_agent: row.user_agent.clone(),
+                request_id: row.request_id,
+                details: row.details.clone(),
+            };
+            let hash = link(&previous_hash, position, &event, row.origin_digest.as_deref());
+            event_ids.push(event.event_id);
+            positions.push(position);
+            previous_hashes.push(previous_hash);
+            hashes.push(hash.clone());
+            previous_hash = hash;
+        }
+
+        sqlx::query!(r"UPDATE audit_events SET chain_position = s.position, previous_hash = s.previous_hash, hash = s.hash
+            FROM UNNEST($1::uuid[], $2::bigint[], $3::bytea[], $4::bytea[]) AS s(event_id, position, previous_hash, hash)
+            WHERE audit_events.event_id = s.event_id;",
+            &event_ids,
+            &positions,
+            &previous_hashes,
+            &hashes
+        ).execute(&mut *tx).await?;
+        sqlx::query!(r"UPDATE audit_chain_head SET chain_position = $1, hash = $2;", position, previous_hash)
+            .execute(&mut *tx).await?;
+
+        tx.commit().await?;
+        sealed += batch.len() as u64;
+        if batch.len() < SEAL_BATCH_SIZE as usize {
+            return Ok(sealed);
+        }
+    }
+}
+
+/// seals new events every [`SEAL_INTERVAL`] for as long as the server runs
+pub fn spawn_sealing(pool: PgPool) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SEAL_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = seal(&pool).await {
+                error!("failed sealing audit events: {e}");
+            }
+        }
+    });
+}
+
+static SIGNING_KEY: OnceLock<SigningKey> = OnceLock::new();
+
+/// the key checkpoints are signed with. `AUDIT_SIGNING_KEY` holds it as the
+/// base64 of its 32 byte scalar, and has to be set in debug builds. release
+/// builds otherwise keep it in the same keyring as the token signing key under
+/// `{user}.audit_signing_key`, created the first time it's needed. panics if
+/// there is none or it's invalid, see [`check_signing_key`]
+pub(super) fn signing_key() -> &'static SigningKey {
+    SIGNING_KEY.get_or_init(|| {
+        let decode = |key: &str| BASE64_STANDARD.decode(key.trim()).ok()
+            .and_then(|k| SigningKey::from_slice(&k).ok());
+        if let Ok(key) = dotenvy::var("AUDIT_SIGNING_KEY") {
+            return decode(&key).expect("invalid AUDIT_SIGNING_KEY, expected the base64 of a 32 byte P-256 scalar");
+        }
+
+        #[cfg(debug_assertions)]
+        {
+            panic!("AUDIT_SIGNING_KEY isn't set, debug builds have no other key to sign the audit log with");
+        }
+        #[cfg(not(debug_assertions))]
+        {
+            let entry = crate::auth::keyring_entry(Some("audit_signing_key"));
+            match entry.get_password() {
+                Ok(o) => decode(&o).expect("invalid audit signing key in the keyring"),
+                Err(keyring::Error::NoEntry) => {
+                    let key = SigningKey::random(&mut rand::rngs::OsRng);
+                    entry.set_password(&BASE64_STANDARD.encode(key.to_bytes())).unwrap();
+                    key
+                },
+                Err(e) => panic!("failed reading audit signing key: {e}"),
+            }
+        }
+    })
+}
+
+/// reads the signing key, so a server without one fails when it starts
+/// instead of with the first event
+pub fn check_signing_key() {
+    signing_key();
+}
+
+/// the key checkpoints are verified with
+pub fn verifying_key() -> VerifyingKey {
+    *signing_key().verifying_key()
+}
+
+/// identifies a verifying key by the first 8 bytes of the SHA-256 hash of
+/// its SEC1 encoding, in hex
+pub fn key_id(key: &VerifyingKey) -> String {
+    HEXLOWER.encode(&Sha256::digest(key.to_encoded_point(true).as_bytes())[..8])
+}
+
+/// what a checkpoint's signature is made over
+fn checkpoint_message(position: i64, hash: &[u8], date: DateTime<Utc>) -> Vec<u8> {
+    format!(
+        "cyber_bank_rs audit checkpoint {position} {} {}",
+        HEXLOWER.encode(hash),
+        date.to_rfc3339_opts(SecondsFormat::Micros, true)
+    ).into_bytes()
+}
+
+/// seals what's left to seal and signs the current end of the chain, unless
+/// nothing was added since the last checkpoint. returns the position that was
+/// signed
+pub async fn checkpoint(pool: &PgPool) -> Result<Option<i64>, sqlx::Error> {
+    seal(pool).await?;
+    let head = sqlx::query!(r"SELECT chain_position, hash FROM audit_chain_head;")
+        .fetch_one(pool).await?;
+    if head.chain_position == 0 {
+        return Ok(None);
+    }
+
+    let now = Utc::now().trunc_subsecs(6);
+    let signature: Signature = signing_key().sign(&checkpoint_message(head.chain_position, &head.hash, now));
+    let insert = sqlx::query!(r"INSERT INTO audit_checkpoints (chain_position, hash, creation_date, key_id, signature)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (chain_position) DO NOTHING;",
+        head.chain_position,
+        head.hash,
+        now,
+        key_id(&verifying_key()),
+        signature.to_bytes().to_vec()
+    ).execute(pool).await?;
+
+    match insert.rows_affected() {
+        0 => return Ok(None),
+        _ => return Ok(Some(head.chain_position)),
+    }
+}
+
+/// signs a checkpoint every [`CHECKPOINT_INTERVAL`] for as long as the
+/// server runs
+pub fn spawn_checkpoints(pool: PgPool) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(CHECKPOINT_INTERVAL);
+        loop {
+            interval.tick().await;
+            match checkpoint(&pool).await {
+                Ok(None) => (),
+                Ok(Some(position)) => info!("signed audit checkpoint at position {position}"),
+                Err(e) => error!("failed signing audit checkpoint: {e}"),
+            }
+        }
+    });
+}
+
+/// the first thing that's wrong with the chain
+#[derive(Debug, PartialEq, Eq)]
+pub enum ChainBreak {
+    /// an event is missing from the middle of the chain
+    Gap { expected_position: i64, found_position: i64 },
+    /// the event's link to the one before it was changed
+    BrokenLink { position: i64, event_id: uuid::Uuid },
+    /// the event was changed after it was recorded
+    Altered { position: i64, event_id: uuid::Uuid },
+    /// the checkpoint's hash isn't the chain's at its position
+    CheckpointMismatch { position: i64 },
+    /// the checkpoint wasn't signed with the verifying key
+    InvalidSignature { position: i64 },
+    /// the chain is known to have gone further than it does, so events were
+    /// removed from its end
+    Truncated { last_position: i64, expected_position: i64 },
+}
+
+impl Display for ChainBreak {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Gap { expected_position, found_position } => {
+                write!(f, "expected the event at position {expected_position}, found position {found_position}")
+            },
+            Self::BrokenLink { position, event_id } => {
+                write!(f, "event {event_id} at position {position} doesn't link to the one before it")
+            },
+            Self::Altered { position, event_id } => {
+                write!(f, "event {event_id} at position {position} was changed")
+            },
+            Self::CheckpointMismatch { position } => {
+                write!(f, "the checkpoint at position {position} doesn't match the chain")
+            },
+            Self::InvalidSignature { position } => {
+                write!(f, "the checkpoint at position {position} has an invalid signature")
+            },
+            Self::Truncated { last_position, expected_position } => {
+                write!(f, "the chain ends at position {last_position}, but went up to {expected_position}")
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum VerifyError {
+    Database(sqlx::Error),
+    Broken(ChainBreak),
+}
+
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Database(e) => write!(f, "failed reading the chain: {e}"),
+            Self::Broken(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+impl From<sqlx::Error> for VerifyError {
+    fn from(e: sqlx::Error) -> Self {
+        Self::Database(e)
+    }
+}
+
+impl From<ChainBreak> for VerifyError {
+    fn from(e: ChainBreak) -> Self {
+        Self::Broken(e)
+    }
+}
+
+/// how much of the chain was verified
+#[derive(Debug)]
+pub struct ChainReport {
+    /// the position of the last event, which is how many there are
+    pub last_position: i64,
+    pub checkpoints: u64,
+    /// the position of the last checkpoint. anything after it is only
+    /// vouched for by the chain itself
+    pub last_checkpoint: Option<i64>,
+}
+
+/// walks the chain from the start, checking that every event links to the
+/// one before it and hashes to what was recorded, and that every checkpoint
+/// matches the chain and was signed with the key
+pub async fn verify(conn: &mut PgConnection, key: &VerifyingKey) -> Result<ChainReport, VerifyError> {
+    let checkpoints = sqlx::query!(r"SELECT chain_position, hash, creation_date, key_id, signature
+        FROM audit_checkpoints
+        ORDER BY chain_position;"
+    ).fetch_all(&mut *conn).await?;
+    let mut checkpoints = checkpoints.into_iter().peekable();
+    let key_id = key_id(key);
+    // read after the checkpoints, which can't be past it then. anything added
+    // while verifying is left for next time
+    let head = sqlx::query_scalar!(r"SELECT chain_position FROM audit_chain_head;")
+        .fetch_one(&mut *conn).await?;
+
+    let mut report = ChainReport { last_position: 0, checkpoints: 0, last_checkpoint: None };
+    let mut previous_hash = GENESIS_HASH.to_vec();
+    loop {
+        let batch = sqlx::query!(r#"SELECT event_id, occurred_date, event_type, succeeded, failure_reason,
+            user_id, actor_id, client_id, ip, user_agent, request_id, details, origin_salt, origin_digest,
+            chain_position AS "chain_position!", previous_hash AS "previous_hash!", hash AS "hash!"
+            FROM audit_events
+            WHERE chain_position > $1 AND chain_position <= $2
+            ORDER BY chain_position
+            LIMIT $3;"#,
+            report.last_position,
+            head,
+            VERIFY_BATCH_SIZE
+        ).fetch_all(&mut *conn).await?;
+        if batch.is_empty() {
+            break;
+        }
+
+        for row in batch {
+            let position = report.last_position + 1;
+            if row.chain_position != position {
+                return Err(ChainBreak::Gap { expected_position: position, found_position: row.chain_position }.into());
+            }
+            if row.previous_hash != previous_hash {
+                return Err(ChainBreak::BrokenLink { position, event_id: row.event_id }.into());
+            }
+            let event = Event {
+                event_id: row.event_id,
+                occurred_date: row.occurred_date,
+                event_type: row.event_type,
+                succeeded: row.succeeded,
+                failure_reason: row.failure_reason,
+                user_id: row.user_id,
+                actor_id: row.actor_id,
+                client_id: row.client_id,
+                ip: row.ip,
+                user_agent: row.user_agent,
+                request_id: row.request_id,
+                details: row.details,
+            };
+            if link(&previous_hash, position, &event, row.origin_digest.as_deref()) != row.hash {
+                return Err(ChainBreak::Altered { position, event_id: row.event_id }.into());
+            }
+            // the address and user agent are only vouched for through their
+            // digest, and once they're scrubbed there's nothing left to check
+            let origin_intact = match (&row.origin_digest, &row.origin_salt) {
+                (Some(digest), Some(salt)) => *digest == origin_digest(salt, event.ip.as_deref(), event.user_agent.as_deref()),
+                (Some(_), None) => event.ip.is_none() && event.user_agent.is_none(),
+                (None, _) => true,
+            };
+            if !origin_intact {
+                return Err(ChainBreak::Altered { position, event_id: row.event_id }.into());
+            }
+            previous_hash = row.hash;
+            report.last_position = position;
+
+            while let Some(checkpoint) = checkpoints.next_if(|c| c.chain_position == position) {
+                if checkpoint.hash != previous_hash {
+                    return Err(ChainBreak::CheckpointMismatch { position }.into());
+                }
+                let message = checkpoint_message(position, &checkpoint.hash, checkpoint.creation_date);
+                let signed = checkpoint.key_id == key_id && Signature::from_slice(&checkpoint.signature)
+                    .is_ok_and(|s| key.verify(&message, &s).is_ok());
+                if !signed {
+                    return Err(ChainBreak::InvalidSignature { position }.into());
+                }
+                report.checkpoints += 1;
+                report.last_checkpoint = Some(position);
+            }
+        }
+    }
+
+    // checkpoints and the head past the last event vouch for events that
+    // aren't there anymore
+    let expected_position = checkpoints.map(|c| c.chain_position).chain([head]).max().unwrap_or(head);
+    if expected_position > report.last_position {
+        return Err(ChainBreak::Truncated { last_position: report.last_position, expected_position }.into());
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{SubsecRound, Utc};
+    use p256::ecdsa::{Signature, SigningKey, signature::{Signer, Verifier}};
+    use uuid::Uuid;
+
+    use super::{canonical, checkpoint_message, link, origin_digest, Event, GENESIS_HASH};
+
+    #[test]
+    fn test_links() {
+        let event = Event {
+            event_id: Uuid::new_v4(),
+            occurred_date: Utc::now().trunc_subsecs(6),
+            event_type: "login".to_string(),
+            succeeded: false,
+            failure_reason: Some("wrong_password".to_string()),
+            user_id: Some(Uuid::new_v4()),
+            actor_id: None,
+            client_id: None,
+            ip: Some("127.0.0.1".to_string()),
+            user_agent: None,
+            request_id: None,
+            details: serde_json::json!({ "username": "alice", "method": "password" }),
+        };
+        let hash = link(&GENESIS_HASH, 1, &event, None);
+        assert_eq!(hash.len(), 64);
+        assert_eq!(link(&GENESIS_HASH, 1, &event, None), hash);
+
+        // the details hash the same whichever order jsonb hands their keys back in
+        let reordered = Event {
+            details: serde_json::from_str(r#"{"method": "password", "username": "alice"}"#).unwrap(),
+            ..event
+        };
+        assert_eq!(link(&GENESIS_HASH, 1, &reordered, None), hash);
+
+        // everything that makes up the event changes the hash
+        assert_ne!(link(&hash, 1, &reordered, None), hash);
+        assert_ne!(link(&GENESIS_HASH, 2, &reordered, None), hash);
+        let altered = Event { succeeded: true, ..reordered.clone() };
+        assert_ne!(link(&GENESIS_HASH, 1, &altered, None), hash);
+
+        // with a digest of the address and user agent, the chain doesn't
+        // change when they're scrubbed
+        let digest = origin_digest(&[0; 16], reordered.ip.as_deref(), None);
+        let hash = link(&GENESIS_HASH, 1, &reordered, Some(&digest));
+        let scrubbed = Event { ip: None, ..reordered };
+        assert_eq!(link(&GENESIS_HASH, 1, &scrubbed, Some(&digest)), hash);
+        assert_ne!(link(&GENESIS_HASH, 1, &scrubbed, None), hash);
+        assert_ne!(origin_digest(&[1; 16], Some("127.0.0.1"), None), digest);
+    }
+
+    #[test]
+    fn test_canonical() {
+        let mut map = serde_json::Map::new();
+        map.insert("z".to_string(), serde_json::json!([{ "b": 1, "a": "\"" }]));
+        map.insert("a".to_string(), serde_json::json!({ "d": null, "c": { "f": true, "e": 1.5 } }));
+        let mut out = String::new();
+        canonical(&serde_json::Value::Object(map), &mut out);
+        assert_eq!(out, r#"{"a":{"c":{"e":1.5,"f":true},"d":null},"z":[{"a":"\"","b":1}]}"#);
+    }
+
+    #[test]
+    fn test_checkpoint_signatures() {
+        let key = SigningKey::random(&mut rand::rngs::OsRng);
+        let now = Utc::now().trunc_subsecs(6);
+        let message = checkpoint_message(42, &GENESIS_HASH, now);
+        let signature: Signature = key.sign(&message);
+        assert!(key.verifying_key().verify(&message, &signature).is_ok());
+        assert!(key.verifying_key().verify(&checkpoint_message(43, &GENESIS_HASH, now), &signature).is_err());
+    }
+}
@@ -0,0 +1,199 @@
+use actix_web::{HttpMessage, HttpRequest, http::header::USER_AGENT, web};
+use chrono::Utc;
+use data_encoding::HEXLOWER;
+use hmac::{Hmac, Mac};
+use log::error;
+use rand::RngCore;
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::auth::token::{ScopeValidator, jwt::{JwtClaims, Scope}};
+
+/// tagging every request with an id, which events it caused are recorded with
+mod request_id;
+pub use request_id::{AssignRequestId, RequestId};
+
+/// looking through recorded events, either their own for users or anyone's
+/// for admins
+pub mod query;
+
+/// chaining events together by their hashes and signing checkpoints of the
+/// chain, so changes to recorded events can be found. only audit events are
+/// chained: there are no journal entries to chain until there's a ledger
+pub mod chain;
+
+/// what happened, as it's stored in the `event_type` column
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum EventType {
+    /// the first login step, with a password or a WebAuthn authenticator
+    Login,
+    SecondFactor,
+    Registration,
+    PasswordChange,
+    PasswordReset,
+    EmailChange,
+    /// an address was verified, replacing the user's current one if it's new
+    EmailVerified,
+    TotpEnabled,
+    TotpDisabled,
+    WebauthnAdded,
+    WebauthnRemoved,
+    TokenCreated,
+    TokenRevoked,
+    SessionRevoked,
+    Logout,
+    ConsentWithdrawn,
+    AccountDeletion,
+    AccountRestored,
+    PayeeAdded,
+    PayeeRemoved,
+    /// an admin looked through the audit log
+    AuditQuery,
+}
+
+impl EventType {
+    pub fn name(&self) -> String {
+        match serde_json::to_value(self) {
+            Ok(serde_json::Value::String(s)) => s,
+            _ => unreachable!("event types always serialize to strings"),
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        serde_json::from_value(serde_json::Value::String(name.to_string())).ok()
+    }
+}
+
+/// an event about to be recorded. the address, user agent and id of the
+/// request it happened in are added by [`AuditLogger::record`]
+#[derive(Debug, Clone)]
+pub struct AuditEvent {
+    event_type: EventType,
+    failure_reason: Option<&'static str>,
+    user_id: Option<Uuid>,
+    actor_id: Option<Uuid>,
+    client_id: Option<Uuid>,
+    details: serde_json::Value,
+}
+
+impl AuditEvent {
+    /// something that happened to the user's account, done by the user
+    /// themselves. the user isn't always known, like for logins with
+    /// usernames that don't exist
+    pub fn new(event_type: EventType, user_id: Option<Uuid>) -> Self {
+        Self {
+            event_type,
+            failure_reason: None,
+            user_id,
+            actor_id: user_id,
+            client_id: None,
+            details: serde_json::Value::Object(Default::default()),
+        }
+    }
+
+    /// done with the token, making its subject the actor, along with the
+    /// OAuth client it was issued to
+    pub fn by(mut self, claims: &JwtClaims) -> Self {
+        self.actor_id = Some(claims.subject());
+        self.client_id = claims.authorized_party();
+        self
+    }
+
+    /// it was attempted and didn't work out, for a snake_case reason
+    pub fn failed(mut self, reason: &'static str) -> Self {
+        self.failure_reason = Some(reason);
+        self
+    }
+
+    /// anything else worth knowing about it. never anything secret, the log
+    /// is kept forever
+    pub fn with_details(mut self, details: serde_json::Value) -> Self {
+        self.details = details;
+        self
+    }
+}
+
+/// stands in for something typed in that can't be kept in the log as it is,
+/// like the username of a failed login, which might as well be a password
+/// pasted into the wrong field. the same value always gets the same
+/// pseudonym, so repeated attempts can be told apart, but it can't be turned
+/// back without the audit signing key it's keyed with
+pub fn pseudonym(value: &str) -> String {
+    // a key of its own, so the signing key is never used for anything else
+    let key = Sha256::new()
+        .chain_update(b"cyber_bank_rs audit pseudonyms")
+        .chain_update(chain::signing_key().to_bytes())
+        .finalize();
+    let mut mac = Hmac::<Sha256>::new_from_slice(&key).expect("hmac takes keys of any length");
+    mac.update(value.as_bytes());
+    HEXLOWER.encode(&mac.finalize().into_bytes())
+}
+
+/// records events in the append-only `audit_events` table. the server shares
+/// one in its app data
+#[derive(Clone)]
+pub struct AuditLogger {
+    pool: PgPool,
+}
+
+impl AuditLogger {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    /// records the event as having happened in the request. it's sealed into
+    /// the [`chain`] in the background shortly after. failing to record it is
+    /// logged, but doesn't fail the request
+    pub async fn record(&self, req: &HttpRequest, event: AuditEvent) {
+        let event_type = event.event_type.name();
+        let ip = req.peer_addr().map(|a| a.ip().to_string());
+        let user_agent = req.headers().get(USER_AGENT).and_then(|h| h.to_str().ok());
+        let mut origin_salt = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut origin_salt);
+        let origin_digest = chain::origin_digest(&origin_salt, ip.as_deref(), user_agent);
+        let insert = sqlx::query!(r"INSERT INTO audit_events
+            (event_id, occurred_date, event_type, succeeded, failure_reason, user_id, actor_id, client_id, ip, user_agent, request_id, details, origin_salt, origin_digest)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14);",
+            Uuid::new_v4(),
+            Utc::now(),
+            event_type,
+            event.failure_reason.is_none(),
+            event.failure_reason,
+            event.user_id,
+            event.actor_id,
+            event.client_id,
+            ip,
+            user_agent,
+            req.extensions().get::<RequestId>().map(|r| r.0),
+            event.details,
+            &origin_salt,
+            origin_digest
+        ).execute(&self.pool);
+        if let Err(e) = insert.await {
+            error!("failed recording {event_type} audit event: {e}");
+        }
+    }
+}
+
+/// records the event with the request's [`AuditLogger`]. it's called once
+/// whatever happened is done, so not being able to record it never fails the
+/// request, whether the server has no logger or the database fails, see
+/// [`AuditLogger::record`]
+pub async fn record(req: &HttpRequest, event: AuditEvent) {
+    match req.app_data::<AuditLogger>() {
+        Some(logger) => logger.record(req, event).await,
+        None => error!("no audit logger to record {} audit event", event.event_type.name()),
+    }
+}
+
+/// adds the endpoint `/audit` to the service, for admins
+pub fn admin_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/audit")
+            .wrap(ScopeValidator::new(&[Scope::Admin]))
+            .route(web::get().to(query::search))
+    );
+}
@@ -0,0 +1,172 @@
+use actix_web::{HttpRequest, Responder, HttpResponse, web::Query};
+use chrono::{DateTime, Utc};
+use log::error;
+use serde::{Serialize, Deserialize};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::auth::token::{is_first_party, jwt::{JwtClaims, TokenHeader}};
+
+use super::{AuditEvent, AuditLogger, EventType};
+
+/// how many events are returned at once if the request doesn't say
+const DEFAULT_LIMIT: i64 = 50;
+
+const MAX_LIMIT: i64 = 500;
+
+/// a recorded event, as it's listed and exported
+#[derive(Clone, Serialize)]
+pub(crate) struct Event {
+    pub(crate) event_id: Uuid,
+    pub(crate) occurred_date: DateTime<Utc>,
+    pub(crate) event_type: String,
+    pub(crate) succeeded: bool,
+    pub(crate) failure_reason: Option<String>,
+    pub(crate) user_id: Option<Uuid>,
+    pub(crate) actor_id: Option<Uuid>,
+    pub(crate) client_id: Option<Uuid>,
+    pub(crate) ip: Option<String>,
+    pub(crate) user_agent: Option<String>,
+    pub(crate) request_id: Option<Uuid>,
+    pub(crate) details: serde_json::Value,
+}
+
+/// which of a user's own events to list, newest first. `before` is the id
+/// of the last event of the previous page
+#[derive(Serialize, Deserialize)]
+pub struct ActivityQuery {
+    event_type: Option<EventType>,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    before: Option<Uuid>,
+    limit: Option<i64>,
+}
+
+/// which events to search for, newest first. every filter that's given has
+/// to match. `before` is the id of the last event of the previous page
+#[derive(Serialize, Deserialize, Default)]
+pub struct EventFilter {
+    user_id: Option<Uuid>,
+    actor_id: Option<Uuid>,
+    client_id: Option<Uuid>,
+    event_type: Option<EventType>,
+    succeeded: Option<bool>,
+    ip: Option<String>,
+    request_id: Option<Uuid>,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    before: Option<Uuid>,
+    limit: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum QueryError {
+    InvalidLimit(i64),
+    /// the event the page should start after doesn't exist
+    UnknownCursor(Uuid),
+}
+
+/// the events matching the filter, or `None` if its cursor doesn't exist
+async fn find(pool: &PgPool, filter: &EventFilter) -> Result<Option<Vec<Event>>, sqlx::Error> {
+    let cursor = match filter.before {
+        Some(o) => {
+            let found = sqlx::query!(r"SELECT occurred_date FROM audit_events WHERE event_id = $1;", o)
+                .fetch_optional(pool).await?;
+            match found {
+                Some(found) => Some((found.occurred_date, o)),
+                None => return Ok(None),
+            }
+        },
+        None => None,
+    };
+
+    let events = sqlx::query_as!(Event, r"SELECT event_id, occurred_date, event_type, succeeded, failure_reason,
+        user_id, actor_id, client_id, ip, user_agent, request_id, details
+        FROM audit_events
+        WHERE ($1::uuid IS NULL OR user_id = $1)
+            AND ($2::uuid IS NULL OR actor_id = $2)
+            AND ($3::uuid IS NULL OR client_id = $3)
+            AND ($4::text IS NULL OR event_type = $4)
+            AND ($5::boolean IS NULL OR succeeded = $5)
+            AND ($6::text IS NULL OR ip = $6)
+            AND ($7::uuid IS NULL OR request_id = $7)
+            AND ($8::timestamptz IS NULL OR occurred_date >= $8)
+            AND ($9::timestamptz IS NULL OR occurred_date < $9)
+            AND ($10::timestamptz IS NULL OR (occurred_date, event_id) < ($10, $11))
+        ORDER BY occurred_date DESC, event_id DESC
+        LIMIT $12;",
+        filter.user_id,
+        filter.actor_id,
+        filter.client_id,
+        filter.event_type.map(|e| e.name()),
+        filter.succeeded,
+        filter.ip,
+        filter.request_id,
+        filter.from,
+        filter.to,
+        cursor.map(|c| c.0),
+        cursor.map(|c| c.1),
+        filter.limit.unwrap_or(DEFAULT_LIMIT)
+    ).fetch_all(pool).await?;
+    Ok(Some(events))
+}
+
+/// responds with the events matching the filter
+async fn respond(pool: &PgPool, filter: &EventFilter) -> HttpResponse {
+    if let Some(o) = filter.limit {
+        if !(1..=MAX_LIMIT).contains(&o) {
+            return HttpResponse::BadRequest().json(vec![QueryError::InvalidLimit(o)]);
+        }
+    }
+
+    match find(pool, filter).await {
+        Ok(Some(o)) => return HttpResponse::Ok().json(o),
+        Ok(None) => return HttpResponse::BadRequest().json(vec![QueryError::UnknownCursor(filter.before.unwrap_or_default())]),
+        Err(e) => {
+            error!("failed searching audit events: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+}
+
+/// lists the events concerning the authenticated user's account, like
+/// logins, password changes and revoked tokens
+pub async fn activity(req: HttpRequest, claims: JwtClaims, query: Query<ActivityQuery>) -> impl Responder {
+    let pool = req.app_data::<PgPool>().unwrap();
+    let query = query.into_inner();
+
+    respond(pool, &EventFilter {
+        user_id: Some(claims.subject()),
+        event_type: query.event_type,
+        from: query.from,
+        to: query.to,
+        before: query.before,
+        limit: query.limit,
+        ..Default::default()
+    }).await
+}
+
+/// searches everyone's events, for admins. only first-party tokens can be
+/// used, and every search is recorded as an event itself
+pub async fn search(req: HttpRequest, claims: JwtClaims, header: TokenHeader, filter: Query<EventFilter>) -> impl Responder {
+    if !is_first_party(&claims, &header) {
+        return HttpResponse::Forbidden().finish();
+    }
+
+    let pool = req.app_data::<PgPool>().unwrap();
+    let filter = filter.into_inner();
+
+    // recorded before anything is searched, so there's no search without a
+    // record of it
+    let logger = match crate::app_data::<AuditLogger>(&req) {
+        Ok(o) => o,
+        Err(response) => return response,
+    };
+    logger.record(&req, AuditEvent::new(EventType::AuditQuery, None)
+        .by(&claims)
+        .with_details(serde_json::json!({ "filter": &filter }))
+    ).await;
+
+    respond(pool, &filter).await
+}
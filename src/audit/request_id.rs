@@ -0,0 +1,76 @@
+use std::{future::{Future, Ready, ready}, pin::Pin, rc::Rc};
+
+use actix_web::{
+    body::MessageBody,
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::{HeaderName, HeaderValue},
+    HttpMessage,
+};
+use uuid::Uuid;
+
+/// the response header the request's id is sent back in, so it can be
+/// quoted when asking about it
+pub const REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// the id of the request, found in its extensions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestId(pub Uuid);
+
+/// gives every request a new id. ids sent by clients aren't used, events
+/// shouldn't be tied together by whatever a client made up
+pub struct AssignRequestId;
+
+pub struct AssignRequestIdMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Transform<S, ServiceRequest> for AssignRequestId
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+
+    type Error = actix_web::Error;
+
+    type Transform = AssignRequestIdMiddleware<S>;
+
+    type InitError = ();
+
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AssignRequestIdMiddleware { service: Rc::new(service) }))
+    }
+}
+
+impl<S, B> Service<ServiceRequest> for AssignRequestIdMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+
+    type Error = actix_web::Error;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&self, ctx: &mut core::task::Context<'_>) -> std::task::Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(ctx)
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+
+        Box::pin(async move {
+            let request_id = Uuid::new_v4();
+            req.extensions_mut().insert(RequestId(request_id));
+
+            let mut res = service.call(req).await?;
+            if let Ok(o) = HeaderValue::from_str(&request_id.to_string()) {
+                res.headers_mut().insert(REQUEST_ID, o);
+            }
+            return Ok(res);
+        })
+    }
+}
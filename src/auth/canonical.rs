@@ -0,0 +1,34 @@
+use unicode_normalization::UnicodeNormalization;
+
+/// the form usernames are stored and compared in. they can only contain
+/// ASCII characters, so lowercasing is all there is to it
+pub fn username(username: &str) -> String {
+    username.trim().to_lowercase()
+}
+
+/// the form email addresses are stored and compared in: NFKC normalized and
+/// lowercased as a whole. the local part is case-sensitive on paper, but no
+/// provider treats it that way and two accounts that only differ in its case
+/// would be more confusing than useful. provider-specific rules, like dots
+/// not mattering for some, aren't applied
+pub fn email(email: &str) -> String {
+    email.trim().nfkc().collect::<String>().to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{email, username};
+
+    #[test]
+    fn test_canonical_forms() {
+        assert_eq!(username(" Alice "), "alice");
+        assert_eq!(username("alice"), username("ALICE"));
+
+        assert_eq!(email(" Alice@Example.COM\n"), "alice@example.com");
+        // fullwidth characters and ligatures are compatibility equivalents
+        assert_eq!(email("ａｌｉｃｅ@example.com"), "alice@example.com");
+        assert_eq!(email("ﬁ@example.com"), "fi@example.com");
+        // dots and tags are left alone
+        assert_eq!(email("a.lice+bank@example.com"), "a.lice+bank@example.com");
+    }
+}
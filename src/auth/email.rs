@@ -0,0 +1,278 @@
+use std::{sync::Arc, time::Duration as StdDuration};
+
+use actix_web::{HttpRequest, Responder, web::Json, HttpResponse};
+use chrono::{DateTime, Duration, Utc};
+use log::error;
+use serde::{Serialize, Deserialize};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{audit::{self, AuditEvent, EventType}, mail::{self, Email, Mailer}, store::{RateLimit, SessionStore, StoreError}, validation::{self, Violation}};
+
+use super::token::jwt::{JwtClaims, TokenHeader};
+
+/// how long a verification token can be used for, in hours
+const VERIFICATION_TOKEN_HOURS: i64 = 24;
+
+/// how long to wait before sending another verification email
+const EMAIL_COOLDOWN: RateLimit = RateLimit {
+    name: "verification_email_cooldown",
+    limit: 1,
+    window: StdDuration::from_secs(60),
+};
+
+/// how many verification emails a user can be sent within a day
+const EMAIL_DAILY_LIMIT: RateLimit = RateLimit {
+    name: "verification_email",
+    limit: 5,
+    window: StdDuration::from_secs(24 * 60 * 60),
+};
+
+#[derive(Serialize, Deserialize)]
+pub struct EmailChange {
+    email: String,
+    password: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Verification {
+    token: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum EmailError {
+    InvalidEmail(Violation),
+    AlreadyVerified,
+    InvalidToken,
+    NotAllowedForToken,
+}
+
+/// emails a token for verifying that the user owns the given address, which
+/// is either their current one or the one they're changing it to
+pub(super) async fn send_verification(pool: &PgPool, mailer: &Arc<dyn Mailer>, user_id: Uuid, email: &str) -> Result<(), sqlx::Error> {
+    let token = super::password::generate_emailed_token();
+    let now = Utc::now();
+    sqlx::query!(r"INSERT INTO email_verification_tokens
+        (token_hash, user_id, email, creation_date, expiration_date)
+        VALUES ($1, $2, $3, $4, $5);",
+        super::hash(token.as_bytes()),
+        user_id,
+        email,
+        now,
+        now + Duration::hours(VERIFICATION_TOKEN_HOURS)
+    ).execute(pool).await?;
+
+    mail::send_in_background(mailer, Email {
+        to: email.to_string(),
+        subject: "Verifying your email address".to_string(),
+        body: format!(
+            "Please confirm that this address belongs to your cyber_bank_rs account \
+            using this token within the next {VERIFICATION_TOKEN_HOURS} hours:\n\n{token}\n\n\
+            If you don't have an account, you can ignore this email."
+        ),
+    });
+    Ok(())
+}
+
+/// counts another verification email for the user, returning when they can
+/// be sent one if they can't right now
+pub(super) async fn count_email(store: &dyn SessionStore, user_id: Uuid) -> Result<Option<DateTime<Utc>>, StoreError> {
+    let key = user_id.to_string();
+    if let Some(until) = EMAIL_COOLDOWN.hit(store, &key).await? {
+        return Ok(Some(until));
+    }
+    return EMAIL_DAILY_LIMIT.hit(store, &key).await;
+}
+
+/// sends another verification email for the user's current address, as
+/// long as they haven't been sent too many already
+pub async fn resend(req: HttpRequest, claims: JwtClaims) -> impl Responder {
+    let pool = req.app_data::<PgPool>().unwrap();
+    let mailer = match crate::app_data::<Arc<dyn Mailer>>(&req) {
+        Ok(o) => o,
+        Err(response) => return response,
+    };
+
+    let user = sqlx::query!(r"SELECT email, email_verified_at FROM users WHERE user_id = $1;",
+        claims.subject()
+    ).fetch_one(pool).await;
+    let user = match user {
+        Ok(o) if o.email_verified_at.is_some() => {
+            return HttpResponse::BadRequest().json(vec![EmailError::AlreadyVerified]);
+        },
+        Ok(o) => o,
+        Err(e) => {
+            error!("failed fetching user: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    let store = match crate::app_data::<Arc<dyn SessionStore>>(&req) {
+        Ok(o) => o,
+        Err(response) => return response,
+    };
+    match count_email(store.as_ref(), claims.subject()).await {
+        Ok(Some(until)) => return super::login::too_many_attempts(until),
+        Ok(None) => (),
+        Err(e) => {
+            error!("failed checking verification email rate limit: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    match send_verification(pool, mailer, claims.subject(), &user.email).await {
+        Ok(_) => return HttpResponse::Accepted().finish(),
+        Err(e) => {
+            error!("failed sending verification email: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+}
+
+/// starts changing the authenticated user's email address, which only
+/// happens once the new one is verified. the old address is told about it,
+/// in case someone else is doing this. addresses other users have are
+/// answered the same, but only get a notice instead of a token
+pub async fn change(req: HttpRequest, claims: JwtClaims, header: TokenHeader, change: Json<EmailChange>) -> impl Responder {
+    let mut errors: Vec<EmailError> = Vec::new();
+    let email = super::canonical::email(&change.email);
+
+    if !super::token::is_first_party(&claims, &header) {
+        errors.push(EmailError::NotAllowedForToken);
+    }
+    if let Err(e) = validation::email(&email) {
+        errors.push(EmailError::InvalidEmail(e));
+    }
+
+    let pool = req.app_data::<PgPool>().unwrap();
+
+    let owner = match sqlx::query_scalar!(r"SELECT user_id FROM users WHERE email = $1;", email)
+        .fetch_optional(pool).await
+    {
+        Ok(o) => o,
+        Err(e) => {
+            error!("failed checking for email in use: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    // it's their address already, which tells them nothing new
+    if owner == Some(claims.subject()) {
+        errors.push(EmailError::InvalidEmail(Violation::AlreadyInUse));
+    }
+
+    if !errors.is_empty() {
+        return HttpResponse::BadRequest().json(errors);
+    }
+
+    let mailer = match crate::app_data::<Arc<dyn Mailer>>(&req) {
+        Ok(o) => o,
+        Err(response) => return response,
+    };
+
+    let event = AuditEvent::new(EventType::EmailChange, Some(claims.subject())).by(&claims);
+    let user = match super::password::check_current_password(&req, claims.subject(), &change.password, &event).await {
+        Ok(o) => o,
+        Err(response) => return response,
+    };
+
+    let store = match crate::app_data::<Arc<dyn SessionStore>>(&req) {
+        Ok(o) => o,
+        Err(response) => return response,
+    };
+    match count_email(store.as_ref(), user.user_id).await {
+        Ok(Some(until)) => return super::login::too_many_attempts(until),
+        Ok(None) => (),
+        Err(e) => {
+            error!("failed checking verification email rate limit: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    match owner {
+        // only the owner is told, so this can't be used to find out who has
+        // an account. there's nothing to verify, it can't be changed to
+        Some(_) => mail::send_in_background(mailer, Email {
+            to: email.clone(),
+            subject: "Someone tried to use your email address".to_string(),
+            body: "Someone asked to change the email address of a cyber_bank_rs account \
+                to this one, which already belongs to your account. Nothing was changed.\n\n\
+                If this was you, you can log in to that account or reset its password. \
+                Otherwise you can ignore this email.".to_string(),
+        }),
+        None => {
+            if let Err(e) = send_verification(pool, mailer, user.user_id, &email).await {
+                error!("failed sending verification email: {e}");
+                return HttpResponse::InternalServerError().finish();
+            }
+        },
+    }
+
+    mail::send_in_background(mailer, Email {
+        to: user.email,
+        subject: "Your email address is being changed".to_string(),
+        body: format!(
+            "Someone asked to change the email address of your cyber_bank_rs account \
+            to {}. It will be changed once the new address is verified.\n\n\
+            If this wasn't you, change your password right away.",
+            email
+        ),
+    });
+
+    audit::record(&req, event).await;
+    return HttpResponse::Accepted().finish();
+}
+
+/// verifies an address using a token from [`send_verification`], which can
+/// only be used once. if it's not the user's current address, it replaces it
+pub async fn verify(req: HttpRequest, verification: Json<Verification>) -> impl Responder {
+    let pool = req.app_data::<PgPool>().unwrap();
+
+    let consumed = sqlx::query!(r"DELETE FROM email_verification_tokens
+        WHERE token_hash = $1
+        RETURNING user_id, email, expiration_date;",
+        super::hash(verification.token.as_bytes())
+    ).fetch_optional(pool).await;
+    let token = match consumed {
+        Ok(Some(o)) if o.expiration_date > Utc::now() => o,
+        Ok(o) => {
+            audit::record(&req, AuditEvent::new(EventType::EmailVerified, o.map(|o| o.user_id))
+                .failed("invalid_token")
+            ).await;
+            return HttpResponse::BadRequest().json(vec![EmailError::InvalidToken]);
+        },
+        Err(e) => {
+            error!("failed consuming email verification token: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    let update = sqlx::query!(r"UPDATE users SET email = $2, email_verified_at = $3 WHERE user_id = $1;",
+        token.user_id,
+        token.email,
+        Utc::now()
+    ).execute(pool).await;
+    let event = AuditEvent::new(EventType::EmailVerified, Some(token.user_id));
+    match update {
+        Ok(_) => (),
+        // someone else got to the address first
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+            audit::record(&req, event.failed("already_in_use")).await;
+            return HttpResponse::BadRequest().json(vec![EmailError::InvalidEmail(Violation::AlreadyInUse)]);
+        },
+        Err(e) => {
+            error!("failed verifying email: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    // whatever else was sent is outdated now
+    let cleanup = sqlx::query!(r"DELETE FROM email_verification_tokens WHERE user_id = $1;", token.user_id)
+        .execute(pool).await;
+    if let Err(e) = cleanup {
+        error!("failed deleting outdated email verification tokens: {e}");
+    }
+
+    audit::record(&req, event).await;
+    return HttpResponse::NoContent().finish();
+}
@@ -1,8 +1,13 @@
-use actix_web::{HttpRequest, Responder, web::Json, HttpResponse};
+use std::sync::Arc;
+
+use actix_web::{HttpRequest, Responder, web::Json, HttpResponse, ResponseError};
+use log::error;
 use serde::{Serialize, Deserialize};
 use sqlx::PgPool;
 
-use super::token::jwt::{JwtClaims, Scope};
+use crate::{audit::{self, AuditEvent, EventType}, mail::Mailer, store::{RateLimit, SessionStore}};
+
+use super::{token::jwt::{JwtClaims, Scope}, session, throttle::{self, ThrottleKey}, totp};
 
 #[derive(Serialize, Deserialize)]
 pub struct LoginRequester {
@@ -12,33 +17,375 @@ pub struct LoginRequester {
 
 #[derive(Serialize, Deserialize)]
 struct ValidLoginResponse {
-    token: String
+    token: String,
+    /// gets new tokens at `/refresh` once this one expires
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    refresh_token: Option<String>,
+    /// set when the token can only be used to finish logging in at `/login/mfa`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    mfa_required: bool
+}
+
+/// a second factor, either a TOTP code or one of the user's recovery codes
+#[derive(Serialize, Deserialize)]
+pub struct MfaRequester {
+    code: Option<String>,
+    recovery_code: Option<String>
+}
+
+/// how long users have to enter their second factor, in minutes
+const MFA_PENDING_MINUTES: i64 = 5;
+
+/// each token from the first login step finishes one login at most, so a
+/// token that leaked after it was used can't start another session
+const MFA_PENDING_USES: RateLimit = RateLimit {
+    name: "mfa_pending_token",
+    limit: 1,
+    window: std::time::Duration::from_secs(MFA_PENDING_MINUTES as u64 * 60),
+};
+
+/// a login token for the session, which doesn't outlive it. admins also get
+/// the [`Scope::Admin`] scope
+fn login_token(user_id: uuid::Uuid, session_id: uuid::Uuid, session_expiration: chrono::DateTime<chrono::Utc>, email_verified: bool, admin: bool) -> String {
+    let mut scopes = match email_verified {
+        true => Scope::USER_LOGIN,
+        false => Scope::UNVERIFIED_LOGIN,
+    }.to_vec();
+    if admin {
+        scopes.push(Scope::Admin);
+    }
+    let expiration = chrono::Utc::now() + chrono::Duration::minutes(session::ACCESS_TOKEN_MINUTES);
+    JwtClaims::new(
+        scopes,
+        user_id,
+        expiration.min(session_expiration)
+    ).with_session(session_id).generate_token()
+}
+
+/// responds to a successful first login step, which is either a full login
+/// token or, for users with a second factor enabled, a token that's only good
+/// for [`login_mfa`]
+pub(super) async fn first_factor_response(req: &HttpRequest, pool: &PgPool, user_id: uuid::Uuid) -> HttpResponse {
+    match totp::is_enabled(pool, user_id).await {
+        Ok(false) => return full_login_response(req, pool, user_id).await,
+        Ok(true) => {
+            return HttpResponse::Ok()
+                .json(ValidLoginResponse {
+                    token: JwtClaims::new(
+                        vec![Scope::MfaPending],
+                        user_id,
+                        chrono::Utc::now() + chrono::Duration::minutes(MFA_PENDING_MINUTES)
+                    ).generate_token(),
+                    refresh_token: None,
+                    mfa_required: true
+                });
+        },
+        Err(e) => {
+            error!("failed checking for second factor: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+}
+
+/// responds to a login that already went through every factor it needs,
+/// starting a new session for it. users who haven't verified their email
+/// address yet get fewer scopes
+pub(super) async fn full_login_response(req: &HttpRequest, pool: &PgPool, user_id: uuid::Uuid) -> HttpResponse {
+    match session::create(pool, req, user_id).await {
+        Ok((session_id, expiration)) => return session_response(req, pool, user_id, session_id, expiration).await,
+        Err(e) => {
+            error!("failed creating session: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+}
+
+/// responds with a new login token and refresh token for the session. users
+/// who haven't verified their email address yet get fewer scopes
+pub(super) async fn session_response(
+    req: &HttpRequest,
+    pool: &PgPool,
+    user_id: uuid::Uuid,
+    session_id: uuid::Uuid,
+    session_expiration: chrono::DateTime<chrono::Utc>
+) -> HttpResponse {
+    let store = match crate::app_data::<Arc<dyn SessionStore>>(req) {
+        Ok(o) => o,
+        Err(response) => return response,
+    };
+
+    let user = sqlx::query!(r"SELECT email_verified_at, is_admin FROM users WHERE user_id = $1;", user_id)
+        .fetch_one(pool).await;
+    let user = match user {
+        Ok(o) => o,
+        Err(e) => {
+            error!("failed checking email verification: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    match session::issue_refresh_token(store.as_ref(), user_id, session_id, session_expiration).await {
+        Ok(refresh_token) => {
+            return HttpResponse::Ok()
+                .json(ValidLoginResponse {
+                    token: login_token(user_id, session_id, session_expiration, user.email_verified_at.is_some(), user.is_admin),
+                    refresh_token: Some(refresh_token),
+                    mfa_required: false
+                });
+        },
+        Err(e) => {
+            error!("failed issuing refresh token: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+}
+
+pub(super) fn too_many_attempts(locked_until: chrono::DateTime<chrono::Utc>) -> HttpResponse {
+    let retry_after = (locked_until - chrono::Utc::now()).num_seconds().max(1);
+    HttpResponse::TooManyRequests()
+        .insert_header((actix_web::http::header::RETRY_AFTER, retry_after.to_string()))
+        .finish()
+}
+
+/// counts the request against the limit for the address it came from,
+/// erroring with the response to send if it went over. requests without an
+/// address aren't limited
+pub(crate) async fn limit_by_address(req: &HttpRequest, limit: &RateLimit) -> Result<(), HttpResponse> {
+    let Some(addr) = req.peer_addr() else {
+        return Ok(());
+    };
+    limit_by_key(req, limit, &addr.ip().to_string()).await
+}
+
+/// counts the request against the limit for the key, erroring with the
+/// response to send if it went over
+pub(crate) async fn limit_by_key(req: &HttpRequest, limit: &RateLimit, key: &str) -> Result<(), HttpResponse> {
+    let store = crate::app_data::<Arc<dyn SessionStore>>(req)?;
+    match limit.hit(store.as_ref(), key).await {
+        Ok(Some(until)) => return Err(too_many_attempts(until)),
+        Ok(None) => return Ok(()),
+        Err(e) => {
+            error!("failed checking {} rate limit: {e}", limit.name);
+            return Err(HttpResponse::InternalServerError().finish());
+        }
+    }
+}
+
+/// what's recorded about a failed password login. the user is recorded along
+/// with it if there is one, otherwise only a [`pseudonym`](audit::pseudonym)
+/// of whatever was typed in as the username
+fn failed_login_details(known_user: bool, username: &str) -> serde_json::Value {
+    match known_user {
+        true => return serde_json::json!({ "method": "password" }),
+        false => return serde_json::json!({ "method": "password", "username_pseudonym": audit::pseudonym(username) }),
+    }
 }
 
 /// checks that given credentials are valid and returns a
-/// scoped authorization token that allows users to perform common tasks
+/// scoped authorization token that allows users to perform common tasks.
+/// users can log in with either their username or their email, both
+/// compared in their [`canonical`](super::canonical) form. users with a
+/// second factor enabled get a token that's only good for [`login_mfa`]
+/// instead
 pub async fn login(req: HttpRequest, userinfo: Json<LoginRequester>) -> impl Responder {
-    let selection = sqlx::query_as!(
-        super::DbUser,
-        "SELECT * FROM users WHERE username = $1;",
-        userinfo.username
-    ).fetch_one(req.app_data::<PgPool>().unwrap()).await;
-    match selection {
-        Ok(o) => {
-            let hashed_salted_passwd = super::salt_and_hash(userinfo.password.clone(), o.salt.as_slice());
-            if o.password == hashed_salted_passwd {
-                return HttpResponse::Ok()
-                    .json(ValidLoginResponse {
-                        token: JwtClaims::new(
-                            Scope::USER_LOGIN.to_vec(),
-                            o.user_id,
-                            chrono::Utc::now() + chrono::Days::new(30)
-                        ).generate_token()
-                    });
-            } else {
-                return HttpResponse::Forbidden().finish();
+    let pool = req.app_data::<PgPool>().unwrap();
+
+    let selection = if userinfo.username.contains('@') {
+        sqlx::query_as!(
+            super::DbUser,
+            "SELECT * FROM users WHERE email = $1;",
+            super::canonical::email(&userinfo.username)
+        ).fetch_optional(pool).await
+    } else {
+        sqlx::query_as!(
+            super::DbUser,
+            "SELECT * FROM users WHERE username = $1;",
+            super::canonical::username(&userinfo.username)
+        ).fetch_optional(pool).await
+    };
+    let user = match selection {
+        Ok(o) => o,
+        Err(e) => {
+            error!("failed fetching user: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    // throttled by username however the user logged in, so switching to the
+    // email doesn't get around it
+    let username = match &user {
+        Some(o) => o.username.clone(),
+        None => super::canonical::username(&userinfo.username),
+    };
+    let ip = req.peer_addr().map(|a| a.ip());
+    let mut keys = vec![ThrottleKey::Username(&username, ip)];
+    if let Some(ip) = ip {
+        keys.push(ThrottleKey::Ip(ip));
+    }
+
+    // fetched before anything is checked, so failures are always counted
+    let store = match crate::app_data::<Arc<dyn SessionStore>>(&req) {
+        Ok(o) => o.as_ref(),
+        Err(response) => return response,
+    };
+    let mailer = match crate::app_data::<Arc<dyn Mailer>>(&req) {
+        Ok(o) => o,
+        Err(response) => return response,
+    };
+    match throttle::locked_until(store, &keys).await {
+        Ok(Some(until)) => {
+            audit::record(&req, AuditEvent::new(EventType::Login, user.as_ref().map(|u| u.user_id))
+                .failed("throttled")
+                .with_details(failed_login_details(user.is_some(), &username))
+            ).await;
+            return too_many_attempts(until);
+        },
+        Ok(None) => (),
+        Err(e) => {
+            error!("failed checking login throttling: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    let (expected, pepper_id) = match &user {
+        Some(o) => (o.password.as_str(), o.password_pepper_id.as_deref()),
+        None => (super::dummy_hash(), super::pepper::current().map(|p| p.id.as_str())),
+    };
+    let matches = match super::verify_password(expected, pepper_id, &userinfo.password).await {
+        Ok(o) => o,
+        Err(e) => return e.error_response(),
+    };
+
+    match user {
+        Some(o) if matches => {
+            if let Err(e) = throttle::reset(store, ThrottleKey::Username(&o.username, ip)).await {
+                error!("failed resetting login throttling: {e}");
             }
+            if super::needs_rehash(&o.password, o.password_pepper_id.as_deref()) {
+                rehash(pool, &o, &userinfo.password).await;
+            }
+            audit::record(&req, AuditEvent::new(EventType::Login, Some(o.user_id))
+                .with_details(serde_json::json!({ "method": "password" }))
+            ).await;
+            return first_factor_response(&req, pool, o.user_id).await;
+        },
+        _ => {
+            let reason = match user {
+                Some(_) => "wrong_password",
+                None => "unknown_user",
+            };
+            for key in keys {
+                if let Err(e) = throttle::record_failure(store, pool, mailer, key).await {
+                    error!("failed recording failed login: {e}");
+                }
+            }
+            audit::record(&req, AuditEvent::new(EventType::Login, user.as_ref().map(|u| u.user_id))
+                .failed(reason)
+                .with_details(failed_login_details(user.is_some(), &username))
+            ).await;
+            return HttpResponse::Forbidden().finish();
+        }
+    }
+}
+
+/// replaces the user's password hash with one made with the current
+/// parameters and pepper. failing to do so doesn't fail the login, the old hash still
+/// works and it's tried again next time
+async fn rehash(pool: &PgPool, user: &super::DbUser, passwd: &str) {
+    let hash = match super::hash_password(passwd).await {
+        Ok(o) => o,
+        // no need to add to the load, the old hash still works
+        Err(_) => return,
+    };
+    // only replaces the hash that was verified, in case the password was
+    // changed in the meantime
+    let update = sqlx::query!(r"UPDATE users SET password = $1, password_pepper_id = $2
+        WHERE user_id = $3 AND password = $4;",
+        hash.encoded,
+        hash.pepper_id,
+        user.user_id,
+        user.password
+    ).execute(pool).await;
+    if let Err(e) = update {
+        error!("failed rehashing password: {e}");
+    }
+}
+
+/// second step of the login for users with TOTP enabled, exchanging a token
+/// with the [`Scope::MfaPending`] scope and a valid code for a login token
+pub async fn login_mfa(req: HttpRequest, claims: JwtClaims, mfa: Json<MfaRequester>) -> impl Responder {
+    let pool = req.app_data::<PgPool>().unwrap();
+    let key = ThrottleKey::Mfa(claims.subject());
+    let event = AuditEvent::new(EventType::SecondFactor, Some(claims.subject()))
+        .with_details(serde_json::json!({
+            "method": if mfa.recovery_code.is_some() { "recovery_code" } else { "totp" },
+        }));
+
+    // fetched before anything is checked, so failures are always counted
+    let store = match crate::app_data::<Arc<dyn SessionStore>>(&req) {
+        Ok(o) => o.as_ref(),
+        Err(response) => return response,
+    };
+    let mailer = match crate::app_data::<Arc<dyn Mailer>>(&req) {
+        Ok(o) => o,
+        Err(response) => return response,
+    };
+    match throttle::locked_until(store, &[key]).await {
+        Ok(Some(until)) => {
+            audit::record(&req, event.failed("throttled")).await;
+            return too_many_attempts(until);
         },
-        Err(_) => return HttpResponse::Forbidden().respond_to(&req),
+        Ok(None) => (),
+        Err(e) => {
+            error!("failed checking login throttling: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    let valid = match (&mfa.code, &mfa.recovery_code) {
+        (Some(code), None) => totp::check_code(pool, claims.subject(), code).await,
+        (None, Some(code)) => totp::use_recovery_code(pool, claims.subject(), code).await,
+        _ => return HttpResponse::BadRequest().finish(),
     };
+
+    match valid {
+        Ok(true) => {
+            // counted, so only one request with the token gets this far,
+            // and revoked, so it isn't accepted anymore after that
+            let Some(token_id) = claims.token_id() else {
+                return HttpResponse::Forbidden().finish();
+            };
+            match MFA_PENDING_USES.hit(store, &token_id.to_string()).await {
+                Ok(None) => (),
+                Ok(Some(_)) => {
+                    audit::record(&req, event.failed("token_reused")).await;
+                    return HttpResponse::Forbidden().finish();
+                },
+                Err(e) => {
+                    error!("failed using up second factor token: {e}");
+                    return HttpResponse::InternalServerError().finish();
+                }
+            }
+            if let Err(e) = store.revoke_token(token_id, claims.expiration()).await {
+                error!("failed revoking second factor token: {e}");
+                return HttpResponse::InternalServerError().finish();
+            }
+            if let Err(e) = throttle::reset(store, key).await {
+                error!("failed resetting login throttling: {e}");
+            }
+            audit::record(&req, event).await;
+            return full_login_response(&req, pool, claims.subject()).await;
+        },
+        Ok(false) => {
+            if let Err(e) = throttle::record_failure(store, pool, mailer, key).await {
+                error!("failed recording failed second factor: {e}");
+            }
+            audit::record(&req, event.failed("invalid_code")).await;
+            return HttpResponse::Forbidden().finish();
+        },
+        Err(e) => {
+            error!("failed checking second factor: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
 }
@@ -1,49 +1,377 @@
+use std::{fmt::Display, sync::OnceLock, time::Duration};
+
+use actix_web::{HttpResponse, ResponseError, http::{StatusCode, header}};
+
+use base64::{Engine, prelude::BASE64_STANDARD_NO_PAD};
 use blake2::{Blake2b512, Digest};
+use log::error;
+use rand::RngCore;
+use tokio::sync::Semaphore;
 
 pub mod login;
 pub mod registration;
 
+/// the forms usernames and email addresses are stored and compared in
+pub mod canonical;
+
+/// changing and resetting forgotten passwords
+pub mod password;
+
+/// what new passwords have to look like, and the breached passwords they
+/// can't be
+pub mod policy;
+
+/// verifying that users own their email address, and changing it
+pub mod email;
+
+/// server-side secrets mixed into password hashes
+mod pepper;
+
+/// tracking of failed login attempts, backing off and locking out usernames
+/// and addresses that fail too often
+pub mod throttle;
+
+/// OAuth 2.0 authorization server, letting third-party apps act on behalf of
+/// users with the scopes they consented to
+pub mod oauth;
+
 pub mod token;
 
+/// the devices users are logged in on, and logging them out
+pub mod session;
+
+/// TOTP second factor enrollment and verification, along with the one-time
+/// recovery codes for when the authenticator is lost
+pub mod totp;
+
+/// passwordless login with WebAuthn authenticators and passkeys
+pub mod webauthn;
+
 #[allow(dead_code)]
-struct DbUser {
-    user_id: uuid::Uuid,
-    email: String,
-    username: String,
-    password: Vec<u8>,
-    salt: Vec<u8>,
-    creation_date: chrono::DateTime<chrono::Utc>
-}
-
-/// salts and hashes the given password using the Argon2id hashing algorithm,
-/// creating a 256-bit long hash with 2 iterations, 1 level of parallelism and
-/// 32MB of memory used
-fn salt_and_hash(passwd: String, salt: &[u8]) -> Vec<u8> {
-    let hashed_salted_passwd = argon2::hash_raw(
-        passwd.as_bytes(),
-        salt,
-        &argon2::Config{
-            ad: &[],
-            hash_length: 32,
-            lanes: 1,
-            mem_cost: 32 * 1024,
-            secret: &[],
-            time_cost: 2,
-            variant: argon2::Variant::Argon2id,
-            version: argon2::Version::Version13,
+pub(crate) struct DbUser {
+    pub(crate) user_id: uuid::Uuid,
+    pub(crate) email: String,
+    pub(crate) username: String,
+    /// PHC string of the password's hash, including its salt and parameters
+    pub(crate) password: String,
+    pub(crate) creation_date: chrono::DateTime<chrono::Utc>,
+    /// the [`pepper::Pepper`] the password was hashed with, if any
+    pub(crate) password_pepper_id: Option<String>,
+    /// login tokens issued before this aren't accepted anymore
+    pub(crate) sessions_revoked_date: Option<chrono::DateTime<chrono::Utc>>,
+    pub(crate) email_verified_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// when the user deleted their account, which was pseudonymized then
+    pub(crate) deletion_date: Option<chrono::DateTime<chrono::Utc>>,
+    /// when a deleted account is removed for good
+    pub(crate) purge_date: Option<chrono::DateTime<chrono::Utc>>,
+    /// whether the user can look through everyone's audit events
+    pub(crate) is_admin: bool
+}
+
+/// the entry in the OS keyring a secret is kept in. the token signing key
+/// has the user's name to itself, other secrets get their name appended
+#[cfg(not(debug_assertions))]
+pub(crate) fn keyring_entry(secret: Option<&str>) -> keyring::Entry {
+    let user = std::env::var("USER")
+        .expect("$USER environment variable could not be retrieved!");
+    let name = match secret {
+        Some(s) => format!("{user}.{s}"),
+        None => user,
+    };
+    keyring::Entry::new("cyber_bank_rs", &name).unwrap()
+}
+
+/// parameters new passwords are hashed with: Argon2id creating a 256-bit
+/// long hash with 2 iterations, 1 level of parallelism and 32MB of memory
+/// used. hashes carry the parameters they were made with, so these can be
+/// raised at any time and older hashes get redone on their user's next login
+const HASH_CONFIG: argon2::Config<'static> = argon2::Config{
+    ad: &[],
+    hash_length: 32,
+    lanes: 1,
+    mem_cost: 32 * 1024,
+    secret: &[],
+    time_cost: 2,
+    variant: argon2::Variant::Argon2id,
+    version: argon2::Version::Version13,
+};
+
+/// the part of a PHC string that comes before the salt, for hashes made
+/// with [`HASH_CONFIG`]
+fn hash_prefix() -> String {
+    format!(
+        "${}$v={}$m={},t={},p={}$",
+        HASH_CONFIG.variant.as_lowercase_str(),
+        HASH_CONFIG.version.as_u32(),
+        HASH_CONFIG.mem_cost,
+        HASH_CONFIG.time_cost,
+        HASH_CONFIG.lanes
+    )
+}
+
+/// a password hash as stored with its user
+struct PasswordHash {
+    /// PHC string of the hash, including its salt and parameters
+    encoded: String,
+    /// the [`pepper::Pepper`] used as the Argon2 secret, if any
+    pepper_id: Option<String>,
+}
+
+/// salts and hashes the given password with [`HASH_CONFIG`] and the current
+/// pepper, if there is one
+fn salt_and_hash(passwd: &str) -> PasswordHash {
+    let mut salt = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let pepper = pepper::current();
+    let config = argon2::Config {
+        secret: pepper.map_or(&[], |p| p.secret.as_slice()),
+        ..HASH_CONFIG
+    };
+    return PasswordHash {
+        encoded: argon2::hash_encoded(passwd.as_bytes(), &salt, &config).unwrap(),
+        pepper_id: pepper.map(|p| p.id.clone()),
+    };
+}
+
+/// checks the password against a PHC string, using whatever parameters and
+/// pepper the hash was made with
+fn verify(encoded: &str, pepper_id: Option<&str>, passwd: &str) -> bool {
+    let secret = match pepper_id.map(pepper::find) {
+        None => [].as_slice(),
+        Some(Some(p)) => p.secret.as_slice(),
+        Some(None) => {
+            error!("password was hashed with unknown pepper {pepper_id:?}");
+            return false;
+        }
+    };
+    match argon2::verify_encoded_ext(encoded, passwd.as_bytes(), secret, &[]) {
+        Ok(o) => return o,
+        Err(e) => {
+            error!("failed verifying password hash: {e}");
+            return false;
         }
-    ).unwrap();
-    return hashed_salted_passwd;
+    }
+}
+
+/// whether the hash was made with other parameters or another pepper than
+/// the current ones and should be replaced the next time the password is known
+fn needs_rehash(encoded: &str, pepper_id: Option<&str>) -> bool {
+    if pepper_id != pepper::current().map(|p| p.id.as_str()) {
+        return true;
+    }
+    let Some(rest) = encoded.strip_prefix(&hash_prefix()) else {
+        return true;
+    };
+    let hash_length = rest.rsplit('$').next()
+        .and_then(|h| BASE64_STANDARD_NO_PAD.decode(h).ok())
+        .map(|h| h.len());
+    return hash_length != Some(HASH_CONFIG.hash_length as usize);
+}
+
+/// a hash with the current parameters that no password matches, verified
+/// against for usernames that don't exist so they take as long to reject as
+/// wrong passwords do
+fn dummy_hash() -> &'static str {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    DUMMY_HASH.get_or_init(|| format!(
+        "{}{}${}",
+        hash_prefix(),
+        BASE64_STANDARD_NO_PAD.encode([0u8; 16]),
+        BASE64_STANDARD_NO_PAD.encode(vec![0u8; HASH_CONFIG.hash_length as usize])
+    ))
+}
+
+static HASHING_PERMITS: OnceLock<Semaphore> = OnceLock::new();
+
+/// since every hash takes 32MB of memory, only so many can be computed at
+/// once. defaults to the number of CPUs, can be set with the
+/// `ARGON2_MAX_CONCURRENCY` environment variable
+fn hashing_permits() -> &'static Semaphore {
+    HASHING_PERMITS.get_or_init(|| {
+        let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let permits = crate::env_var("ARGON2_MAX_CONCURRENCY", cpus);
+        Semaphore::new(permits)
+    })
+}
+
+/// how long a hash waits for its turn before giving up. defaults to 5
+/// seconds, can be set in milliseconds with the `ARGON2_QUEUE_TIMEOUT_MS`
+/// environment variable
+fn hashing_queue_timeout() -> Duration {
+    static TIMEOUT: OnceLock<Duration> = OnceLock::new();
+    *TIMEOUT.get_or_init(|| {
+        let millis = crate::env_var("ARGON2_QUEUE_TIMEOUT_MS", 5000);
+        Duration::from_millis(millis)
+    })
+}
+
+/// too many hashes were queued already for this one to get its turn in time
+#[derive(Debug)]
+pub struct HashingOverloaded;
+
+impl Display for HashingOverloaded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "too many passwords being hashed")
+    }
+}
+
+impl std::error::Error for HashingOverloaded {}
+
+impl ResponseError for HashingOverloaded {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::ServiceUnavailable()
+            .insert_header((header::RETRY_AFTER, "1"))
+            .finish()
+    }
+}
+
+/// runs a hashing function on tokio's blocking threads so it doesn't hold up
+/// the async workers, once one of the [`hashing_permits`] is free. the permit
+/// is only given back once the hash is done, even if the request was dropped
+/// in the meantime
+async fn run_hashing<T, F>(f: F) -> Result<T, HashingOverloaded>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let permit = match tokio::time::timeout(hashing_queue_timeout(), hashing_permits().acquire()).await {
+        Ok(o) => o.expect("the semaphore is never closed"),
+        Err(_) => return Err(HashingOverloaded),
+    };
+    let result = tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        f()
+    }).await;
+    match result {
+        Ok(o) => return Ok(o),
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+/// [`salt_and_hash`], off the async workers
+async fn hash_password(passwd: &str) -> Result<PasswordHash, HashingOverloaded> {
+    let passwd = policy::normalize(passwd);
+    run_hashing(move || salt_and_hash(&passwd)).await
+}
+
+/// [`verify`], off the async workers
+async fn verify_password(encoded: &str, pepper_id: Option<&str>, passwd: &str) -> Result<bool, HashingOverloaded> {
+    let (encoded, pepper_id, passwd) = (encoded.to_string(), pepper_id.map(str::to_string), policy::normalize(passwd));
+    run_hashing(move || verify(&encoded, pepper_id.as_deref(), &passwd)).await
 }
 
 /// # ⚠️ WARNING ⚠️
 /// do not use for passwords dumbass
-fn hash(data: &[u8]) -> Vec<u8> {
+pub(crate) fn hash(data: &[u8]) -> Vec<u8> {
     Blake2b512::new().chain_update(data).finalize().to_vec()
 }
 
-/// adds the endpoints `/register` and `/login` to the service
+/// adds the endpoints `/register`, `/login`, `/login/mfa`, `/refresh`,
+/// `/logout`, `/password`, `/email`, `/totp`, `/webauthn`, `/tokens`,
+/// `/sessions` and `/oauth` to the service
 pub fn config(cfg: &mut actix_web::web::ServiceConfig) {
-    cfg.route("/register", actix_web::web::post().to(registration::register))
-        .route("/login", actix_web::web::post().to(login::login));
+    use actix_web::web;
+    use token::{ScopeValidator, jwt::Scope, personal};
+    use crate::store::Idempotent;
+
+    cfg.route("/register", web::post().to(registration::register))
+        .route("/login", web::post().to(login::login))
+        .service(
+            web::resource("/login/mfa")
+                .wrap(ScopeValidator::new(&[Scope::MfaPending]))
+                .route(web::post().to(login::login_mfa))
+        )
+        .route("/refresh", web::post().to(session::refresh))
+        .service(
+            web::resource("/logout")
+                .wrap(ScopeValidator::new(&[Scope::User]))
+                .route(web::post().to(session::logout))
+        )
+        .service(
+            web::resource("/password")
+                .wrap(ScopeValidator::new(&[Scope::User]))
+                .route(web::post().to(password::change))
+        )
+        .route("/password/reset", web::post().to(password::request_reset))
+        .route("/password/reset/confirm", web::post().to(password::confirm_reset))
+        .service(
+            web::resource("/email")
+                .wrap(ScopeValidator::new(&[Scope::User]))
+                .route(web::post().to(email::change))
+        )
+        .route("/email/verify", web::post().to(email::verify))
+        .service(
+            web::resource("/email/verify/resend")
+                .wrap(ScopeValidator::new(&[Scope::User]))
+                .route(web::post().to(email::resend))
+        )
+        .service(
+            web::scope("/totp")
+                .wrap(ScopeValidator::new(&[Scope::User]))
+                .route("", web::post().to(totp::enroll))
+                .route("", web::delete().to(totp::disable))
+                .route("/activate", web::post().to(totp::activate))
+        )
+        .service(web::scope("/webauthn").configure(webauthn::config))
+        .service(
+            web::scope("/tokens")
+                .wrap(ScopeValidator::new(&[Scope::User]))
+                .service(
+                    web::resource("")
+                        .wrap(Idempotent::withholding())
+                        .route(web::post().to(personal::create))
+                        .route(web::get().to(personal::list))
+                )
+                .route("/{token_id}", web::delete().to(personal::revoke))
+        )
+        .service(
+            web::scope("/sessions")
+                .wrap(ScopeValidator::new(&[Scope::User]))
+                .route("", web::get().to(session::list))
+                .route("", web::delete().to(session::revoke_others))
+                .route("/{session_id}", web::delete().to(session::revoke))
+        )
+        .service(web::scope("/oauth").configure(oauth::config));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{dummy_hash, needs_rehash, pepper, salt_and_hash, verify};
+
+    #[test]
+    fn test_password_hashes() {
+        let hash = salt_and_hash("Passw0rd!");
+        let (encoded, pepper_id) = (hash.encoded.as_str(), hash.pepper_id.as_deref());
+        assert!(encoded.starts_with("$argon2id$v=19$m=32768,t=2,p=1$"));
+        assert!(verify(encoded, pepper_id, "Passw0rd!"));
+        assert!(!verify(encoded, pepper_id, "passw0rd!"));
+        assert!(!needs_rehash(encoded, pepper_id));
+
+        // the pepper is needed to verify it, and missing or unknown ones
+        // don't match anything
+        assert_eq!(pepper_id, pepper::current().map(|p| p.id.as_str()));
+        assert!(!verify(encoded, None, "Passw0rd!"));
+        assert!(!verify(encoded, Some("unknown"), "Passw0rd!"));
+
+        // made with cheaper parameters, still verifies but should be redone
+        let cheap = argon2::hash_encoded(b"Passw0rd!", &[0u8; 16], &argon2::Config {
+            mem_cost: 8 * 1024,
+            ..super::HASH_CONFIG
+        }).unwrap();
+        assert!(verify(&cheap, None, "Passw0rd!"));
+        assert!(needs_rehash(&cheap, None));
+
+        // made before there was a pepper
+        let unpeppered = argon2::hash_encoded(b"Passw0rd!", &[0u8; 16], &super::HASH_CONFIG).unwrap();
+        assert!(verify(&unpeppered, None, "Passw0rd!"));
+        assert!(needs_rehash(&unpeppered, None));
+
+        assert!(!needs_rehash(dummy_hash(), pepper_id));
+        assert!(!verify(dummy_hash(), pepper_id, ""));
+        assert!(!verify("not a hash", None, "Passw0rd!"));
+    }
 }
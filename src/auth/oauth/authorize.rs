@@ -0,0 +1,243 @@
+use actix_web::{HttpRequest, Responder, web::{Json, Query}, HttpResponse};
+use log::error;
+use serde::{Serialize, Deserialize};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::auth::token::{jwt::{JwtClaims, Scope, TokenHeader}, is_first_party};
+use super::{OAuthError, OAuthErrorCode, CodeChallengeMethod};
+
+/// the parameters of an authorization request, as sent by the client in the
+/// query string of the link it sends the user to
+#[derive(Serialize, Deserialize)]
+pub struct AuthorizationRequest {
+    response_type: String,
+    client_id: Uuid,
+    redirect_uri: String,
+    #[serde(default)]
+    scope: String,
+    state: Option<String>,
+    code_challenge: Option<String>,
+    code_challenge_method: Option<String>,
+}
+
+/// what the user decided on the consent screen
+#[derive(Serialize, Deserialize)]
+pub struct AuthorizationDecision {
+    #[serde(flatten)]
+    request: AuthorizationRequest,
+    approve: bool,
+}
+
+/// everything needed to show the user a consent screen
+#[derive(Serialize)]
+struct ConsentScreen {
+    client_id: Uuid,
+    client_name: String,
+    requested_scopes: Vec<Scope>,
+    /// scopes the user already consented to in the past
+    granted_scopes: Vec<Scope>,
+}
+
+struct ValidRequest {
+    client_name: String,
+    scopes: Vec<Scope>,
+    granted_scopes: Vec<Scope>,
+    code_challenge: Option<(String, CodeChallengeMethod)>,
+}
+
+/// validates an authorization request against the client's registration.
+/// errors on the client or redirect URI must not be redirected, since the
+/// redirect URI can't be trusted, so they're just returned to the user
+async fn validate_request(pool: &PgPool, user_id: Uuid, request: &AuthorizationRequest) -> Result<ValidRequest, HttpResponse> {
+    let client = sqlx::query!(r"SELECT name, redirect_uris, scopes, client_secret_hash IS NULL AS public
+        FROM oauth_clients
+        WHERE client_id = $1;",
+        request.client_id
+    ).fetch_optional(pool).await;
+
+    let client = match client {
+        Ok(Some(o)) => o,
+        Ok(None) => return Err(OAuthError::new(OAuthErrorCode::InvalidClient, "unknown client").response()),
+        Err(e) => {
+            error!("failed fetching oauth client: {e}");
+            return Err(OAuthError::new(OAuthErrorCode::ServerError, "").response());
+        }
+    };
+
+    if !client.redirect_uris.contains(&request.redirect_uri) {
+        return Err(OAuthError::new(OAuthErrorCode::InvalidRequest, "redirect_uri is not registered for this client").response());
+    }
+
+    if request.response_type != "code" {
+        return Err(error_redirect(request, OAuthErrorCode::UnsupportedResponseType));
+    }
+
+    let allowed_scopes = super::scopes_from_names(&client.scopes);
+    let scopes = match super::parse_scopes(&request.scope) {
+        Some(o) if !o.is_empty() && o.iter().all(|s| allowed_scopes.contains(s)) => o,
+        _ => return Err(error_redirect(request, OAuthErrorCode::InvalidScope)),
+    };
+
+    let code_challenge = match (&request.code_challenge, &request.code_challenge_method) {
+        (Some(challenge), method) => {
+            // plain is the default according to the RFC
+            match CodeChallengeMethod::from_name(method.as_deref().unwrap_or("plain")) {
+                Some(m) => Some((challenge.clone(), m)),
+                None => return Err(error_redirect(request, OAuthErrorCode::InvalidRequest)),
+            }
+        },
+        (None, _) => None,
+    };
+
+    // public clients can't keep a secret, so PKCE is the only thing stopping
+    // an intercepted code from being used
+    if client.public.unwrap_or(true) && code_challenge.is_none() {
+        return Err(error_redirect(request, OAuthErrorCode::InvalidRequest));
+    }
+
+    let granted = sqlx::query!(r"SELECT scopes FROM oauth_consents
+        WHERE user_id = $1 AND client_id = $2;",
+        user_id,
+        request.client_id
+    ).fetch_optional(pool).await;
+
+    let granted_scopes = match granted {
+        Ok(o) => o.map(|o| super::scopes_from_names(&o.scopes)).unwrap_or_default(),
+        Err(e) => {
+            error!("failed fetching oauth consent: {e}");
+            return Err(OAuthError::new(OAuthErrorCode::ServerError, "").response());
+        }
+    };
+
+    Ok(ValidRequest {
+        client_name: client.name,
+        scopes,
+        granted_scopes,
+        code_challenge,
+    })
+}
+
+/// sends the user back to the client with the given query parameters added
+/// to the redirect URI
+fn redirect(request: &AuthorizationRequest, params: &[(&str, &str)]) -> HttpResponse {
+    let mut uri = match url::Url::parse(&request.redirect_uri) {
+        Ok(o) => o,
+        Err(_) => return HttpResponse::BadRequest().finish(),
+    };
+    {
+        let mut query = uri.query_pairs_mut();
+        for (key, value) in params {
+            query.append_pair(key, value);
+        }
+        if let Some(state) = &request.state {
+            query.append_pair("state", state);
+        }
+    }
+    HttpResponse::Found()
+        .insert_header((actix_web::http::header::LOCATION, uri.as_str()))
+        .finish()
+}
+
+fn error_redirect(request: &AuthorizationRequest, error: OAuthErrorCode) -> HttpResponse {
+    let code = match serde_json::to_value(&error) {
+        Ok(serde_json::Value::String(s)) => s,
+        _ => unreachable!("error codes always serialize to strings"),
+    };
+    redirect(request, &[("error", &code)])
+}
+
+/// returns the information needed to show the user a consent screen for an
+/// authorization request, or an error if the request is invalid
+pub async fn consent_screen(req: HttpRequest, claims: JwtClaims, header: TokenHeader, request: Query<AuthorizationRequest>) -> impl Responder {
+    if !is_first_party(&claims, &header) {
+        return HttpResponse::Forbidden().finish();
+    }
+
+    let pool = req.app_data::<PgPool>().unwrap();
+
+    match validate_request(pool, claims.subject(), &request).await {
+        Ok(o) => {
+            return HttpResponse::Ok().json(ConsentScreen {
+                client_id: request.client_id,
+                client_name: o.client_name,
+                requested_scopes: o.scopes,
+                granted_scopes: o.granted_scopes,
+            });
+        },
+        Err(e) => return e,
+    }
+}
+
+/// records the user's decision on the consent screen and, if they approved,
+/// redirects back to the client with an authorization code
+pub async fn authorize(req: HttpRequest, claims: JwtClaims, header: TokenHeader, decision: Json<AuthorizationDecision>) -> impl Responder {
+    if !is_first_party(&claims, &header) {
+        return HttpResponse::Forbidden().finish();
+    }
+
+    let pool = req.app_data::<PgPool>().unwrap();
+    let request = &decision.request;
+
+    let valid = match validate_request(pool, claims.subject(), request).await {
+        Ok(o) => o,
+        Err(e) => return e,
+    };
+
+    if !decision.approve {
+        return error_redirect(request, OAuthErrorCode::AccessDenied);
+    }
+
+    // users can't consent to more than they have themselves
+    if !valid.scopes.iter().all(|s| claims.scope.contains(s)) {
+        return error_redirect(request, OAuthErrorCode::InvalidScope);
+    }
+
+    let mut consented = valid.granted_scopes.clone();
+    for scope in valid.scopes.iter() {
+        if !consented.contains(scope) {
+            consented.push(scope.clone());
+        }
+    }
+
+    let code = super::generate_secret();
+    let now = chrono::Utc::now();
+
+    let inserted: Result<(), sqlx::Error> = async {
+        let mut tx = pool.begin().await?;
+
+        sqlx::query!(r"INSERT INTO oauth_consents
+            (user_id, client_id, scopes, granted_date)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (user_id, client_id) DO UPDATE
+            SET scopes = EXCLUDED.scopes, granted_date = EXCLUDED.granted_date;",
+            claims.subject(),
+            request.client_id,
+            &super::scope_names(&consented),
+            now
+        ).execute(&mut *tx).await?;
+
+        sqlx::query!(r"INSERT INTO oauth_authorization_codes
+            (code_hash, client_id, user_id, redirect_uri, scopes, code_challenge, code_challenge_method, expiration_date)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8);",
+            crate::auth::hash(code.as_bytes()),
+            request.client_id,
+            claims.subject(),
+            request.redirect_uri,
+            &super::scope_names(&valid.scopes),
+            valid.code_challenge.as_ref().map(|(c, _)| c.clone()),
+            valid.code_challenge.as_ref().map(|(_, m)| m.name()),
+            now + chrono::Duration::minutes(super::CODE_LIFETIME_MINUTES)
+        ).execute(&mut *tx).await?;
+
+        tx.commit().await
+    }.await;
+
+    match inserted {
+        Ok(_) => return redirect(request, &[("code", &code)]),
+        Err(e) => {
+            error!("failed issuing oauth authorization code: {e}");
+            return error_redirect(request, OAuthErrorCode::ServerError);
+        }
+    }
+}
@@ -0,0 +1,265 @@
+use actix_web::{HttpRequest, Responder, web::{Json, Path}, HttpResponse};
+use log::error;
+use serde::{Serialize, Deserialize};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{audit::{self, AuditEvent, EventType}, auth::token::{jwt::{JwtClaims, Scope, TokenHeader}, is_first_party}};
+
+#[derive(Serialize, Deserialize)]
+pub struct ClientRegistration {
+    name: String,
+    redirect_uris: Vec<String>,
+    scopes: Vec<Scope>,
+    /// confidential clients get a secret, public ones have to use PKCE
+    confidential: bool,
+}
+
+#[derive(Serialize)]
+struct RegisteredClient {
+    client_id: Uuid,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_secret: Option<String>,
+    name: String,
+    redirect_uris: Vec<String>,
+    scopes: Vec<Scope>,
+}
+
+#[derive(Serialize)]
+struct OAuthClient {
+    client_id: Uuid,
+    name: String,
+    redirect_uris: Vec<String>,
+    scopes: Vec<String>,
+    confidential: bool,
+    creation_date: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Serialize)]
+struct Consent {
+    client_id: Uuid,
+    client_name: String,
+    scopes: Vec<String>,
+    granted_date: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ClientRegistrationError {
+    InvalidName,
+    NoRedirectUris,
+    InvalidRedirectUri(String),
+    ScopeNotGranted(Scope),
+    NotAllowedForToken,
+}
+
+/// redirect URIs must be absolute and have no fragment. plain HTTP is only
+/// allowed for loopback addresses, so clients can be tested locally
+fn validate_redirect_uri(uri: &str) -> bool {
+    let Ok(parsed) = url::Url::parse(uri) else {
+        return false;
+    };
+    if parsed.fragment().is_some() {
+        return false;
+    }
+    match parsed.scheme() {
+        "https" => parsed.host().is_some(),
+        "http" => matches!(parsed.host_str(), Some("localhost" | "127.0.0.1" | "[::1]")),
+        _ => false,
+    }
+}
+
+/// registers a new OAuth client owned by the authenticated user. since the
+/// owner is the one asking for the scopes, they also count as having
+/// consented to them, which is what the client credentials grant uses
+pub async fn register(req: HttpRequest, claims: JwtClaims, header: TokenHeader, clientinfo: Json<ClientRegistration>) -> impl Responder {
+    let mut errors: Vec<ClientRegistrationError> = Vec::new();
+
+    if !is_first_party(&claims, &header) {
+        errors.push(ClientRegistrationError::NotAllowedForToken);
+    }
+
+    let name = clientinfo.name.trim();
+    if name.is_empty() || name.chars().count() > 64 {
+        errors.push(ClientRegistrationError::InvalidName);
+    }
+
+    if clientinfo.redirect_uris.is_empty() {
+        errors.push(ClientRegistrationError::NoRedirectUris);
+    }
+    for uri in clientinfo.redirect_uris.iter() {
+        if !validate_redirect_uri(uri) {
+            errors.push(ClientRegistrationError::InvalidRedirectUri(uri.clone()));
+        }
+    }
+
+    for scope in clientinfo.scopes.iter() {
+        if !claims.scope.contains(scope) || !scope.is_delegable() {
+            errors.push(ClientRegistrationError::ScopeNotGranted(scope.clone()));
+        }
+    }
+
+    if !errors.is_empty() {
+        return HttpResponse::BadRequest().json(errors);
+    }
+
+    let pool = req.app_data::<PgPool>().unwrap();
+
+    let client_secret = clientinfo.confidential.then(super::generate_secret);
+    let scopes = super::scope_names(&clientinfo.scopes);
+    let now = chrono::Utc::now();
+
+    let inserted: Result<Uuid, sqlx::Error> = async {
+        let mut tx = pool.begin().await?;
+
+        let client_id = sqlx::query!(r"INSERT INTO oauth_clients
+            (client_id, owner_id, name, client_secret_hash, redirect_uris, scopes, creation_date)
+            VALUES (gen_random_uuid(), $1, $2, $3, $4, $5, $6)
+            RETURNING client_id;",
+            claims.subject(),
+            name,
+            client_secret.as_ref().map(|s| crate::auth::hash(s.as_bytes())),
+            &clientinfo.redirect_uris,
+            &scopes,
+            now
+        ).fetch_one(&mut *tx).await?.client_id;
+
+        sqlx::query!(r"INSERT INTO oauth_consents
+            (user_id, client_id, scopes, granted_date)
+            VALUES ($1, $2, $3, $4);",
+            claims.subject(),
+            client_id,
+            &scopes,
+            now
+        ).execute(&mut *tx).await?;
+
+        tx.commit().await?;
+        Ok(client_id)
+    }.await;
+
+    match inserted {
+        Ok(client_id) => {
+            return HttpResponse::Created().json(RegisteredClient {
+                client_id,
+                client_secret,
+                name: name.to_string(),
+                redirect_uris: clientinfo.redirect_uris.clone(),
+                scopes: clientinfo.scopes.clone(),
+            });
+        },
+        Err(e) => {
+            error!("failed registering oauth client: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+}
+
+/// lists the OAuth clients owned by the authenticated user
+pub async fn list(req: HttpRequest, claims: JwtClaims) -> impl Responder {
+    let pool = req.app_data::<PgPool>().unwrap();
+
+    let clients = sqlx::query_as!(
+        OAuthClient,
+        r#"SELECT client_id, name, redirect_uris, scopes, client_secret_hash IS NOT NULL AS "confidential!", creation_date
+        FROM oauth_clients
+        WHERE owner_id = $1
+        ORDER BY creation_date DESC;"#,
+        claims.subject()
+    ).fetch_all(pool).await;
+
+    match clients {
+        Ok(o) => return HttpResponse::Ok().json(o),
+        Err(e) => {
+            error!("failed listing oauth clients: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+}
+
+/// deletes one of the authenticated user's OAuth clients, along with every
+/// consent and pending authorization code tied to it
+pub async fn delete(req: HttpRequest, claims: JwtClaims, header: TokenHeader, client_id: Path<Uuid>) -> impl Responder {
+    if !is_first_party(&claims, &header) {
+        return HttpResponse::Forbidden().finish();
+    }
+
+    let pool = req.app_data::<PgPool>().unwrap();
+
+    let deletion = sqlx::query!(r"DELETE FROM oauth_clients
+        WHERE client_id = $1 AND owner_id = $2;",
+        client_id.into_inner(),
+        claims.subject()
+    ).execute(pool).await;
+
+    match deletion {
+        Ok(o) => {
+            if o.rows_affected() == 0 {
+                return HttpResponse::NotFound().finish();
+            } else {
+                return HttpResponse::NoContent().finish();
+            }
+        },
+        Err(e) => {
+            error!("failed deleting oauth client: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+}
+
+/// lists the OAuth clients the authenticated user has granted access to
+pub async fn list_consents(req: HttpRequest, claims: JwtClaims) -> impl Responder {
+    let pool = req.app_data::<PgPool>().unwrap();
+
+    let consents = sqlx::query_as!(
+        Consent,
+        r"SELECT oauth_consents.client_id, oauth_clients.name AS client_name, oauth_consents.scopes, oauth_consents.granted_date
+        FROM oauth_consents
+        JOIN oauth_clients ON oauth_clients.client_id = oauth_consents.client_id
+        WHERE user_id = $1
+        ORDER BY granted_date DESC;",
+        claims.subject()
+    ).fetch_all(pool).await;
+
+    match consents {
+        Ok(o) => return HttpResponse::Ok().json(o),
+        Err(e) => {
+            error!("failed listing oauth consents: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+}
+
+/// withdraws the consent given to an OAuth client, so it can't get new tokens
+/// for the user without asking again
+pub async fn revoke_consent(req: HttpRequest, claims: JwtClaims, header: TokenHeader, client_id: Path<Uuid>) -> impl Responder {
+    if !is_first_party(&claims, &header) {
+        return HttpResponse::Forbidden().finish();
+    }
+
+    let pool = req.app_data::<PgPool>().unwrap();
+    let client_id = client_id.into_inner();
+
+    let deletion = sqlx::query!(r"DELETE FROM oauth_consents
+        WHERE client_id = $1 AND user_id = $2;",
+        client_id,
+        claims.subject()
+    ).execute(pool).await;
+
+    match deletion {
+        Ok(o) => {
+            if o.rows_affected() == 0 {
+                return HttpResponse::NotFound().finish();
+            } else {
+                audit::record(&req, AuditEvent::new(EventType::ConsentWithdrawn, Some(claims.subject()))
+                    .by(&claims)
+                    .with_details(serde_json::json!({ "client_id": client_id }))
+                ).await;
+                return HttpResponse::NoContent().finish();
+            }
+        },
+        Err(e) => {
+            error!("failed revoking oauth consent: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+}
@@ -0,0 +1,192 @@
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use rand::RngCore;
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+
+use super::token::jwt::Scope;
+
+pub mod authorize;
+pub mod clients;
+pub mod token;
+
+/// how long authorization codes can be exchanged for, in minutes
+const CODE_LIFETIME_MINUTES: i64 = 10;
+
+/// how long access tokens issued to OAuth clients are valid for, in minutes
+const ACCESS_TOKEN_LIFETIME_MINUTES: i64 = 60;
+
+/// error codes from [RFC 6749](https://www.rfc-editor.org/rfc/rfc6749#section-5.2),
+/// used both on the token endpoint and in authorization error redirects
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OAuthErrorCode {
+    InvalidRequest,
+    InvalidClient,
+    InvalidGrant,
+    UnauthorizedClient,
+    UnsupportedGrantType,
+    UnsupportedResponseType,
+    InvalidScope,
+    AccessDenied,
+    ServerError,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OAuthError {
+    error: OAuthErrorCode,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_description: Option<&'static str>,
+}
+
+impl OAuthError {
+    fn new(error: OAuthErrorCode, description: &'static str) -> Self {
+        Self {
+            error,
+            error_description: Some(description),
+        }
+    }
+
+    fn response(&self) -> actix_web::HttpResponse {
+        let mut res = match self.error {
+            OAuthErrorCode::InvalidClient => actix_web::HttpResponse::Unauthorized(),
+            OAuthErrorCode::ServerError => actix_web::HttpResponse::InternalServerError(),
+            _ => actix_web::HttpResponse::BadRequest(),
+        };
+        res.json(self)
+    }
+}
+
+/// generates a random, URL-safe secret for client secrets and authorization codes
+fn generate_secret() -> String {
+    let mut secret = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut secret);
+    BASE64_URL_SAFE_NO_PAD.encode(secret)
+}
+
+/// parses a space-delimited OAuth scope string, failing on any unknown scope
+/// or one that clients can't be given
+fn parse_scopes(scopes: &str) -> Option<Vec<Scope>> {
+    let mut parsed = Vec::new();
+    for name in scopes.split(' ').filter(|s| !s.is_empty()) {
+        let scope = Scope::from_name(name).filter(Scope::is_delegable)?;
+        if !parsed.contains(&scope) {
+            parsed.push(scope);
+        }
+    }
+    Some(parsed)
+}
+
+fn format_scopes(scopes: &[Scope]) -> String {
+    scopes.iter().map(Scope::name).collect::<Vec<String>>().join(" ")
+}
+
+fn scope_names(scopes: &[Scope]) -> Vec<String> {
+    scopes.iter().map(Scope::name).collect()
+}
+
+fn scopes_from_names(names: &[String]) -> Vec<Scope> {
+    names.iter().filter_map(|s| Scope::from_name(s)).collect()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CodeChallengeMethod {
+    #[serde(rename = "S256")]
+    S256,
+    #[serde(rename = "plain")]
+    Plain,
+}
+
+impl CodeChallengeMethod {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::S256 => "S256",
+            Self::Plain => "plain",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "S256" => Some(Self::S256),
+            "plain" => Some(Self::Plain),
+            _ => None,
+        }
+    }
+}
+
+/// checks a PKCE code verifier against the challenge sent when the
+/// authorization code was requested, as described in
+/// [RFC 7636](https://www.rfc-editor.org/rfc/rfc7636#section-4.6)
+fn verify_code_challenge(verifier: &str, challenge: &str, method: CodeChallengeMethod) -> bool {
+    // verifiers are 43 to 128 characters from the unreserved URI character set
+    if verifier.len() < 43 || verifier.len() > 128 {
+        return false;
+    }
+    if !verifier.chars().all(|c| c.is_ascii_alphanumeric() || ['-', '.', '_', '~'].contains(&c)) {
+        return false;
+    }
+
+    match method {
+        CodeChallengeMethod::S256 => {
+            BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes())) == challenge
+        },
+        CodeChallengeMethod::Plain => verifier == challenge,
+    }
+}
+
+/// adds the OAuth 2.0 authorization server endpoints to the service
+pub fn config(cfg: &mut actix_web::web::ServiceConfig) {
+    use actix_web::web;
+    use super::token::ScopeValidator;
+    use crate::store::Idempotent;
+
+    cfg.route("/token", web::post().to(token::token))
+        .service(
+            web::scope("/authorize")
+                .wrap(ScopeValidator::new(&[Scope::User]))
+                .route("", web::get().to(authorize::consent_screen))
+                .route("", web::post().to(authorize::authorize))
+        )
+        .service(
+            web::scope("/clients")
+                .wrap(ScopeValidator::new(&[Scope::User]))
+                .service(
+                    web::resource("")
+                        .wrap(Idempotent::withholding())
+                        .route(web::post().to(clients::register))
+                        .route(web::get().to(clients::list))
+                )
+                .route("/{client_id}", web::delete().to(clients::delete))
+        )
+        .service(
+            web::scope("/consents")
+                .wrap(ScopeValidator::new(&[Scope::User]))
+                .route("", web::get().to(clients::list_consents))
+                .route("/{client_id}", web::delete().to(clients::revoke_consent))
+        );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{verify_code_challenge, CodeChallengeMethod, parse_scopes};
+    use crate::auth::token::jwt::Scope;
+
+    #[test]
+    fn test_pkce() {
+        // example from RFC 7636, appendix B
+        let verifier = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+        let challenge = "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM";
+
+        assert!(verify_code_challenge(verifier, challenge, CodeChallengeMethod::S256));
+        assert!(!verify_code_challenge(verifier, verifier, CodeChallengeMethod::S256));
+        assert!(verify_code_challenge(verifier, verifier, CodeChallengeMethod::Plain));
+        assert!(!verify_code_challenge("too-short", "too-short", CodeChallengeMethod::Plain));
+    }
+
+    #[test]
+    fn test_parse_scopes() {
+        assert_eq!(parse_scopes("user  user_info user"), Some(vec![Scope::User, Scope::UserInfo]));
+        assert_eq!(parse_scopes(""), Some(vec![]));
+        assert_eq!(parse_scopes("user admin"), None);
+        assert_eq!(parse_scopes("user mfa_pending"), None);
+    }
+}
@@ -0,0 +1,235 @@
+use actix_web::{HttpRequest, Responder, web::Form, HttpResponse};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use log::error;
+use serde::{Serialize, Deserialize};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::auth::token::jwt::{JwtClaims, Scope};
+use super::{OAuthError, OAuthErrorCode, CodeChallengeMethod};
+
+/// a token request, which per the RFC is sent form-encoded
+#[derive(Serialize, Deserialize)]
+pub struct TokenRequest {
+    grant_type: String,
+    code: Option<String>,
+    redirect_uri: Option<String>,
+    client_id: Option<Uuid>,
+    client_secret: Option<String>,
+    code_verifier: Option<String>,
+    scope: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    token_type: &'static str,
+    expires_in: i64,
+    scope: String,
+}
+
+struct AuthenticatedClient {
+    client_id: Uuid,
+    owner_id: Uuid,
+    scopes: Vec<Scope>,
+    confidential: bool,
+}
+
+/// gets the client's credentials out of the `Authorization` header using the
+/// basic scheme, falling back to the ones in the request body
+fn client_credentials(req: &HttpRequest, form: &TokenRequest) -> Option<(Uuid, Option<String>)> {
+    if let Some(header) = req.headers().get("Authorization") {
+        let (scheme, credentials) = header.to_str().ok()?.split_once(' ')?;
+        if scheme != "Basic" {
+            return None;
+        }
+        let decoded = String::from_utf8(BASE64_STANDARD.decode(credentials).ok()?).ok()?;
+        let (id, secret) = decoded.split_once(':')?;
+        return Some((id.parse().ok()?, Some(secret.to_string())));
+    }
+    Some((form.client_id?, form.client_secret.clone()))
+}
+
+async fn authenticate_client(pool: &PgPool, client_id: Uuid, secret: Option<&str>) -> Result<AuthenticatedClient, OAuthError> {
+    let client = sqlx::query!(r"SELECT owner_id, client_secret_hash, scopes
+        FROM oauth_clients
+        WHERE client_id = $1;",
+        client_id
+    ).fetch_optional(pool).await;
+
+    let client = match client {
+        Ok(Some(o)) => o,
+        Ok(None) => return Err(OAuthError::new(OAuthErrorCode::InvalidClient, "unknown client")),
+        Err(e) => {
+            error!("failed fetching oauth client: {e}");
+            return Err(OAuthError::new(OAuthErrorCode::ServerError, "failed fetching client"));
+        }
+    };
+
+    if let Some(secret_hash) = &client.client_secret_hash {
+        match secret {
+            Some(s) if crate::auth::hash(s.as_bytes()) == *secret_hash => (),
+            _ => return Err(OAuthError::new(OAuthErrorCode::InvalidClient, "invalid client credentials")),
+        }
+    }
+
+    Ok(AuthenticatedClient {
+        client_id,
+        owner_id: client.owner_id,
+        scopes: super::scopes_from_names(&client.scopes),
+        confidential: client.client_secret_hash.is_some(),
+    })
+}
+
+/// the scopes the user currently consents to giving the client, which might
+/// be fewer than when the code was issued if they've withdrawn consent since
+async fn consented_scopes(pool: &PgPool, user_id: Uuid, client_id: Uuid) -> Result<Vec<Scope>, OAuthError> {
+    let consent = sqlx::query!(r"SELECT scopes FROM oauth_consents
+        WHERE user_id = $1 AND client_id = $2;",
+        user_id,
+        client_id
+    ).fetch_optional(pool).await;
+
+    match consent {
+        Ok(o) => Ok(o.map(|o| super::scopes_from_names(&o.scopes)).unwrap_or_default()),
+        Err(e) => {
+            error!("failed fetching oauth consent: {e}");
+            Err(OAuthError::new(OAuthErrorCode::ServerError, "failed fetching consent"))
+        }
+    }
+}
+
+/// exchanges a single-use authorization code for an access token
+async fn authorization_code_grant(pool: &PgPool, client: &AuthenticatedClient, form: &TokenRequest) -> Result<(Uuid, Vec<Scope>), OAuthError> {
+    let (Some(code), Some(redirect_uri)) = (&form.code, &form.redirect_uri) else {
+        return Err(OAuthError::new(OAuthErrorCode::InvalidRequest, "code and redirect_uri are required"));
+    };
+
+    // only codes issued to this client and redirect URI are looked at, so
+    // nobody else holding the code can use it up
+    let found = sqlx::query!(r"SELECT user_id, scopes, code_challenge, code_challenge_method
+        FROM oauth_authorization_codes
+        WHERE code_hash = $1 AND NOT used AND client_id = $2 AND redirect_uri = $3 AND expiration_date > $4;",
+        crate::auth::hash(code.as_bytes()),
+        client.client_id,
+        redirect_uri,
+        chrono::Utc::now()
+    ).fetch_optional(pool).await;
+
+    let found = match found {
+        Ok(Some(o)) => o,
+        Ok(None) => return Err(OAuthError::new(OAuthErrorCode::InvalidGrant, "invalid authorization code")),
+        Err(e) => {
+            error!("failed fetching oauth authorization code: {e}");
+            return Err(OAuthError::new(OAuthErrorCode::ServerError, "failed fetching authorization code"));
+        }
+    };
+
+    if let Some(challenge) = &found.code_challenge {
+        let method = found.code_challenge_method.as_deref()
+            .and_then(CodeChallengeMethod::from_name)
+            .unwrap_or(CodeChallengeMethod::Plain);
+        match &form.code_verifier {
+            Some(verifier) if super::verify_code_challenge(verifier, challenge, method) => (),
+            _ => return Err(OAuthError::new(OAuthErrorCode::InvalidGrant, "invalid code_verifier")),
+        }
+    }
+
+    // only one of several concurrent exchanges gets to use it
+    let used = sqlx::query!(r"UPDATE oauth_authorization_codes
+        SET used = true
+        WHERE code_hash = $1 AND NOT used AND client_id = $2;",
+        crate::auth::hash(code.as_bytes()),
+        client.client_id
+    ).execute(pool).await;
+
+    match used {
+        Ok(o) if o.rows_affected() == 0 => return Err(OAuthError::new(OAuthErrorCode::InvalidGrant, "invalid authorization code")),
+        Ok(_) => (),
+        Err(e) => {
+            error!("failed using oauth authorization code: {e}");
+            return Err(OAuthError::new(OAuthErrorCode::ServerError, "failed using authorization code"));
+        }
+    }
+
+    let consented = consented_scopes(pool, found.user_id, client.client_id).await?;
+    let scopes: Vec<Scope> = super::scopes_from_names(&found.scopes)
+        .into_iter()
+        .filter(|s| consented.contains(s))
+        .collect();
+
+    if scopes.is_empty() {
+        return Err(OAuthError::new(OAuthErrorCode::InvalidGrant, "consent has been withdrawn"));
+    }
+
+    Ok((found.user_id, scopes))
+}
+
+/// issues a token for the client to act on behalf of the user who registered
+/// it, with the scopes the owner consented to when registering
+async fn client_credentials_grant(pool: &PgPool, client: &AuthenticatedClient, form: &TokenRequest) -> Result<(Uuid, Vec<Scope>), OAuthError> {
+    if !client.confidential {
+        return Err(OAuthError::new(OAuthErrorCode::UnauthorizedClient, "public clients can't use the client credentials grant"));
+    }
+
+    let consented = consented_scopes(pool, client.owner_id, client.client_id).await?;
+    let allowed: Vec<Scope> = client.scopes.iter()
+        .filter(|s| consented.contains(s))
+        .cloned()
+        .collect();
+
+    let scopes = match form.scope.as_deref().map(super::parse_scopes) {
+        None => allowed,
+        Some(Some(requested)) if !requested.is_empty() && requested.iter().all(|s| allowed.contains(s)) => requested,
+        Some(_) => return Err(OAuthError::new(OAuthErrorCode::InvalidScope, "scope exceeds what was consented to")),
+    };
+
+    if scopes.is_empty() {
+        return Err(OAuthError::new(OAuthErrorCode::InvalidScope, "client has no consented scopes"));
+    }
+
+    Ok((client.owner_id, scopes))
+}
+
+/// the token endpoint of the authorization server, supporting the
+/// `authorization_code` and `client_credentials` grants
+pub async fn token(req: HttpRequest, form: Form<TokenRequest>) -> impl Responder {
+    let pool = req.app_data::<PgPool>().unwrap();
+
+    let Some((client_id, secret)) = client_credentials(&req, &form) else {
+        return OAuthError::new(OAuthErrorCode::InvalidClient, "missing client credentials").response();
+    };
+
+    let client = match authenticate_client(pool, client_id, secret.as_deref()).await {
+        Ok(o) => o,
+        Err(e) => return e.response(),
+    };
+
+    let granted = match form.grant_type.as_str() {
+        "authorization_code" => authorization_code_grant(pool, &client, &form).await,
+        "client_credentials" => client_credentials_grant(pool, &client, &form).await,
+        _ => Err(OAuthError::new(OAuthErrorCode::UnsupportedGrantType, "unsupported grant_type")),
+    };
+
+    match granted {
+        Ok((subject, scopes)) => {
+            let expires_in = super::ACCESS_TOKEN_LIFETIME_MINUTES * 60;
+            let scope = super::format_scopes(&scopes);
+            let token = JwtClaims::new(
+                scopes,
+                subject,
+                chrono::Utc::now() + chrono::Duration::seconds(expires_in)
+            ).with_authorized_party(client.client_id).generate_token();
+
+            return HttpResponse::Ok()
+                .insert_header((actix_web::http::header::CACHE_CONTROL, "no-store"))
+                .json(TokenResponse {
+                    access_token: token,
+                    token_type: "Bearer",
+                    expires_in,
+                    scope,
+                });
+        },
+        Err(e) => return e.response(),
+    }
+}
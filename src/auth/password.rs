@@ -0,0 +1,309 @@
+use std::sync::Arc;
+
+use actix_web::{HttpRequest, Responder, web::Json, HttpResponse, ResponseError};
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use log::error;
+use rand::RngCore;
+use serde::{Serialize, Deserialize};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{audit::{self, AuditEvent, EventType}, mail::{self, Email, Mailer}, store::{RateLimit, SessionStore, StoreError}};
+
+use super::{
+    policy::InvalidPasswordError,
+    throttle::{self, ThrottleKey},
+    token::jwt::{JwtClaims, TokenHeader},
+};
+
+/// how long a reset token can be used for, in minutes
+const RESET_TOKEN_MINUTES: i64 = 30;
+
+/// how long to wait before sending another reset email to the same user
+const RESET_COOLDOWN: RateLimit = RateLimit {
+    name: "password_reset_cooldown",
+    limit: 1,
+    window: std::time::Duration::from_secs(60),
+};
+
+/// reset emails that can be asked for from a single address, whoever they
+/// are for
+const RESET_LIMIT: RateLimit = RateLimit {
+    name: "password_reset",
+    limit: 10,
+    window: std::time::Duration::from_secs(60 * 60),
+};
+
+#[derive(Serialize, Deserialize)]
+pub struct PasswordChange {
+    current_password: String,
+    new_password: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ResetRequest {
+    email: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ResetConfirmation {
+    token: String,
+    new_password: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum PasswordError {
+    InvalidPassword(InvalidPasswordError),
+    InvalidToken,
+    NotAllowedForToken,
+}
+
+/// replaces the user's password and logs them out everywhere, revoking
+/// their personal access tokens too, since whoever knew the old password
+/// could have made some. returns their username
+async fn set_password(pool: &PgPool, user_id: Uuid, passwd: &str) -> Result<String, HttpResponse> {
+    let hash = match super::hash_password(passwd).await {
+        Ok(o) => o,
+        Err(e) => return Err(e.error_response()),
+    };
+
+    let update = async {
+        let mut transaction = pool.begin().await?;
+        let username = sqlx::query!(r"UPDATE users
+            SET password = $2, password_pepper_id = $3, sessions_revoked_date = $4
+            WHERE user_id = $1
+            RETURNING username;",
+            user_id,
+            hash.encoded,
+            hash.pepper_id,
+            chrono::Utc::now()
+        ).fetch_one(&mut *transaction).await?.username;
+        // any reset links still around were meant for the old password
+        sqlx::query!(r"DELETE FROM password_reset_tokens WHERE user_id = $1;", user_id)
+            .execute(&mut *transaction).await?;
+        sqlx::query!(r"DELETE FROM sessions WHERE user_id = $1;", user_id)
+            .execute(&mut *transaction).await?;
+        sqlx::query!(r"UPDATE personal_access_tokens SET revoked = true WHERE user_id = $1 AND NOT revoked;", user_id)
+            .execute(&mut *transaction).await?;
+        transaction.commit().await?;
+        Ok::<_, sqlx::Error>(username)
+    };
+
+    match update.await {
+        Ok(o) => return Ok(o),
+        Err(e) => {
+            error!("failed updating password: {e}");
+            return Err(HttpResponse::InternalServerError().finish());
+        }
+    }
+}
+
+/// makes sure the user knows their current password before changing their
+/// credentials, returning the user if they do. failures count towards
+/// locking the user out, guessing the password here is no different from
+/// guessing it at the login. failures are recorded as the given event
+/// having failed
+pub(crate) async fn check_current_password(req: &HttpRequest, user_id: Uuid, passwd: &str, event: &AuditEvent) -> Result<super::DbUser, HttpResponse> {
+    let pool = req.app_data::<PgPool>().unwrap();
+    let selection = sqlx::query_as!(
+        super::DbUser,
+        "SELECT * FROM users WHERE user_id = $1;",
+        user_id
+    ).fetch_one(pool).await;
+    let user = match selection {
+        Ok(o) => o,
+        Err(e) => {
+            error!("failed fetching user: {e}");
+            return Err(HttpResponse::InternalServerError().finish());
+        }
+    };
+
+    // fetched before anything is checked, so failures are always counted
+    let store = crate::app_data::<Arc<dyn SessionStore>>(req)?.as_ref();
+    let mailer = crate::app_data::<Arc<dyn Mailer>>(req)?;
+    let key = ThrottleKey::Username(&user.username, req.peer_addr().map(|a| a.ip()));
+    match throttle::locked_until(store, &[key]).await {
+        Ok(Some(until)) => {
+            audit::record(req, event.clone().failed("throttled")).await;
+            return Err(super::login::too_many_attempts(until));
+        },
+        Ok(None) => (),
+        Err(e) => {
+            error!("failed checking login throttling: {e}");
+            return Err(HttpResponse::InternalServerError().finish());
+        }
+    }
+
+    let matches = match super::verify_password(&user.password, user.password_pepper_id.as_deref(), passwd).await {
+        Ok(o) => o,
+        Err(e) => return Err(e.error_response()),
+    };
+    if !matches {
+        if let Err(e) = throttle::record_failure(store, pool, mailer, key).await {
+            error!("failed recording failed password check: {e}");
+        }
+        audit::record(req, event.clone().failed("wrong_password")).await;
+        return Err(HttpResponse::Forbidden().finish());
+    }
+
+    return Ok(user);
+}
+
+/// changes the password of the authenticated user, who has to know their
+/// current one. every other session is logged out, so a new login token is
+/// returned for the current one
+pub async fn change(req: HttpRequest, claims: JwtClaims, header: TokenHeader, change: Json<PasswordChange>) -> impl Responder {
+    if !super::token::is_first_party(&claims, &header) {
+        return HttpResponse::BadRequest().json(vec![PasswordError::NotAllowedForToken]);
+    }
+
+    let pool = req.app_data::<PgPool>().unwrap();
+    let event = AuditEvent::new(EventType::PasswordChange, Some(claims.subject())).by(&claims);
+
+    let user = match check_current_password(&req, claims.subject(), &change.current_password, &event).await {
+        Ok(o) => o,
+        Err(response) => return response,
+    };
+
+    if let Err(e) = super::policy::current().check(&change.new_password, &user.username, &user.email) {
+        return HttpResponse::BadRequest().json(vec![PasswordError::InvalidPassword(e)]);
+    }
+
+    match set_password(pool, user.user_id, &change.new_password).await {
+        Ok(_) => {
+            audit::record(&req, event).await;
+            return super::login::full_login_response(&req, pool, user.user_id).await;
+        },
+        Err(response) => return response,
+    }
+}
+
+/// a random, URL-safe token for sending to users by email
+pub(crate) fn generate_emailed_token() -> String {
+    let mut secret = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut secret);
+    BASE64_URL_SAFE_NO_PAD.encode(secret)
+}
+
+/// emails a single-use reset token to the user with the given address. the
+/// response is the same whether there is such a user or not
+pub async fn request_reset(req: HttpRequest, request: Json<ResetRequest>) -> impl Responder {
+    if let Err(response) = super::login::limit_by_address(&req, &RESET_LIMIT).await {
+        return response;
+    }
+
+    let pool = req.app_data::<PgPool>().unwrap().clone();
+    let mailer = match crate::app_data::<Arc<dyn Mailer>>(&req) {
+        Ok(o) => Arc::clone(o),
+        Err(response) => return response,
+    };
+    let store = match crate::app_data::<Arc<dyn SessionStore>>(&req) {
+        Ok(o) => Arc::clone(o),
+        Err(response) => return response,
+    };
+
+    // looked up after responding, so how long it takes doesn't give away
+    // whether there is such a user
+    let email = super::canonical::email(&request.email);
+    tokio::spawn(async move {
+        if let Err(e) = send_reset(&pool, &mailer, store.as_ref(), &email).await {
+            error!("failed sending password reset token: {e}");
+        }
+    });
+
+    return HttpResponse::Accepted().finish();
+}
+
+/// emails a reset token to the user with the address, unless there is none
+/// or one was sent a moment ago
+async fn send_reset(pool: &PgPool, mailer: &Arc<dyn Mailer>, store: &dyn SessionStore, email: &str) -> Result<(), StoreError> {
+    let now = chrono::Utc::now();
+    let user = sqlx::query!(r"SELECT user_id, email FROM users WHERE email = $1;", email)
+        .fetch_optional(pool).await?;
+    let Some(user) = user else {
+        return Ok(());
+    };
+    if RESET_COOLDOWN.hit(store, &user.user_id.to_string()).await?.is_some() {
+        return Ok(());
+    }
+
+    let token = generate_emailed_token();
+    sqlx::query!(r"INSERT INTO password_reset_tokens
+        (token_hash, user_id, creation_date, expiration_date)
+        VALUES ($1, $2, $3, $4);",
+        super::hash(token.as_bytes()),
+        user.user_id,
+        now,
+        now + chrono::Duration::minutes(RESET_TOKEN_MINUTES)
+    ).execute(pool).await?;
+
+    mail::send_in_background(mailer, Email {
+        to: user.email,
+        subject: "Resetting your password".to_string(),
+        body: format!(
+            "Someone asked to reset the password of your cyber_bank_rs account.\n\n\
+            If it was you, use this token to choose a new one within the next \
+            {RESET_TOKEN_MINUTES} minutes:\n\n{token}\n\n\
+            If it wasn't, you can ignore this email."
+        ),
+    });
+    Ok(())
+}
+
+/// sets a new password using a token from [`request_reset`], which can only
+/// be used once. logs the user out everywhere and lifts the lockout for the
+/// address the reset comes from
+pub async fn confirm_reset(req: HttpRequest, confirmation: Json<ResetConfirmation>) -> impl Responder {
+    let pool = req.app_data::<PgPool>().unwrap();
+    let token_hash = super::hash(confirmation.token.as_bytes());
+
+    // checked before the token is used up, so a rejected password doesn't
+    // need a new email
+    let selection = sqlx::query!(r"SELECT users.username, users.email FROM password_reset_tokens t
+        JOIN users ON users.user_id = t.user_id
+        WHERE t.token_hash = $1 AND t.expiration_date > $2;",
+        token_hash,
+        chrono::Utc::now()
+    ).fetch_optional(pool).await;
+    let user = match selection {
+        Ok(Some(o)) => o,
+        Ok(None) => return HttpResponse::BadRequest().json(vec![PasswordError::InvalidToken]),
+        Err(e) => {
+            error!("failed fetching password reset token: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    if let Err(e) = super::policy::current().check(&confirmation.new_password, &user.username, &user.email) {
+        return HttpResponse::BadRequest().json(vec![PasswordError::InvalidPassword(e)]);
+    }
+
+    let consumed = sqlx::query!(r"DELETE FROM password_reset_tokens
+        WHERE token_hash = $1
+        RETURNING user_id, expiration_date;",
+        token_hash
+    ).fetch_optional(pool).await;
+    let user_id = match consumed {
+        Ok(Some(o)) if o.expiration_date > chrono::Utc::now() => o.user_id,
+        Ok(_) => return HttpResponse::BadRequest().json(vec![PasswordError::InvalidToken]),
+        Err(e) => {
+            error!("failed consuming password reset token: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    match set_password(pool, user_id, &confirmation.new_password).await {
+        Ok(username) => {
+            let store = match crate::app_data::<Arc<dyn SessionStore>>(&req) {
+                Ok(o) => o.as_ref(),
+                Err(response) => return response,
+            };
+            if let Err(e) = throttle::reset(store, ThrottleKey::Username(&username, req.peer_addr().map(|a| a.ip()))).await {
+                error!("failed resetting login throttling: {e}");
+            }
+            audit::record(&req, AuditEvent::new(EventType::PasswordReset, Some(user_id))).await;
+            return HttpResponse::NoContent().finish();
+        },
+        Err(response) => return response,
+    }
+}
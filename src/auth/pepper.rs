@@ -0,0 +1,78 @@
+use std::sync::OnceLock;
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+
+/// a server-side secret mixed into password hashes, so a copy of the
+/// database alone isn't enough to start guessing passwords
+pub(super) struct Pepper {
+    /// stored along with every hash made using this pepper
+    pub id: String,
+    pub secret: Vec<u8>,
+}
+
+/// parses a whitespace separated list of `{id}:{base64 secret}` peppers
+fn parse(list: &str) -> Result<Vec<Pepper>, String> {
+    list.split_whitespace()
+        .map(|entry| {
+            let (id, secret) = entry.split_once(':')
+                .ok_or_else(|| format!("pepper `{entry}` is missing its id"))?;
+            let secret = BASE64_STANDARD.decode(secret)
+                .map_err(|e| format!("pepper `{id}` is not valid base64: {e}"))?;
+            if id.is_empty() || secret.is_empty() {
+                return Err(format!("pepper `{entry}` is empty"));
+            }
+            Ok(Pepper { id: id.to_string(), secret })
+        })
+        .collect()
+}
+
+static PEPPERS: OnceLock<Vec<Pepper>> = OnceLock::new();
+
+/// every known pepper, kept in the same keyring as the token signing key
+/// under `{user}.password_peppers`. there are none unless that entry was
+/// created, in which case passwords aren't peppered at all
+fn peppers() -> &'static [Pepper] {
+    PEPPERS.get_or_init(|| {
+        #[cfg(debug_assertions)]
+        {
+            parse("debug:ZGVidWc=").unwrap()
+        }
+        #[cfg(not(debug_assertions))]
+        {
+            match super::keyring_entry(Some("password_peppers")).get_password() {
+                Ok(o) => parse(&o).unwrap_or_else(|e| panic!("invalid password peppers: {e}")),
+                Err(keyring::Error::NoEntry) => Vec::new(),
+                Err(e) => panic!("failed reading password peppers: {e}"),
+            }
+        }
+    })
+}
+
+/// the pepper new hashes are made with, which is the last one in the list.
+/// older ones are only kept around until every hash using them got redone
+pub(super) fn current() -> Option<&'static Pepper> {
+    peppers().last()
+}
+
+pub(super) fn find(id: &str) -> Option<&'static Pepper> {
+    peppers().iter().find(|p| p.id == id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+
+    #[test]
+    fn test_parse() {
+        let peppers = parse("2023:c2VjcmV0\n 2024:b3RoZXI= ").unwrap();
+        assert_eq!(peppers.len(), 2);
+        assert_eq!(peppers[0].id, "2023");
+        assert_eq!(peppers[0].secret, b"secret");
+        assert_eq!(peppers[1].id, "2024");
+
+        assert!(parse("").unwrap().is_empty());
+        assert!(parse("c2VjcmV0").is_err());
+        assert!(parse("2023:not base64!").is_err());
+        assert!(parse(":c2VjcmV0").is_err());
+    }
+}
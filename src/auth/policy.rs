@@ -0,0 +1,274 @@
+use std::{f64::consts::LN_2, fs, io, path::Path, sync::OnceLock};
+
+use serde::{Serialize, Deserialize};
+use sha1::{Digest, Sha1};
+use unicode_normalization::UnicodeNormalization;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InvalidPasswordError {
+    /// the length in characters, after normalization
+    InvalidLength(usize),
+    /// the estimated strength in bits, see [`estimate_strength`]
+    TooWeak(u32),
+    ContainsUsername,
+    ContainsEmail,
+    Breached,
+}
+
+/// the form passwords are checked and hashed in. NFKC normalized, so the
+/// same password typed on different keyboards or input methods is still
+/// the same password. ASCII passwords are left as they are
+pub fn normalize(passwd: &str) -> String {
+    passwd.nfkc().collect()
+}
+
+/// roughly how many bits of entropy the password has, going by the kinds of
+/// characters in it. repeating the previous character or counting up or down
+/// from it adds next to nothing. this is no match for a proper cracking
+/// dictionary, that's what the breached passwords are for
+pub fn estimate_strength(passwd: &str) -> f64 {
+    let mut pool = 0;
+    if passwd.chars().any(|c| c.is_ascii_lowercase()) {
+        pool += 26;
+    }
+    if passwd.chars().any(|c| c.is_ascii_uppercase()) {
+        pool += 26;
+    }
+    if passwd.chars().any(|c| c.is_ascii_digit()) {
+        pool += 10;
+    }
+    if passwd.chars().any(|c| c.is_ascii_punctuation() || c == ' ') {
+        pool += 33;
+    }
+    // a rough guess at how many characters of whatever script is used are
+    // likely to show up
+    if !passwd.is_ascii() {
+        pool += 100;
+    }
+    let bits_per_char = f64::from(pool.max(1)).log2();
+
+    let mut bits = 0.0;
+    let mut previous: Option<char> = None;
+    for c in passwd.chars() {
+        bits += match previous {
+            Some(p) if (c as i64 - p as i64).abs() <= 1 => 1.0,
+            _ => bits_per_char,
+        };
+        previous = Some(c);
+    }
+    bits
+}
+
+/// what passwords have to look like, along the lines of NIST SP 800-63B:
+/// long enough, not guessable and not known from a breach, without any rules
+/// about which kinds of characters to use
+pub struct PasswordPolicy {
+    /// in characters, after normalization
+    pub min_length: usize,
+    pub max_length: usize,
+    /// see [`estimate_strength`]
+    pub min_strength_bits: f64,
+    pub breached: Option<BreachedPasswords>,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        Self {
+            min_length: 8,
+            max_length: 64,
+            min_strength_bits: 40.0,
+            breached: None,
+        }
+    }
+}
+
+impl PasswordPolicy {
+    /// the default policy, changed by the `PASSWORD_MIN_LENGTH`,
+    /// `PASSWORD_MAX_LENGTH` and `PASSWORD_MIN_STRENGTH_BITS` environment
+    /// variables. breached passwords are only checked when
+    /// `BREACHED_PASSWORDS_FILE` points to a filter made by the
+    /// `breached_filter` binary
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        let breached = dotenvy::var("BREACHED_PASSWORDS_FILE").ok().map(|path| {
+            BreachedPasswords::read(&path)
+                .unwrap_or_else(|e| panic!("failed reading breached passwords from {path}: {e}"))
+        });
+
+        Self {
+            min_length: crate::env_var("PASSWORD_MIN_LENGTH", default.min_length),
+            max_length: crate::env_var("PASSWORD_MAX_LENGTH", default.max_length),
+            min_strength_bits: crate::env_var("PASSWORD_MIN_STRENGTH_BITS", default.min_strength_bits),
+            breached,
+        }
+    }
+
+    /// checks a new password of the user with the given username and email,
+    /// both in their [`canonical`](super::canonical) form
+    pub fn check(&self, passwd: &str, username: &str, email: &str) -> Result<(), InvalidPasswordError> {
+        use InvalidPasswordError as E;
+
+        let passwd = normalize(passwd);
+        let length = passwd.chars().count();
+        if length < self.min_length || length > self.max_length {
+            return Err(E::InvalidLength(length));
+        }
+
+        let lowercase = passwd.to_lowercase();
+        if !username.is_empty() && lowercase.contains(username) {
+            return Err(E::ContainsUsername);
+        }
+        // very short local parts would rule out too much
+        let local_part = email.split('@').next().unwrap_or_default();
+        if local_part.chars().count() >= 4 && lowercase.contains(local_part) {
+            return Err(E::ContainsEmail);
+        }
+
+        let strength = estimate_strength(&passwd);
+        if strength < self.min_strength_bits {
+            return Err(E::TooWeak(strength as u32));
+        }
+
+        if self.breached.as_ref().is_some_and(|b| b.contains(&passwd)) {
+            return Err(E::Breached);
+        }
+        return Ok(());
+    }
+}
+
+static POLICY: OnceLock<PasswordPolicy> = OnceLock::new();
+
+/// the policy from [`PasswordPolicy::from_env`], loaded the first time it's
+/// needed. the server loads it on startup, so a missing breached passwords
+/// file doesn't go unnoticed until someone registers
+pub fn current() -> &'static PasswordPolicy {
+    POLICY.get_or_init(PasswordPolicy::from_env)
+}
+
+/// identifies filter files, followed by the version of the format
+const FILTER_MAGIC: &[u8; 8] = b"CBRSBLM1";
+
+/// a bloom filter of the SHA-1 hashes of passwords known from breaches. it
+/// never misses a password that was added, but can report ones that weren't
+/// with a false positive rate chosen when creating it. SHA-1 because that's
+/// what corpora like Have I Been Pwned come in
+pub struct BreachedPasswords {
+    bits: Vec<u64>,
+    hashes: u32,
+}
+
+impl BreachedPasswords {
+    /// an empty filter sized for `expected` passwords at the given false
+    /// positive rate
+    pub fn new(expected: u64, false_positive_rate: f64) -> Self {
+        let expected = expected.max(1) as f64;
+        let bits = (-expected * false_positive_rate.ln() / (LN_2 * LN_2)).ceil().max(64.0);
+        let hashes = (bits / expected * LN_2).round().max(1.0);
+        Self {
+            bits: vec![0; (bits as usize).div_ceil(64)],
+            hashes: hashes as u32,
+        }
+    }
+
+    /// the bits a hash maps to, using double hashing on the hash's first 16
+    /// bytes
+    fn positions(&self, sha1: &[u8; 20]) -> impl Iterator<Item = usize> {
+        let h1 = u64::from_le_bytes(sha1[0..8].try_into().unwrap());
+        // odd, so it has no factor of two in common with the filter's length
+        let h2 = u64::from_le_bytes(sha1[8..16].try_into().unwrap()) | 1;
+        let len = self.bits.len() as u64 * 64;
+        (0..u64::from(self.hashes)).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+
+    fn sha1(passwd: &str) -> [u8; 20] {
+        Sha1::digest(normalize(passwd).as_bytes()).into()
+    }
+
+    /// adds a password by its SHA-1 hash
+    pub fn insert_sha1(&mut self, sha1: &[u8; 20]) {
+        for i in self.positions(sha1).collect::<Vec<_>>() {
+            self.bits[i / 64] |= 1 << (i % 64);
+        }
+    }
+
+    pub fn insert(&mut self, passwd: &str) {
+        self.insert_sha1(&Self::sha1(passwd));
+    }
+
+    /// whether the password is probably in the filter
+    pub fn contains(&self, passwd: &str) -> bool {
+        self.positions(&Self::sha1(passwd)).all(|i| self.bits[i / 64] & (1 << (i % 64)) != 0)
+    }
+
+    pub fn read(path: impl AsRef<Path>) -> io::Result<Self> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "not a breached passwords filter");
+
+        let data = fs::read(path)?;
+        if data.len() < 20 || &data[..8] != FILTER_MAGIC || (data.len() - 12) % 8 != 0 {
+            return Err(invalid());
+        }
+        let hashes = u32::from_le_bytes(data[8..12].try_into().unwrap());
+        if hashes == 0 {
+            return Err(invalid());
+        }
+        let bits = data[12..]
+            .chunks_exact(8)
+            .map(|word| u64::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+        Ok(Self { bits, hashes })
+    }
+
+    pub fn write(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut data = Vec::with_capacity(12 + self.bits.len() * 8);
+        data.extend_from_slice(FILTER_MAGIC);
+        data.extend_from_slice(&self.hashes.to_le_bytes());
+        for word in &self.bits {
+            data.extend_from_slice(&word.to_le_bytes());
+        }
+        fs::write(path, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{estimate_strength, BreachedPasswords, InvalidPasswordError as E, PasswordPolicy};
+
+    #[test]
+    fn test_policy() {
+        let policy = PasswordPolicy::default();
+        let check = |passwd| policy.check(passwd, "alice", "smith.j@example.com");
+
+        assert_eq!(check("Passw0rd!"), Ok(()));
+        assert_eq!(check("correct horse battery staple"), Ok(()));
+        // no ASCII required, and the fullwidth form is the same password
+        assert_eq!(check("Пароль-для-банка"), Ok(()));
+        assert_eq!(check("ｓｈｏｒｔ"), Err(E::InvalidLength(5)));
+
+        assert_eq!(check("Hi_Alice_1234"), Err(E::ContainsUsername));
+        assert_eq!(check("Smith.J_Bank99"), Err(E::ContainsEmail));
+        assert!(matches!(check("aaaaaaaaaaaa"), Err(E::TooWeak(_))));
+        assert!(matches!(check("abcdefgh12345678"), Err(E::TooWeak(_))));
+        assert!(estimate_strength("Tr0ub4dor&3") > estimate_strength("troubador"));
+    }
+
+    #[test]
+    fn test_breached_passwords() {
+        let mut filter = BreachedPasswords::new(1000, 0.001);
+        for i in 0..1000 {
+            filter.insert(&format!("breached password {i}"));
+        }
+
+        let path = std::env::temp_dir().join(format!("cyber_bank_rs_breached_{}", std::process::id()));
+        filter.write(&path).unwrap();
+        let filter = BreachedPasswords::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!((0..1000).all(|i| filter.contains(&format!("breached password {i}"))));
+        let false_positives = (0..10000).filter(|i| filter.contains(&format!("fine password {i}"))).count();
+        assert!(false_positives < 50, "{false_positives} false positives");
+
+        let policy = PasswordPolicy { breached: Some(filter), ..Default::default() };
+        assert_eq!(policy.check("breached password 7", "alice", "a@example.com"), Err(E::Breached));
+    }
+}
@@ -1,125 +1,11 @@
-use std::sync::OnceLock;
+use std::sync::Arc;
 
-use actix_web::{web::Json, HttpRequest, Responder, HttpResponse};
+use actix_web::{web::Json, HttpRequest, Responder, HttpResponse, ResponseError};
 use log::error;
-use rand::RngCore;
-use regex::Regex;
 use serde::{Serialize, Deserialize};
 use sqlx::PgPool;
 
-
-#[derive(Serialize, Deserialize)]
-#[serde(rename_all = "snake_case")]
-enum InvalidPasswordError {
-    InvalidLength(usize),
-    NotEnoughUppercaseChars(u32),
-    NotEnoughLowercaseChars(u32),
-    NotEnoughDigits(u32),
-    NotEnoughSpecialChars(u32),
-    InvalidChar(char),
-}
-
-/// checks that the password fits a number of security constraints:
-/// - length greater than 8
-/// - contains at least one lowercase ASCII character
-/// - contains at least one uppercase ASCII character
-/// - contains at least one numeric ASCII character
-/// - contains at least one "special" ASCII character, as defined by `char::is_ascii_punctuation()`
-/// - does not contain any non-ASCII characters (sorry)
-fn validate_password(passwd: &str) -> Result<(), InvalidPasswordError> {
-    use InvalidPasswordError as E;
-
-    if passwd.len() < 8 || passwd.len() > 64 {
-        return Err(E::InvalidLength(passwd.len()));
-    }
-
-    let mut uppercase_count: u32 = 0;
-    let mut lowercase_count: u32 = 0;
-    let mut number_count: u32 = 0;
-    let mut special_count: u32 = 0;
-    let mut invalid_char: Option<char> = None;
-    for i in passwd.chars() {
-        if !i.is_ascii() {
-            invalid_char = Some(i);
-            break;
-        }
-        if i.is_ascii_uppercase() {
-            uppercase_count += 1;
-        } else if i.is_ascii_lowercase() {
-            lowercase_count += 1;
-        } else if i.is_ascii_digit() {
-            number_count += 1;
-        } else if i.is_ascii_punctuation() || i == ' ' {
-            special_count += 1;
-        } else {
-            invalid_char = Some(i);
-            break;
-        }
-    }
-
-    if let Some(c) = invalid_char {
-        return Err(E::InvalidChar(c));
-    } else if uppercase_count == 0 {
-        return Err(E::NotEnoughUppercaseChars(uppercase_count));
-    } else if lowercase_count == 0 {
-        return Err(E::NotEnoughLowercaseChars(lowercase_count));
-    } else if number_count == 0 {
-        return Err(E::NotEnoughDigits(number_count));
-    } else if special_count == 0 {
-        return Err(E::NotEnoughSpecialChars(special_count));
-    } else {
-        return Ok(());
-    }
-}
-
-#[derive(Serialize, Deserialize)]
-#[serde(rename_all = "snake_case")]
-enum InvalidUsernameError {
-    InvalidLength(usize),
-    InvalidChar(char),
-    AlreadyInUse
-}
-
-/// checks that usernames fit a number of constraints:
-/// - length is greater than 4 and smaller than 32 (might be changed later)
-/// - only contains ASCII alphanumeric characters and/or the characters '.', '_' and '-'
-fn validate_username(username: &str) -> Result<(), InvalidUsernameError> {
-    use InvalidUsernameError as E;
-
-    if username.len() < 4 || username.len() > 32 {
-        return Err(E::InvalidLength(username.len()));
-    }
-
-    let mut invalid_char = None;
-    for i in username.chars() {
-        if i.is_ascii_alphanumeric() {
-            continue;
-        } else if ['.', '_', '-'].contains(&i) {
-            continue;
-        }
-        invalid_char = Some(i);
-    }
-    if let Some(c) = invalid_char {
-        return Err(E::InvalidChar(c));
-    }
-    return Ok(());
-}
-
-#[derive(Serialize, Deserialize)]
-#[serde(rename_all = "snake_case")]
-enum InvalidEmailError {
-    AlreadyInUse,
-    InvalidFormat,
-}
-
-#[derive(Serialize, Deserialize)]
-#[serde(rename_all = "snake_case")]
-enum RegisterError {
-    InvalidPassword(InvalidPasswordError),
-    InvalidUsername(InvalidUsernameError),
-    InvalidEmail(InvalidEmailError),
-    RegistrationError,
-}
+use crate::{audit::{self, AuditEvent, EventType}, mail::Mailer, store::SessionStore, validation::{self, Validate, ValidationErrors, Violation}};
 
 #[derive(Serialize, Deserialize)]
 pub struct Registerer {
@@ -128,79 +14,62 @@ pub struct Registerer {
     password: String
 }
 
-static EMAIL_VALIDATION: OnceLock<Regex> = OnceLock::new();
+impl Validate for Registerer {
+    /// expects the username and email in their [`canonical`](super::canonical)
+    /// form. usernames have to fit a number of constraints:
+    /// - length is between 4 and 32 (might be changed later)
+    /// - only contains ASCII alphanumeric characters and/or the characters '.', '_' and '-'
+    ///
+    /// passwords are checked against the [`policy`](super::policy)
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::new();
+        errors.check("username", validation::length(&self.username, 4, 32));
+        errors.check("username", validation::charset(&self.username, |c| {
+            c.is_ascii_alphanumeric() || ['.', '_', '-'].contains(&c)
+        }));
+        errors.check("email", validation::email(&self.email));
+        errors.check("password", super::policy::current().check(&self.password, &self.username, &self.email));
+        errors.into_result()
+    }
+}
 
-fn get_email_validator() -> &'static Regex {
-    EMAIL_VALIDATION.get_or_init(|| {
-        Regex::new(r"^[\w\-]+(?:\.[\w\-])*@(?:[\w\-]\.)*[\w\-]+\.[a-z]{2,6}(?:\.[a-z]{2})?$").unwrap()
-    })
+/// which of the user's details is already taken, going by the unique
+/// constraint an insert violated
+fn already_in_use(constraint: Option<&str>) -> Option<ValidationErrors> {
+    match constraint? {
+        "users_username_key" | "users_username_canonical" => {
+            return Some(ValidationErrors::single("username", Violation::AlreadyInUse));
+        },
+        "users_email_key" | "users_email_canonical" => {
+            return Some(ValidationErrors::single("email", Violation::AlreadyInUse));
+        },
+        _ => return None,
+    }
 }
 
 /// creates a user account, verifying validity of given username, email and
-/// password.
-// TODO: make sure usernames are case-insensitive and can only be lowercase
+/// password. whether the username and email are taken is left to the
+/// database's unique constraints, so concurrent registrations can't both get
+/// them. usernames and emails are stored in their [`canonical`](super::canonical)
+/// form, so they are case-insensitive
 pub async fn register(req: HttpRequest, userinfo: Json<Registerer>) -> impl Responder {
-    let mut errors: Vec<RegisterError> = Vec::new();
-
-    match validate_password(&userinfo.password) {
-        Ok(_) => (),
-        Err(e) => {
-            errors.push(RegisterError::InvalidPassword(e));
-        }
-    };
+    let mut userinfo = userinfo.into_inner();
+    userinfo.username = super::canonical::username(&userinfo.username);
+    userinfo.email = super::canonical::email(&userinfo.email);
 
-    match validate_username(&userinfo.username) {
-        Ok(_) => (),
-        Err(e) => {
-            errors.push(RegisterError::InvalidUsername(e));
-        },
-    };
-
-    if !get_email_validator().is_match(&userinfo.email) {
-        errors.push(RegisterError::InvalidEmail(InvalidEmailError::InvalidFormat));
+    if let Err(errors) = userinfo.validate() {
+        return HttpResponse::BadRequest().json(errors);
     }
 
     let pool = req.app_data::<PgPool>().unwrap();
 
-    match sqlx::query!(r"SELECT COUNT(username) FROM users
-        WHERE username = $1
-        ",
-        userinfo.username
-    ).fetch_one(pool).await {
-        Ok(o) => {
-            if let Some(count) = o.count {
-                if count != 0 {
-                    errors.push(RegisterError::InvalidUsername(InvalidUsernameError::AlreadyInUse));
-                }
-            }
-        },
-        Err(e) => panic!("{e}"),
-    };
-
-    match sqlx::query!(r"SELECT COUNT(email) FROM users
-        WHERE email = $1",
-        userinfo.email
-    ).fetch_one(pool).await {
-        Ok(o) => {
-            if let Some(count) = o.count {
-                if count != 0 {
-                    errors.push(RegisterError::InvalidEmail(InvalidEmailError::AlreadyInUse));
-                }
-            }
-        },
-        Err(e) => panic!("{e}"),
+    let hashed_salted_passwd = match super::hash_password(&userinfo.password).await {
+        Ok(o) => o,
+        Err(e) => return e.error_response(),
     };
 
-    if !errors.is_empty() {
-        return HttpResponse::BadRequest().json(errors);
-    }
-
-    let mut salt = [0u8; 64];
-    rand::thread_rng().fill_bytes(&mut salt);
-    let hashed_salted_passwd: Vec<u8> = super::salt_and_hash(userinfo.password.clone(), &salt);
-
     let insert = sqlx::query!(r"INSERT INTO users
-        (user_id, email, username, password, salt, creation_date)
+        (user_id, email, username, password, password_pepper_id, creation_date)
         VALUES (
         gen_random_uuid(),
         $1,
@@ -208,24 +77,40 @@ pub async fn register(req: HttpRequest, userinfo: Json<Registerer>) -> impl Resp
         $3,
         $4,
         $5
-        );",
+        )
+        RETURNING user_id;",
         userinfo.email,
         userinfo.username,
-        hashed_salted_passwd,
-        &salt,
+        hashed_salted_passwd.encoded,
+        hashed_salted_passwd.pepper_id,
         chrono::Utc::now()
-    ).execute(pool).await;
+    ).fetch_one(pool).await;
     match insert {
         Ok(o) => {
-            if o.rows_affected() == 0 {
-                return HttpResponse::BadRequest().json(RegisterError::RegistrationError);
-            } else {
-                return HttpResponse::Created().finish();
+            // the user can always ask for another one
+            let mailer = match crate::app_data::<Arc<dyn Mailer>>(&req) {
+                Ok(o) => o,
+                Err(response) => return response,
+            };
+            if let Err(e) = super::email::send_verification(pool, mailer, o.user_id, &userinfo.email).await {
+                error!("failed sending verification email: {e}");
             }
+            // counts towards how many the user can be sent, it's the first so
+            // it's always allowed
+            if let Ok(store) = crate::app_data::<Arc<dyn SessionStore>>(&req) {
+                if let Err(e) = super::email::count_email(store.as_ref(), o.user_id).await {
+                    error!("failed counting verification email: {e}");
+                }
+            }
+            audit::record(&req, AuditEvent::new(EventType::Registration, Some(o.user_id))).await;
+            return HttpResponse::Created().finish();
         },
         Err(e) => {
+            if let Some(error) = e.as_database_error().and_then(|e| already_in_use(e.constraint())) {
+                return HttpResponse::BadRequest().json(error);
+            }
             error!("failed inserting user into table: {e}");
-            return HttpResponse::BadRequest().json(RegisterError::RegistrationError);
+            return HttpResponse::InternalServerError().finish();
         }
     };
 }
@@ -0,0 +1,299 @@
+use std::sync::Arc;
+
+use actix_web::{HttpRequest, Responder, web::{Json, Path}, HttpResponse, http::header::USER_AGENT};
+use chrono::{DateTime, Duration, Utc};
+use log::error;
+use serde::{Serialize, Deserialize};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{audit::{self, AuditEvent, EventType}, store::{RefreshToken, SessionStore, StoreError}};
+
+use super::token::{jwt::{JwtClaims, TokenHeader}, personal};
+
+/// how long a login lasts, in days
+const SESSION_DAYS: i64 = 30;
+
+/// how long login tokens are good for, in minutes. refresh tokens get new
+/// ones for as long as the session lasts
+pub(super) const ACCESS_TOKEN_MINUTES: i64 = 60;
+
+/// how often a session's last seen date is updated, in seconds. every
+/// request would be too much writing for too little information
+const LAST_SEEN_PRECISION_SECONDS: i64 = 60;
+
+#[derive(Serialize)]
+struct Session {
+    session_id: Uuid,
+    device_label: Option<String>,
+    user_agent: Option<String>,
+    ip: Option<String>,
+    creation_date: DateTime<Utc>,
+    last_seen_date: DateTime<Utc>,
+    expiration_date: DateTime<Utc>,
+    /// whether this is the session the request was made with
+    current: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RefreshRequest {
+    refresh_token: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum SessionError {
+    InvalidRefreshToken,
+    /// personal access tokens are revoked at `/tokens` instead
+    NotAllowedForToken,
+}
+
+/// a short, human readable name for the device behind a user agent, like
+/// "Firefox on Linux", as long as it's one of the common ones
+fn device_label(user_agent: &str) -> Option<String> {
+    let browser = [
+        ("Edg/", "Edge"),
+        ("OPR/", "Opera"),
+        ("Firefox/", "Firefox"),
+        ("Chrome/", "Chrome"),
+        ("Safari/", "Safari"),
+        ("curl/", "curl"),
+    ].into_iter().find(|(token, _)| user_agent.contains(token)).map(|(_, name)| name);
+    let os = [
+        ("Android", "Android"),
+        ("iPhone", "iOS"),
+        ("iPad", "iPadOS"),
+        ("Windows", "Windows"),
+        ("Mac OS X", "macOS"),
+        ("Linux", "Linux"),
+    ].into_iter().find(|(token, _)| user_agent.contains(token)).map(|(_, name)| name);
+
+    match (browser, os) {
+        (Some(browser), Some(os)) => Some(format!("{browser} on {os}")),
+        (Some(name), None) | (None, Some(name)) => Some(name.to_string()),
+        (None, None) => None,
+    }
+}
+
+/// records a new session for a login made with the request, returning its id
+/// and when it expires
+pub(super) async fn create(pool: &PgPool, req: &HttpRequest, user_id: Uuid) -> Result<(Uuid, DateTime<Utc>), sqlx::Error> {
+    let user_agent = req.headers().get(USER_AGENT).and_then(|h| h.to_str().ok());
+    let now = Utc::now();
+
+    // expired sessions of the user are of no use to anyone anymore
+    sqlx::query!(r"DELETE FROM sessions WHERE user_id = $1 AND expiration_date < $2;", user_id, now)
+        .execute(pool).await?;
+
+    let session = sqlx::query!(r"INSERT INTO sessions
+        (session_id, user_id, device_label, user_agent, ip, creation_date, last_seen_date, expiration_date)
+        VALUES (gen_random_uuid(), $1, $2, $3, $4, $5, $5, $6)
+        RETURNING session_id, expiration_date;",
+        user_id,
+        user_agent.and_then(device_label),
+        user_agent,
+        req.peer_addr().map(|a| a.ip().to_string()),
+        now,
+        now + Duration::days(SESSION_DAYS)
+    ).fetch_one(pool).await?;
+    Ok((session.session_id, session.expiration_date))
+}
+
+/// checks that the session is still around and belongs to the user, noting
+/// that it was just seen from the given address. returns when it expires,
+/// if it's still around
+pub async fn touch(pool: &PgPool, session_id: Uuid, user_id: Uuid, ip: Option<String>) -> Result<Option<DateTime<Utc>>, sqlx::Error> {
+    let now = Utc::now();
+    let found = sqlx::query!(r"SELECT last_seen_date, ip, expiration_date FROM sessions
+        WHERE session_id = $1 AND user_id = $2 AND expiration_date > $3;",
+        session_id,
+        user_id,
+        now
+    ).fetch_optional(pool).await?;
+    let Some(session) = found else {
+        return Ok(None);
+    };
+
+    if session.last_seen_date < now - Duration::seconds(LAST_SEEN_PRECISION_SECONDS) || (ip.is_some() && session.ip != ip) {
+        sqlx::query!(r"UPDATE sessions SET last_seen_date = $2, ip = COALESCE($3, ip) WHERE session_id = $1;",
+            session_id,
+            now,
+            ip
+        ).execute(pool).await?;
+    }
+    Ok(Some(session.expiration_date))
+}
+
+/// stores a new refresh token for the session, good for as long as the
+/// session lasts, and returns it
+pub(super) async fn issue_refresh_token(store: &dyn SessionStore, user_id: Uuid, session_id: Uuid, expiration: DateTime<Utc>) -> Result<String, StoreError> {
+    let token = super::password::generate_emailed_token();
+    store.put_refresh_token(
+        &super::hash(token.as_bytes()),
+        &RefreshToken { user_id, session_id },
+        expiration
+    ).await?;
+    Ok(token)
+}
+
+/// trades a refresh token for a new login token and refresh token, as long
+/// as its session is still around. refresh tokens can only be used once
+pub async fn refresh(req: HttpRequest, request: Json<RefreshRequest>) -> impl Responder {
+    let pool = req.app_data::<PgPool>().unwrap();
+    let store = match crate::app_data::<Arc<dyn SessionStore>>(&req) {
+        Ok(o) => o,
+        Err(response) => return response,
+    };
+
+    let token = match store.take_refresh_token(&super::hash(request.refresh_token.as_bytes())).await {
+        Ok(Some(o)) => o,
+        Ok(None) => return HttpResponse::BadRequest().json(vec![SessionError::InvalidRefreshToken]),
+        Err(e) => {
+            error!("failed taking refresh token: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    let ip = req.peer_addr().map(|a| a.ip().to_string());
+    match touch(pool, token.session_id, token.user_id, ip).await {
+        Ok(Some(expiration)) => {
+            return super::login::session_response(&req, pool, token.user_id, token.session_id, expiration).await;
+        },
+        Ok(None) => return HttpResponse::BadRequest().json(vec![SessionError::InvalidRefreshToken]),
+        Err(e) => {
+            error!("failed checking session: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+}
+
+/// logs out with the token the request was made with, which stops working
+/// right away, along with the session it belongs to
+pub async fn logout(req: HttpRequest, claims: JwtClaims, header: TokenHeader) -> impl Responder {
+    if header.0.starts_with(personal::TOKEN_PREFIX) {
+        return HttpResponse::BadRequest().json(vec![SessionError::NotAllowedForToken]);
+    }
+
+    let pool = req.app_data::<PgPool>().unwrap();
+    let store = match crate::app_data::<Arc<dyn SessionStore>>(&req) {
+        Ok(o) => o,
+        Err(response) => return response,
+    };
+
+    if let Some(token_id) = claims.token_id() {
+        if let Err(e) = store.revoke_token(token_id, claims.expiration()).await {
+            error!("failed revoking token: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    if let Some(session_id) = claims.session_id() {
+        let deletion = sqlx::query!(r"DELETE FROM sessions WHERE session_id = $1 AND user_id = $2;",
+            session_id,
+            claims.subject()
+        ).execute(pool).await;
+        if let Err(e) = deletion {
+            error!("failed deleting session: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    audit::record(&req, AuditEvent::new(EventType::Logout, Some(claims.subject()))
+        .by(&claims)
+        .with_details(serde_json::json!({ "session_id": claims.session_id() }))
+    ).await;
+    return HttpResponse::NoContent().finish();
+}
+
+/// lists the authenticated user's sessions, most recently seen first
+pub async fn list(req: HttpRequest, claims: JwtClaims) -> impl Responder {
+    let pool = req.app_data::<PgPool>().unwrap();
+
+    let sessions = sqlx::query_as!(
+        Session,
+        r#"SELECT session_id, device_label, user_agent, ip, creation_date, last_seen_date, expiration_date,
+        session_id = $3 AS "current!"
+        FROM sessions
+        WHERE user_id = $1 AND expiration_date > $2
+        ORDER BY last_seen_date DESC;"#,
+        claims.subject(),
+        Utc::now(),
+        claims.session_id()
+    ).fetch_all(pool).await;
+
+    match sessions {
+        Ok(o) => return HttpResponse::Ok().json(o),
+        Err(e) => {
+            error!("failed listing sessions: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+}
+
+/// logs one of the authenticated user's sessions out, which can also be the
+/// current one
+pub async fn revoke(req: HttpRequest, claims: JwtClaims, session_id: Path<Uuid>) -> impl Responder {
+    let pool = req.app_data::<PgPool>().unwrap();
+    let session_id = session_id.into_inner();
+
+    let deletion = sqlx::query!(r"DELETE FROM sessions WHERE session_id = $1 AND user_id = $2;",
+        session_id,
+        claims.subject()
+    ).execute(pool).await;
+
+    match deletion {
+        Ok(o) if o.rows_affected() == 0 => return HttpResponse::NotFound().finish(),
+        Ok(_) => {
+            audit::record(&req, AuditEvent::new(EventType::SessionRevoked, Some(claims.subject()))
+                .by(&claims)
+                .with_details(serde_json::json!({ "session_id": session_id }))
+            ).await;
+            return HttpResponse::NoContent().finish();
+        },
+        Err(e) => {
+            error!("failed revoking session: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+}
+
+/// logs out every session of the authenticated user except the current one
+pub async fn revoke_others(req: HttpRequest, claims: JwtClaims) -> impl Responder {
+    let pool = req.app_data::<PgPool>().unwrap();
+
+    let deletion = sqlx::query!(r"DELETE FROM sessions WHERE user_id = $1 AND session_id IS DISTINCT FROM $2;",
+        claims.subject(),
+        claims.session_id()
+    ).execute(pool).await;
+
+    match deletion {
+        Ok(o) => {
+            audit::record(&req, AuditEvent::new(EventType::SessionRevoked, Some(claims.subject()))
+                .by(&claims)
+                .with_details(serde_json::json!({ "all_except": claims.session_id(), "count": o.rows_affected() }))
+            ).await;
+            return HttpResponse::NoContent().finish();
+        },
+        Err(e) => {
+            error!("failed revoking sessions: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::device_label;
+
+    #[test]
+    fn test_device_label() {
+        let firefox = "Mozilla/5.0 (X11; Linux x86_64; rv:121.0) Gecko/20100101 Firefox/121.0";
+        assert_eq!(device_label(firefox).as_deref(), Some("Firefox on Linux"));
+        let chrome = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36";
+        assert_eq!(device_label(chrome).as_deref(), Some("Chrome on Windows"));
+        let safari = "Mozilla/5.0 (iPhone; CPU iPhone OS 17_2 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.2 Mobile/15E148 Safari/604.1";
+        assert_eq!(device_label(safari).as_deref(), Some("Safari on iOS"));
+        assert_eq!(device_label("curl/8.5.0").as_deref(), Some("curl"));
+        assert_eq!(device_label("something else"), None);
+    }
+}
@@ -0,0 +1,185 @@
+use std::{net::IpAddr, sync::Arc};
+
+use chrono::{DateTime, Duration, Utc};
+use log::warn;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{mail::{self, Email, Mailer}, store::{SessionStore, StoreError}};
+
+/// failures are counted for this long after the first one, then start over
+const FAILURE_WINDOW_MINUTES: i64 = 60;
+
+/// longest a single backoff can get, in seconds
+const MAX_BACKOFF_SECONDS: i64 = 15 * 60;
+
+/// how many failed attempts in a row lock an account, from the address they
+/// came from
+const LOCKOUT_THRESHOLD: i32 = 10;
+
+const LOCKOUT_MINUTES: i64 = 15;
+
+/// something failed logins are tracked for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThrottleKey<'a> {
+    /// the username being logged in as, whether it exists or not, along
+    /// with the address the attempt came from. someone failing to log in as
+    /// a user only throttles themselves, never the user logging in from
+    /// somewhere else
+    Username(&'a str, Option<IpAddr>),
+    /// the address the attempt came from
+    Ip(IpAddr),
+    /// second factor attempts for a user who already got their password right
+    Mfa(Uuid),
+}
+
+impl ThrottleKey<'_> {
+    fn key(&self) -> String {
+        match self {
+            Self::Username(u, Some(ip)) => format!("username:{u}:ip:{ip}"),
+            Self::Username(u, None) => format!("username:{u}"),
+            Self::Ip(ip) => format!("ip:{ip}"),
+            Self::Mfa(id) => format!("mfa:{id}"),
+        }
+    }
+
+    /// the session store counter of failed attempts
+    fn counter_key(&self) -> String {
+        format!("login_failures:{}", self.key())
+    }
+
+    /// the session store lock that backs off further attempts
+    fn lock_key(&self) -> String {
+        format!("login:{}", self.key())
+    }
+
+    /// how many attempts can fail before any backoff kicks in. addresses get
+    /// more leeway since many people can be behind the same one
+    fn free_attempts(&self) -> i32 {
+        match self {
+            Self::Username(..) | Self::Mfa(_) => 3,
+            Self::Ip(_) => 10,
+        }
+    }
+
+    /// whether too many failures lock the target out entirely, instead of
+    /// just backing off
+    fn locks_out(&self) -> bool {
+        !matches!(self, Self::Ip(_))
+    }
+}
+
+/// how long to wait before the next attempt after `attempts` failures,
+/// doubling with every failure past the free ones
+fn backoff(key: &ThrottleKey, attempts: i32) -> Option<Duration> {
+    if key.locks_out() && attempts >= LOCKOUT_THRESHOLD {
+        return Some(Duration::minutes(LOCKOUT_MINUTES));
+    }
+
+    let over = attempts - key.free_attempts();
+    if over <= 0 {
+        return None;
+    }
+    // shifting would wrap around to negative past 62 doublings, powers
+    // just overflow
+    let seconds = 2i64.checked_pow((over - 1) as u32)
+        .map_or(MAX_BACKOFF_SECONDS, |s| s.min(MAX_BACKOFF_SECONDS));
+    Some(Duration::seconds(seconds))
+}
+
+/// returns until when the latest of the given keys is locked, if any of them
+/// currently is
+pub async fn locked_until(store: &dyn SessionStore, keys: &[ThrottleKey<'_>]) -> Result<Option<DateTime<Utc>>, StoreError> {
+    let mut latest = None;
+    for key in keys {
+        let until = store.locked_until(&key.lock_key()).await?;
+        latest = latest.max(until);
+    }
+    Ok(latest)
+}
+
+/// records a failed attempt, locking the key for a while if there were too
+/// many of them
+pub async fn record_failure(store: &dyn SessionStore, pool: &PgPool, mailer: &Arc<dyn Mailer>, key: ThrottleKey<'_>) -> Result<(), StoreError> {
+    let window = Duration::minutes(FAILURE_WINDOW_MINUTES);
+    let attempts = store.increment(&key.counter_key(), window).await?.count;
+    let attempts = i32::try_from(attempts).unwrap_or(i32::MAX);
+
+    if let Some(delay) = backoff(&key, attempts) {
+        store.lock(&key.lock_key(), Utc::now() + delay).await?;
+    }
+
+    if key.locks_out() && attempts == LOCKOUT_THRESHOLD {
+        notify_lockout(pool, mailer, key).await?;
+    }
+
+    Ok(())
+}
+
+/// forgets about past failures, after a successful attempt
+pub async fn reset(store: &dyn SessionStore, key: ThrottleKey<'_>) -> Result<(), StoreError> {
+    store.reset_counter(&key.counter_key()).await?;
+    store.unlock(&key.lock_key()).await?;
+    Ok(())
+}
+
+/// lets the user know their account was locked, in case it wasn't them
+async fn notify_lockout(pool: &PgPool, mailer: &Arc<dyn Mailer>, key: ThrottleKey<'_>) -> Result<(), sqlx::Error> {
+    warn!("{} locked out for {LOCKOUT_MINUTES} minutes after {LOCKOUT_THRESHOLD} failed attempts", key.key());
+
+    let email = match key {
+        ThrottleKey::Username(username, _) => {
+            sqlx::query_scalar!(r"SELECT email FROM users WHERE username = $1;", username)
+                .fetch_optional(pool).await?
+        },
+        ThrottleKey::Mfa(user_id) => {
+            sqlx::query_scalar!(r"SELECT email FROM users WHERE user_id = $1;", user_id)
+                .fetch_optional(pool).await?
+        },
+        ThrottleKey::Ip(_) => None,
+    };
+
+    if let Some(to) = email {
+        mail::send_in_background(mailer, Email {
+            to,
+            subject: "Your account was locked".to_string(),
+            body: format!(
+                "There were {LOCKOUT_THRESHOLD} failed attempts in a row to log into your \
+                cyber_bank_rs account from the same address, so logging in from there \
+                is blocked for the next {LOCKOUT_MINUTES} minutes.\n\n\
+                If this wasn't you, someone might be trying to guess your password. \
+                Consider resetting it."
+            ),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::{backoff, ThrottleKey, LOCKOUT_THRESHOLD, MAX_BACKOFF_SECONDS};
+
+    #[test]
+    fn test_backoff() {
+        let user = ThrottleKey::Username("alice", Some([127, 0, 0, 1].into()));
+        assert_eq!(backoff(&user, 3), None);
+        assert_eq!(backoff(&user, 4), Some(Duration::seconds(1)));
+        assert_eq!(backoff(&user, 5), Some(Duration::seconds(2)));
+        assert_eq!(backoff(&user, 6), Some(Duration::seconds(4)));
+        assert_eq!(backoff(&user, LOCKOUT_THRESHOLD), Some(Duration::minutes(15)));
+
+        // addresses are never locked out, but the backoff is capped
+        let ip = ThrottleKey::Ip([127, 0, 0, 1].into());
+        assert_eq!(backoff(&ip, LOCKOUT_THRESHOLD), None);
+        assert_eq!(backoff(&ip, 1000), Some(Duration::seconds(MAX_BACKOFF_SECONDS)));
+
+        // around where the doubling stops fitting in an i64
+        let free = ip.free_attempts();
+        for over in [62, 63, 64, 65] {
+            assert_eq!(backoff(&ip, free + over), Some(Duration::seconds(MAX_BACKOFF_SECONDS)), "{over} over");
+        }
+        assert_eq!(backoff(&ip, i32::MAX), Some(Duration::seconds(MAX_BACKOFF_SECONDS)));
+    }
+}
@@ -1,9 +1,11 @@
 use std::{sync::OnceLock, fmt::Display, future::{Ready, ready}};
 
 use actix_web::{FromRequest, ResponseError};
+#[cfg(not(debug_assertions))]
 use base64::{Engine, prelude::BASE64_STANDARD};
 use chrono::Utc;
 use jsonwebtoken::{EncodingKey, DecodingKey, Validation, Algorithm};
+#[cfg(not(debug_assertions))]
 use rand::RngCore;
 use serde::{Serialize, Deserialize};
 use uuid::Uuid;
@@ -13,22 +15,65 @@ use uuid::Uuid;
 pub enum Scope {
     User,
     UserInfo,
+    /// only good for finishing a login with a second factor
+    MfaPending,
+    /// making outgoing transfers, which needs a verified email address
+    Transfers,
+    /// looking through everyone's audit events. only admins get it when they
+    /// log in, and it can't be handed on to tokens or clients
+    Admin,
 }
 
 impl Scope {
     pub const USER_LOGIN: &'static [Self] = &[
+        Self::User,
+        Self::UserInfo,
+        Self::Transfers
+    ];
+
+    /// what users get until they've verified their email address
+    pub const UNVERIFIED_LOGIN: &'static [Self] = &[
         Self::User,
         Self::UserInfo
     ];
+
+    /// whether personal access tokens and OAuth clients can be given the scope
+    pub fn is_delegable(&self) -> bool {
+        !matches!(self, Self::MfaPending | Self::Admin)
+    }
+
+    /// the name of the scope as it appears in tokens and in the database
+    pub fn name(&self) -> String {
+        match serde_json::to_value(self) {
+            Ok(serde_json::Value::String(s)) => s,
+            _ => unreachable!("scopes always serialize to strings"),
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        serde_json::from_value(serde_json::Value::String(name.to_string())).ok()
+    }
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct JwtClaims {
     iat: i64,
     sub: Uuid,
     exp: i64,
-    pub scope: Vec<Scope>
+    pub scope: Vec<Scope>,
+    /// the OAuth client this token was issued to, if it wasn't issued to the
+    /// user directly
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    azp: Option<Uuid>,
+    /// the session a login token belongs to, which has to still be around
+    /// for the token to be accepted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sid: Option<Uuid>,
+    /// identifies the token, so it can be revoked on its own. tokens issued
+    /// before these were introduced don't have one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    jti: Option<Uuid>
 }
 
 impl JwtClaims {
@@ -37,10 +82,51 @@ impl JwtClaims {
             iat: chrono::Utc::now().timestamp(),
             sub: subject,
             exp: expiration.timestamp(),
-            scope: scope
+            scope,
+            azp: None,
+            sid: None,
+            jti: Some(Uuid::new_v4())
         }
     }
 
+    /// marks the token as issued to a third-party OAuth client
+    pub fn with_authorized_party(mut self, client_id: Uuid) -> Self {
+        self.azp = Some(client_id);
+        self
+    }
+
+    pub fn authorized_party(&self) -> Option<Uuid> {
+        self.azp
+    }
+
+    /// ties the token to a login session
+    pub fn with_session(mut self, session_id: Uuid) -> Self {
+        self.sid = Some(session_id);
+        self
+    }
+
+    pub fn session_id(&self) -> Option<Uuid> {
+        self.sid
+    }
+
+    pub fn token_id(&self) -> Option<Uuid> {
+        self.jti
+    }
+
+    /// the user this token was issued for
+    pub fn subject(&self) -> Uuid {
+        self.sub
+    }
+
+    pub fn issued_at(&self) -> chrono::DateTime<Utc> {
+        chrono::DateTime::from_timestamp(self.iat, 0).unwrap_or_default()
+    }
+
+    /// when the token stops being accepted, to the second
+    pub fn expiration(&self) -> chrono::DateTime<Utc> {
+        chrono::DateTime::from_timestamp(self.exp, 0).unwrap_or_default()
+    }
+
     pub fn decode_from_token(token: &str) -> Result<Self, jsonwebtoken::errors::Error> {
         match jsonwebtoken::decode::<Self>(
             token,
@@ -71,9 +157,7 @@ fn get_secret() -> &'static [u8] {
         }
         #[cfg(not(debug_assertions))]
         {
-            let user = std::env::var("USER")
-                .expect("$USER environment variable could not be retrieved!");
-            let entry = keyring::Entry::new("cyber_bank_rs", &user).unwrap();
+            let entry = crate::auth::keyring_entry(None);
             match entry.get_password() {
                 Ok(o) => return BASE64_STANDARD.decode(o).unwrap(),
                 Err(_) => {
@@ -89,7 +173,16 @@ fn get_secret() -> &'static [u8] {
     })
 }
 
-pub struct TokenHeader(String);
+/// a key for something other than signing tokens, derived from the signing
+/// key so there isn't another secret to keep. every `purpose` gets its own
+pub(crate) fn derived_key(purpose: &str) -> Vec<u8> {
+    let mut data = purpose.as_bytes().to_vec();
+    data.push(0);
+    data.extend_from_slice(get_secret());
+    crate::auth::hash(&data)
+}
+
+pub struct TokenHeader(pub String);
 
 #[derive(Debug)]
 pub enum TokenParsingError {
@@ -1,13 +1,15 @@
-use std::{future::Future, pin::Pin, fmt::Display};
+use std::{future::{Future, Ready, ready}, pin::Pin, fmt::Display, rc::Rc, sync::Arc};
 
-use actix_web::{dev::{Service, ServiceResponse, ServiceRequest}, body::{EitherBody, BoxBody}, HttpResponse};
-use futures_util::TryFutureExt;
-use log::debug;
+use actix_web::{dev::{Service, ServiceResponse, ServiceRequest}, body::{EitherBody, BoxBody}, FromRequest, HttpMessage, HttpResponse, ResponseError};
+use log::{debug, error};
+use sqlx::PgPool;
 
-use super::jwt::{Scope, TokenParsingError, JwtClaims};
+use crate::store::SessionStore;
+
+use super::{jwt::{Scope, TokenParsingError, JwtClaims}, personal};
 
 pub struct ScopeValidatorMiddleware<S> {
-    service: S,
+    service: Rc<S>,
     required: &'static [Scope]
 }
 
@@ -16,22 +18,40 @@ pub enum ScopeValidationError {
     NoToken,
     BadToken(TokenParsingError),
     ExpiredToken,
-    InvalidScopes
+    InvalidScopes,
+    /// the token couldn't be checked, e.g. because the database is down
+    Internal,
 }
 
 impl Display for ScopeValidationError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f,
-            "{}",
-            match &self {
-                _ => "bad auth header",
-            }
-        )
+        write!(f, "bad auth header")
     }
 }
 
 impl std::error::Error for ScopeValidationError{}
 
+impl ResponseError for ScopeValidationError {
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        match self {
+            Self::Internal => return actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
+            _ => return actix_web::http::StatusCode::FORBIDDEN,
+        }
+    }
+}
+
+/// claims of a request that went through a [`ScopeValidator`] can be
+/// extracted directly by handlers
+impl FromRequest for JwtClaims {
+    type Error = ScopeValidationError;
+
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &actix_web::HttpRequest, _payload: &mut actix_web::dev::Payload) -> Self::Future {
+        ready(req.extensions().get::<JwtClaims>().cloned().ok_or(ScopeValidationError::NoToken))
+    }
+}
+
 pub struct ScopeValidator {
     required: &'static [Scope]
 }
@@ -52,12 +72,117 @@ where
 
     fn new_transform(&self, service: S) -> Self::Future {
         std::future::ready(Ok(ScopeValidatorMiddleware {
-            service: service,
+            service: Rc::new(service),
             required: self.required,
         }))
     }
 }
 
+/// gets the bearer token out of the request's `Authorization` header
+fn bearer_token(req: &ServiceRequest) -> Result<String, TokenParsingError> {
+    if let Some(header) = req.headers().get("Authorization") {
+        match header.to_str() {
+            Ok(o) => {
+                if let Some((id, token)) = o.split_once(' ') {
+                    if id != "Bearer" {
+                        Err(TokenParsingError::InvalidHeader)
+                    } else {
+                        Ok(token.to_string())
+                    }
+                } else {
+                    Err(TokenParsingError::NoIdentifier)
+                }
+            },
+            Err(_) => Err(TokenParsingError::InvalidHeader)
+        }
+    } else {
+        Err(TokenParsingError::HeaderMissing)
+    }
+}
+
+/// validates the request's token, which can either be a JWT or a personal
+/// access token, returning the claims it grants
+async fn authenticate(req: &ServiceRequest) -> Result<JwtClaims, ScopeValidationError> {
+    let token = bearer_token(req).map_err(ScopeValidationError::BadToken)?;
+
+    if token.starts_with(personal::TOKEN_PREFIX) {
+        // personal access tokens only live in the database
+        let Some(pool) = req.app_data::<PgPool>() else {
+            return Err(ScopeValidationError::NoToken);
+        };
+        let ip = req.peer_addr().map(|a| a.ip().to_string());
+        return personal::authenticate(pool, &token, ip).await;
+    }
+
+    let claims = JwtClaims::decode_from_token(&token).map_err(|e| match e.kind() {
+        jsonwebtoken::errors::ErrorKind::ExpiredSignature => ScopeValidationError::ExpiredToken,
+        _ => ScopeValidationError::NoToken,
+    })?;
+
+    if let Some(token_id) = claims.token_id() {
+        // without a store there's no telling whether the token was revoked
+        let Some(store) = req.app_data::<Arc<dyn SessionStore>>() else {
+            error!("no session store to check for revoked tokens");
+            return Err(ScopeValidationError::Internal);
+        };
+        match store.is_token_revoked(token_id).await {
+            Ok(false) => (),
+            Ok(true) => return Err(ScopeValidationError::ExpiredToken),
+            Err(e) => {
+                error!("failed checking for revoked token: {e}");
+                return Err(ScopeValidationError::Internal);
+            }
+        }
+    }
+
+    if let Some(pool) = req.app_data::<PgPool>() {
+        check_not_revoked(pool, &claims).await?;
+        if let Some(session_id) = claims.session_id() {
+            let ip = req.peer_addr().map(|a| a.ip().to_string());
+            match crate::auth::session::touch(pool, session_id, claims.subject(), ip).await {
+                Ok(Some(_)) => (),
+                Ok(None) => return Err(ScopeValidationError::ExpiredToken),
+                Err(e) => {
+                    error!("failed checking session: {e}");
+                    return Err(ScopeValidationError::Internal);
+                }
+            }
+        }
+    }
+    Ok(claims)
+}
+
+/// whether the token was issued before its user's sessions were revoked.
+/// tokens only know the second they were issued in, so ones from the same
+/// second as the revocation count as revoked too. tokens tied to a session
+/// are left to the session check: revoking ends every session, so one
+/// that's still around was started afterwards
+fn revoked_by(claims: &JwtClaims, revocation: chrono::DateTime<chrono::Utc>) -> bool {
+    claims.session_id().is_none() && claims.issued_at().timestamp() <= revocation.timestamp()
+}
+
+/// makes sure the token wasn't issued before its user's sessions were
+/// revoked, e.g. by changing their password, and that the user still exists
+/// and didn't delete their account
+async fn check_not_revoked(pool: &PgPool, claims: &JwtClaims) -> Result<(), ScopeValidationError> {
+    let found = sqlx::query!(r"SELECT sessions_revoked_date, deletion_date FROM users WHERE user_id = $1;",
+        claims.subject()
+    ).fetch_optional(pool).await;
+
+    match found {
+        Ok(Some(o)) if o.deletion_date.is_some() => return Err(ScopeValidationError::NoToken),
+        Ok(Some(o)) if o.sessions_revoked_date.is_some_and(|r| revoked_by(claims, r)) => {
+            return Err(ScopeValidationError::ExpiredToken);
+        },
+        Ok(Some(_)) => return Ok(()),
+        Ok(None) => return Err(ScopeValidationError::NoToken),
+        Err(e) => {
+            error!("failed checking for revoked sessions: {e}");
+            return Err(ScopeValidationError::Internal);
+        }
+    }
+}
+
 impl<S, B> Service<ServiceRequest> for ScopeValidatorMiddleware<S>
 where
     S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
@@ -73,46 +198,32 @@ where
     }
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
-        let mut token_invalid = false;
-
-        let token = if let Some(header) = req.headers().get("Authorization") {
-            match header.to_str() {
-                Ok(o) => {
-                    debug!("auth header: {o}");
-                    if let Some((id, token)) = o.split_once(" ") {
-                        if id != "Bearer" {
-                            Err(TokenParsingError::InvalidHeader)
-                        } else {
-                            Ok(token)
-                        }
-                    } else {
-                        Err(TokenParsingError::NoIdentifier)
-                    }
-                },
-                Err(_) => Err(TokenParsingError::InvalidHeader)
-            }
-        } else {
-            Err(TokenParsingError::HeaderMissing)
-        };
-
-        if let Ok(token) = token {
-            match JwtClaims::decode_from_token(token) {
-                Ok(o) => { token_invalid = !self.required.iter().all(|s| o.scope.contains(s)); },
-                Err(_e) => { token_invalid = true; },
-            };
-        } else {
-            token_invalid = true;
-        }
+        let service = Rc::clone(&self.service);
+        let required = self.required;
 
-        if token_invalid {
-            debug!("token used is invalid: {token:?}");
-            return Box::pin(async move {
-                    Ok(req.into_response(HttpResponse::Forbidden().finish()).map_into_right_body())
+        Box::pin(async move {
+            let claims = authenticate(&req).await.and_then(|claims| {
+                if required.iter().all(|s| claims.scope.contains(s)) {
+                    Ok(claims)
+                } else {
+                    Err(ScopeValidationError::InvalidScopes)
                 }
-            )
-        }
+            });
 
-        return Box::pin(self.service.call(req).map_ok(|o| o.map_into_left_body()))
+            match claims {
+                Ok(claims) => {
+                    req.extensions_mut().insert(claims);
+                    return service.call(req).await.map(|o| o.map_into_left_body());
+                },
+                Err(ScopeValidationError::Internal) => {
+                    return Ok(req.into_response(HttpResponse::InternalServerError().finish()).map_into_right_body());
+                },
+                Err(e) => {
+                    debug!("token used is invalid: {e:?}");
+                    return Ok(req.into_response(HttpResponse::Forbidden().finish()).map_into_right_body());
+                }
+            }
+        })
     }
 }
 
@@ -123,3 +234,27 @@ impl ScopeValidator {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Utc};
+    use uuid::Uuid;
+
+    use super::{revoked_by, JwtClaims, Scope};
+
+    #[test]
+    fn test_revoked_by() {
+        let claims = JwtClaims::new(vec![Scope::UserInfo], Uuid::new_v4(), Utc::now() + Duration::hours(1));
+        let issued_at = claims.issued_at();
+
+        // the same second, however far into it
+        assert!(revoked_by(&claims, issued_at));
+        assert!(revoked_by(&claims, issued_at + Duration::milliseconds(999)));
+        assert!(revoked_by(&claims, issued_at + Duration::hours(1)));
+        assert!(!revoked_by(&claims, issued_at - Duration::milliseconds(1)));
+
+        // sessions are checked on their own
+        let claims = claims.with_session(Uuid::new_v4());
+        assert!(!revoked_by(&claims, issued_at + Duration::hours(1)));
+    }
+}
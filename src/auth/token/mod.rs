@@ -7,6 +7,18 @@ pub mod jwt;
 pub mod middleware;
 pub use middleware::ScopeValidator;
 
+/// named, scoped and expiring personal access tokens for programmatic
+/// clients, accepted by [`ScopeValidator`] alongside JWTs
+pub mod personal;
+
+/// whether the token is one the user logged in with, as opposed to a personal
+/// access token or one issued to an OAuth client. some things, like managing
+/// credentials, should only be done with the former
+pub fn is_first_party(claims: &jwt::JwtClaims, header: &jwt::TokenHeader) -> bool {
+    !header.0.starts_with(personal::TOKEN_PREFIX) && claims.authorized_party().is_none()
+}
+
+#[cfg(test)]
 #[allow(unused_imports)]
 mod tests {
     use actix_web::{test, App, web, dev::Service, http::StatusCode};
@@ -30,7 +42,10 @@ mod tests {
 
         let res_valid = app.call(test_valid).await.unwrap();
 
-        assert_eq!(res_valid.status(), StatusCode::OK);
+        // without a session store there's no telling whether the token was
+        // revoked, so it isn't accepted. valid tokens are tested against the
+        // whole app in the integration tests
+        assert_eq!(res_valid.status(), StatusCode::INTERNAL_SERVER_ERROR);
 
         let test_invalid = test::TestRequest::with_uri("/test").to_request();
 
@@ -38,4 +53,28 @@ mod tests {
 
         assert_eq!(res_invalid.status(), StatusCode::FORBIDDEN);
     }
+
+    #[test]
+    async fn test_personal_token_scopes() {
+        use super::jwt::Scope;
+
+        for scope in Scope::USER_LOGIN {
+            assert_eq!(Scope::from_name(&scope.name()).as_ref(), Some(scope));
+        }
+
+        // without a database there is nothing a personal access token can be checked against
+        let app = test::init_service(
+            App::new()
+                .wrap(ScopeValidator::new(&[]))
+                .service(web::resource("/test").to(|| async { "OK" }))
+        ).await;
+
+        let test_pat = test::TestRequest::with_uri("/test")
+            .insert_header(("Authorization", format!("Bearer {}dGVzdA", super::personal::TOKEN_PREFIX)))
+            .to_request();
+
+        let res_pat = app.call(test_pat).await.unwrap();
+
+        assert_eq!(res_pat.status(), StatusCode::FORBIDDEN);
+    }
 }
@@ -0,0 +1,216 @@
+use actix_web::{HttpRequest, Responder, web::{Json, Path}, HttpResponse};
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use log::error;
+use rand::RngCore;
+use serde::{Serialize, Deserialize};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::audit::{self, AuditEvent, EventType};
+
+use super::{jwt::{JwtClaims, Scope, TokenHeader}, middleware::ScopeValidationError};
+
+/// every personal access token starts with this, which is how they're told
+/// apart from JWTs
+pub const TOKEN_PREFIX: &str = "cbpat_";
+
+/// how long a personal access token can be valid for, in days
+const MAX_LIFETIME_DAYS: u64 = 365;
+
+#[derive(Serialize)]
+struct PersonalAccessToken {
+    token_id: Uuid,
+    name: String,
+    scopes: Vec<String>,
+    creation_date: chrono::DateTime<chrono::Utc>,
+    expiration_date: chrono::DateTime<chrono::Utc>,
+    last_used_date: Option<chrono::DateTime<chrono::Utc>>,
+    last_used_ip: Option<String>,
+    revoked: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TokenRequest {
+    name: String,
+    scopes: Vec<Scope>,
+    expires_in_days: u64,
+}
+
+#[derive(Serialize)]
+struct CreatedTokenResponse {
+    token_id: Uuid,
+    /// the only time the token itself is ever shown
+    token: String,
+    name: String,
+    scopes: Vec<Scope>,
+    expiration_date: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum TokenRequestError {
+    InvalidName,
+    InvalidExpiration(u64),
+    NoScopes,
+    ScopeNotGranted(Scope),
+    NotAllowedForToken,
+}
+
+fn generate_token() -> String {
+    let mut secret = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut secret);
+    format!("{TOKEN_PREFIX}{}", BASE64_URL_SAFE_NO_PAD.encode(secret))
+}
+
+/// looks up a personal access token, making sure it's neither expired nor
+/// revoked, and records when and from where it was used
+pub async fn authenticate(pool: &PgPool, token: &str, ip: Option<String>) -> Result<JwtClaims, ScopeValidationError> {
+    let now = chrono::Utc::now();
+    let found = sqlx::query!(r"UPDATE personal_access_tokens
+        SET last_used_date = $2, last_used_ip = $3
+        WHERE token_hash = $1 AND NOT revoked AND expiration_date > $2
+        RETURNING user_id, scopes, expiration_date",
+        crate::auth::hash(token.as_bytes()),
+        now,
+        ip
+    ).fetch_optional(pool).await;
+
+    match found {
+        Ok(Some(o)) => Ok(JwtClaims::new(
+            o.scopes.iter().filter_map(|s| Scope::from_name(s)).collect(),
+            o.user_id,
+            o.expiration_date
+        )),
+        Ok(None) => Err(ScopeValidationError::NoToken),
+        Err(e) => {
+            error!("failed looking up personal access token: {e}");
+            Err(ScopeValidationError::Internal)
+        }
+    }
+}
+
+/// creates a new personal access token for the authenticated user, which is
+/// returned only this once
+pub async fn create(req: HttpRequest, claims: JwtClaims, header: TokenHeader, tokeninfo: Json<TokenRequest>) -> impl Responder {
+    let mut errors: Vec<TokenRequestError> = Vec::new();
+
+    // otherwise a leaked token could be used to mint new ones
+    if !super::is_first_party(&claims, &header) {
+        errors.push(TokenRequestError::NotAllowedForToken);
+    }
+
+    let name = tokeninfo.name.trim();
+    if name.is_empty() || name.chars().count() > 64 {
+        errors.push(TokenRequestError::InvalidName);
+    }
+
+    if tokeninfo.expires_in_days == 0 || tokeninfo.expires_in_days > MAX_LIFETIME_DAYS {
+        errors.push(TokenRequestError::InvalidExpiration(tokeninfo.expires_in_days));
+    }
+
+    if tokeninfo.scopes.is_empty() {
+        errors.push(TokenRequestError::NoScopes);
+    }
+    for scope in tokeninfo.scopes.iter() {
+        if !claims.scope.contains(scope) || !scope.is_delegable() {
+            errors.push(TokenRequestError::ScopeNotGranted(scope.clone()));
+        }
+    }
+
+    if !errors.is_empty() {
+        return HttpResponse::BadRequest().json(errors);
+    }
+
+    let pool = req.app_data::<PgPool>().unwrap();
+
+    let token = generate_token();
+    let now = chrono::Utc::now();
+    let expiration_date = now + chrono::Days::new(tokeninfo.expires_in_days);
+    let scope_names: Vec<String> = tokeninfo.scopes.iter().map(Scope::name).collect();
+
+    let insert = sqlx::query!(r"INSERT INTO personal_access_tokens
+        (token_id, user_id, name, token_hash, scopes, creation_date, expiration_date)
+        VALUES (gen_random_uuid(), $1, $2, $3, $4, $5, $6)
+        RETURNING token_id;",
+        claims.subject(),
+        name,
+        crate::auth::hash(token.as_bytes()),
+        &scope_names,
+        now,
+        expiration_date
+    ).fetch_one(pool).await;
+
+    match insert {
+        Ok(o) => {
+            audit::record(&req, AuditEvent::new(EventType::TokenCreated, Some(claims.subject()))
+                .by(&claims)
+                .with_details(serde_json::json!({ "token_id": o.token_id, "name": name, "scopes": &scope_names }))
+            ).await;
+            return HttpResponse::Created().json(CreatedTokenResponse {
+                token_id: o.token_id,
+                token,
+                name: name.to_string(),
+                scopes: tokeninfo.scopes.clone(),
+                expiration_date,
+            });
+        },
+        Err(e) => {
+            error!("failed inserting personal access token: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+}
+
+/// lists all of the authenticated user's personal access tokens, without
+/// the tokens themselves
+pub async fn list(req: HttpRequest, claims: JwtClaims) -> impl Responder {
+    let pool = req.app_data::<PgPool>().unwrap();
+
+    let tokens = sqlx::query_as!(
+        PersonalAccessToken,
+        r"SELECT token_id, name, scopes, creation_date, expiration_date, last_used_date, last_used_ip, revoked
+        FROM personal_access_tokens
+        WHERE user_id = $1
+        ORDER BY creation_date DESC;",
+        claims.subject()
+    ).fetch_all(pool).await;
+
+    match tokens {
+        Ok(o) => return HttpResponse::Ok().json(o),
+        Err(e) => {
+            error!("failed listing personal access tokens: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+}
+
+/// revokes one of the authenticated user's personal access tokens
+pub async fn revoke(req: HttpRequest, claims: JwtClaims, token_id: Path<Uuid>) -> impl Responder {
+    let pool = req.app_data::<PgPool>().unwrap();
+    let token_id = token_id.into_inner();
+
+    let update = sqlx::query!(r"UPDATE personal_access_tokens
+        SET revoked = true
+        WHERE token_id = $1 AND user_id = $2 AND NOT revoked;",
+        token_id,
+        claims.subject()
+    ).execute(pool).await;
+
+    match update {
+        Ok(o) => {
+            if o.rows_affected() == 0 {
+                return HttpResponse::NotFound().finish();
+            } else {
+                audit::record(&req, AuditEvent::new(EventType::TokenRevoked, Some(claims.subject()))
+                    .by(&claims)
+                    .with_details(serde_json::json!({ "token_id": token_id }))
+                ).await;
+                return HttpResponse::NoContent().finish();
+            }
+        },
+        Err(e) => {
+            error!("failed revoking personal access token: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+}
@@ -0,0 +1,317 @@
+use actix_web::{HttpRequest, Responder, web::Json, HttpResponse};
+use data_encoding::{BASE32_NOPAD, HEXLOWER};
+use hmac::{Hmac, Mac};
+use log::error;
+use rand::RngCore;
+use serde::{Serialize, Deserialize};
+use sha1::Sha1;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::audit::{self, AuditEvent, EventType};
+
+use super::token::{jwt::{JwtClaims, TokenHeader}, is_first_party};
+
+/// shown as the account's issuer in authenticator apps
+const ISSUER: &str = "CyberBank";
+
+/// length of a time step, in seconds
+const STEP: i64 = 30;
+
+const DIGITS: u32 = 6;
+
+/// how many steps before and after the current one are still accepted, to
+/// account for clock drift
+const ALLOWED_DRIFT: i64 = 1;
+
+const RECOVERY_CODE_COUNT: usize = 10;
+
+/// computes an HOTP value as described in
+/// [RFC 4226](https://www.rfc-editor.org/rfc/rfc4226#section-5.3)
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret)
+        .expect("HMAC accepts keys of any length");
+    mac.update(&counter.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let truncated = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+    truncated % 10u32.pow(DIGITS)
+}
+
+/// checks a TOTP code as described in [RFC 6238](https://www.rfc-editor.org/rfc/rfc6238),
+/// returning the time step it matched so it can't be used again
+fn verify_code(secret: &[u8], code: &str, timestamp: i64) -> Option<i64> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+
+    let current = timestamp / STEP;
+    (current - ALLOWED_DRIFT..=current + ALLOWED_DRIFT)
+        .find(|step| *step >= 0 && hotp(secret, *step as u64) == code)
+}
+
+/// the `otpauth://` URI authenticator apps expect, usually shown as a QR code
+fn provisioning_uri(secret: &[u8], account: &str) -> String {
+    let label: String = url::form_urlencoded::byte_serialize(format!("{ISSUER}:{account}").as_bytes()).collect();
+    format!(
+        "otpauth://totp/{label}?secret={}&issuer={ISSUER}&algorithm=SHA1&digits={DIGITS}&period={STEP}",
+        BASE32_NOPAD.encode(secret)
+    )
+}
+
+fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODE_COUNT).map(|_| {
+        let mut code = [0u8; 5];
+        rand::thread_rng().fill_bytes(&mut code);
+        let hex = HEXLOWER.encode(&code);
+        format!("{}-{}", &hex[..5], &hex[5..])
+    }).collect()
+}
+
+/// recovery codes are compared without the dash and regardless of case, since
+/// people will be typing them in by hand
+fn hash_recovery_code(code: &str) -> Vec<u8> {
+    let normalized: String = code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    super::hash(normalized.as_bytes())
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TotpCode {
+    code: String,
+}
+
+#[derive(Serialize)]
+struct Enrollment {
+    secret: String,
+    provisioning_uri: String,
+}
+
+#[derive(Serialize)]
+struct RecoveryCodes {
+    recovery_codes: Vec<String>,
+}
+
+/// whether the user has to go through the second login step
+pub async fn is_enabled(pool: &PgPool, user_id: Uuid) -> Result<bool, sqlx::Error> {
+    let found = sqlx::query!(r"SELECT user_id FROM totp_secrets
+        WHERE user_id = $1 AND enabled_date IS NOT NULL;",
+        user_id
+    ).fetch_optional(pool).await?;
+    Ok(found.is_some())
+}
+
+/// checks a code against the user's enabled TOTP secret, making sure it
+/// hasn't been used already
+pub async fn check_code(pool: &PgPool, user_id: Uuid, code: &str) -> Result<bool, sqlx::Error> {
+    let found = sqlx::query!(r"SELECT secret FROM totp_secrets
+        WHERE user_id = $1 AND enabled_date IS NOT NULL;",
+        user_id
+    ).fetch_optional(pool).await?;
+
+    let Some(found) = found else {
+        return Ok(false);
+    };
+    let Some(step) = verify_code(&found.secret, code, chrono::Utc::now().timestamp()) else {
+        return Ok(false);
+    };
+
+    let update = sqlx::query!(r"UPDATE totp_secrets
+        SET last_used_step = $2
+        WHERE user_id = $1 AND (last_used_step IS NULL OR last_used_step < $2);",
+        user_id,
+        step
+    ).execute(pool).await?;
+    Ok(update.rows_affected() == 1)
+}
+
+/// uses up one of the user's recovery codes, if it's valid
+pub async fn use_recovery_code(pool: &PgPool, user_id: Uuid, code: &str) -> Result<bool, sqlx::Error> {
+    let update = sqlx::query!(r"UPDATE recovery_codes
+        SET used_date = $3
+        WHERE user_id = $1 AND code_hash = $2 AND used_date IS NULL;",
+        user_id,
+        hash_recovery_code(code),
+        chrono::Utc::now()
+    ).execute(pool).await?;
+    Ok(update.rows_affected() == 1)
+}
+
+/// starts TOTP enrollment, returning a new secret that only takes effect once
+/// a code generated with it is sent to [`activate`]
+pub async fn enroll(req: HttpRequest, claims: JwtClaims, header: TokenHeader) -> impl Responder {
+    if !is_first_party(&claims, &header) {
+        return HttpResponse::Forbidden().finish();
+    }
+
+    let pool = req.app_data::<PgPool>().unwrap();
+
+    let mut secret = [0u8; 20];
+    rand::thread_rng().fill_bytes(&mut secret);
+
+    // replaces any enrollment that was never finished, but never an enabled one
+    let upsert = sqlx::query!(r"INSERT INTO totp_secrets
+        (user_id, secret, creation_date)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (user_id) DO UPDATE
+        SET secret = EXCLUDED.secret, creation_date = EXCLUDED.creation_date
+        WHERE totp_secrets.enabled_date IS NULL
+        RETURNING (SELECT username FROM users WHERE user_id = $1);",
+        claims.subject(),
+        &secret,
+        chrono::Utc::now()
+    ).fetch_optional(pool).await;
+
+    match upsert {
+        Ok(Some(o)) => {
+            let account = o.username.unwrap_or_default();
+            return HttpResponse::Created().json(Enrollment {
+                secret: BASE32_NOPAD.encode(&secret),
+                provisioning_uri: provisioning_uri(&secret, &account),
+            });
+        },
+        Ok(None) => return HttpResponse::Conflict().finish(),
+        Err(e) => {
+            error!("failed enrolling totp secret: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+}
+
+/// enables TOTP after checking a code generated with the newly enrolled
+/// secret, returning a fresh set of recovery codes
+pub async fn activate(req: HttpRequest, claims: JwtClaims, header: TokenHeader, body: Json<TotpCode>) -> impl Responder {
+    if !is_first_party(&claims, &header) {
+        return HttpResponse::Forbidden().finish();
+    }
+
+    let pool = req.app_data::<PgPool>().unwrap();
+    let recovery_codes = generate_recovery_codes();
+
+    let activated: Result<bool, sqlx::Error> = async {
+        let mut tx = pool.begin().await?;
+
+        let pending = sqlx::query!(r"SELECT secret FROM totp_secrets
+            WHERE user_id = $1 AND enabled_date IS NULL
+            FOR UPDATE;",
+            claims.subject()
+        ).fetch_optional(&mut *tx).await?;
+
+        let Some(pending) = pending else {
+            return Ok(false);
+        };
+        let Some(step) = verify_code(&pending.secret, &body.code, chrono::Utc::now().timestamp()) else {
+            return Ok(false);
+        };
+
+        sqlx::query!(r"UPDATE totp_secrets
+            SET enabled_date = $2, last_used_step = $3
+            WHERE user_id = $1;",
+            claims.subject(),
+            chrono::Utc::now(),
+            step
+        ).execute(&mut *tx).await?;
+
+        sqlx::query!(r"DELETE FROM recovery_codes WHERE user_id = $1;", claims.subject())
+            .execute(&mut *tx).await?;
+
+        let hashes: Vec<Vec<u8>> = recovery_codes.iter().map(|c| hash_recovery_code(c)).collect();
+        sqlx::query!(r"INSERT INTO recovery_codes (user_id, code_hash)
+            SELECT $1, * FROM UNNEST($2::bytea[]);",
+            claims.subject(),
+            &hashes
+        ).execute(&mut *tx).await?;
+
+        tx.commit().await?;
+        Ok(true)
+    }.await;
+
+    match activated {
+        Ok(true) => {
+            audit::record(&req, AuditEvent::new(EventType::TotpEnabled, Some(claims.subject())).by(&claims)).await;
+            return HttpResponse::Ok().json(RecoveryCodes { recovery_codes });
+        },
+        Ok(false) => return HttpResponse::BadRequest().finish(),
+        Err(e) => {
+            error!("failed activating totp: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+}
+
+/// turns TOTP off, which requires a valid code so a stolen session alone
+/// isn't enough to do it
+pub async fn disable(req: HttpRequest, claims: JwtClaims, header: TokenHeader, body: Json<TotpCode>) -> impl Responder {
+    if !is_first_party(&claims, &header) {
+        return HttpResponse::Forbidden().finish();
+    }
+
+    let pool = req.app_data::<PgPool>().unwrap();
+
+    let disabled: Result<bool, sqlx::Error> = async {
+        if !check_code(pool, claims.subject(), &body.code).await? {
+            return Ok(false);
+        }
+
+        let mut tx = pool.begin().await?;
+        sqlx::query!(r"DELETE FROM recovery_codes WHERE user_id = $1;", claims.subject())
+            .execute(&mut *tx).await?;
+        sqlx::query!(r"DELETE FROM totp_secrets WHERE user_id = $1;", claims.subject())
+            .execute(&mut *tx).await?;
+        tx.commit().await?;
+        Ok(true)
+    }.await;
+
+    let event = AuditEvent::new(EventType::TotpDisabled, Some(claims.subject())).by(&claims);
+    match disabled {
+        Ok(true) => {
+            audit::record(&req, event).await;
+            return HttpResponse::NoContent().finish();
+        },
+        Ok(false) => {
+            audit::record(&req, event.failed("invalid_code")).await;
+            return HttpResponse::BadRequest().finish();
+        },
+        Err(e) => {
+            error!("failed disabling totp: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hotp, verify_code, hash_recovery_code};
+
+    // test vectors from RFC 6238, appendix B, truncated to 6 digits
+    const SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn test_totp_vectors() {
+        assert_eq!(hotp(SECRET, 59 / 30), 287082);
+        assert_eq!(hotp(SECRET, 1111111109 / 30), 81804);
+        assert_eq!(hotp(SECRET, 1234567890 / 30), 5924);
+        assert_eq!(hotp(SECRET, 2000000000 / 30), 279037);
+
+        assert_eq!(verify_code(SECRET, "081804", 1111111109), Some(1111111109 / 30));
+        // one step of drift either way is fine, two are not
+        assert!(verify_code(SECRET, "081804", 1111111109 + 30).is_some());
+        assert!(verify_code(SECRET, "081804", 1111111109 + 60).is_none());
+        assert!(verify_code(SECRET, "81804", 1111111109).is_none());
+    }
+
+    #[test]
+    fn test_recovery_code_normalization() {
+        assert_eq!(hash_recovery_code("abcde-12345"), hash_recovery_code(" ABCDE12345 "));
+    }
+}
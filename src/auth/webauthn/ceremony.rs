@@ -0,0 +1,287 @@
+use std::fmt::Display;
+
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use ciborium::Value;
+use p256::ecdsa::{Signature, VerifyingKey, signature::Verifier};
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+
+/// COSE algorithm identifier for ECDSA with P-256 and SHA-256, the only one
+/// supported for now
+pub const ES256: i64 = -7;
+
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_CREDENTIAL_DATA: u8 = 0x40;
+
+/// the relying party, i.e. this server, as seen by authenticators
+#[derive(Debug, Clone)]
+pub struct RelyingParty {
+    /// the domain credentials are scoped to
+    pub id: String,
+    /// the origin the browser reports the ceremonies happening on
+    pub origin: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CeremonyError {
+    InvalidEncoding,
+    /// the credential isn't a `public-key` one, the only type there is
+    WrongCredentialType,
+    WrongCeremony,
+    ChallengeMismatch,
+    OriginMismatch,
+    RelyingPartyMismatch,
+    UserNotPresent,
+    MissingCredentialData,
+    UnsupportedAlgorithm,
+    InvalidSignature,
+    SignCountRegressed,
+}
+
+impl Display for CeremonyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+impl std::error::Error for CeremonyError {}
+
+/// the `response` of a `PublicKeyCredential` returned by `navigator.credentials.create()`,
+/// with every binary field base64url-encoded
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AttestationResponse {
+    pub client_data_json: String,
+    pub attestation_object: String,
+}
+
+/// the `response` of a `PublicKeyCredential` returned by `navigator.credentials.get()`,
+/// with every binary field base64url-encoded
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AssertionResponse {
+    pub client_data_json: String,
+    pub authenticator_data: String,
+    pub signature: String,
+    pub user_handle: Option<String>,
+}
+
+/// a `PublicKeyCredential` as serialized by the client
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PublicKeyCredential<R> {
+    pub id: String,
+    pub raw_id: String,
+    #[serde(rename = "type")]
+    pub credential_type: String,
+    pub response: R,
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    ceremony: String,
+    challenge: String,
+    origin: String,
+}
+
+/// a newly registered credential, ready to be stored
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegisteredCredential {
+    pub credential_id: Vec<u8>,
+    /// uncompressed SEC1 encoding of the public key
+    pub public_key: Vec<u8>,
+    pub sign_count: u32,
+    pub user_verified: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VerifiedAssertion {
+    pub sign_count: u32,
+    pub user_verified: bool,
+}
+
+struct AuthenticatorData<'a> {
+    rp_id_hash: &'a [u8],
+    flags: u8,
+    sign_count: u32,
+    /// whatever comes after the fixed header, which is where the attested
+    /// credential data lives when there is any
+    rest: &'a [u8],
+}
+
+pub fn decode(data: &str) -> Result<Vec<u8>, CeremonyError> {
+    BASE64_URL_SAFE_NO_PAD.decode(data.trim_end_matches('='))
+        .map_err(|_| CeremonyError::InvalidEncoding)
+}
+
+pub fn encode(data: &[u8]) -> String {
+    BASE64_URL_SAFE_NO_PAD.encode(data)
+}
+
+fn parse_authenticator_data(data: &[u8]) -> Result<AuthenticatorData<'_>, CeremonyError> {
+    if data.len() < 37 {
+        return Err(CeremonyError::InvalidEncoding);
+    }
+    Ok(AuthenticatorData {
+        rp_id_hash: &data[..32],
+        flags: data[32],
+        sign_count: u32::from_be_bytes([data[33], data[34], data[35], data[36]]),
+        rest: &data[37..],
+    })
+}
+
+/// the checks shared by both ceremonies: the client data has to be for the
+/// right ceremony, challenge and origin, and the authenticator data for this
+/// relying party with the user present
+fn verify_common<'a>(
+    rp: &RelyingParty,
+    ceremony: &str,
+    challenge: &[u8],
+    client_data_json: &[u8],
+    authenticator_data: &'a [u8],
+) -> Result<AuthenticatorData<'a>, CeremonyError> {
+    let client_data: ClientData = serde_json::from_slice(client_data_json)
+        .map_err(|_| CeremonyError::InvalidEncoding)?;
+
+    if client_data.ceremony != ceremony {
+        return Err(CeremonyError::WrongCeremony);
+    }
+    if decode(&client_data.challenge)? != challenge {
+        return Err(CeremonyError::ChallengeMismatch);
+    }
+    if client_data.origin != rp.origin {
+        return Err(CeremonyError::OriginMismatch);
+    }
+
+    let auth_data = parse_authenticator_data(authenticator_data)?;
+    if auth_data.rp_id_hash != Sha256::digest(rp.id.as_bytes()).as_slice() {
+        return Err(CeremonyError::RelyingPartyMismatch);
+    }
+    if auth_data.flags & FLAG_USER_PRESENT == 0 {
+        return Err(CeremonyError::UserNotPresent);
+    }
+
+    Ok(auth_data)
+}
+
+fn map_get<'a>(map: &'a [(Value, Value)], key: &Value) -> Option<&'a Value> {
+    map.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+}
+
+/// converts an ES256 COSE key into an uncompressed SEC1 point
+fn parse_cose_key(data: &[u8]) -> Result<Vec<u8>, CeremonyError> {
+    let key: Value = ciborium::de::from_reader(data).map_err(|_| CeremonyError::InvalidEncoding)?;
+    let key = key.as_map().ok_or(CeremonyError::InvalidEncoding)?;
+
+    let int = |i: i64| Value::Integer(i.into());
+    let kty = map_get(key, &int(1)).and_then(Value::as_integer).map(i128::from);
+    let alg = map_get(key, &int(3)).and_then(Value::as_integer).map(i128::from);
+    let crv = map_get(key, &int(-1)).and_then(Value::as_integer).map(i128::from);
+
+    // EC2 key type on the P-256 curve
+    if kty != Some(2) || alg != Some(ES256 as i128) || crv != Some(1) {
+        return Err(CeremonyError::UnsupportedAlgorithm);
+    }
+
+    let x = map_get(key, &int(-2)).and_then(Value::as_bytes).ok_or(CeremonyError::InvalidEncoding)?;
+    let y = map_get(key, &int(-3)).and_then(Value::as_bytes).ok_or(CeremonyError::InvalidEncoding)?;
+    if x.len() != 32 || y.len() != 32 {
+        return Err(CeremonyError::InvalidEncoding);
+    }
+
+    let mut point = Vec::with_capacity(65);
+    point.push(0x04);
+    point.extend_from_slice(x);
+    point.extend_from_slice(y);
+
+    // makes sure the point is actually on the curve
+    VerifyingKey::from_sec1_bytes(&point).map_err(|_| CeremonyError::InvalidEncoding)?;
+    Ok(point)
+}
+
+/// verifies the result of a registration ceremony as described in
+/// [§7.1 of the spec](https://www.w3.org/TR/webauthn-2/#sctn-registering-a-new-credential).
+/// attestation statements aren't verified, since `none` attestation is
+/// requested and there's no list of trusted authenticators to check against
+pub fn verify_registration(
+    rp: &RelyingParty,
+    challenge: &[u8],
+    credential: &PublicKeyCredential<AttestationResponse>,
+) -> Result<RegisteredCredential, CeremonyError> {
+    if credential.credential_type != "public-key" {
+        return Err(CeremonyError::WrongCredentialType);
+    }
+    let client_data_json = decode(&credential.response.client_data_json)?;
+    let attestation_object: Value = ciborium::de::from_reader(decode(&credential.response.attestation_object)?.as_slice())
+        .map_err(|_| CeremonyError::InvalidEncoding)?;
+    let attestation_object = attestation_object.as_map().ok_or(CeremonyError::InvalidEncoding)?;
+    let authenticator_data = map_get(attestation_object, &Value::Text("authData".to_string()))
+        .and_then(Value::as_bytes)
+        .ok_or(CeremonyError::InvalidEncoding)?;
+
+    let auth_data = verify_common(rp, "webauthn.create", challenge, &client_data_json, authenticator_data)?;
+
+    if auth_data.flags & FLAG_ATTESTED_CREDENTIAL_DATA == 0 || auth_data.rest.len() < 18 {
+        return Err(CeremonyError::MissingCredentialData);
+    }
+
+    // 16 bytes of AAGUID, then the length-prefixed credential ID and the key
+    let id_length = u16::from_be_bytes([auth_data.rest[16], auth_data.rest[17]]) as usize;
+    let credential_data = &auth_data.rest[18..];
+    if credential_data.len() < id_length {
+        return Err(CeremonyError::MissingCredentialData);
+    }
+    let (credential_id, public_key) = credential_data.split_at(id_length);
+
+    if decode(&credential.raw_id)? != credential_id {
+        return Err(CeremonyError::InvalidEncoding);
+    }
+
+    Ok(RegisteredCredential {
+        credential_id: credential_id.to_vec(),
+        public_key: parse_cose_key(public_key)?,
+        sign_count: auth_data.sign_count,
+        user_verified: auth_data.flags & FLAG_USER_VERIFIED != 0,
+    })
+}
+
+/// verifies the result of an authentication ceremony as described in
+/// [§7.2 of the spec](https://www.w3.org/TR/webauthn-2/#sctn-verifying-assertion)
+/// against a previously registered credential
+pub fn verify_authentication(
+    rp: &RelyingParty,
+    challenge: &[u8],
+    public_key: &[u8],
+    stored_sign_count: u32,
+    credential: &PublicKeyCredential<AssertionResponse>,
+) -> Result<VerifiedAssertion, CeremonyError> {
+    if credential.credential_type != "public-key" {
+        return Err(CeremonyError::WrongCredentialType);
+    }
+    let client_data_json = decode(&credential.response.client_data_json)?;
+    let authenticator_data = decode(&credential.response.authenticator_data)?;
+    let signature = decode(&credential.response.signature)?;
+
+    let auth_data = verify_common(rp, "webauthn.get", challenge, &client_data_json, &authenticator_data)?;
+
+    let key = VerifyingKey::from_sec1_bytes(public_key).map_err(|_| CeremonyError::InvalidEncoding)?;
+    let signature = Signature::from_der(&signature).map_err(|_| CeremonyError::InvalidSignature)?;
+
+    let mut signed = authenticator_data.clone();
+    signed.extend_from_slice(&Sha256::digest(&client_data_json));
+    key.verify(&signed, &signature).map_err(|_| CeremonyError::InvalidSignature)?;
+
+    // authenticators that keep a counter always increase it, so one that
+    // didn't probably means the credential was cloned
+    if (auth_data.sign_count != 0 || stored_sign_count != 0) && auth_data.sign_count <= stored_sign_count {
+        return Err(CeremonyError::SignCountRegressed);
+    }
+
+    Ok(VerifiedAssertion {
+        sign_count: auth_data.sign_count,
+        user_verified: auth_data.flags & FLAG_USER_VERIFIED != 0,
+    })
+}
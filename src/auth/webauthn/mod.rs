@@ -0,0 +1,445 @@
+use std::sync::OnceLock;
+
+use actix_web::{HttpRequest, Responder, web::{Json, Path}, HttpResponse};
+use log::error;
+use rand::RngCore;
+use serde::{Serialize, Deserialize};
+use serde_json::json;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::audit::{self, AuditEvent, EventType};
+
+use super::token::{jwt::{JwtClaims, TokenHeader}, is_first_party};
+use ceremony::{RelyingParty, PublicKeyCredential, AttestationResponse, AssertionResponse, ES256};
+
+/// verification of the registration and authentication ceremonies, kept free
+/// of any database access so it can be tested with a software authenticator
+pub mod ceremony;
+
+/// how long ceremonies can take before their challenge expires, in seconds
+const CHALLENGE_LIFETIME_SECONDS: i64 = 300;
+
+static RELYING_PARTY: OnceLock<RelyingParty> = OnceLock::new();
+
+/// the relying party ID and origin, taken from the `WEBAUTHN_RP_ID` and
+/// `WEBAUTHN_ORIGIN` environment variables, defaulting to a local server
+pub fn relying_party() -> &'static RelyingParty {
+    RELYING_PARTY.get_or_init(|| {
+        RelyingParty {
+            id: dotenvy::var("WEBAUTHN_RP_ID").unwrap_or_else(|_| "localhost".to_string()),
+            origin: dotenvy::var("WEBAUTHN_ORIGIN").unwrap_or_else(|_| "http://localhost:8080".to_string()),
+        }
+    })
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RegistrationFinish {
+    challenge_id: Uuid,
+    /// lets users tell their authenticators apart
+    name: String,
+    credential: PublicKeyCredential<AttestationResponse>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AuthenticationBegin {
+    /// can be left out to use discoverable credentials
+    username: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AuthenticationFinish {
+    challenge_id: Uuid,
+    credential: PublicKeyCredential<AssertionResponse>,
+}
+
+#[derive(Serialize)]
+struct Credential {
+    credential_id: String,
+    name: String,
+    creation_date: chrono::DateTime<chrono::Utc>,
+    last_used_date: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// stores a new random challenge, which can only be used once
+async fn create_challenge(pool: &PgPool, user_id: Option<Uuid>, ceremony: &str) -> Result<(Uuid, Vec<u8>), sqlx::Error> {
+    let mut challenge = vec![0u8; 32];
+    rand::thread_rng().fill_bytes(&mut challenge);
+
+    let created = sqlx::query!(r"INSERT INTO webauthn_challenges
+        (challenge_id, user_id, challenge, ceremony, expiration_date)
+        VALUES (gen_random_uuid(), $1, $2, $3, $4)
+        RETURNING challenge_id;",
+        user_id,
+        &challenge,
+        ceremony,
+        chrono::Utc::now() + chrono::Duration::seconds(CHALLENGE_LIFETIME_SECONDS)
+    ).fetch_one(pool).await?;
+
+    Ok((created.challenge_id, challenge))
+}
+
+/// takes a challenge out of the database, so it can't be used again
+async fn consume_challenge(pool: &PgPool, challenge_id: Uuid, ceremony: &str) -> Result<Option<(Option<Uuid>, Vec<u8>)>, sqlx::Error> {
+    let consumed = sqlx::query!(r"DELETE FROM webauthn_challenges
+        WHERE challenge_id = $1 AND ceremony = $2 AND expiration_date > $3
+        RETURNING user_id, challenge;",
+        challenge_id,
+        ceremony,
+        chrono::Utc::now()
+    ).fetch_optional(pool).await?;
+
+    Ok(consumed.map(|o| (o.user_id, o.challenge)))
+}
+
+async fn credential_descriptors(pool: &PgPool, user_id: Uuid) -> Result<Vec<serde_json::Value>, sqlx::Error> {
+    let credentials = sqlx::query!(r"SELECT credential_id FROM webauthn_credentials WHERE user_id = $1;", user_id)
+        .fetch_all(pool).await?;
+    Ok(credentials.iter()
+        .map(|c| json!({ "type": "public-key", "id": ceremony::encode(&c.credential_id) }))
+        .collect())
+}
+
+/// made up authenticators for users without any, including usernames nobody
+/// has, so they look like users with some. the same username always gets the
+/// same ones, so asking twice doesn't give them away either
+fn decoy_descriptors(username: &str) -> Vec<serde_json::Value> {
+    let mut seed = super::token::jwt::derived_key("webauthn_decoys");
+    seed.extend_from_slice(username.as_bytes());
+    let seed = crate::auth::hash(&seed);
+
+    // one or two, like most users have
+    let count = 1 + (seed[0] & 1);
+    (0..count)
+        .map(|i| {
+            let mut data = seed.clone();
+            data.push(i);
+            let credential_id = &crate::auth::hash(&data)[..32];
+            json!({ "type": "public-key", "id": ceremony::encode(credential_id) })
+        })
+        .collect()
+}
+
+/// starts registering a new authenticator for the logged in user, returning
+/// the options to pass to `navigator.credentials.create()`
+pub async fn register_begin(req: HttpRequest, claims: JwtClaims, header: TokenHeader) -> impl Responder {
+    if !is_first_party(&claims, &header) {
+        return HttpResponse::Forbidden().finish();
+    }
+
+    let pool = req.app_data::<PgPool>().unwrap();
+    let rp = relying_party();
+
+    let options: Result<serde_json::Value, sqlx::Error> = async {
+        let user = sqlx::query!(r"SELECT username FROM users WHERE user_id = $1;", claims.subject())
+            .fetch_one(pool).await?;
+        let exclude = credential_descriptors(pool, claims.subject()).await?;
+        let (challenge_id, challenge) = create_challenge(pool, Some(claims.subject()), "registration").await?;
+
+        Ok(json!({
+            "challenge_id": challenge_id,
+            "publicKey": {
+                "rp": { "id": rp.id, "name": "CyberBank" },
+                "user": {
+                    "id": ceremony::encode(claims.subject().as_bytes()),
+                    "name": user.username,
+                    "displayName": user.username,
+                },
+                "challenge": ceremony::encode(&challenge),
+                "pubKeyCredParams": [{ "type": "public-key", "alg": ES256 }],
+                "timeout": CHALLENGE_LIFETIME_SECONDS * 1000,
+                "attestation": "none",
+                "excludeCredentials": exclude,
+                "authenticatorSelection": {
+                    "residentKey": "preferred",
+                    "userVerification": "preferred",
+                },
+            }
+        }))
+    }.await;
+
+    match options {
+        Ok(o) => return HttpResponse::Ok().json(o),
+        Err(e) => {
+            error!("failed starting webauthn registration: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+}
+
+/// finishes registering an authenticator, storing its public key
+pub async fn register_finish(req: HttpRequest, claims: JwtClaims, header: TokenHeader, body: Json<RegistrationFinish>) -> impl Responder {
+    if !is_first_party(&claims, &header) {
+        return HttpResponse::Forbidden().finish();
+    }
+
+    let name = body.name.trim();
+    if name.is_empty() || name.chars().count() > 64 {
+        return HttpResponse::BadRequest().finish();
+    }
+
+    let pool = req.app_data::<PgPool>().unwrap();
+
+    let challenge = match consume_challenge(pool, body.challenge_id, "registration").await {
+        Ok(Some((Some(user_id), challenge))) if user_id == claims.subject() => challenge,
+        Ok(_) => return HttpResponse::BadRequest().finish(),
+        Err(e) => {
+            error!("failed fetching webauthn challenge: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    let credential = match ceremony::verify_registration(relying_party(), &challenge, &body.credential) {
+        Ok(o) => o,
+        Err(e) => return HttpResponse::BadRequest().json(e),
+    };
+
+    let insert = sqlx::query!(r"INSERT INTO webauthn_credentials
+        (credential_id, user_id, name, public_key, sign_count, creation_date)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        ON CONFLICT (credential_id) DO NOTHING;",
+        credential.credential_id,
+        claims.subject(),
+        name,
+        credential.public_key,
+        credential.sign_count as i64,
+        chrono::Utc::now()
+    ).execute(pool).await;
+
+    match insert {
+        Ok(o) => {
+            if o.rows_affected() == 0 {
+                return HttpResponse::Conflict().finish();
+            } else {
+                let credential_id = ceremony::encode(&credential.credential_id);
+                audit::record(&req, AuditEvent::new(EventType::WebauthnAdded, Some(claims.subject()))
+                    .by(&claims)
+                    .with_details(json!({ "credential_id": credential_id, "name": name }))
+                ).await;
+                return HttpResponse::Created().json(json!({
+                    "credential_id": credential_id
+                }));
+            }
+        },
+        Err(e) => {
+            error!("failed inserting webauthn credential: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+}
+
+/// starts a passwordless login, returning the options to pass to
+/// `navigator.credentials.get()`
+pub async fn login_begin(req: HttpRequest, body: Json<AuthenticationBegin>) -> impl Responder {
+    let pool = req.app_data::<PgPool>().unwrap();
+
+    let options: Result<serde_json::Value, sqlx::Error> = async {
+        let username = body.username.as_deref().map(super::canonical::username);
+        let user_id = match &username {
+            Some(username) => sqlx::query!(r"SELECT user_id FROM users WHERE username = $1;", username)
+                .fetch_optional(pool).await?
+                .map(|o| o.user_id),
+            None => None,
+        };
+        let mut allow = match user_id {
+            Some(user_id) => credential_descriptors(pool, user_id).await?,
+            None => Vec::new(),
+        };
+        // an empty list would tell who has authenticators, and the lack of
+        // a list who has an account
+        if let Some(username) = username.as_deref().filter(|_| allow.is_empty()) {
+            allow = decoy_descriptors(username);
+        }
+        let (challenge_id, challenge) = create_challenge(pool, user_id, "authentication").await?;
+
+        Ok(json!({
+            "challenge_id": challenge_id,
+            "publicKey": {
+                "rpId": relying_party().id,
+                "challenge": ceremony::encode(&challenge),
+                "timeout": CHALLENGE_LIFETIME_SECONDS * 1000,
+                "allowCredentials": allow,
+                "userVerification": "preferred",
+            }
+        }))
+    }.await;
+
+    match options {
+        Ok(o) => return HttpResponse::Ok().json(o),
+        Err(e) => {
+            error!("failed starting webauthn authentication: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+}
+
+/// finishes a passwordless login. authenticators that verified the user
+/// (with a PIN or biometrics) count as two factors on their own, otherwise
+/// users with TOTP enabled still have to go through `/login/mfa`
+pub async fn login_finish(req: HttpRequest, body: Json<AuthenticationFinish>) -> impl Responder {
+    let pool = req.app_data::<PgPool>().unwrap();
+
+    let (expected_user, challenge) = match consume_challenge(pool, body.challenge_id, "authentication").await {
+        Ok(Some(o)) => o,
+        Ok(None) => return HttpResponse::Forbidden().finish(),
+        Err(e) => {
+            error!("failed fetching webauthn challenge: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    let Ok(credential_id) = ceremony::decode(&body.credential.raw_id) else {
+        return HttpResponse::Forbidden().finish();
+    };
+
+    let stored = sqlx::query!(r"SELECT user_id, public_key, sign_count FROM webauthn_credentials
+        WHERE credential_id = $1;",
+        credential_id
+    ).fetch_optional(pool).await;
+
+    let stored = match stored {
+        Ok(Some(o)) if expected_user.unwrap_or(o.user_id) == o.user_id => o,
+        Ok(_) => {
+            audit::record(&req, AuditEvent::new(EventType::Login, expected_user)
+                .failed("unknown_credential")
+                .with_details(json!({ "method": "webauthn" }))
+            ).await;
+            return HttpResponse::Forbidden().finish();
+        },
+        Err(e) => {
+            error!("failed fetching webauthn credential: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    let event = AuditEvent::new(EventType::Login, Some(stored.user_id))
+        .with_details(json!({ "method": "webauthn", "credential_id": ceremony::encode(&credential_id) }));
+
+    let assertion = match ceremony::verify_authentication(
+        relying_party(),
+        &challenge,
+        &stored.public_key,
+        stored.sign_count as u32,
+        &body.credential
+    ) {
+        Ok(o) => o,
+        Err(_) => {
+            audit::record(&req, event.failed("invalid_assertion")).await;
+            return HttpResponse::Forbidden().finish();
+        },
+    };
+
+    // only moves forward if nobody else used the credential in the meantime
+    let update = sqlx::query!(r"UPDATE webauthn_credentials
+        SET sign_count = $2, last_used_date = $3
+        WHERE credential_id = $1 AND sign_count = $4;",
+        credential_id,
+        assertion.sign_count as i64,
+        chrono::Utc::now(),
+        stored.sign_count
+    ).execute(pool).await;
+
+    match update {
+        Ok(o) if o.rows_affected() == 1 => (),
+        Ok(_) => {
+            audit::record(&req, event.failed("credential_reused")).await;
+            return HttpResponse::Forbidden().finish();
+        },
+        Err(e) => {
+            error!("failed updating webauthn credential: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+    audit::record(&req, event).await;
+
+    if assertion.user_verified {
+        return super::login::full_login_response(&req, pool, stored.user_id).await;
+    } else {
+        return super::login::first_factor_response(&req, pool, stored.user_id).await;
+    }
+}
+
+/// lists the authenticators registered by the logged in user
+pub async fn list(req: HttpRequest, claims: JwtClaims) -> impl Responder {
+    let pool = req.app_data::<PgPool>().unwrap();
+
+    let credentials = sqlx::query!(r"SELECT credential_id, name, creation_date, last_used_date
+        FROM webauthn_credentials
+        WHERE user_id = $1
+        ORDER BY creation_date DESC;",
+        claims.subject()
+    ).fetch_all(pool).await;
+
+    match credentials {
+        Ok(o) => {
+            return HttpResponse::Ok().json(o.into_iter().map(|c| Credential {
+                credential_id: ceremony::encode(&c.credential_id),
+                name: c.name,
+                creation_date: c.creation_date,
+                last_used_date: c.last_used_date,
+            }).collect::<Vec<Credential>>());
+        },
+        Err(e) => {
+            error!("failed listing webauthn credentials: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+}
+
+/// removes one of the logged in user's authenticators
+pub async fn delete(req: HttpRequest, claims: JwtClaims, header: TokenHeader, credential_id: Path<String>) -> impl Responder {
+    if !is_first_party(&claims, &header) {
+        return HttpResponse::Forbidden().finish();
+    }
+
+    let Ok(credential_id) = ceremony::decode(&credential_id) else {
+        return HttpResponse::NotFound().finish();
+    };
+
+    let pool = req.app_data::<PgPool>().unwrap();
+
+    let deletion = sqlx::query!(r"DELETE FROM webauthn_credentials
+        WHERE credential_id = $1 AND user_id = $2;",
+        credential_id,
+        claims.subject()
+    ).execute(pool).await;
+
+    match deletion {
+        Ok(o) => {
+            if o.rows_affected() == 0 {
+                return HttpResponse::NotFound().finish();
+            } else {
+                audit::record(&req, AuditEvent::new(EventType::WebauthnRemoved, Some(claims.subject()))
+                    .by(&claims)
+                    .with_details(json!({ "credential_id": ceremony::encode(&credential_id) }))
+                ).await;
+                return HttpResponse::NoContent().finish();
+            }
+        },
+        Err(e) => {
+            error!("failed deleting webauthn credential: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+}
+
+/// adds the WebAuthn registration, login and credential management endpoints
+pub fn config(cfg: &mut actix_web::web::ServiceConfig) {
+    use actix_web::web;
+    use super::token::{ScopeValidator, jwt::Scope};
+
+    cfg.route("/login/begin", web::post().to(login_begin))
+        .route("/login/finish", web::post().to(login_finish))
+        .service(
+            web::scope("/register")
+                .wrap(ScopeValidator::new(&[Scope::User]))
+                .route("/begin", web::post().to(register_begin))
+                .route("/finish", web::post().to(register_finish))
+        )
+        .service(
+            web::scope("/credentials")
+                .wrap(ScopeValidator::new(&[Scope::User]))
+                .route("", web::get().to(list))
+                .route("/{credential_id}", web::delete().to(delete))
+        );
+}
@@ -0,0 +1,53 @@
+//! walks the audit log's hash chain in the database from `.env`, checking
+//! every event and signed checkpoint. exits with 1 and reports the first
+//! broken link if there is one. needs to run as the same user as the server
+//! to get at the checkpoint signing key
+//!
+//! usage: `audit_verify [--checkpoint]`, which signs a checkpoint of the
+//! chain as it is first with `--checkpoint`
+
+use cyber_bank_rs::{audit::chain::{self, VerifyError}, db};
+
+#[tokio::main]
+async fn main() {
+    let pool = db::get_db_pool().await;
+
+    if std::env::args().any(|a| a == "--checkpoint") {
+        match chain::checkpoint(&pool).await {
+            Ok(Some(position)) => println!("signed a checkpoint at position {position}"),
+            Ok(None) => println!("nothing to sign since the last checkpoint"),
+            Err(e) => {
+                eprintln!("failed signing a checkpoint: {e}");
+                std::process::exit(2);
+            }
+        }
+    }
+
+    let key = chain::verifying_key();
+    let mut conn = pool.acquire().await.expect("failed connecting to the database");
+    match chain::verify(&mut conn, &key).await {
+        Ok(o) => {
+            println!(
+                "the chain is intact up to position {}, with {} checkpoints signed by key {}",
+                o.last_position,
+                o.checkpoints,
+                chain::key_id(&key)
+            );
+            match o.last_checkpoint {
+                Some(position) if position < o.last_position => {
+                    println!("events after position {position} aren't covered by a checkpoint yet");
+                },
+                Some(_) => (),
+                None => println!("no checkpoint was signed yet"),
+            }
+        },
+        Err(VerifyError::Broken(e)) => {
+            eprintln!("the chain is broken: {e}");
+            std::process::exit(1);
+        },
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(2);
+        }
+    }
+}
@@ -0,0 +1,52 @@
+//! builds the filter of breached passwords the server checks new passwords
+//! against, reading one password per line from stdin. lines can either be
+//! plain passwords or SHA-1 hashes in hex, optionally followed by `:{count}`
+//! like in the Have I Been Pwned downloads.
+//!
+//! usage: `breached_filter {output file} {expected number of passwords} [false positive rate]`,
+//! the false positive rate defaults to 0.001
+
+use std::io::BufRead;
+
+use cyber_bank_rs::auth::policy::BreachedPasswords;
+use data_encoding::HEXUPPER_PERMISSIVE;
+
+/// the SHA-1 hash a line holds, if it isn't a plain password
+fn parse_sha1(line: &str) -> Option<[u8; 20]> {
+    let hex = line.split_once(':').map_or(line, |(hash, _)| hash);
+    if hex.len() != 40 {
+        return None;
+    }
+    HEXUPPER_PERMISSIVE.decode(hex.as_bytes()).ok()?.try_into().ok()
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 3 {
+        eprintln!("usage: {} {{output file}} {{expected number of passwords}} [false positive rate]", args[0]);
+        std::process::exit(2);
+    }
+    let expected = args[2].parse().expect("invalid number of passwords");
+    let false_positive_rate = args.get(3).map_or(0.001, |r| r.parse().expect("invalid false positive rate"));
+
+    let mut filter = BreachedPasswords::new(expected, false_positive_rate);
+    let mut count: u64 = 0;
+    for line in std::io::stdin().lock().lines() {
+        let line = line.expect("failed reading stdin");
+        let line = line.trim_end_matches('\r');
+        if line.is_empty() {
+            continue;
+        }
+        match parse_sha1(line) {
+            Some(sha1) => filter.insert_sha1(&sha1),
+            None => filter.insert(line),
+        }
+        count += 1;
+    }
+
+    filter.write(&args[1]).expect("failed writing filter");
+    if count > expected {
+        eprintln!("warning: got {count} passwords instead of {expected}, the false positive rate is higher than asked for");
+    }
+    println!("added {count} passwords to {}", args[1]);
+}
@@ -1,5 +1,5 @@
 use actix_web::{HttpServer, App, web, middleware::Logger};
-use cyber_bank_rs::{db, auth::token::{middleware::ScopeValidator, jwt::Scope}};
+use cyber_bank_rs::{audit::{self, AuditLogger}, db, mail, store};
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
@@ -13,10 +13,25 @@ async fn main() -> std::io::Result<()> {
     // sets up tables and stuff for the database (in case it wasn't already set up)
     db::set_up_db_tables(&pool).await;
 
+    let mailer = mail::from_env(&pool);
+    let session_store = store::from_env(&pool).await;
+    let audit_logger = AuditLogger::new(pool.clone());
+
+    // fails early if the breached passwords can't be read
+    cyber_bank_rs::auth::policy::current();
+    cyber_bank_rs::users::check_env();
+    audit::chain::check_signing_key();
+
+    cyber_bank_rs::users::deletion::spawn_purging(pool.clone());
+    audit::chain::spawn_sealing(pool.clone());
+    audit::chain::spawn_checkpoints(pool.clone());
+
     HttpServer::new(move || {
         let conn = pool.clone();
         App::new()
-            .wrap(Logger::default())
+            .wrap(audit::AssignRequestId)
+            // the default format, with the request's id added
+            .wrap(Logger::new(r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T %{x-request-id}o"#))
             // authentication endpoints
             .service(
                 web::scope("/auth")
@@ -28,7 +43,12 @@ async fn main() -> std::io::Result<()> {
                     // should be set to latest version
                     .configure(cyber_bank_rs::auth::config)
             )
+            .service(web::scope("/users").configure(cyber_bank_rs::users::config))
+            .service(web::scope("/admin").configure(audit::admin_config))
             .app_data(conn)
+            .app_data(mailer.clone())
+            .app_data(session_store.clone())
+            .app_data(audit_logger.clone())
     }).bind(("0.0.0.0", 8080))
         .unwrap()
         .run()
@@ -6,3 +6,40 @@ pub mod auth;
 
 /// convenience mathods for connecting to and setting up the database
 pub mod db;
+
+/// sending emails to users, or keeping them in an outbox during development
+pub mod mail;
+
+/// checking request payloads, reporting every problem with them at once
+pub mod validation;
+
+/// endpoints for users to manage their own accounts
+pub mod users;
+
+/// short-lived state like revoked tokens, refresh tokens, rate limit
+/// counters and idempotency keys, kept in Postgres or Redis
+pub mod store;
+
+/// an append-only record of security relevant events, like logins, password
+/// changes and revoked tokens
+pub mod audit;
+
+
+/// the app data of type `T`, or a response to send if the server wasn't set
+/// up with it
+pub(crate) fn app_data<T: 'static>(req: &actix_web::HttpRequest) -> Result<&T, actix_web::HttpResponse> {
+    req.app_data::<T>().ok_or_else(|| {
+        log::error!("no {} in the app data", std::any::type_name::<T>());
+        actix_web::HttpResponse::InternalServerError().finish()
+    })
+}
+
+/// the environment variable parsed as a `T`, or the default if it isn't set.
+/// panics if it's set to something that can't be parsed, so a typo doesn't
+/// quietly fall back to the default
+pub(crate) fn env_var<T: std::str::FromStr>(name: &str, default: T) -> T {
+    match dotenvy::var(name) {
+        Ok(v) => v.parse().unwrap_or_else(|_| panic!("invalid {name} {v}")),
+        Err(_) => default,
+    }
+}
@@ -0,0 +1,76 @@
+use std::{fmt::Display, sync::Arc};
+
+use async_trait::async_trait;
+use log::error;
+use sqlx::PgPool;
+
+/// the Postgres and file outboxes, which keep emails around instead of
+/// sending them
+mod outbox;
+pub use outbox::{FileOutbox, PgOutbox};
+
+mod smtp;
+pub use smtp::SmtpMailer;
+
+/// a plain text email
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+#[derive(Debug)]
+pub enum MailError {
+    InvalidAddress(String),
+    Delivery(String),
+}
+
+impl Display for MailError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidAddress(a) => write!(f, "invalid address: {a}"),
+            Self::Delivery(e) => write!(f, "failed delivering email: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for MailError {}
+
+/// something emails to users can be sent through. the server shares one as
+/// an `Arc<dyn Mailer>` in its app data
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: Email) -> Result<(), MailError>;
+}
+
+/// the address emails are sent from, set with the `MAIL_FROM` environment
+/// variable
+fn sender() -> String {
+    dotenvy::var("MAIL_FROM").unwrap_or_else(|_| "cyber_bank_rs <noreply@localhost>".to_string())
+}
+
+/// picks a mailer based on the `MAILER` environment variable, which can be
+/// `smtp`, `file` or `postgres`. defaults to the Postgres outbox, so nothing
+/// gets sent by accident during development
+pub fn from_env(pool: &PgPool) -> Arc<dyn Mailer> {
+    match dotenvy::var("MAILER").as_deref() {
+        Ok("smtp") => Arc::new(SmtpMailer::from_env()),
+        Ok("file") => Arc::new(FileOutbox::new(
+            dotenvy::var("MAIL_OUTBOX_DIR").unwrap_or_else(|_| "outbox".to_string())
+        )),
+        Ok("postgres") | Err(_) => Arc::new(PgOutbox::new(pool.clone())),
+        Ok(other) => panic!("unknown MAILER {other}"),
+    }
+}
+
+/// sends the email without waiting for it, so how long sending takes can't
+/// tell anything about who it was sent to. failures are only logged
+pub fn send_in_background(mailer: &Arc<dyn Mailer>, email: Email) {
+    let mailer = Arc::clone(mailer);
+    tokio::spawn(async move {
+        if let Err(e) = mailer.send(email).await {
+            error!("failed sending email: {e}");
+        }
+    });
+}
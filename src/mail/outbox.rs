@@ -0,0 +1,65 @@
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use lettre::{AsyncFileTransport, AsyncTransport, Tokio1Executor, message::Mailbox};
+use sqlx::PgPool;
+
+use super::{Email, MailError, Mailer, smtp::build_message};
+
+/// stores emails in the `mail_outbox` table instead of sending them, which
+/// is where tests and local development can read them from
+pub struct PgOutbox {
+    pool: PgPool,
+}
+
+impl PgOutbox {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl Mailer for PgOutbox {
+    async fn send(&self, email: Email) -> Result<(), MailError> {
+        sqlx::query!(r"INSERT INTO mail_outbox
+            (mail_id, recipient, subject, body, creation_date)
+            VALUES (gen_random_uuid(), $1, $2, $3, $4);",
+            email.to,
+            email.subject,
+            email.body,
+            chrono::Utc::now()
+        ).execute(&self.pool).await
+            .map_err(|e| MailError::Delivery(e.to_string()))?;
+        Ok(())
+    }
+}
+
+/// writes every email into its own `.eml` file in a directory
+pub struct FileOutbox {
+    transport: AsyncFileTransport<Tokio1Executor>,
+    dir: PathBuf,
+    from: Mailbox,
+}
+
+impl FileOutbox {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        let dir = dir.into();
+        Self {
+            transport: AsyncFileTransport::new(&dir),
+            dir,
+            from: super::sender().parse().expect("MAIL_FROM is not an address"),
+        }
+    }
+}
+
+#[async_trait]
+impl Mailer for FileOutbox {
+    async fn send(&self, email: Email) -> Result<(), MailError> {
+        let message = build_message(&self.from, email)?;
+        tokio::fs::create_dir_all(&self.dir).await
+            .map_err(|e| MailError::Delivery(e.to_string()))?;
+        self.transport.send(message).await
+            .map_err(|e| MailError::Delivery(e.to_string()))?;
+        Ok(())
+    }
+}
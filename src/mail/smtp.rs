@@ -0,0 +1,67 @@
+use async_trait::async_trait;
+use lettre::{
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+    message::{Mailbox, header::ContentType},
+    transport::smtp::authentication::Credentials,
+};
+
+use super::{Email, MailError, Mailer};
+
+/// sends emails through an SMTP server
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    pub fn new(transport: AsyncSmtpTransport<Tokio1Executor>, from: Mailbox) -> Self {
+        Self { transport, from }
+    }
+
+    /// connects to `SMTP_HOST`, using `SMTP_USERNAME` and `SMTP_PASSWORD` if
+    /// they are set. `SMTP_TLS` can be `starttls` (the default), `tls` or
+    /// `none`, the last of which is only meant for local SMTP sinks.
+    /// `SMTP_PORT` defaults to whatever fits the TLS mode
+    pub fn from_env() -> Self {
+        let host = dotenvy::var("SMTP_HOST")
+            .expect("SMTP_HOST ENV VAR NOT SET");
+        let mut builder = match dotenvy::var("SMTP_TLS").as_deref() {
+            Ok("starttls") | Err(_) => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&host).unwrap(),
+            Ok("tls") => AsyncSmtpTransport::<Tokio1Executor>::relay(&host).unwrap(),
+            Ok("none") => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&host),
+            Ok(other) => panic!("unknown SMTP_TLS {other}"),
+        };
+        if let Ok(port) = dotenvy::var("SMTP_PORT") {
+            builder = builder.port(port.parse().expect("SMTP_PORT is not a port"));
+        }
+        if let (Ok(username), Ok(password)) = (dotenvy::var("SMTP_USERNAME"), dotenvy::var("SMTP_PASSWORD")) {
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+
+        Self::new(builder.build(), super::sender().parse().expect("MAIL_FROM is not an address"))
+    }
+}
+
+/// turns the email into a message ready to be sent by one of lettre's
+/// transports
+pub(super) fn build_message(from: &Mailbox, email: Email) -> Result<Message, MailError> {
+    let to: Mailbox = email.to.parse()
+        .map_err(|_| MailError::InvalidAddress(email.to.clone()))?;
+    Message::builder()
+        .from(from.clone())
+        .to(to)
+        .subject(email.subject)
+        .header(ContentType::TEXT_PLAIN)
+        .body(email.body)
+        .map_err(|e| MailError::Delivery(e.to_string()))
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: Email) -> Result<(), MailError> {
+        let message = build_message(&self.from, email)?;
+        self.transport.send(message).await
+            .map_err(|e| MailError::Delivery(e.to_string()))?;
+        Ok(())
+    }
+}
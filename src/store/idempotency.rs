@@ -0,0 +1,195 @@
+use std::{future::{Future, Ready, ready}, pin::Pin, rc::Rc, sync::Arc};
+
+use actix_web::{
+    body::{self, BoxBody, EitherBody, MessageBody},
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::{header::{HeaderName, CONTENT_TYPE}, Method, StatusCode},
+    web::Bytes,
+    HttpMessage, HttpResponse,
+};
+use chrono::{Duration, Utc};
+use data_encoding::HEXLOWER;
+use log::error;
+use sha2::{Digest, Sha256};
+
+use crate::auth::token::jwt::JwtClaims;
+
+use super::{SessionStore, StoredResponse};
+
+/// the header clients send a key of their choosing in, unique per operation
+pub const IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
+
+/// set on responses that were replayed instead of handling the request again
+pub const IDEMPOTENT_REPLAYED: HeaderName = HeaderName::from_static("idempotent-replayed");
+
+/// how long responses are kept around for retries
+const IDEMPOTENCY_HOURS: i64 = 24;
+
+const MAX_KEY_LENGTH: usize = 255;
+
+/// makes requests with an `Idempotency-Key` header safe to retry: the first
+/// one is handled as usual, later ones with the same key get the same
+/// response without being handled again. only applies to authenticated,
+/// unsafe requests, so it has to be wrapped inside a
+/// [`ScopeValidator`](crate::auth::token::ScopeValidator). keys are kept
+/// apart by user, method and path. server errors aren't stored, so those
+/// requests can be retried
+pub struct Idempotent {
+    keep_body: bool,
+}
+
+impl Idempotent {
+    /// replays the whole response
+    pub fn replaying() -> Self {
+        Self { keep_body: true }
+    }
+
+    /// for responses with secrets in them, which are only ever shown once.
+    /// only the status is kept, and retries get HTTP Status 409 without a
+    /// body instead of the secret again
+    pub fn withholding() -> Self {
+        Self { keep_body: false }
+    }
+}
+
+pub struct IdempotentMiddleware<S> {
+    service: Rc<S>,
+    keep_body: bool,
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Idempotent
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+
+    type Error = actix_web::Error;
+
+    type Transform = IdempotentMiddleware<S>;
+
+    type InitError = ();
+
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(IdempotentMiddleware { service: Rc::new(service), keep_body: self.keep_body }))
+    }
+}
+
+/// the key the request's idempotency key is stored under, if it has a usable
+/// one and should be handled idempotently at all
+fn store_key(req: &ServiceRequest) -> Option<String> {
+    if matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+        return None;
+    }
+    let key = req.headers().get(IDEMPOTENCY_KEY)?.to_str().ok()?;
+    if key.is_empty() || key.len() > MAX_KEY_LENGTH {
+        return None;
+    }
+    let user_id = req.extensions().get::<JwtClaims>()?.subject();
+    Some(format!("{user_id}:{}:{}:{key}", req.method(), req.path()))
+}
+
+fn replay(response: StoredResponse) -> HttpResponse {
+    let Some(body) = response.body else {
+        // the request was already handled, but what it got can't be sent again
+        return HttpResponse::Conflict().insert_header((IDEMPOTENT_REPLAYED, "true")).finish();
+    };
+    let status = StatusCode::from_u16(response.status).unwrap_or(StatusCode::OK);
+    let mut builder = HttpResponse::build(status);
+    builder.insert_header((IDEMPOTENT_REPLAYED, "true"));
+    if let Some(content_type) = response.content_type {
+        builder.insert_header((CONTENT_TYPE, content_type));
+    }
+    builder.body(body)
+}
+
+impl<S, B> Service<ServiceRequest> for IdempotentMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+
+    type Error = actix_web::Error;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&self, ctx: &mut core::task::Context<'_>) -> std::task::Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(ctx)
+    }
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let keep_body = self.keep_body;
+
+        Box::pin(async move {
+            let store = req.app_data::<Arc<dyn SessionStore>>().cloned();
+            let (Some(key), Some(store)) = (store_key(&req), store) else {
+                return service.call(req).await.map(|o| o.map_into_left_body());
+            };
+
+            // the body is read to tell requests apart that reuse a key, and
+            // put back for the handler
+            let payload = req.extract::<Bytes>().await?;
+            let fingerprint = HEXLOWER.encode(&Sha256::digest(&payload));
+            let (_, mut replacement) = actix_http::h1::Payload::create(true);
+            replacement.unread_data(payload);
+            req.set_payload(replacement.into());
+
+            let expiration = Utc::now() + Duration::hours(IDEMPOTENCY_HOURS);
+            match store.start_request(&key, &fingerprint, expiration).await {
+                Ok(None) => (),
+                Ok(Some(o)) if o.fingerprint != fingerprint => {
+                    return Ok(req.into_response(HttpResponse::UnprocessableEntity().finish()).map_into_right_body());
+                },
+                Ok(Some(o)) => {
+                    let response = match o.response {
+                        Some(response) => replay(response),
+                        // the first request is still being handled
+                        None => HttpResponse::Conflict().finish(),
+                    };
+                    return Ok(req.into_response(response).map_into_right_body());
+                },
+                Err(e) => {
+                    error!("failed checking idempotency key: {e}");
+                    return Ok(req.into_response(HttpResponse::InternalServerError().finish()).map_into_right_body());
+                }
+            }
+
+            let res = match service.call(req).await {
+                Ok(o) => o,
+                Err(e) => {
+                    if let Err(e) = store.abandon_request(&key).await {
+                        error!("failed abandoning idempotency key: {e}");
+                    }
+                    return Err(e);
+                }
+            };
+
+            let (req, res) = res.into_parts();
+            let (res, body) = res.into_parts();
+            let body = body::to_bytes(body).await.map_err(|e| actix_web::error::ErrorInternalServerError(e.into()))?;
+
+            let content_type = res.headers().get(CONTENT_TYPE)
+                .and_then(|h| h.to_str().ok())
+                .filter(|_| keep_body)
+                .map(str::to_string);
+            let stored = match String::from_utf8(body.to_vec()) {
+                Ok(o) if !res.status().is_server_error() => store.finish_request(&key, &StoredResponse {
+                    status: res.status().as_u16(),
+                    content_type,
+                    body: Some(o).filter(|_| keep_body),
+                }).await,
+                _ => store.abandon_request(&key).await,
+            };
+            if let Err(e) = stored {
+                error!("failed storing idempotent response: {e}");
+            }
+
+            let res = res.set_body(BoxBody::new(body));
+            return Ok(ServiceResponse::new(req, res).map_into_right_body());
+        })
+    }
+}
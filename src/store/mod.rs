@@ -0,0 +1,179 @@
+use std::{fmt::Display, sync::Arc, time::Duration as StdDuration};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Serialize, Deserialize};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// the store kept in Postgres, which needs nothing else running
+mod postgres;
+pub use postgres::PgStore;
+
+mod redis;
+pub use self::redis::RedisStore;
+
+/// replaying the responses of requests that are retried with the same
+/// `Idempotency-Key`
+mod idempotency;
+pub use idempotency::{Idempotent, IDEMPOTENCY_KEY, IDEMPOTENT_REPLAYED};
+
+/// the login session a refresh token renews tokens for
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RefreshToken {
+    pub user_id: Uuid,
+    pub session_id: Uuid,
+}
+
+/// how many times a rate limit counter was hit within its window
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Counter {
+    pub count: u64,
+    /// when the window ends and the counter starts over
+    pub reset_date: DateTime<Utc>,
+}
+
+/// the response a request with an idempotency key got the first time
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredResponse {
+    pub status: u16,
+    pub content_type: Option<String>,
+    /// empty for responses with secrets in them, which are only handed out
+    /// once and never kept
+    pub body: Option<String>,
+}
+
+/// what was recorded for an idempotency key that was used before
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdempotentRequest {
+    /// tells apart requests that reuse a key with something else
+    pub fingerprint: String,
+    /// empty while the first request is still being handled
+    pub response: Option<StoredResponse>,
+}
+
+#[derive(Debug)]
+pub enum StoreError {
+    Postgres(sqlx::Error),
+    Redis(::redis::RedisError),
+    /// something was stored that can't be read back
+    Corrupt(serde_json::Error),
+}
+
+impl Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Postgres(e) => write!(f, "postgres: {e}"),
+            Self::Redis(e) => write!(f, "redis: {e}"),
+            Self::Corrupt(e) => write!(f, "corrupt entry: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<sqlx::Error> for StoreError {
+    fn from(e: sqlx::Error) -> Self {
+        Self::Postgres(e)
+    }
+}
+
+impl From<::redis::RedisError> for StoreError {
+    fn from(e: ::redis::RedisError) -> Self {
+        Self::Redis(e)
+    }
+}
+
+impl From<serde_json::Error> for StoreError {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Corrupt(e)
+    }
+}
+
+/// short-lived state that's checked on many requests and only matters until
+/// it expires. everything is stored with an expiration date, after which it's
+/// gone. the server shares one as an `Arc<dyn SessionStore>` in its app data
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+    /// keeps the token from being accepted until it expires on its own
+    async fn revoke_token(&self, jti: Uuid, expiration: DateTime<Utc>) -> Result<(), StoreError>;
+
+    async fn is_token_revoked(&self, jti: Uuid) -> Result<bool, StoreError>;
+
+    async fn put_refresh_token(&self, token_hash: &[u8], token: &RefreshToken, expiration: DateTime<Utc>) -> Result<(), StoreError>;
+
+    /// removes the refresh token and returns what it was for, so each one
+    /// can only be used once
+    async fn take_refresh_token(&self, token_hash: &[u8]) -> Result<Option<RefreshToken>, StoreError>;
+
+    /// counts another hit of the counter, which starts over `window` after
+    /// its first hit
+    async fn increment(&self, key: &str, window: Duration) -> Result<Counter, StoreError>;
+
+    /// forgets the counter, as if it was never hit
+    async fn reset_counter(&self, key: &str) -> Result<(), StoreError>;
+
+    /// locks the key until the given date, replacing any earlier lock
+    async fn lock(&self, key: &str, until: DateTime<Utc>) -> Result<(), StoreError>;
+
+    /// returns until when the key is locked, if it currently is
+    async fn locked_until(&self, key: &str) -> Result<Option<DateTime<Utc>>, StoreError>;
+
+    /// lifts the key's lock early
+    async fn unlock(&self, key: &str) -> Result<(), StoreError>;
+
+    /// records the idempotency key if it's new, returning `None`, or what
+    /// was recorded for it if it isn't
+    async fn start_request(&self, key: &str, fingerprint: &str, expiration: DateTime<Utc>) -> Result<Option<IdempotentRequest>, StoreError>;
+
+    /// stores the response for replaying to requests that reuse the key
+    async fn finish_request(&self, key: &str, response: &StoredResponse) -> Result<(), StoreError>;
+
+    /// forgets the key, so the request can be tried again
+    async fn abandon_request(&self, key: &str) -> Result<(), StoreError>;
+}
+
+/// picks a store based on the `SESSION_STORE` environment variable, which
+/// can be `postgres` or `redis`, connecting to `REDIS_URL` for the latter.
+/// defaults to Postgres, whose expired entries are cleaned up in the
+/// background
+pub async fn from_env(pool: &PgPool) -> Arc<dyn SessionStore> {
+    match dotenvy::var("SESSION_STORE").as_deref() {
+        Ok("redis") => {
+            let url = dotenvy::var("REDIS_URL")
+                .expect("REDIS_URL ENV VAR NOT SET");
+            let store = RedisStore::connect(&url).await
+                .expect("failed connecting to redis");
+            Arc::new(store)
+        },
+        Ok("postgres") | Err(_) => {
+            let store = PgStore::new(pool.clone());
+            store.spawn_cleanup();
+            Arc::new(store)
+        },
+        Ok(other) => panic!("unknown SESSION_STORE {other}"),
+    }
+}
+
+/// at most `limit` hits per `window` for each key
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    /// what's being limited, keeping counters of different limits apart
+    pub name: &'static str,
+    pub limit: u64,
+    /// a std duration, so limits can be constants
+    pub window: StdDuration,
+}
+
+impl RateLimit {
+    /// counts a hit, returning until when further ones are refused if it
+    /// went over the limit
+    pub async fn hit(&self, store: &dyn SessionStore, key: &str) -> Result<Option<DateTime<Utc>>, StoreError> {
+        let window = Duration::from_std(self.window).unwrap_or(Duration::max_value());
+        let counter = store.increment(&format!("{}:{key}", self.name), window).await?;
+        match counter.count > self.limit {
+            true => return Ok(Some(counter.reset_date)),
+            false => return Ok(None),
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use std::time::Duration as StdDuration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use log::{error, info};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use super::{Counter, IdempotentRequest, RefreshToken, SessionStore, StoreError, StoredResponse};
+
+/// how often expired entries are removed
+const CLEANUP_INTERVAL: StdDuration = StdDuration::from_secs(10 * 60);
+
+/// keeps everything in tables of the application's database. expired
+/// entries are ignored until [`PgStore::cleanup`] removes them
+pub struct PgStore {
+    pool: PgPool,
+}
+
+impl PgStore {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    /// removes expired entries, returning how many there were
+    pub async fn cleanup(&self) -> Result<u64, sqlx::Error> {
+        let now = Utc::now();
+        let mut removed = 0;
+        removed += sqlx::query!(r"DELETE FROM revoked_tokens WHERE expiration_date < $1;", now)
+            .execute(&self.pool).await?.rows_affected();
+        removed += sqlx::query!(r"DELETE FROM refresh_tokens WHERE expiration_date < $1;", now)
+            .execute(&self.pool).await?.rows_affected();
+        removed += sqlx::query!(r"DELETE FROM rate_limit_counters WHERE expiration_date < $1;", now)
+            .execute(&self.pool).await?.rows_affected();
+        removed += sqlx::query!(r"DELETE FROM idempotency_keys WHERE expiration_date < $1;", now)
+            .execute(&self.pool).await?.rows_affected();
+        removed += sqlx::query!(r"DELETE FROM locks WHERE expiration_date < $1;", now)
+            .execute(&self.pool).await?.rows_affected();
+        Ok(removed)
+    }
+
+    /// cleans up every [`CLEANUP_INTERVAL`] for as long as the server runs
+    pub fn spawn_cleanup(&self) {
+        let store = Self::new(self.pool.clone());
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(CLEANUP_INTERVAL);
+            loop {
+                interval.tick().await;
+                match store.cleanup().await {
+                    Ok(0) => (),
+                    Ok(removed) => info!("removed {removed} expired session store entries"),
+                    Err(e) => error!("failed cleaning up session store: {e}"),
+                }
+            }
+        });
+    }
+}
+
+#[async_trait]
+impl SessionStore for PgStore {
+    async fn revoke_token(&self, jti: Uuid, expiration: DateTime<Utc>) -> Result<(), StoreError> {
+        sqlx::query!(r"INSERT INTO revoked_tokens (jti, expiration_date) VALUES ($1, $2)
+            ON CONFLICT (jti) DO NOTHING;",
+            jti,
+            expiration
+        ).execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn is_token_revoked(&self, jti: Uuid) -> Result<bool, StoreError> {
+        let found = sqlx::query_scalar!(r"SELECT jti FROM revoked_tokens WHERE jti = $1 AND expiration_date > $2;",
+            jti,
+            Utc::now()
+        ).fetch_optional(&self.pool).await?;
+        Ok(found.is_some())
+    }
+
+    async fn put_refresh_token(&self, token_hash: &[u8], token: &RefreshToken, expiration: DateTime<Utc>) -> Result<(), StoreError> {
+        sqlx::query!(r"INSERT INTO refresh_tokens (token_hash, user_id, session_id, expiration_date)
+            VALUES ($1, $2, $3, $4);",
+            token_hash,
+            token.user_id,
+            token.session_id,
+            expiration
+        ).execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn take_refresh_token(&self, token_hash: &[u8]) -> Result<Option<RefreshToken>, StoreError> {
+        let taken = sqlx::query!(r"DELETE FROM refresh_tokens WHERE token_hash = $1
+            RETURNING user_id, session_id, expiration_date;",
+            token_hash
+        ).fetch_optional(&self.pool).await?;
+        match taken {
+            Some(o) if o.expiration_date > Utc::now() => {
+                return Ok(Some(RefreshToken { user_id: o.user_id, session_id: o.session_id }));
+            },
+            _ => return Ok(None),
+        }
+    }
+
+    async fn increment(&self, key: &str, window: Duration) -> Result<Counter, StoreError> {
+        let now = Utc::now();
+        let counter = sqlx::query!(r"INSERT INTO rate_limit_counters (counter_key, count, expiration_date)
+            VALUES ($1, 1, $2)
+            ON CONFLICT (counter_key) DO UPDATE
+            SET count = CASE
+                    WHEN rate_limit_counters.expiration_date <= $3 THEN 1
+                    ELSE rate_limit_counters.count + 1
+                END,
+                expiration_date = CASE
+                    WHEN rate_limit_counters.expiration_date <= $3 THEN EXCLUDED.expiration_date
+                    ELSE rate_limit_counters.expiration_date
+                END
+            RETURNING count, expiration_date;",
+            key,
+            now + window,
+            now
+        ).fetch_one(&self.pool).await?;
+        Ok(Counter { count: counter.count as u64, reset_date: counter.expiration_date })
+    }
+
+    async fn reset_counter(&self, key: &str) -> Result<(), StoreError> {
+        sqlx::query!(r"DELETE FROM rate_limit_counters WHERE counter_key = $1;", key)
+            .execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn lock(&self, key: &str, until: DateTime<Utc>) -> Result<(), StoreError> {
+        sqlx::query!(r"INSERT INTO locks (lock_key, expiration_date) VALUES ($1, $2)
+            ON CONFLICT (lock_key) DO UPDATE SET expiration_date = EXCLUDED.expiration_date;",
+            key,
+            until
+        ).execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn locked_until(&self, key: &str) -> Result<Option<DateTime<Utc>>, StoreError> {
+        let until = sqlx::query_scalar!(r"SELECT expiration_date FROM locks WHERE lock_key = $1 AND expiration_date > $2;",
+            key,
+            Utc::now()
+        ).fetch_optional(&self.pool).await?;
+        Ok(until)
+    }
+
+    async fn unlock(&self, key: &str) -> Result<(), StoreError> {
+        sqlx::query!(r"DELETE FROM locks WHERE lock_key = $1;", key)
+            .execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn start_request(&self, key: &str, fingerprint: &str, expiration: DateTime<Utc>) -> Result<Option<IdempotentRequest>, StoreError> {
+        loop {
+            let now = Utc::now();
+            // an expired key counts as new, it's replaced along with whatever
+            // was recorded for it
+            let started = sqlx::query!(r"INSERT INTO idempotency_keys (idempotency_key, fingerprint, expiration_date)
+                VALUES ($1, $2, $3)
+                ON CONFLICT (idempotency_key) DO UPDATE
+                SET fingerprint = EXCLUDED.fingerprint,
+                    response_status = NULL,
+                    response_content_type = NULL,
+                    response_body = NULL,
+                    expiration_date = EXCLUDED.expiration_date
+                WHERE idempotency_keys.expiration_date <= $4;",
+                key,
+                fingerprint,
+                expiration,
+                now
+            ).execute(&self.pool).await?;
+            if started.rows_affected() == 1 {
+                return Ok(None);
+            }
+
+            let recorded = sqlx::query!(r"SELECT fingerprint, response_status, response_content_type, response_body
+                FROM idempotency_keys WHERE idempotency_key = $1;",
+                key
+            ).fetch_optional(&self.pool).await?;
+            // abandoned in the meantime, so it's as good as new
+            let Some(recorded) = recorded else {
+                continue;
+            };
+            let response = recorded.response_status.map(|status| StoredResponse {
+                status: status as u16,
+                content_type: recorded.response_content_type,
+                body: recorded.response_body,
+            });
+            return Ok(Some(IdempotentRequest { fingerprint: recorded.fingerprint, response }));
+        }
+    }
+
+    async fn finish_request(&self, key: &str, response: &StoredResponse) -> Result<(), StoreError> {
+        sqlx::query!(r"UPDATE idempotency_keys
+            SET response_status = $2, response_content_type = $3, response_body = $4
+            WHERE idempotency_key = $1;",
+            key,
+            response.status as i16,
+            response.content_type,
+            response.body
+        ).execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn abandon_request(&self, key: &str) -> Result<(), StoreError> {
+        sqlx::query!(r"DELETE FROM idempotency_keys WHERE idempotency_key = $1;", key)
+            .execute(&self.pool).await?;
+        Ok(())
+    }
+}
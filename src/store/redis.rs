@@ -0,0 +1,194 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use data_encoding::HEXLOWER;
+use redis::{aio::ConnectionManager, AsyncCommands, Script};
+use uuid::Uuid;
+
+use super::{Counter, IdempotentRequest, RefreshToken, SessionStore, StoreError, StoredResponse};
+
+/// what every key starts with, so the store can share a Redis database
+const KEY_PREFIX: &str = "cyber_bank_rs";
+
+/// increments the counter, starting its window on the first hit. returns
+/// the count and how many milliseconds are left of the window
+const INCREMENT_SCRIPT: &str = r"
+local count = redis.call('INCR', KEYS[1])
+if count == 1 then
+    redis.call('PEXPIRE', KEYS[1], ARGV[1])
+end
+return {count, redis.call('PTTL', KEYS[1])}
+";
+
+/// keeps everything in Redis, which expires entries by itself. needs Redis
+/// 6.2 or newer
+pub struct RedisStore {
+    connection: ConnectionManager,
+}
+
+/// milliseconds until the expiration date, at least one so nothing is
+/// stored without expiring
+fn millis_until(expiration: DateTime<Utc>) -> u64 {
+    (expiration - Utc::now()).num_milliseconds().max(1) as u64
+}
+
+impl RedisStore {
+    /// connects to the Redis server at the URL, like `redis://localhost:6379/0`.
+    /// lost connections are reestablished as needed
+    pub async fn connect(url: &str) -> Result<Self, StoreError> {
+        let client = redis::Client::open(url)?;
+        let connection = ConnectionManager::new(client).await?;
+        Ok(Self { connection })
+    }
+
+    fn revoked_key(jti: Uuid) -> String {
+        format!("{KEY_PREFIX}:revoked:{jti}")
+    }
+
+    fn refresh_key(token_hash: &[u8]) -> String {
+        format!("{KEY_PREFIX}:refresh:{}", HEXLOWER.encode(token_hash))
+    }
+
+    fn counter_key(key: &str) -> String {
+        format!("{KEY_PREFIX}:counter:{key}")
+    }
+
+    fn lock_key(key: &str) -> String {
+        format!("{KEY_PREFIX}:lock:{key}")
+    }
+
+    fn idempotency_key(key: &str) -> String {
+        format!("{KEY_PREFIX}:idempotency:{key}")
+    }
+}
+
+#[async_trait]
+impl SessionStore for RedisStore {
+    async fn revoke_token(&self, jti: Uuid, expiration: DateTime<Utc>) -> Result<(), StoreError> {
+        let mut connection = self.connection.clone();
+        redis::cmd("SET")
+            .arg(Self::revoked_key(jti))
+            .arg(1)
+            .arg("PX")
+            .arg(millis_until(expiration))
+            .query_async::<_, ()>(&mut connection).await?;
+        Ok(())
+    }
+
+    async fn is_token_revoked(&self, jti: Uuid) -> Result<bool, StoreError> {
+        let mut connection = self.connection.clone();
+        Ok(connection.exists(Self::revoked_key(jti)).await?)
+    }
+
+    async fn put_refresh_token(&self, token_hash: &[u8], token: &RefreshToken, expiration: DateTime<Utc>) -> Result<(), StoreError> {
+        let mut connection = self.connection.clone();
+        redis::cmd("SET")
+            .arg(Self::refresh_key(token_hash))
+            .arg(serde_json::to_string(token)?)
+            .arg("PX")
+            .arg(millis_until(expiration))
+            .query_async::<_, ()>(&mut connection).await?;
+        Ok(())
+    }
+
+    async fn take_refresh_token(&self, token_hash: &[u8]) -> Result<Option<RefreshToken>, StoreError> {
+        let mut connection = self.connection.clone();
+        let taken: Option<String> = redis::cmd("GETDEL")
+            .arg(Self::refresh_key(token_hash))
+            .query_async(&mut connection).await?;
+        match taken {
+            Some(o) => return Ok(Some(serde_json::from_str(&o)?)),
+            None => return Ok(None),
+        }
+    }
+
+    async fn increment(&self, key: &str, window: Duration) -> Result<Counter, StoreError> {
+        let mut connection = self.connection.clone();
+        let (count, millis_left): (u64, i64) = Script::new(INCREMENT_SCRIPT)
+            .key(Self::counter_key(key))
+            .arg(window.num_milliseconds().max(1))
+            .invoke_async(&mut connection).await?;
+        Ok(Counter { count, reset_date: Utc::now() + Duration::milliseconds(millis_left.max(0)) })
+    }
+
+    async fn reset_counter(&self, key: &str) -> Result<(), StoreError> {
+        let mut connection = self.connection.clone();
+        connection.del::<_, ()>(Self::counter_key(key)).await?;
+        Ok(())
+    }
+
+    async fn lock(&self, key: &str, until: DateTime<Utc>) -> Result<(), StoreError> {
+        let mut connection = self.connection.clone();
+        redis::cmd("SET")
+            .arg(Self::lock_key(key))
+            .arg(1)
+            .arg("PX")
+            .arg(millis_until(until))
+            .query_async::<_, ()>(&mut connection).await?;
+        Ok(())
+    }
+
+    async fn locked_until(&self, key: &str) -> Result<Option<DateTime<Utc>>, StoreError> {
+        let mut connection = self.connection.clone();
+        // negative if the key doesn't exist
+        let millis_left: i64 = connection.pttl(Self::lock_key(key)).await?;
+        match millis_left > 0 {
+            true => return Ok(Some(Utc::now() + Duration::milliseconds(millis_left))),
+            false => return Ok(None),
+        }
+    }
+
+    async fn unlock(&self, key: &str) -> Result<(), StoreError> {
+        let mut connection = self.connection.clone();
+        connection.del::<_, ()>(Self::lock_key(key)).await?;
+        Ok(())
+    }
+
+    async fn start_request(&self, key: &str, fingerprint: &str, expiration: DateTime<Utc>) -> Result<Option<IdempotentRequest>, StoreError> {
+        let mut connection = self.connection.clone();
+        let key = Self::idempotency_key(key);
+        let started = IdempotentRequest { fingerprint: fingerprint.to_string(), response: None };
+        let started = serde_json::to_string(&started)?;
+        loop {
+            let set: Option<String> = redis::cmd("SET")
+                .arg(&key)
+                .arg(&started)
+                .arg("NX")
+                .arg("PX")
+                .arg(millis_until(expiration))
+                .query_async(&mut connection).await?;
+            if set.is_some() {
+                return Ok(None);
+            }
+
+            let recorded: Option<String> = connection.get(&key).await?;
+            if let Some(o) = recorded {
+                return Ok(Some(serde_json::from_str(&o)?));
+            }
+            // expired in the meantime, so it's as good as new
+        }
+    }
+
+    async fn finish_request(&self, key: &str, response: &StoredResponse) -> Result<(), StoreError> {
+        let mut connection = self.connection.clone();
+        let key = Self::idempotency_key(key);
+        let recorded: Option<String> = connection.get(&key).await?;
+        let Some(recorded) = recorded else {
+            return Ok(());
+        };
+        let mut recorded: IdempotentRequest = serde_json::from_str(&recorded)?;
+        recorded.response = Some(response.clone());
+        redis::cmd("SET")
+            .arg(&key)
+            .arg(serde_json::to_string(&recorded)?)
+            .arg("XX")
+            .arg("KEEPTTL")
+            .query_async::<_, ()>(&mut connection).await?;
+        Ok(())
+    }
+
+    async fn abandon_request(&self, key: &str) -> Result<(), StoreError> {
+        let mut connection = self.connection.clone();
+        connection.del::<_, ()>(Self::idempotency_key(key)).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,276 @@
+use std::{sync::Arc, time::Duration as StdDuration};
+
+use actix_web::{HttpRequest, Responder, web::Json, HttpResponse};
+use chrono::{Duration, Utc};
+use log::{error, info};
+use serde::{Serialize, Deserialize};
+use sqlx::PgPool;
+
+use crate::{
+    audit::{self, AuditEvent, EventType},
+    auth::{throttle::{self, ThrottleKey}, token::{is_first_party, jwt::{JwtClaims, TokenHeader}}},
+    mail::{self, Email, Mailer},
+    store::{RateLimit, SessionStore},
+};
+
+/// how often deleted accounts past their purge date are looked for
+const PURGE_INTERVAL: StdDuration = StdDuration::from_secs(60 * 60);
+
+/// how many restoration tokens can be tried from one address
+const RESTORE_LIMIT: RateLimit = RateLimit {
+    name: "account_restore",
+    limit: 10,
+    window: std::time::Duration::from_secs(60 * 60),
+};
+
+#[derive(Serialize, Deserialize)]
+pub struct DeletionRequest {
+    password: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RestoreRequest {
+    token: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum DeletionError {
+    NotAllowedForToken,
+    InvalidToken,
+    /// someone registered the username or email address in the meantime
+    AlreadyInUse,
+}
+
+/// how long deleted accounts can be restored before their personal data is
+/// removed for good, set in days with the `ACCOUNT_RETENTION_DAYS`
+/// environment variable. defaults to 30 days, panics if it's invalid
+pub(super) fn retention() -> Duration {
+    Duration::days(crate::env_var("ACCOUNT_RETENTION_DAYS", 30))
+}
+
+/// deletes the authenticated user's account after checking their password.
+/// the `users` row is pseudonymized instead of removed, so anything that
+/// refers to it keeps working, and what's needed to [`restore`] it is kept
+/// until the retention period is over. every credential and token of the
+/// user stops working right away
+pub async fn delete(req: HttpRequest, claims: JwtClaims, header: TokenHeader, deletion: Json<DeletionRequest>) -> impl Responder {
+    if !is_first_party(&claims, &header) {
+        return HttpResponse::BadRequest().json(vec![DeletionError::NotAllowedForToken]);
+    }
+
+    let pool = req.app_data::<PgPool>().unwrap();
+    let mailer = match crate::app_data::<Arc<dyn Mailer>>(&req) {
+        Ok(o) => o,
+        Err(response) => return response,
+    };
+    let store = match crate::app_data::<Arc<dyn SessionStore>>(&req) {
+        Ok(o) => o,
+        Err(response) => return response,
+    };
+    let event = AuditEvent::new(EventType::AccountDeletion, Some(claims.subject())).by(&claims);
+
+    let user = match crate::auth::password::check_current_password(&req, claims.subject(), &deletion.password, &event).await {
+        Ok(o) => o,
+        Err(response) => return response,
+    };
+
+    let now = Utc::now();
+    let purge_date = now + retention();
+    let token = crate::auth::password::generate_emailed_token();
+    let pseudonymize = async {
+        let mut tx = pool.begin().await?;
+
+        sqlx::query!(r"INSERT INTO account_restorations
+            (user_id, token_hash, username, email, password, password_pepper_id, email_verified_at, expiration_date)
+            SELECT user_id, $2, username, email, password, password_pepper_id, email_verified_at, $3
+            FROM users WHERE user_id = $1;",
+            user.user_id,
+            crate::auth::hash(token.as_bytes()),
+            purge_date
+        ).execute(&mut *tx).await?;
+
+        // the pseudonyms can't be registered, they're too long for a username
+        // and not a valid address
+        sqlx::query!(r"UPDATE users
+            SET username = 'deleted-' || user_id,
+                email = 'deleted-' || user_id || '@invalid',
+                password = '',
+                password_pepper_id = NULL,
+                email_verified_at = NULL,
+                sessions_revoked_date = $2,
+                deletion_date = $2,
+                purge_date = $3
+            WHERE user_id = $1;",
+            user.user_id,
+            now,
+            purge_date
+        ).execute(&mut *tx).await?;
+
+        sqlx::query!(r"UPDATE personal_access_tokens SET revoked = true WHERE user_id = $1;", user.user_id)
+            .execute(&mut *tx).await?;
+        sqlx::query!(r"DELETE FROM sessions WHERE user_id = $1;", user.user_id)
+            .execute(&mut *tx).await?;
+        // clients owned by the user go too, along with everyone's consent to them
+        sqlx::query!(r"DELETE FROM oauth_clients WHERE owner_id = $1;", user.user_id)
+            .execute(&mut *tx).await?;
+        sqlx::query!(r"DELETE FROM oauth_consents WHERE user_id = $1;", user.user_id)
+            .execute(&mut *tx).await?;
+        sqlx::query!(r"DELETE FROM oauth_authorization_codes WHERE user_id = $1;", user.user_id)
+            .execute(&mut *tx).await?;
+        sqlx::query!(r"DELETE FROM totp_secrets WHERE user_id = $1;", user.user_id)
+            .execute(&mut *tx).await?;
+        sqlx::query!(r"DELETE FROM recovery_codes WHERE user_id = $1;", user.user_id)
+            .execute(&mut *tx).await?;
+        sqlx::query!(r"DELETE FROM webauthn_credentials WHERE user_id = $1;", user.user_id)
+            .execute(&mut *tx).await?;
+        sqlx::query!(r"DELETE FROM webauthn_challenges WHERE user_id = $1;", user.user_id)
+            .execute(&mut *tx).await?;
+        sqlx::query!(r"DELETE FROM password_reset_tokens WHERE user_id = $1;", user.user_id)
+            .execute(&mut *tx).await?;
+        sqlx::query!(r"DELETE FROM email_verification_tokens WHERE user_id = $1;", user.user_id)
+            .execute(&mut *tx).await?;
+        sqlx::query!(r"DELETE FROM user_profiles WHERE user_id = $1;", user.user_id)
+            .execute(&mut *tx).await?;
+        sqlx::query!(r"DELETE FROM payees WHERE user_id = $1;", user.user_id)
+            .execute(&mut *tx).await?;
+
+        tx.commit().await
+    };
+    if let Err(e) = pseudonymize.await {
+        error!("failed deleting account: {e}");
+        return HttpResponse::InternalServerError().finish();
+    }
+    if let Err(e) = throttle::reset(store.as_ref(), ThrottleKey::Username(&user.username, req.peer_addr().map(|a| a.ip()))).await {
+        error!("failed resetting login throttling: {e}");
+    }
+    audit::record(&req, event).await;
+
+    mail::send_in_background(mailer, Email {
+        to: user.email,
+        subject: "Your account was deleted".to_string(),
+        body: format!(
+            "Your cyber_bank_rs account {} was deleted.\n\n\
+            If you change your mind, you can restore it within the next {} \
+            days with this token, and log in with the same password:\n\n{token}\n\n\
+            After that your account details are removed for good. Our security \
+            log of sign-ins and account changes is kept, without the addresses \
+            and devices they came from.",
+            user.username,
+            retention().num_days()
+        ),
+    });
+
+    return HttpResponse::NoContent().finish();
+}
+
+/// undoes a deletion with the token from its email, as long as the account
+/// wasn't purged yet. the user gets their username, email address and
+/// password back, but has to log in again. everything else that was removed
+/// with the account stays gone
+pub async fn restore(req: HttpRequest, request: Json<RestoreRequest>) -> impl Responder {
+    if let Err(response) = crate::auth::login::limit_by_address(&req, &RESTORE_LIMIT).await {
+        return response;
+    }
+
+    let pool = req.app_data::<PgPool>().unwrap();
+    let restoration = async {
+        let mut tx = pool.begin().await?;
+
+        let restoration = sqlx::query!(r"DELETE FROM account_restorations
+            WHERE token_hash = $1 AND expiration_date > $2
+            RETURNING user_id, username, email, password, password_pepper_id, email_verified_at;",
+            crate::auth::hash(request.token.as_bytes()),
+            Utc::now()
+        ).fetch_optional(&mut *tx).await?;
+        let Some(restoration) = restoration else {
+            return Ok(None);
+        };
+
+        // tokens from before the deletion stay revoked
+        sqlx::query!(r"UPDATE users
+            SET username = $2,
+                email = $3,
+                password = $4,
+                password_pepper_id = $5,
+                email_verified_at = $6,
+                deletion_date = NULL,
+                purge_date = NULL
+            WHERE user_id = $1;",
+            restoration.user_id,
+            restoration.username,
+            restoration.email,
+            restoration.password,
+            restoration.password_pepper_id,
+            restoration.email_verified_at
+        ).execute(&mut *tx).await?;
+
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>(Some(restoration.user_id))
+    };
+
+    match restoration.await {
+        Ok(Some(user_id)) => {
+            audit::record(&req, AuditEvent::new(EventType::AccountRestored, Some(user_id))).await;
+            return HttpResponse::NoContent().finish();
+        },
+        Ok(None) => return HttpResponse::BadRequest().json(vec![DeletionError::InvalidToken]),
+        Err(e) if e.as_database_error().is_some_and(|e| e.is_unique_violation()) => {
+            return HttpResponse::BadRequest().json(vec![DeletionError::AlreadyInUse]);
+        },
+        Err(e) => {
+            error!("failed restoring account: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+}
+
+/// removes the personal data that's left of deleted accounts whose purge
+/// date has passed, returning how many there were. the `users` rows are kept
+/// with nothing but their id and when they were deleted, so the audit log and
+/// other users' payees still refer to something, but the accounts can't be
+/// restored anymore. the addresses and user agents of their audit events are
+/// scrubbed, except for events recorded before those could be without
+/// breaking the [`chain`](crate::audit::chain)
+pub async fn purge(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    // already pseudonymized when the account was deleted
+    let purged = sqlx::query_scalar!(r"UPDATE users
+        SET purge_date = NULL,
+            creation_date = deletion_date,
+            sessions_revoked_date = NULL,
+            is_admin = false
+        WHERE purge_date < $1
+        RETURNING user_id;",
+        Utc::now()
+    ).fetch_all(&mut *tx).await?;
+    sqlx::query!(r"UPDATE audit_events SET ip = NULL, user_agent = NULL, origin_salt = NULL
+        WHERE (user_id = ANY($1) OR actor_id = ANY($1))
+            AND origin_digest IS NOT NULL AND origin_salt IS NOT NULL;",
+        &purged
+    ).execute(&mut *tx).await?;
+    sqlx::query!(r"DELETE FROM account_restorations WHERE user_id = ANY($1);", &purged)
+        .execute(&mut *tx).await?;
+    sqlx::query!(r"DELETE FROM personal_access_tokens WHERE user_id = ANY($1);", &purged)
+        .execute(&mut *tx).await?;
+
+    tx.commit().await?;
+    Ok(purged.len() as u64)
+}
+
+/// purges deleted accounts every [`PURGE_INTERVAL`] for as long as the
+/// server runs
+pub fn spawn_purging(pool: PgPool) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PURGE_INTERVAL);
+        loop {
+            interval.tick().await;
+            match purge(&pool).await {
+                Ok(0) => (),
+                Ok(purged) => info!("purged {purged} deleted accounts"),
+                Err(e) => error!("failed purging deleted accounts: {e}"),
+            }
+        }
+    });
+}
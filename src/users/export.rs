@@ -0,0 +1,212 @@
+use actix_web::{HttpRequest, Responder, HttpResponse, http::header::{ContentDisposition, DispositionParam, DispositionType}};
+use chrono::{DateTime, Utc};
+use log::error;
+use serde::Serialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{audit::query::Event, auth::token::{is_first_party, jwt::{JwtClaims, TokenHeader}}};
+
+#[derive(Serialize)]
+struct ExportedUser {
+    user_id: Uuid,
+    username: String,
+    email: String,
+    email_verified_at: Option<DateTime<Utc>>,
+    creation_date: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+struct ExportedProfile {
+    display_name: Option<String>,
+    home_currency: Option<String>,
+    locale: Option<String>,
+    timezone: Option<String>,
+    update_date: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+struct ExportedPersonalToken {
+    token_id: Uuid,
+    name: String,
+    scopes: Vec<String>,
+    creation_date: DateTime<Utc>,
+    expiration_date: DateTime<Utc>,
+    last_used_date: Option<DateTime<Utc>>,
+    last_used_ip: Option<String>,
+    revoked: bool,
+}
+
+#[derive(Serialize)]
+struct ExportedClient {
+    client_id: Uuid,
+    name: String,
+    redirect_uris: Vec<String>,
+    scopes: Vec<String>,
+    creation_date: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+struct ExportedConsent {
+    client_id: Uuid,
+    client_name: String,
+    scopes: Vec<String>,
+    granted_date: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+struct ExportedTotp {
+    creation_date: DateTime<Utc>,
+    enabled_date: Option<DateTime<Utc>>,
+    unused_recovery_codes: i64,
+}
+
+#[derive(Serialize)]
+struct ExportedAuthenticator {
+    name: String,
+    creation_date: DateTime<Utc>,
+    last_used_date: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+struct ExportedSession {
+    device_label: Option<String>,
+    user_agent: Option<String>,
+    ip: Option<String>,
+    creation_date: DateTime<Utc>,
+    last_seen_date: DateTime<Utc>,
+    expiration_date: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+struct ExportedPayee {
+    nickname: String,
+    name: String,
+    identifier_kind: String,
+    identifier: String,
+    name_check: String,
+    creation_date: DateTime<Utc>,
+}
+
+/// everything stored about a user. secrets like password hashes, TOTP
+/// secrets and public keys are left out, they're of no use to anyone but the
+/// server
+#[derive(Serialize)]
+struct Export {
+    export_date: DateTime<Utc>,
+    user: ExportedUser,
+    profile: Option<ExportedProfile>,
+    personal_access_tokens: Vec<ExportedPersonalToken>,
+    oauth_clients: Vec<ExportedClient>,
+    oauth_consents: Vec<ExportedConsent>,
+    totp: Option<ExportedTotp>,
+    webauthn_credentials: Vec<ExportedAuthenticator>,
+    sessions: Vec<ExportedSession>,
+    payees: Vec<ExportedPayee>,
+    /// the user's audit events, oldest first
+    activity: Vec<Event>,
+}
+
+async fn collect(pool: &PgPool, user_id: Uuid) -> Result<Export, sqlx::Error> {
+    let user = sqlx::query_as!(ExportedUser, r"SELECT user_id, username, email, email_verified_at, creation_date
+        FROM users WHERE user_id = $1;",
+        user_id
+    ).fetch_one(pool).await?;
+
+    let profile = sqlx::query_as!(ExportedProfile, r"SELECT display_name, home_currency, locale, timezone, update_date
+        FROM user_profiles WHERE user_id = $1;",
+        user_id
+    ).fetch_optional(pool).await?;
+
+    let personal_access_tokens = sqlx::query_as!(ExportedPersonalToken, r"SELECT token_id, name, scopes,
+        creation_date, expiration_date, last_used_date, last_used_ip, revoked
+        FROM personal_access_tokens WHERE user_id = $1
+        ORDER BY creation_date;",
+        user_id
+    ).fetch_all(pool).await?;
+
+    let oauth_clients = sqlx::query_as!(ExportedClient, r"SELECT client_id, name, redirect_uris, scopes, creation_date
+        FROM oauth_clients WHERE owner_id = $1
+        ORDER BY creation_date;",
+        user_id
+    ).fetch_all(pool).await?;
+
+    let oauth_consents = sqlx::query_as!(ExportedConsent, r"SELECT c.client_id, clients.name AS client_name, c.scopes, c.granted_date
+        FROM oauth_consents c JOIN oauth_clients clients ON clients.client_id = c.client_id
+        WHERE c.user_id = $1
+        ORDER BY c.granted_date;",
+        user_id
+    ).fetch_all(pool).await?;
+
+    let totp = sqlx::query_as!(ExportedTotp, r#"SELECT creation_date, enabled_date,
+        (SELECT COUNT(*) FROM recovery_codes r WHERE r.user_id = t.user_id AND r.used_date IS NULL) AS "unused_recovery_codes!"
+        FROM totp_secrets t WHERE user_id = $1;"#,
+        user_id
+    ).fetch_optional(pool).await?;
+
+    let webauthn_credentials = sqlx::query_as!(ExportedAuthenticator, r"SELECT name, creation_date, last_used_date
+        FROM webauthn_credentials WHERE user_id = $1
+        ORDER BY creation_date;",
+        user_id
+    ).fetch_all(pool).await?;
+
+    let sessions = sqlx::query_as!(ExportedSession, r"SELECT device_label, user_agent, ip,
+        creation_date, last_seen_date, expiration_date
+        FROM sessions WHERE user_id = $1
+        ORDER BY creation_date;",
+        user_id
+    ).fetch_all(pool).await?;
+
+    let payees = sqlx::query_as!(ExportedPayee, r"SELECT nickname, name, identifier_kind, identifier, name_check, creation_date
+        FROM payees WHERE user_id = $1
+        ORDER BY creation_date;",
+        user_id
+    ).fetch_all(pool).await?;
+
+    let activity = sqlx::query_as!(Event, r"SELECT event_id, occurred_date, event_type, succeeded, failure_reason,
+        user_id, actor_id, client_id, ip, user_agent, request_id, details
+        FROM audit_events WHERE user_id = $1
+        ORDER BY occurred_date, event_id;",
+        user_id
+    ).fetch_all(pool).await?;
+
+    Ok(Export {
+        export_date: Utc::now(),
+        user,
+        profile,
+        personal_access_tokens,
+        oauth_clients,
+        oauth_consents,
+        totp,
+        webauthn_credentials,
+        sessions,
+        payees,
+        activity,
+    })
+}
+
+/// returns everything stored about the authenticated user as a JSON file.
+/// only first-party tokens can be used, this is more than any app needs
+pub async fn export(req: HttpRequest, claims: JwtClaims, header: TokenHeader) -> impl Responder {
+    if !is_first_party(&claims, &header) {
+        return HttpResponse::Forbidden().finish();
+    }
+
+    let pool = req.app_data::<PgPool>().unwrap();
+
+    match collect(pool, claims.subject()).await {
+        Ok(o) => {
+            let filename = format!("cyber_bank_rs_{}_{}.json", o.user.username, o.export_date.format("%Y-%m-%d"));
+            return HttpResponse::Ok()
+                .insert_header(ContentDisposition {
+                    disposition: DispositionType::Attachment,
+                    parameters: vec![DispositionParam::Filename(filename)],
+                })
+                .json(o);
+        },
+        Err(e) => {
+            error!("failed exporting user data: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use actix_web::web;
+
+use crate::{auth::token::{ScopeValidator, jwt::Scope}, store::Idempotent};
+
+/// the authenticated user's details and preferences
+pub mod profile;
+
+/// everything stored about a user, for them to take with them
+pub mod export;
+
+/// users deleting their own accounts, and removing them for good later
+pub mod deletion;
+
+/// the people and accounts users send money to, checked against the names
+/// they're expected to have
+pub mod payees;
+
+/// reads the settings from the environment that the endpoints here use, so
+/// invalid ones fail when the server starts instead of in a request
+pub fn check_env() {
+    deletion::retention();
+    payees::cooling_off();
+    payees::cooling_off_limit();
+}
+
+/// adds the endpoints `/me`, `/me/export`, `/me/activity`, `/me/payees` and
+/// `/restore` to the service
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        // deleting the account takes more than reading and changing the
+        // profile does
+        web::resource("/me")
+            .route(web::get().to(profile::get).wrap(ScopeValidator::new(&[Scope::UserInfo])))
+            .route(web::patch().to(profile::update).wrap(ScopeValidator::new(&[Scope::UserInfo])))
+            .route(web::delete().to(deletion::delete).wrap(ScopeValidator::new(&[Scope::User])))
+    )
+    .service(
+        web::resource("/me/export")
+            .wrap(ScopeValidator::new(&[Scope::User]))
+            .route(web::get().to(export::export))
+    )
+    .service(
+        web::resource("/me/activity")
+            .wrap(ScopeValidator::new(&[Scope::User]))
+            .route(web::get().to(crate::audit::query::activity))
+    )
+    .service(
+        web::scope("/me/payees")
+            .wrap(ScopeValidator::new(&[Scope::Transfers]))
+            .service(
+                web::resource("")
+                    .wrap(Idempotent::replaying())
+                    .route(web::get().to(payees::list))
+                    .route(web::post().to(payees::add))
+            )
+            .route("/{payee_id}", web::delete().to(payees::delete))
+    )
+    .route("/restore", web::post().to(deletion::restore));
+}
@@ -0,0 +1,443 @@
+use actix_web::{HttpRequest, Responder, web::{Json, Path}, HttpResponse};
+use chrono::{DateTime, Duration, Utc};
+use log::error;
+use serde::{Serialize, Deserialize};
+use sqlx::PgPool;
+use unicode_normalization::{UnicodeNormalization, char::is_combining_mark};
+use uuid::Uuid;
+
+use crate::{
+    audit::{self, AuditEvent, EventType},
+    auth::{canonical, login::limit_by_key, token::jwt::JwtClaims},
+    store::RateLimit,
+    validation::{self, Validate, ValidationErrors, Violation},
+};
+
+/// payees a user can try to add, each from a single user and address. every
+/// one checks a name, which mustn't turn into a way of finding out users'
+/// names
+const ADD_LIMIT: RateLimit = RateLimit {
+    name: "payee",
+    limit: 10,
+    window: std::time::Duration::from_secs(60 * 60),
+};
+
+/// who a payee is, as users enter it. usernames are for other users of the
+/// bank, IBANs for accounts anywhere else
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PayeeIdentifier {
+    Username(String),
+    Iban(String),
+}
+
+impl PayeeIdentifier {
+    /// the form identifiers are stored and compared in: usernames in their
+    /// [`canonical`] form, IBANs without spaces and uppercased
+    fn canonical(&self) -> Self {
+        match self {
+            Self::Username(o) => return Self::Username(canonical::username(o)),
+            Self::Iban(o) => return Self::Iban(o.chars().filter(|c| !c.is_whitespace()).collect::<String>().to_uppercase()),
+        }
+    }
+
+    /// the kind of identifier, as it's stored in the `identifier_kind` column
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Username(_) => return "username",
+            Self::Iban(_) => return "iban",
+        }
+    }
+
+    fn value(&self) -> &str {
+        match self {
+            Self::Username(o) | Self::Iban(o) => return o,
+        }
+    }
+
+    /// the identifier from its columns, which only allow the kinds there are
+    fn from_columns(kind: &str, value: String) -> Self {
+        match kind {
+            "iban" => return Self::Iban(value),
+            _ => return Self::Username(value),
+        }
+    }
+}
+
+/// how the name a payee was added with compares to the name they actually
+/// have, known as confirmation of payee
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum NameCheck {
+    Match,
+    /// only differs in the order of names, initials or a typo or two
+    CloseMatch,
+    /// also what users who don't exist, are being deleted or haven't set a
+    /// display name get, so the check can't tell them apart
+    NoMatch,
+    /// there's no one to ask, like for accounts at other banks
+    Unavailable,
+}
+
+impl NameCheck {
+    pub fn name(&self) -> String {
+        match serde_json::to_value(self) {
+            Ok(serde_json::Value::String(s)) => s,
+            _ => unreachable!("name checks always serialize to strings"),
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        serde_json::from_value(serde_json::Value::String(name.to_string())).ok()
+    }
+}
+
+/// the words of a name, lowercased and without accents or punctuation
+fn name_words(name: &str) -> Vec<String> {
+    name.nfkd()
+        .filter(|c| !is_combining_mark(*c))
+        .collect::<String>()
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// how many characters have to be inserted, removed or replaced to get from
+/// one to the other
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b) in b.iter().enumerate() {
+            let replaced = previous[j] + usize::from(a != *b);
+            current.push(replaced.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// compares the name a payee is expected to have with the one they have.
+/// case, accents and punctuation don't matter at all
+pub fn check_name(expected: &str, actual: &str) -> NameCheck {
+    let expected = name_words(expected);
+    let actual = name_words(actual);
+    if expected.is_empty() || actual.is_empty() {
+        return NameCheck::NoMatch;
+    }
+    if expected == actual {
+        return NameCheck::Match;
+    }
+
+    let mut expected_sorted = expected.clone();
+    expected_sorted.sort();
+    let mut actual_sorted = actual.clone();
+    actual_sorted.sort();
+    if expected_sorted == actual_sorted {
+        return NameCheck::CloseMatch;
+    }
+
+    // "J. Doe" for "Jane Doe", as long as the last name is right
+    let initials = expected.len() == actual.len()
+        && expected.last() == actual.last()
+        && expected.iter().zip(actual.iter()).all(|(e, a)| {
+            e == a || (e.chars().count() == 1 && a.starts_with(e.as_str())) || (a.chars().count() == 1 && e.starts_with(a.as_str()))
+        });
+    // a typo or two, but not in names so short that'd make them another name
+    let (expected, actual) = (expected.join(" "), actual.join(" "));
+    let typos = expected.chars().count().min(actual.chars().count()) >= 6 && edit_distance(&expected, &actual) <= 2;
+
+    match initials || typos {
+        true => return NameCheck::CloseMatch,
+        false => return NameCheck::NoMatch,
+    }
+}
+
+/// how long after being added payees can only get small transfers, set in
+/// hours with the `PAYEE_COOLING_OFF_HOURS` environment variable. defaults
+/// to 24 hours. panics if it's invalid
+pub(super) fn cooling_off() -> Duration {
+    Duration::hours(crate::env_var("PAYEE_COOLING_OFF_HOURS", 24))
+}
+
+/// the largest transfer, in cents, that can go to a payee still cooling off,
+/// set in whole units with the `PAYEE_COOLING_OFF_LIMIT` environment
+/// variable. defaults to 1000. panics if it's invalid
+pub(super) fn cooling_off_limit() -> i64 {
+    crate::env_var::<i64>("PAYEE_COOLING_OFF_LIMIT", 1000).saturating_mul(100)
+}
+
+#[derive(Serialize)]
+struct Payee {
+    payee_id: Uuid,
+    nickname: String,
+    name: String,
+    identifier: PayeeIdentifier,
+    name_check: NameCheck,
+    creation_date: DateTime<Utc>,
+    /// until when only transfers up to the cooling off limit can go to it
+    cooling_off_until: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PayeeRequest {
+    nickname: String,
+    /// the name the payee is expected to have, which is checked if possible
+    name: String,
+    identifier: PayeeIdentifier,
+    /// saves the payee even if the name didn't quite match, after the user
+    /// was told about it
+    #[serde(default)]
+    accept_name_mismatch: bool,
+}
+
+/// problems with an identifier that only come up when looking it up
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum IdentifierViolation {
+    /// users can't add themselves, they'd be moving money between their own
+    /// accounts
+    OwnAccount,
+}
+
+impl Validate for PayeeRequest {
+    /// expects the nickname and name trimmed and the identifier canonical
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::new();
+        errors.check("nickname", validation::length(&self.nickname, 1, 64));
+        errors.check("nickname", validation::charset(&self.nickname, |c| !c.is_control()));
+        errors.check("name", validation::length(&self.name, 1, 128));
+        errors.check("name", validation::charset(&self.name, |c| !c.is_control()));
+        match &self.identifier {
+            PayeeIdentifier::Username(o) => errors.check("identifier", validation::length(o, 1, 32)),
+            PayeeIdentifier::Iban(o) => errors.check("identifier", validation::iban(o)),
+        }
+        errors.into_result()
+    }
+}
+
+/// which of the payee's details the user already has another payee with,
+/// going by the unique constraint an insert violated
+fn already_in_use(constraint: Option<&str>) -> Option<ValidationErrors> {
+    match constraint? {
+        "payees_nickname" => return Some(ValidationErrors::single("nickname", Violation::AlreadyInUse)),
+        "payees_identifier" => return Some(ValidationErrors::single("identifier", Violation::AlreadyInUse)),
+        _ => return None,
+    }
+}
+
+/// the user behind a username and how the name compares to theirs. users
+/// being deleted are as good as gone
+async fn look_up_user(pool: &PgPool, username: &str, name: &str) -> Result<Option<(Uuid, NameCheck)>, sqlx::Error> {
+    let found = sqlx::query!(r"SELECT users.user_id, p.display_name
+        FROM users LEFT JOIN user_profiles p ON p.user_id = users.user_id
+        WHERE users.username = $1 AND users.deletion_date IS NULL;",
+        username
+    ).fetch_optional(pool).await?;
+
+    Ok(found.map(|o| {
+        let name_check = match o.display_name {
+            Some(display_name) => check_name(name, &display_name),
+            None => NameCheck::NoMatch,
+        };
+        (o.user_id, name_check)
+    }))
+}
+
+/// lists the authenticated user's payees by nickname. payees whose user is
+/// being deleted no longer match
+pub async fn list(req: HttpRequest, claims: JwtClaims) -> impl Responder {
+    let pool = req.app_data::<PgPool>().unwrap();
+
+    let payees = sqlx::query!(r#"SELECT p.payee_id, p.nickname, p.name, p.identifier_kind, p.identifier, p.creation_date,
+        CASE WHEN target.deletion_date IS NULL THEN p.name_check ELSE 'no_match' END AS "name_check!"
+        FROM payees p LEFT JOIN users target ON target.user_id = p.target_user_id
+        WHERE p.user_id = $1
+        ORDER BY p.nickname;"#,
+        claims.subject()
+    ).fetch_all(pool).await;
+
+    match payees {
+        Ok(o) => {
+            let cooling_off = cooling_off();
+            let payees: Vec<Payee> = o.into_iter().map(|p| Payee {
+                payee_id: p.payee_id,
+                nickname: p.nickname,
+                name: p.name,
+                identifier: PayeeIdentifier::from_columns(&p.identifier_kind, p.identifier),
+                name_check: NameCheck::from_name(&p.name_check).unwrap_or(NameCheck::Unavailable),
+                creation_date: p.creation_date,
+                cooling_off_until: p.creation_date + cooling_off,
+            }).collect();
+            return HttpResponse::Ok().json(payees);
+        },
+        Err(e) => {
+            error!("failed listing payees: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+}
+
+/// saves a payee for the authenticated user. the name is checked against
+/// the display name of the user behind a username, and payees whose name
+/// doesn't match are only saved once the user accepts the mismatch. the
+/// actual name is never revealed, and usernames nobody has don't match any
+/// name, so the check can't be used to find out who has an account. they're
+/// never saved, accepted or not. tries are limited by user and address.
+/// returns the new payee
+pub async fn add(req: HttpRequest, claims: JwtClaims, payee: Json<PayeeRequest>) -> impl Responder {
+    let mut payee = payee.into_inner();
+    payee.nickname = payee.nickname.trim().to_string();
+    payee.name = payee.name.trim().to_string();
+    payee.identifier = payee.identifier.canonical();
+
+    if let Err(errors) = payee.validate() {
+        return HttpResponse::BadRequest().json(errors);
+    }
+
+    if let Err(response) = limit_by_key(&req, &ADD_LIMIT, &format!("user:{}", claims.subject())).await {
+        return response;
+    }
+    if let Some(addr) = req.peer_addr() {
+        if let Err(response) = limit_by_key(&req, &ADD_LIMIT, &format!("ip:{}", addr.ip())).await {
+            return response;
+        }
+    }
+
+    let pool = req.app_data::<PgPool>().unwrap();
+    let event = AuditEvent::new(EventType::PayeeAdded, Some(claims.subject())).by(&claims);
+
+    let (target_user_id, name_check) = match &payee.identifier {
+        PayeeIdentifier::Username(username) => match look_up_user(pool, username, &payee.name).await {
+            Ok(Some((user_id, _))) if user_id == claims.subject() => {
+                return HttpResponse::BadRequest().json(ValidationErrors::single("identifier", IdentifierViolation::OwnAccount));
+            },
+            Ok(Some((user_id, name_check))) => (Some(user_id), name_check),
+            // answered like any other mismatch, but never saved
+            Ok(None) => (None, NameCheck::NoMatch),
+            Err(e) => {
+                error!("failed looking up payee: {e}");
+                return HttpResponse::InternalServerError().finish();
+            }
+        },
+        // other banks can't be asked yet
+        PayeeIdentifier::Iban(_) => (None, NameCheck::Unavailable),
+    };
+
+    // there'd be nobody to send money to
+    let unknown_user = matches!(payee.identifier, PayeeIdentifier::Username(_)) && target_user_id.is_none();
+    if matches!(name_check, NameCheck::CloseMatch | NameCheck::NoMatch) && (!payee.accept_name_mismatch || unknown_user) {
+        audit::record(&req, event
+            .failed("name_mismatch")
+            .with_details(serde_json::json!({ "identifier_kind": payee.identifier.kind(), "name_check": name_check }))
+        ).await;
+        return HttpResponse::BadRequest().json(ValidationErrors::single("name", name_check));
+    }
+
+    let now = Utc::now();
+    let insert = sqlx::query!(r"INSERT INTO payees
+        (payee_id, user_id, nickname, name, identifier_kind, identifier, target_user_id, name_check, creation_date)
+        VALUES (gen_random_uuid(), $1, $2, $3, $4, $5, $6, $7, $8)
+        RETURNING payee_id, creation_date;",
+        claims.subject(),
+        payee.nickname,
+        payee.name,
+        payee.identifier.kind(),
+        payee.identifier.value(),
+        target_user_id,
+        name_check.name(),
+        now
+    ).fetch_one(pool).await;
+
+    match insert {
+        Ok(o) => {
+            audit::record(&req, event.with_details(serde_json::json!({
+                "payee_id": o.payee_id,
+                "identifier_kind": payee.identifier.kind(),
+                "name_check": name_check,
+            }))).await;
+            return HttpResponse::Created().json(Payee {
+                payee_id: o.payee_id,
+                nickname: payee.nickname,
+                name: payee.name,
+                identifier: payee.identifier,
+                name_check,
+                creation_date: o.creation_date,
+                cooling_off_until: o.creation_date + cooling_off(),
+            });
+        },
+        Err(e) => {
+            if let Some(error) = e.as_database_error().and_then(|e| already_in_use(e.constraint())) {
+                return HttpResponse::BadRequest().json(error);
+            }
+            error!("failed adding payee: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+}
+
+/// removes one of the authenticated user's payees
+pub async fn delete(req: HttpRequest, claims: JwtClaims, payee_id: Path<Uuid>) -> impl Responder {
+    let pool = req.app_data::<PgPool>().unwrap();
+    let payee_id = payee_id.into_inner();
+
+    let deletion = sqlx::query!(r"DELETE FROM payees WHERE payee_id = $1 AND user_id = $2;",
+        payee_id,
+        claims.subject()
+    ).execute(pool).await;
+
+    match deletion {
+        Ok(o) if o.rows_affected() == 0 => return HttpResponse::NotFound().finish(),
+        Ok(_) => {
+            audit::record(&req, AuditEvent::new(EventType::PayeeRemoved, Some(claims.subject()))
+                .by(&claims)
+                .with_details(serde_json::json!({ "payee_id": payee_id }))
+            ).await;
+            return HttpResponse::NoContent().finish();
+        },
+        Err(e) => {
+            error!("failed removing payee: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_name, edit_distance, NameCheck, PayeeIdentifier};
+
+    #[test]
+    fn test_check_name() {
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+
+        assert_eq!(check_name("Jane Doe", "Jane Doe"), NameCheck::Match);
+        assert_eq!(check_name("jane  DOE", "Jane Doe"), NameCheck::Match);
+        assert_eq!(check_name("Zoë O'Brien", "Zoe O Brien"), NameCheck::Match);
+
+        assert_eq!(check_name("Doe, Jane", "Jane Doe"), NameCheck::CloseMatch);
+        assert_eq!(check_name("J. Doe", "Jane Doe"), NameCheck::CloseMatch);
+        assert_eq!(check_name("Jane Doe", "Jane Dow"), NameCheck::CloseMatch);
+
+        assert_eq!(check_name("Jane Doe", "John Smith"), NameCheck::NoMatch);
+        assert_eq!(check_name("J. Smith", "Jane Doe"), NameCheck::NoMatch);
+        // too short for a typo to tell
+        assert_eq!(check_name("Ann", "Ian"), NameCheck::NoMatch);
+        assert_eq!(check_name("...", "Jane Doe"), NameCheck::NoMatch);
+    }
+
+    #[test]
+    fn test_canonical_identifiers() {
+        assert_eq!(
+            PayeeIdentifier::Iban("de89 3704 0044 0532 0130 00".to_string()).canonical(),
+            PayeeIdentifier::Iban("DE89370400440532013000".to_string())
+        );
+        assert_eq!(
+            PayeeIdentifier::Username(" Alice ".to_string()).canonical(),
+            PayeeIdentifier::Username("alice".to_string())
+        );
+    }
+}
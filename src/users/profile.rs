@@ -0,0 +1,139 @@
+use actix_web::{HttpRequest, Responder, web::Json, HttpResponse};
+use chrono::{DateTime, Utc};
+use log::error;
+use serde::{Serialize, Deserialize, Deserializer};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{auth::token::{is_first_party, jwt::{JwtClaims, TokenHeader}}, validation::{self, Validate, ValidationErrors}};
+
+/// what users get to see about themselves, leaving out credentials and
+/// anything else only the server needs
+#[derive(Serialize)]
+struct Profile {
+    user_id: Uuid,
+    username: String,
+    email: String,
+    creation_date: DateTime<Utc>,
+    display_name: Option<String>,
+    home_currency: Option<String>,
+    locale: Option<String>,
+    timezone: Option<String>,
+}
+
+/// tells a field that was left out, which stays as it is, apart from one
+/// that's `null`, which unsets it
+fn present<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Option<String>>, D::Error> {
+    Option::deserialize(deserializer).map(Some)
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ProfileError {
+    NotAllowedForToken,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ProfileUpdate {
+    #[serde(default, deserialize_with = "present")]
+    display_name: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    home_currency: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    locale: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    timezone: Option<Option<String>>,
+}
+
+impl Validate for ProfileUpdate {
+    /// only checks the fields being set, expects the display name trimmed
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::new();
+        if let Some(Some(name)) = &self.display_name {
+            errors.check("display_name", validation::length(name, 1, 64));
+            errors.check("display_name", validation::charset(name, |c| !c.is_control()));
+        }
+        if let Some(Some(currency)) = &self.home_currency {
+            errors.check("home_currency", validation::currency_code(currency));
+        }
+        if let Some(Some(locale)) = &self.locale {
+            errors.check("locale", validation::locale(locale));
+        }
+        if let Some(Some(timezone)) = &self.timezone {
+            errors.check("timezone", validation::timezone(timezone));
+        }
+        errors.into_result()
+    }
+}
+
+async fn fetch(pool: &PgPool, user_id: Uuid) -> Result<Option<Profile>, sqlx::Error> {
+    sqlx::query_as!(Profile, r"SELECT users.user_id, users.username, users.email, users.creation_date,
+        p.display_name, p.home_currency, p.locale, p.timezone
+        FROM users LEFT JOIN user_profiles p ON p.user_id = users.user_id
+        WHERE users.user_id = $1;",
+        user_id
+    ).fetch_optional(pool).await
+}
+
+fn respond(profile: Result<Option<Profile>, sqlx::Error>) -> HttpResponse {
+    match profile {
+        Ok(Some(o)) => return HttpResponse::Ok().json(o),
+        Ok(None) => return HttpResponse::NotFound().finish(),
+        Err(e) => {
+            error!("failed fetching profile: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+}
+
+/// returns the authenticated user's profile
+pub async fn get(req: HttpRequest, claims: JwtClaims) -> impl Responder {
+    let pool = req.app_data::<PgPool>().unwrap();
+    respond(fetch(pool, claims.subject()).await)
+}
+
+/// changes the authenticated user's preferences. fields that are left out
+/// stay as they are, `null` unsets them. returns the updated profile. only
+/// the user themselves can, not personal access tokens or OAuth clients that
+/// were allowed to read it
+pub async fn update(req: HttpRequest, claims: JwtClaims, header: TokenHeader, update: Json<ProfileUpdate>) -> impl Responder {
+    if !is_first_party(&claims, &header) {
+        return HttpResponse::BadRequest().json(vec![ProfileError::NotAllowedForToken]);
+    }
+
+    let mut update = update.into_inner();
+    update.display_name = update.display_name.map(|n| n.map(|n| n.trim().to_string()));
+
+    if let Err(errors) = update.validate() {
+        return HttpResponse::BadRequest().json(errors);
+    }
+
+    let pool = req.app_data::<PgPool>().unwrap();
+
+    let upsert = sqlx::query!(r"INSERT INTO user_profiles
+        (user_id, display_name, home_currency, locale, timezone, update_date)
+        VALUES ($1, $2, $4, $6, $8, $10)
+        ON CONFLICT (user_id) DO UPDATE
+        SET display_name = CASE WHEN $3 THEN EXCLUDED.display_name ELSE user_profiles.display_name END,
+            home_currency = CASE WHEN $5 THEN EXCLUDED.home_currency ELSE user_profiles.home_currency END,
+            locale = CASE WHEN $7 THEN EXCLUDED.locale ELSE user_profiles.locale END,
+            timezone = CASE WHEN $9 THEN EXCLUDED.timezone ELSE user_profiles.timezone END,
+            update_date = EXCLUDED.update_date;",
+        claims.subject(),
+        update.display_name.clone().flatten(),
+        update.display_name.is_some(),
+        update.home_currency.clone().flatten(),
+        update.home_currency.is_some(),
+        update.locale.clone().flatten(),
+        update.locale.is_some(),
+        update.timezone.clone().flatten(),
+        update.timezone.is_some(),
+        Utc::now()
+    ).execute(pool).await;
+    if let Err(e) = upsert {
+        error!("failed updating profile: {e}");
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    respond(fetch(pool, claims.subject()).await)
+}
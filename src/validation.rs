@@ -0,0 +1,255 @@
+use std::sync::OnceLock;
+
+use regex::Regex;
+use serde::{Serialize, Deserialize, ser::SerializeSeq};
+
+/// what's wrong with a single field. validators stop at the first problem
+/// they find, so each one reports at most one of these
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Violation {
+    /// the actual length, in characters
+    InvalidLength(usize),
+    /// the first character that isn't allowed
+    InvalidChar(char),
+    InvalidFormat,
+    OutOfRange,
+    /// well-formed, but the check digits don't add up
+    InvalidChecksum,
+    AlreadyInUse,
+}
+
+/// every problem found with a payload, keyed by field. serialized as a list
+/// of `{"invalid_{field}": {violation}}` objects, so it can be sent as is
+/// with HTTP Status 400
+#[derive(Debug, Default)]
+pub struct ValidationErrors {
+    errors: Vec<(&'static str, serde_json::Value)>,
+}
+
+impl ValidationErrors {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// a single problem, for things only found out after validating, like a
+    /// taken username
+    pub fn single(field: &'static str, violation: impl Serialize) -> Self {
+        let mut errors = Self::new();
+        errors.add(field, violation);
+        errors
+    }
+
+    /// records a problem with the field. anything that serializes can be a
+    /// violation, for fields with their own kinds of problems
+    pub fn add(&mut self, field: &'static str, violation: impl Serialize) {
+        let violation = serde_json::to_value(violation).expect("violations serialize to JSON");
+        self.errors.push((field, violation));
+    }
+
+    /// records the problem the validator found, if it found one
+    pub fn check<V: Serialize>(&mut self, field: &'static str, result: Result<(), V>) {
+        if let Err(violation) = result {
+            self.add(field, violation);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    /// the fields problems were found with, in the order they were found
+    pub fn fields(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.errors.iter().map(|(field, _)| *field)
+    }
+
+    pub fn into_result(self) -> Result<(), ValidationErrors> {
+        match self.is_empty() {
+            true => Ok(()),
+            false => Err(self),
+        }
+    }
+}
+
+impl Serialize for ValidationErrors {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.errors.len()))?;
+        for (field, violation) in self.errors.iter() {
+            let mut entry = serde_json::Map::new();
+            entry.insert(format!("invalid_{field}"), violation.clone());
+            seq.serialize_element(&entry)?;
+        }
+        seq.end()
+    }
+}
+
+/// a request payload that can check itself, reporting every problem at once
+pub trait Validate {
+    fn validate(&self) -> Result<(), ValidationErrors>;
+}
+
+/// at least `min` and at most `max` characters long
+pub fn length(value: &str, min: usize, max: usize) -> Result<(), Violation> {
+    let length = value.chars().count();
+    if length < min || length > max {
+        return Err(Violation::InvalidLength(length));
+    }
+    return Ok(());
+}
+
+/// only made of characters the predicate allows
+pub fn charset(value: &str, allowed: impl Fn(char) -> bool) -> Result<(), Violation> {
+    match value.chars().find(|c| !allowed(*c)) {
+        Some(c) => return Err(Violation::InvalidChar(c)),
+        None => return Ok(()),
+    }
+}
+
+/// matches the regex, which should be anchored to match all of the value
+pub fn regex(value: &str, pattern: &Regex) -> Result<(), Violation> {
+    match pattern.is_match(value) {
+        true => return Ok(()),
+        false => return Err(Violation::InvalidFormat),
+    }
+}
+
+/// within `min..=max`
+pub fn range<T: PartialOrd>(value: T, min: T, max: T) -> Result<(), Violation> {
+    match value >= min && value <= max {
+        true => return Ok(()),
+        false => return Err(Violation::OutOfRange),
+    }
+}
+
+static EMAIL: OnceLock<Regex> = OnceLock::new();
+
+/// an email address, in its [`canonical`](crate::auth::canonical) form
+pub fn email(value: &str) -> Result<(), Violation> {
+    let pattern = EMAIL.get_or_init(|| {
+        Regex::new(r"^[\w\-]+(?:\.[\w\-]+)*@(?:[\w\-]+\.)+[a-z]{2,6}$").unwrap()
+    });
+    regex(value, pattern)
+}
+
+/// a three letter currency code, like `EUR`. only the format is checked, not
+/// whether ISO 4217 actually has it
+pub fn currency_code(value: &str) -> Result<(), Violation> {
+    length(value, 3, 3)?;
+    charset(value, |c| c.is_ascii_uppercase())
+}
+
+static LOCALE: OnceLock<Regex> = OnceLock::new();
+
+/// a BCP 47 language tag made of a language and optionally a script and a
+/// region, like `en`, `de-AT` or `zh-Hant-TW`
+pub fn locale(value: &str) -> Result<(), Violation> {
+    let pattern = LOCALE.get_or_init(|| {
+        Regex::new(r"^[a-z]{2,3}(?:-[A-Z][a-z]{3})?(?:-(?:[A-Z]{2}|[0-9]{3}))?$").unwrap()
+    });
+    regex(value, pattern)
+}
+
+/// a time zone from the IANA database, like `Europe/Berlin` or `UTC`
+pub fn timezone(value: &str) -> Result<(), Violation> {
+    match value.parse::<chrono_tz::Tz>() {
+        Ok(_) => return Ok(()),
+        Err(_) => return Err(Violation::InvalidFormat),
+    }
+}
+
+/// a positive amount of money with at most two decimal places, like `12.50`,
+/// that fits in an `i64` of cents
+pub fn money_amount(value: &str) -> Result<(), Violation> {
+    let (units, cents) = value.split_once('.').unwrap_or((value, ""));
+    let digits = |s: &str| s.chars().all(|c| c.is_ascii_digit());
+    if units.is_empty() || !digits(units) || !digits(cents) || cents.len() > 2 || value.ends_with('.') {
+        return Err(Violation::InvalidFormat);
+    }
+
+    let cents = format!("{cents:0<2}");
+    match format!("{units}{cents}").parse::<i64>() {
+        Ok(0) | Err(_) => return Err(Violation::OutOfRange),
+        Ok(_) => return Ok(()),
+    }
+}
+
+/// an IBAN in its electronic form, without spaces: a country code, two check
+/// digits and up to 30 letters and digits, with the check digits verified
+/// using ISO 7064 mod 97-10
+pub fn iban(value: &str) -> Result<(), Violation> {
+    length(value, 15, 34)?;
+    charset(value, |c| c.is_ascii_uppercase() || c.is_ascii_digit())?;
+    let (country, rest) = value.split_at(2);
+    if !country.chars().all(|c| c.is_ascii_uppercase()) || !rest[..2].chars().all(|c| c.is_ascii_digit()) {
+        return Err(Violation::InvalidFormat);
+    }
+
+    // the first four characters go to the end, letters count as 10 to 35
+    let remainder = value[4..].chars().chain(value[..4].chars()).fold(0u32, |remainder, c| {
+        let digit = c.to_digit(36).unwrap();
+        match digit {
+            0..=9 => (remainder * 10 + digit) % 97,
+            _ => (remainder * 100 + digit) % 97,
+        }
+    });
+    match remainder {
+        1 => return Ok(()),
+        _ => return Err(Violation::InvalidChecksum),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{charset, currency_code, email, iban, length, locale, money_amount, range, timezone, ValidationErrors, Violation};
+
+    #[test]
+    fn test_validators() {
+        assert_eq!(length("alice", 4, 32), Ok(()));
+        assert_eq!(length("äl", 4, 32), Err(Violation::InvalidLength(2)));
+        assert_eq!(charset("al!ce?", |c| c.is_ascii_alphanumeric()), Err(Violation::InvalidChar('!')));
+        assert_eq!(range(5, 1, 10), Ok(()));
+        assert_eq!(range(0, 1, 10), Err(Violation::OutOfRange));
+
+        for valid in ["a@example.com", "a@mail.example.com", "first.last@mail.example.co.uk", "a_b-c@x-y.example.org"] {
+            assert_eq!(email(valid), Ok(()), "{valid}");
+        }
+        for invalid in ["a@example", "a@.example.com", "a@mail..example.com", ".a@example.com", "a.@example.com", "a@example.c"] {
+            assert_eq!(email(invalid), Err(Violation::InvalidFormat), "{invalid}");
+        }
+
+        assert_eq!(currency_code("EUR"), Ok(()));
+        assert_eq!(currency_code("eur"), Err(Violation::InvalidChar('e')));
+        assert_eq!(locale("en"), Ok(()));
+        assert_eq!(locale("zh-Hant-TW"), Ok(()));
+        assert_eq!(locale("en_US"), Err(Violation::InvalidFormat));
+        assert_eq!(timezone("Europe/Berlin"), Ok(()));
+        assert_eq!(timezone("Mars/Olympus_Mons"), Err(Violation::InvalidFormat));
+
+        assert_eq!(money_amount("12.5"), Ok(()));
+        assert_eq!(money_amount("0.01"), Ok(()));
+        assert_eq!(money_amount("0.00"), Err(Violation::OutOfRange));
+        assert_eq!(money_amount("99999999999999999999"), Err(Violation::OutOfRange));
+        for invalid in ["", "-1", "1.234", "1.", ".5", "1,50", "1e3"] {
+            assert_eq!(money_amount(invalid), Err(Violation::InvalidFormat), "{invalid}");
+        }
+
+        assert_eq!(iban("DE89370400440532013000"), Ok(()));
+        assert_eq!(iban("GB82WEST12345698765432"), Ok(()));
+        assert_eq!(iban("DE89370400440532013001"), Err(Violation::InvalidChecksum));
+        assert_eq!(iban("DE89 3704 0044 0532 0130 00"), Err(Violation::InvalidChar(' ')));
+        assert_eq!(iban("1289370400440532013000"), Err(Violation::InvalidFormat));
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut errors = ValidationErrors::new();
+        errors.check("username", length("al", 4, 32));
+        errors.check("username", charset("al", |c| c.is_ascii_alphanumeric()));
+        errors.check("email", Err(Violation::InvalidFormat));
+        assert_eq!(errors.fields().collect::<Vec<_>>(), ["username", "email"]);
+        assert_eq!(
+            serde_json::to_value(errors).unwrap(),
+            serde_json::json!([{"invalid_username": {"invalid_length": 2}}, {"invalid_email": "invalid_format"}])
+        );
+    }
+}
@@ -0,0 +1,232 @@
+//! goes through registering and logging in against the database from `.env`,
+//! checking what ends up in the audit log and who gets to see it, and that
+//! changes to recorded events are found
+
+use actix_web::{test, http::StatusCode};
+use cyber_bank_rs::audit::{self, AuditEvent, AuditLogger, EventType, chain::{self, ChainBreak, VerifyError}};
+use serde_json::{json, Value};
+
+mod common;
+
+#[actix_web::test]
+async fn test_audit_log() {
+    let pool = common::pool().await;
+    let app = common::app(&pool).await;
+
+    let username = common::username("audit");
+    common::register(&app, &username).await;
+
+    let body = common::last_email(&pool, &format!("{username}@example.com"), "Verifying your email address").await;
+    let request = test::TestRequest::post()
+        .uri("/auth/email/verify")
+        .set_json(json!({ "token": body.split("\n\n").nth(1).unwrap() }))
+        .to_request();
+    assert_eq!(test::call_service(&app, request).await.status(), StatusCode::NO_CONTENT);
+
+    let request = test::TestRequest::post()
+        .uri("/auth/login")
+        .set_json(json!({ "username": username, "password": "wrong" }))
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let token = common::token(&app, &username).await;
+
+    let request = test::TestRequest::get()
+        .uri("/users/me/activity")
+        .insert_header(("Authorization", format!("Bearer {token}")))
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().contains_key("x-request-id"));
+    let events: Vec<Value> = test::read_body_json(response).await;
+    let summary: Vec<(&str, Option<&str>)> = events.iter()
+        .map(|e| (e["event_type"].as_str().unwrap(), e["failure_reason"].as_str()))
+        .collect();
+    // newest first
+    assert_eq!(summary, vec![
+        ("login", None),
+        ("login", Some("wrong_password")),
+        ("email_verified", None),
+        ("registration", None),
+    ]);
+    let user_id = events[0]["user_id"].as_str().unwrap().to_string();
+    assert!(events[0]["request_id"].is_string());
+
+    // paging on from the last event of the first page
+    let request = test::TestRequest::get()
+        .uri(&format!("/users/me/activity?limit=1&before={}", events[1]["event_id"].as_str().unwrap()))
+        .insert_header(("Authorization", format!("Bearer {token}")))
+        .to_request();
+    let page: Vec<Value> = test::call_and_read_body_json(&app, request).await;
+    assert_eq!(page.len(), 1);
+    assert_eq!(page[0]["event_type"], "email_verified");
+
+    // only admins can search everyone's events
+    let search = |token: &str| test::TestRequest::get()
+        .uri(&format!("/admin/audit?user_id={user_id}&succeeded=false"))
+        .insert_header(("Authorization", format!("Bearer {token}")))
+        .to_request();
+    assert_eq!(test::call_service(&app, search(&token)).await.status(), StatusCode::FORBIDDEN);
+
+    sqlx::query!("UPDATE users SET is_admin = true WHERE username = $1;", username)
+        .execute(&pool).await.unwrap();
+    let admin_token = common::token(&app, &username).await;
+
+    let found: Vec<Value> = test::call_and_read_body_json(&app, search(&admin_token)).await;
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0]["failure_reason"], "wrong_password");
+    // the user is recorded, so what was typed in isn't
+    assert_eq!(found[0]["details"], json!({ "method": "password" }));
+
+    // names nobody has are only kept as pseudonyms
+    let nobody = common::username("nobody");
+    let request = test::TestRequest::post()
+        .uri("/auth/login")
+        .set_json(json!({ "username": nobody, "password": "wrong" }))
+        .to_request();
+    assert_eq!(test::call_service(&app, request).await.status(), StatusCode::FORBIDDEN);
+    let recorded = sqlx::query!(r#"SELECT details AS "details!" FROM audit_events
+        WHERE details->>'username_pseudonym' = $1;"#,
+        audit::pseudonym(&nobody)
+    ).fetch_one(&pool).await.unwrap();
+    assert!(!recorded.details.to_string().contains(&nobody));
+
+    // the search itself was recorded
+    let request = test::TestRequest::get()
+        .uri(&format!("/admin/audit?actor_id={user_id}&event_type=audit_query"))
+        .insert_header(("Authorization", format!("Bearer {admin_token}")))
+        .to_request();
+    let found: Vec<Value> = test::call_and_read_body_json(&app, request).await;
+    assert_eq!(found.len(), 2);
+    assert_eq!(found[1]["details"]["filter"]["user_id"], user_id);
+
+    // nothing recorded can be changed or removed
+    let event_id: uuid::Uuid = events[0]["event_id"].as_str().unwrap().parse().unwrap();
+    assert!(sqlx::query!("UPDATE audit_events SET succeeded = false WHERE event_id = $1;", event_id)
+        .execute(&pool).await.is_err());
+    assert!(sqlx::query!("DELETE FROM audit_events WHERE event_id = $1;", event_id)
+        .execute(&pool).await.is_err());
+
+    common::delete_users(&pool, &[&username]).await;
+}
+
+/// a copy of the chain in a schema of its own, without the triggers that keep
+/// anyone from changing it, and a connection that uses it instead of the real
+/// one. the copy is made head first, so it has every event the head covers
+async fn copy_chain(pool: &sqlx::PgPool, schema: &str) -> sqlx::pool::PoolConnection<sqlx::Postgres> {
+    let mut conn = pool.acquire().await.unwrap();
+    for statement in [
+        format!("CREATE SCHEMA {schema};"),
+        format!("CREATE TABLE {schema}.audit_checkpoints (LIKE public.audit_checkpoints INCLUDING ALL);"),
+        format!("INSERT INTO {schema}.audit_checkpoints SELECT * FROM public.audit_checkpoints;"),
+        format!("CREATE TABLE {schema}.audit_chain_head (LIKE public.audit_chain_head INCLUDING ALL);"),
+        format!("INSERT INTO {schema}.audit_chain_head SELECT * FROM public.audit_chain_head;"),
+        format!("CREATE TABLE {schema}.audit_events (LIKE public.audit_events INCLUDING ALL);"),
+        format!("INSERT INTO {schema}.audit_events SELECT * FROM public.audit_events WHERE chain_position IS NOT NULL;"),
+        format!("SET search_path TO {schema};"),
+    ] {
+        sqlx::query(&statement).execute(&mut *conn).await.unwrap();
+    }
+    conn
+}
+
+/// changes the copied events in a transaction that's rolled back, returning
+/// how the chain broke
+async fn tamper(conn: &mut sqlx::PgConnection, change: &str) -> ChainBreak {
+    let mut tx = sqlx::Connection::begin(&mut *conn).await.unwrap();
+    sqlx::query(change).execute(&mut *tx).await.unwrap();
+    let verified = chain::verify(&mut tx, &chain::verifying_key()).await;
+    tx.rollback().await.unwrap();
+    match verified {
+        Err(VerifyError::Broken(o)) => o,
+        other => panic!("expected a broken chain, got {other:?}"),
+    }
+}
+
+#[actix_web::test]
+async fn test_hash_chain() {
+    let pool = common::pool().await;
+    let logger = AuditLogger::new(pool.clone());
+
+    let req = test::TestRequest::default().to_http_request();
+    let user_id = uuid::Uuid::new_v4();
+    logger.record(&req, AuditEvent::new(EventType::Login, Some(user_id))
+        .with_details(json!({ "method": "password", "username_pseudonym": "chain" }))
+    ).await;
+    logger.record(&req, AuditEvent::new(EventType::Logout, Some(user_id))).await;
+
+    // events only get their place in the chain once they're sealed
+    let positions = || sqlx::query_scalar!("SELECT chain_position FROM audit_events
+        WHERE user_id = $1 ORDER BY occurred_date;",
+        user_id
+    ).fetch_all(&pool);
+    assert_eq!(positions().await.unwrap(), [None, None]);
+    chain::seal(&pool).await.unwrap();
+    let positions: Vec<i64> = positions().await.unwrap().into_iter().map(Option::unwrap).collect();
+    assert!(positions[0] < positions[1]);
+
+    // sealed events can't be sealed again
+    assert!(sqlx::query!("UPDATE audit_events SET chain_position = chain_position + 1 WHERE user_id = $1;", user_id)
+        .execute(&pool).await.is_err());
+
+    chain::checkpoint(&pool).await.unwrap();
+    let mut conn = pool.acquire().await.unwrap();
+    let report = chain::verify(&mut conn, &chain::verifying_key()).await.unwrap();
+    assert!(report.last_position >= positions[1]);
+    assert!(report.last_checkpoint.is_some_and(|c| c >= positions[1]));
+
+    let schema = format!("audit_tamper_{}", uuid::Uuid::new_v4().simple());
+    let mut copy = copy_chain(&pool, &schema).await;
+
+    let position = positions[0];
+    let event_id: uuid::Uuid = sqlx::query_scalar!("SELECT event_id FROM audit_events WHERE chain_position = $1;", position)
+        .fetch_one(&pool).await.unwrap();
+
+    let altered = tamper(&mut copy, &format!("UPDATE audit_events SET details = '{{}}' WHERE chain_position = {position};")).await;
+    assert_eq!(altered, ChainBreak::Altered { position, event_id });
+
+    // pointing an event somewhere else breaks its link
+    let next = positions[1];
+    let next_id: uuid::Uuid = sqlx::query_scalar!("SELECT event_id FROM audit_events WHERE chain_position = $1;", next)
+        .fetch_one(&pool).await.unwrap();
+    let relinked = tamper(&mut copy, &format!("UPDATE audit_events SET previous_hash = '\\x00' WHERE chain_position = {next};")).await;
+    assert_eq!(relinked, ChainBreak::BrokenLink { position: next, event_id: next_id });
+
+    let removed = tamper(&mut copy, &format!("DELETE FROM audit_events WHERE chain_position = {position};")).await;
+    assert_eq!(removed, ChainBreak::Gap { expected_position: position, found_position: position + 1 });
+
+    for statement in ["RESET search_path;".to_string(), format!("DROP SCHEMA {schema} CASCADE;")] {
+        sqlx::query(&statement).execute(&mut *copy).await.unwrap();
+    }
+}
+
+#[actix_web::test]
+async fn test_unrecorded_actions_still_succeed() {
+    use actix_web::{web, App};
+    use cyber_bank_rs::{mail, store};
+
+    let pool = common::pool().await;
+    // everything the server has but the audit logger
+    let app = test::init_service(
+        App::new()
+            .service(web::scope("/auth").configure(cyber_bank_rs::auth::config))
+            .app_data(pool.clone())
+            .app_data(mail::from_env(&pool))
+            .app_data(store::from_env(&pool).await)
+    ).await;
+
+    let username = common::username("unaudited");
+    common::register(&app, &username).await;
+    let token = common::token(&app, &username).await;
+
+    // the session is gone by the time it would be recorded, which can't
+    // turn it into an error anymore
+    let logout = || test::TestRequest::post()
+        .uri("/auth/logout")
+        .insert_header(("Authorization", format!("Bearer {token}")))
+        .to_request();
+    assert_eq!(test::call_service(&app, logout()).await.status(), StatusCode::NO_CONTENT);
+    assert_eq!(test::call_service(&app, logout()).await.status(), StatusCode::FORBIDDEN);
+
+    common::delete_users(&pool, &[&username]).await;
+}
@@ -0,0 +1,131 @@
+//! what the tests against the database from `.env` share: an app with every
+//! endpoint and the same app data as the server, and users to log in with
+
+// every test crate only uses some of it
+#![allow(dead_code)]
+
+use actix_http::Request;
+use actix_web::{
+    body::MessageBody,
+    dev::{Service, ServiceResponse},
+    http::StatusCode,
+    test, web, App, Error,
+};
+use cyber_bank_rs::{audit::{self, AuditLogger}, db, mail, store};
+use rand::RngCore;
+use serde_json::{json, Value};
+use sqlx::PgPool;
+
+pub const PASSWORD: &str = "Passw0rd!";
+
+/// the database from `.env`, with every migration applied
+pub async fn pool() -> PgPool {
+    // every registration and login hashes a password, which takes a while in
+    // debug builds on few CPUs
+    std::env::set_var("ARGON2_QUEUE_TIMEOUT_MS", "120000");
+    // never the key the server signs with
+    std::env::set_var("AUDIT_SIGNING_KEY", "wzBV1cc5HfppuFQAuMcmmxCQomB1vJ37O1xGG8FyKGs=");
+
+    let pool = db::get_db_pool().await;
+    db::set_up_db_tables(&pool).await;
+    pool
+}
+
+/// `/auth`, `/users` and `/admin` with the mailer, session store and audit
+/// logger the server would have
+pub async fn app(pool: &PgPool) -> impl Service<Request, Response = ServiceResponse<impl MessageBody>, Error = Error> {
+    let mailer = mail::from_env(pool);
+    let session_store = store::from_env(pool).await;
+
+    test::init_service(
+        App::new()
+            .wrap(audit::AssignRequestId)
+            .service(web::scope("/auth").configure(cyber_bank_rs::auth::config))
+            .service(web::scope("/users").configure(cyber_bank_rs::users::config))
+            .service(web::scope("/admin").configure(audit::admin_config))
+            .app_data(pool.clone())
+            .app_data(mailer)
+            .app_data(session_store)
+            .app_data(AuditLogger::new(pool.clone()))
+    ).await
+}
+
+/// a username no other test uses
+pub fn username(prefix: &str) -> String {
+    format!("{prefix}_{:08x}", rand::thread_rng().next_u32())
+}
+
+/// registers the user with [`PASSWORD`] and `{username}@example.com`
+pub async fn register<S, B>(app: &S, username: &str)
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = Error>,
+    B: MessageBody,
+{
+    let request = test::TestRequest::post()
+        .uri("/auth/register")
+        .set_json(json!({
+            "email": format!("{username}@example.com"),
+            "username": username,
+            "password": PASSWORD,
+        }))
+        .to_request();
+    assert_eq!(test::call_service(app, request).await.status(), StatusCode::CREATED);
+}
+
+/// logs the user in with [`PASSWORD`], returning the whole response
+pub async fn login<S, B>(app: &S, username: &str) -> Value
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = Error>,
+    B: MessageBody,
+{
+    let request = test::TestRequest::post()
+        .uri("/auth/login")
+        .set_json(json!({ "username": username, "password": PASSWORD }))
+        .to_request();
+    let response = test::call_service(app, request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    test::read_body_json(response).await
+}
+
+/// logs the user in with [`PASSWORD`], returning the login token
+pub async fn token<S, B>(app: &S, username: &str) -> String
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = Error>,
+    B: MessageBody,
+{
+    login(app, username).await["token"].as_str().unwrap().to_string()
+}
+
+/// marks the user's email address as verified, which the transfers scope
+/// needs
+pub async fn verify_email(pool: &PgPool, username: &str) {
+    sqlx::query("UPDATE users SET email_verified_at = now() WHERE username = $1;")
+        .bind(username)
+        .execute(pool).await.unwrap();
+}
+
+/// removes the users for good, along with everything tied to them
+pub async fn delete_users(pool: &PgPool, usernames: &[&str]) {
+    sqlx::query("DELETE FROM users WHERE username = ANY($1);")
+        .bind(usernames)
+        .execute(pool).await.unwrap();
+}
+
+/// the body of the last email with the subject sent to the address, waiting
+/// for it a little since emails are sent in the background. needs the
+/// default outbox mailer
+pub async fn last_email(pool: &PgPool, recipient: &str, subject: &str) -> String {
+    for _ in 0..50 {
+        let body: Option<String> = sqlx::query_scalar("SELECT body FROM mail_outbox
+            WHERE recipient = $1 AND subject = $2
+            ORDER BY creation_date DESC LIMIT 1;")
+            .bind(recipient)
+            .bind(subject)
+            .fetch_optional(pool).await.unwrap();
+        if let Some(body) = body {
+            return body;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    panic!("no email {subject:?} to {recipient}");
+}
@@ -0,0 +1,157 @@
+//! deletes, restores, exports and purges accounts against the database from
+//! `.env`
+
+use actix_web::{test, http::StatusCode};
+use cyber_bank_rs::{audit::chain, users::deletion};
+use serde_json::{json, Value};
+
+mod common;
+
+#[actix_web::test]
+async fn test_deletion_and_restoring() {
+    let pool = common::pool().await;
+    let app = common::app(&pool).await;
+
+    let username = common::username("deleted");
+    common::register(&app, &username).await;
+    let token = common::token(&app, &username).await;
+
+    let request = test::TestRequest::patch()
+        .uri("/users/me")
+        .insert_header(("Authorization", format!("Bearer {token}")))
+        .set_json(json!({ "display_name": "Jane Doe", "timezone": "Europe/Berlin" }))
+        .to_request();
+    assert_eq!(test::call_service(&app, request).await.status(), StatusCode::OK);
+
+    // the export has everything, including the profile
+    let request = test::TestRequest::get()
+        .uri("/users/me/export")
+        .insert_header(("Authorization", format!("Bearer {token}")))
+        .to_request();
+    let export: Value = test::call_and_read_body_json(&app, request).await;
+    assert_eq!(export["user"]["username"], username);
+    assert_eq!(export["profile"]["display_name"], "Jane Doe");
+    assert_eq!(export["profile"]["timezone"], "Europe/Berlin");
+    assert_eq!(export["sessions"].as_array().unwrap().len(), 1);
+
+    // a token that can only read the profile can't delete the account
+    let request = test::TestRequest::post()
+        .uri("/auth/tokens")
+        .insert_header(("Authorization", format!("Bearer {token}")))
+        .set_json(json!({ "name": "profile reader", "scopes": ["user_info"], "expires_in_days": 1 }))
+        .to_request();
+    let created: Value = test::call_and_read_body_json(&app, request).await;
+    let reader = created["token"].as_str().unwrap();
+    let request = test::TestRequest::delete()
+        .uri("/users/me")
+        .insert_header(("Authorization", format!("Bearer {reader}")))
+        .set_json(json!({ "password": common::PASSWORD }))
+        .to_request();
+    assert_eq!(test::call_service(&app, request).await.status(), StatusCode::FORBIDDEN);
+
+    let delete = |password: &str| test::TestRequest::delete()
+        .uri("/users/me")
+        .insert_header(("Authorization", format!("Bearer {token}")))
+        .set_json(json!({ "password": password }))
+        .to_request();
+    assert_eq!(test::call_service(&app, delete("wrong")).await.status(), StatusCode::FORBIDDEN);
+    assert_eq!(test::call_service(&app, delete(common::PASSWORD)).await.status(), StatusCode::NO_CONTENT);
+
+    // nothing works anymore, and the username can't log in
+    let request = test::TestRequest::get()
+        .uri("/users/me")
+        .insert_header(("Authorization", format!("Bearer {token}")))
+        .to_request();
+    assert_eq!(test::call_service(&app, request).await.status(), StatusCode::FORBIDDEN);
+    let login = || test::TestRequest::post()
+        .uri("/auth/login")
+        .set_json(json!({ "username": username, "password": common::PASSWORD }))
+        .to_request();
+    assert_eq!(test::call_service(&app, login()).await.status(), StatusCode::FORBIDDEN);
+
+    let email = common::last_email(&pool, &format!("{username}@example.com"), "Your account was deleted").await;
+    let restoration_token = email.split("\n\n").nth(2).unwrap();
+
+    let restore = |token: &str| test::TestRequest::post()
+        .uri("/users/restore")
+        .set_json(json!({ "token": token }))
+        .to_request();
+    let response = test::call_service(&app, restore("wrong")).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body: Value = test::read_body_json(response).await;
+    assert_eq!(body, json!(["invalid_token"]));
+
+    // restoring brings back the login, but not what was removed with it
+    assert_eq!(test::call_service(&app, restore(restoration_token)).await.status(), StatusCode::NO_CONTENT);
+    assert_eq!(test::call_service(&app, login()).await.status(), StatusCode::OK);
+    let token = common::token(&app, &username).await;
+    let request = test::TestRequest::get()
+        .uri("/users/me")
+        .insert_header(("Authorization", format!("Bearer {token}")))
+        .to_request();
+    let profile: Value = test::call_and_read_body_json(&app, request).await;
+    assert_eq!(profile["display_name"], Value::Null);
+
+    // and only works once
+    assert_eq!(test::call_service(&app, restore(restoration_token)).await.status(), StatusCode::BAD_REQUEST);
+
+    common::delete_users(&pool, &[&username]).await;
+}
+
+#[actix_web::test]
+async fn test_purging() {
+    let pool = common::pool().await;
+    let app = common::app(&pool).await;
+
+    let expired = common::username("expired");
+    let retained = common::username("retained");
+    let mut user_ids = Vec::new();
+    for username in [&expired, &retained] {
+        common::register(&app, username).await;
+        let token = common::token(&app, username).await;
+        let request = test::TestRequest::delete()
+            .uri("/users/me")
+            .peer_addr("127.0.0.1:4321".parse().unwrap())
+            .insert_header(("Authorization", format!("Bearer {token}")))
+            .insert_header(("User-Agent", "purge test"))
+            .set_json(json!({ "password": common::PASSWORD }))
+            .to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::NO_CONTENT);
+        let user_id: uuid::Uuid = sqlx::query_scalar!("SELECT user_id FROM account_restorations WHERE username = $1;", username)
+            .fetch_one(&pool).await.unwrap();
+        user_ids.push(user_id);
+    }
+    sqlx::query!("UPDATE users SET purge_date = now() - interval '1 day' WHERE user_id = $1;", user_ids[0])
+        .execute(&pool).await.unwrap();
+    // scrubbing sealed events doesn't break the chain
+    chain::seal(&pool).await.unwrap();
+
+    assert!(deletion::purge(&pool).await.unwrap() >= 1);
+
+    // only the expired account's events lose where they came from
+    let origins = |user_id: uuid::Uuid| sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM audit_events
+        WHERE user_id = $1 AND (ip IS NOT NULL OR user_agent IS NOT NULL);"#,
+        user_id
+    ).fetch_one(&pool);
+    assert_eq!(origins(user_ids[0]).await.unwrap(), 0);
+    assert!(origins(user_ids[1]).await.unwrap() > 0);
+    let mut conn = pool.acquire().await.unwrap();
+    chain::verify(&mut conn, &chain::verifying_key()).await.unwrap();
+
+    // the expired account is anonymized and kept, the other one can still be restored
+    let users = sqlx::query!("SELECT user_id, username, purge_date,
+        EXISTS (SELECT 1 FROM account_restorations r WHERE r.user_id = users.user_id) AS \"restorable!\"
+        FROM users WHERE user_id = ANY($1)
+        ORDER BY username;",
+        &user_ids
+    ).fetch_all(&pool).await.unwrap();
+    assert_eq!(users.len(), 2);
+    let purged = users.iter().find(|u| u.user_id == user_ids[0]).unwrap();
+    assert_eq!(purged.username, format!("deleted-{}", user_ids[0]));
+    assert!(purged.purge_date.is_none() && !purged.restorable);
+    let kept = users.iter().find(|u| u.user_id == user_ids[1]).unwrap();
+    assert!(kept.purge_date.is_some() && kept.restorable);
+
+    sqlx::query!("DELETE FROM users WHERE user_id = ANY($1);", &user_ids)
+        .execute(&pool).await.unwrap();
+}
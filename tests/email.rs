@@ -0,0 +1,52 @@
+//! changes email addresses against the database from `.env`, checking what
+//! the addresses involved are sent
+
+use actix_web::{test, http::StatusCode};
+use cyber_bank_rs::store;
+use serde_json::json;
+
+mod common;
+
+#[actix_web::test]
+async fn test_change_to_taken_address() {
+    let pool = common::pool().await;
+    let app = common::app(&pool).await;
+    let store = store::from_env(&pool).await;
+
+    let username = common::username("changer");
+    let owner = common::username("owner");
+    common::register(&app, &username).await;
+    common::register(&app, &owner).await;
+    let token = common::token(&app, &username).await;
+
+    // registering sent a verification email just now
+    let user_id = sqlx::query_scalar!("SELECT user_id FROM users WHERE username = $1;", username)
+        .fetch_one(&pool).await.unwrap();
+    store.reset_counter(&format!("verification_email_cooldown:{user_id}")).await.unwrap();
+
+    let change = |email: String| test::TestRequest::post()
+        .uri("/auth/email")
+        .insert_header(("Authorization", format!("Bearer {token}")))
+        .set_json(json!({ "email": email, "password": common::PASSWORD }))
+        .to_request();
+
+    // answered like any other address
+    let taken = format!("{owner}@example.com");
+    assert_eq!(test::call_service(&app, change(taken.clone())).await.status(), StatusCode::ACCEPTED);
+    let notice = common::last_email(&pool, &taken, "Someone tried to use your email address").await;
+    assert!(notice.contains("Nothing was changed"));
+    common::last_email(&pool, &format!("{username}@example.com"), "Your email address is being changed").await;
+    let tokens = sqlx::query_scalar!("SELECT COUNT(*) FROM email_verification_tokens WHERE user_id = $1 AND email = $2;",
+        user_id,
+        taken
+    ).fetch_one(&pool).await.unwrap();
+    assert_eq!(tokens, Some(0));
+
+    // the user's own address is no secret
+    let response = test::call_service(&app, change(format!("{username}@example.com"))).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body: serde_json::Value = test::read_body_json(response).await;
+    assert_eq!(body, json!([{"invalid_email": "already_in_use"}]));
+
+    common::delete_users(&pool, &[&username, &owner]).await;
+}
@@ -0,0 +1,77 @@
+//! retries requests with an `Idempotency-Key` header against the database
+//! from `.env`, creating personal access tokens
+
+use actix_web::{test, http::{StatusCode, header::CONTENT_TYPE}};
+use chrono::{Duration, Utc};
+use cyber_bank_rs::store::{self, IDEMPOTENCY_KEY, IDEMPOTENT_REPLAYED};
+use data_encoding::HEXLOWER;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+mod common;
+
+const BODY: &str = r#"{"name": "backup script", "scopes": ["user_info"], "expires_in_days": 30}"#;
+
+#[actix_web::test]
+async fn test_idempotent_requests() {
+    let pool = common::pool().await;
+    let app = common::app(&pool).await;
+
+    let username = common::username("idempotent");
+    common::register(&app, &username).await;
+    let token = common::token(&app, &username).await;
+
+    let create = |key: &str, body: &str| test::TestRequest::post()
+        .uri("/auth/tokens")
+        .insert_header(("Authorization", format!("Bearer {token}")))
+        .insert_header((IDEMPOTENCY_KEY, key.to_string()))
+        .insert_header((CONTENT_TYPE, "application/json"))
+        .set_payload(body.to_string())
+        .to_request();
+
+    let response = test::call_service(&app, create("first", BODY)).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    assert!(!response.headers().contains_key(IDEMPOTENT_REPLAYED));
+    let created: Value = test::read_body_json(response).await;
+    assert!(created["token"].as_str().unwrap().starts_with("cbpat_"));
+
+    // a retry doesn't create another token, and doesn't get the first one again
+    let response = test::call_service(&app, create("first", BODY)).await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    assert_eq!(response.headers().get(IDEMPOTENT_REPLAYED).unwrap(), "true");
+    let replayed = test::read_body(response).await;
+    assert!(replayed.is_empty());
+
+    // the secret isn't kept for replays either
+    let stored: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM idempotency_keys WHERE response_body LIKE '%cbpat_%';")
+        .fetch_one(&pool).await.unwrap();
+    assert_eq!(stored, 0);
+
+    // the same key can't be used for something else
+    let other = r#"{"name": "other script", "scopes": ["user_info"], "expires_in_days": 30}"#;
+    let response = test::call_service(&app, create("first", other)).await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    // a duplicate of a request that's still being handled has to wait for it
+    let user_id: uuid::Uuid = sqlx::query_scalar("SELECT user_id FROM users WHERE username = $1;")
+        .bind(&username)
+        .fetch_one(&pool).await.unwrap();
+    let session_store = store::from_env(&pool).await;
+    let fingerprint = HEXLOWER.encode(&Sha256::digest(BODY.as_bytes()));
+    session_store.start_request(
+        &format!("{user_id}:POST:/auth/tokens:second"),
+        &fingerprint,
+        Utc::now() + Duration::minutes(1)
+    ).await.unwrap();
+    let response = test::call_service(&app, create("second", BODY)).await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+
+    let request = test::TestRequest::get()
+        .uri("/auth/tokens")
+        .insert_header(("Authorization", format!("Bearer {token}")))
+        .to_request();
+    let tokens: Vec<Value> = test::call_and_read_body_json(&app, request).await;
+    assert_eq!(tokens.len(), 1);
+
+    common::delete_users(&pool, &[&username]).await;
+}
@@ -0,0 +1,99 @@
+//! sends emails through the SMTP mailer into a local SMTP sink, and through
+//! the file outbox into a temporary directory
+
+use cyber_bank_rs::mail::{Email, FileOutbox, Mailer, SmtpMailer};
+use lettre::{AsyncSmtpTransport, Tokio1Executor};
+use tokio::{io::{AsyncBufReadExt, AsyncWriteExt, BufReader}, net::TcpListener, sync::oneshot};
+
+/// accepts a single SMTP session, acknowledging everything, and returns the
+/// data of the first message that was sent
+async fn smtp_sink(listener: TcpListener, received: oneshot::Sender<String>) {
+    let mut received = Some(received);
+    let (stream, _) = listener.accept().await.unwrap();
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    writer.write_all(b"220 localhost sink\r\n").await.unwrap();
+    let mut data = String::new();
+    let mut in_data = false;
+    while let Some(line) = lines.next_line().await.unwrap() {
+        if in_data {
+            if line == "." {
+                in_data = false;
+                writer.write_all(b"250 queued\r\n").await.unwrap();
+                if let Some(received) = received.take() {
+                    received.send(std::mem::take(&mut data)).unwrap();
+                }
+            } else {
+                data.push_str(&line);
+                data.push('\n');
+            }
+            continue;
+        }
+
+        let command = line.split(' ').next().unwrap_or_default().to_ascii_uppercase();
+        let reply: &[u8] = match command.as_str() {
+            "EHLO" | "HELO" => b"250 localhost\r\n",
+            "DATA" => {
+                in_data = true;
+                b"354 go ahead\r\n"
+            },
+            "QUIT" => {
+                writer.write_all(b"221 bye\r\n").await.unwrap();
+                break;
+            },
+            _ => b"250 ok\r\n",
+        };
+        writer.write_all(reply).await.unwrap();
+    }
+}
+
+fn email() -> Email {
+    Email {
+        to: "alice@example.com".to_string(),
+        subject: "Resetting your password".to_string(),
+        body: "use this token: abc123".to_string(),
+    }
+}
+
+#[tokio::test]
+async fn test_smtp_mailer() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (sender, received) = oneshot::channel();
+    tokio::spawn(smtp_sink(listener, sender));
+
+    let transport = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous("127.0.0.1")
+        .port(port)
+        .build();
+    let mailer = SmtpMailer::new(transport, "bank <noreply@example.com>".parse().unwrap());
+    mailer.send(email()).await.unwrap();
+
+    let data = received.await.unwrap();
+    assert!(data.contains("To: alice@example.com"));
+    assert!(data.contains("Subject: Resetting your password"));
+    assert!(data.contains("use this token: abc123"));
+}
+
+#[tokio::test]
+async fn test_invalid_address() {
+    let transport = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous("127.0.0.1").build();
+    let mailer = SmtpMailer::new(transport, "bank <noreply@example.com>".parse().unwrap());
+    let email = Email { to: "not an address".to_string(), ..email() };
+    assert!(mailer.send(email).await.is_err());
+}
+
+#[tokio::test]
+async fn test_file_outbox() {
+    let dir = std::env::temp_dir().join(format!("cyber_bank_rs_outbox_{}", std::process::id()));
+    let outbox = FileOutbox::new(&dir);
+    outbox.send(email()).await.unwrap();
+
+    let files: Vec<_> = std::fs::read_dir(&dir).unwrap().map(|f| f.unwrap().path()).collect();
+    assert_eq!(files.len(), 1);
+    let contents = std::fs::read_to_string(&files[0]).unwrap();
+    assert!(contents.contains("To: alice@example.com"));
+    assert!(contents.contains("use this token: abc123"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,115 @@
+//! goes through the authorization code flow with PKCE against the database
+//! from `.env`, as a client running on `localhost` would
+
+use actix_web::{test, http::{StatusCode, header::LOCATION}};
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+mod common;
+
+const REDIRECT_URI: &str = "http://localhost:9000/callback";
+
+/// a PKCE code verifier and its S256 challenge
+fn pkce(seed: char) -> (String, String) {
+    let verifier = seed.to_string().repeat(64);
+    let challenge = BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+    (verifier, challenge)
+}
+
+#[actix_web::test]
+async fn test_authorization_code_flow() {
+    let pool = common::pool().await;
+    let app = common::app(&pool).await;
+
+    let username = common::username("oauth");
+    common::register(&app, &username).await;
+    let token = common::token(&app, &username).await;
+
+    let register_client = |name: &str| test::TestRequest::post()
+        .uri("/auth/oauth/clients")
+        .insert_header(("Authorization", format!("Bearer {token}")))
+        .set_json(json!({
+            "name": name,
+            "redirect_uris": [REDIRECT_URI],
+            "scopes": ["user_info"],
+            "confidential": false,
+        }))
+        .to_request();
+    let client: Value = test::call_and_read_body_json(&app, register_client("Budget app")).await;
+    let client_id = client["client_id"].as_str().unwrap().to_string();
+    let other: Value = test::call_and_read_body_json(&app, register_client("Someone else")).await;
+    let other_id = other["client_id"].as_str().unwrap().to_string();
+
+    // the user approves, and the client gets a code on its redirect URI
+    let (verifier, challenge) = pkce('v');
+    let authorize = || test::TestRequest::post()
+        .uri("/auth/oauth/authorize")
+        .insert_header(("Authorization", format!("Bearer {token}")))
+        .set_json(json!({
+            "response_type": "code",
+            "client_id": client_id,
+            "redirect_uri": REDIRECT_URI,
+            "scope": "user_info",
+            "state": "xyz",
+            "code_challenge": challenge,
+            "code_challenge_method": "S256",
+            "approve": true,
+        }))
+        .to_request();
+    let code = |response: actix_web::dev::ServiceResponse<_>| {
+        assert_eq!(response.status(), StatusCode::FOUND);
+        let location = url::Url::parse(response.headers().get(LOCATION).unwrap().to_str().unwrap()).unwrap();
+        assert!(location.as_str().starts_with(REDIRECT_URI));
+        let params: std::collections::HashMap<_, _> = location.query_pairs().into_owned().collect();
+        assert_eq!(params["state"], "xyz");
+        params["code"].clone()
+    };
+
+    let exchange = |code: &str, client_id: &str, verifier: &str| test::TestRequest::post()
+        .uri("/auth/oauth/token")
+        .set_form([
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", REDIRECT_URI),
+            ("client_id", client_id),
+            ("code_verifier", verifier),
+        ])
+        .to_request();
+    let invalid_grant = |body: Value| assert_eq!(body["error"], "invalid_grant", "{body}");
+
+    let first = code(test::call_service(&app, authorize()).await);
+    let response = test::call_service(&app, exchange(&first, &client_id, &verifier)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: Value = test::read_body_json(response).await;
+    assert_eq!(body["scope"], "user_info");
+    let access_token = body["access_token"].as_str().unwrap().to_string();
+
+    let request = test::TestRequest::get()
+        .uri("/users/me")
+        .insert_header(("Authorization", format!("Bearer {access_token}")))
+        .to_request();
+    assert_eq!(test::call_service(&app, request).await.status(), StatusCode::OK);
+
+    // codes only work once
+    let response = test::call_service(&app, exchange(&first, &client_id, &verifier)).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    invalid_grant(test::read_body_json(response).await);
+
+    // another client can't use the code, nor use it up
+    let second = code(test::call_service(&app, authorize()).await);
+    let response = test::call_service(&app, exchange(&second, &other_id, &verifier)).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    invalid_grant(test::read_body_json(response).await);
+
+    // neither can anyone without the verifier
+    let (wrong_verifier, _) = pkce('w');
+    let response = test::call_service(&app, exchange(&second, &client_id, &wrong_verifier)).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    invalid_grant(test::read_body_json(response).await);
+
+    let response = test::call_service(&app, exchange(&second, &client_id, &verifier)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    common::delete_users(&pool, &[&username]).await;
+}
@@ -0,0 +1,112 @@
+//! changes and resets passwords against the database from `.env`, checking
+//! which tokens and passwords keep working
+
+use actix_web::{test, http::StatusCode};
+use serde_json::{json, Value};
+
+mod common;
+
+const NEW_PASSWORD: &str = "N3w_Passw0rd!";
+
+#[actix_web::test]
+async fn test_password_change() {
+    let pool = common::pool().await;
+    let app = common::app(&pool).await;
+
+    let username = common::username("change");
+    common::register(&app, &username).await;
+    let token = common::token(&app, &username).await;
+    let other_token = common::token(&app, &username).await;
+
+    let request = test::TestRequest::post()
+        .uri("/auth/tokens")
+        .insert_header(("Authorization", format!("Bearer {token}")))
+        .set_json(json!({ "name": "script", "scopes": ["user_info"], "expires_in_days": 30 }))
+        .to_request();
+    let created: Value = test::call_and_read_body_json(&app, request).await;
+    let personal_token = created["token"].as_str().unwrap().to_string();
+
+    let change = |current: &str, new: &str| test::TestRequest::post()
+        .uri("/auth/password")
+        .insert_header(("Authorization", format!("Bearer {token}")))
+        .set_json(json!({ "current_password": current, "new_password": new }))
+        .to_request();
+    let me = |token: &str| test::TestRequest::get()
+        .uri("/users/me")
+        .insert_header(("Authorization", format!("Bearer {token}")))
+        .to_request();
+
+    assert_eq!(test::call_service(&app, change("wrong", NEW_PASSWORD)).await.status(), StatusCode::FORBIDDEN);
+    let response = test::call_service(&app, change(common::PASSWORD, "short")).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let response = test::call_service(&app, change(common::PASSWORD, NEW_PASSWORD)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: Value = test::read_body_json(response).await;
+    let new_token = body["token"].as_str().unwrap();
+
+    // only the new token works, straight away
+    assert_eq!(test::call_service(&app, me(new_token)).await.status(), StatusCode::OK);
+    for old in [&token, &other_token, &personal_token] {
+        assert_eq!(test::call_service(&app, me(old)).await.status(), StatusCode::FORBIDDEN);
+    }
+
+    let login = |password: &str| test::TestRequest::post()
+        .uri("/auth/login")
+        .set_json(json!({ "username": username, "password": password }))
+        .to_request();
+    assert_eq!(test::call_service(&app, login(common::PASSWORD)).await.status(), StatusCode::FORBIDDEN);
+    assert_eq!(test::call_service(&app, login(NEW_PASSWORD)).await.status(), StatusCode::OK);
+
+    common::delete_users(&pool, &[&username]).await;
+}
+
+#[actix_web::test]
+async fn test_password_reset() {
+    let pool = common::pool().await;
+    let app = common::app(&pool).await;
+
+    let username = common::username("reset");
+    common::register(&app, &username).await;
+    let token = common::token(&app, &username).await;
+
+    let request_reset = |email: &str| test::TestRequest::post()
+        .uri("/auth/password/reset")
+        .set_json(json!({ "email": email }))
+        .to_request();
+    let email = format!("{username}@example.com");
+    // the same for addresses nobody has
+    let nobody = format!("{}@example.com", common::username("nobody"));
+    assert_eq!(test::call_service(&app, request_reset(&nobody)).await.status(), StatusCode::ACCEPTED);
+    assert_eq!(test::call_service(&app, request_reset(&email.to_uppercase())).await.status(), StatusCode::ACCEPTED);
+
+    let body = common::last_email(&pool, &email, "Resetting your password").await;
+    let reset_token = body.split("\n\n").nth(2).unwrap();
+
+    let confirm = |token: &str, password: &str| test::TestRequest::post()
+        .uri("/auth/password/reset/confirm")
+        .set_json(json!({ "token": token, "new_password": password }))
+        .to_request();
+    let response = test::call_service(&app, confirm("wrong", NEW_PASSWORD)).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let errors: Value = test::read_body_json(response).await;
+    assert_eq!(errors, json!(["invalid_token"]));
+
+    // an invalid password doesn't use up the token
+    assert_eq!(test::call_service(&app, confirm(reset_token, "short")).await.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(test::call_service(&app, confirm(reset_token, NEW_PASSWORD)).await.status(), StatusCode::NO_CONTENT);
+    assert_eq!(test::call_service(&app, confirm(reset_token, NEW_PASSWORD)).await.status(), StatusCode::BAD_REQUEST);
+
+    let request = test::TestRequest::get()
+        .uri("/users/me")
+        .insert_header(("Authorization", format!("Bearer {token}")))
+        .to_request();
+    assert_eq!(test::call_service(&app, request).await.status(), StatusCode::FORBIDDEN);
+    let request = test::TestRequest::post()
+        .uri("/auth/login")
+        .set_json(json!({ "username": username, "password": NEW_PASSWORD }))
+        .to_request();
+    assert_eq!(test::call_service(&app, request).await.status(), StatusCode::OK);
+
+    common::delete_users(&pool, &[&username]).await;
+}
@@ -0,0 +1,159 @@
+//! saves payees against the database from `.env`, checking the names they're
+//! added with, who can't be added at all and how often names can be checked
+
+use actix_web::{test, http::StatusCode};
+use serde_json::{json, Value};
+
+mod common;
+
+#[actix_web::test]
+async fn test_payees() {
+    let pool = common::pool().await;
+    let app = common::app(&pool).await;
+
+    let payer = common::username("payer");
+    let payee = common::username("payee");
+    common::register(&app, &payer).await;
+    common::register(&app, &payee).await;
+
+    let add = |token: &str, payee: Value| test::TestRequest::post()
+        .uri("/users/me/payees")
+        .insert_header(("Authorization", format!("Bearer {token}")))
+        .set_json(payee)
+        .to_request();
+
+    // payees are for making transfers, which needs a verified email address
+    let token = common::token(&app, &payer).await;
+    let response = test::call_service(&app, add(&token, json!({
+        "nickname": "Jane",
+        "name": "Jane Doe",
+        "identifier": { "username": payee },
+    }))).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    common::verify_email(&pool, &payer).await;
+    sqlx::query!(r"INSERT INTO user_profiles (user_id, display_name, update_date)
+        SELECT user_id, 'Jane Doe', now() FROM users WHERE username = $1;",
+        payee
+    ).execute(&pool).await.unwrap();
+    let token = common::token(&app, &payer).await;
+
+    let rejected = [
+        (json!({ "username": payee }), "John Smith", json!([{"invalid_name": "no_match"}])),
+        (json!({ "username": payee }), "J. Doe", json!([{"invalid_name": "close_match"}])),
+        (json!({ "username": payer }), "Me", json!([{"invalid_identifier": "own_account"}])),
+        // no different from a name that doesn't match
+        (json!({ "username": common::username("nobody") }), "Nobody", json!([{"invalid_name": "no_match"}])),
+        (json!({ "iban": "DE89 3704 0044 0532 0130 01" }), "Jane Doe", json!([{"invalid_identifier": "invalid_checksum"}])),
+    ];
+    for (identifier, name, errors) in rejected {
+        let response = test::call_service(&app, add(&token, json!({
+            "nickname": "Jane",
+            "name": name,
+            "identifier": identifier,
+        }))).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body: Value = test::read_body_json(response).await;
+        assert_eq!(body, errors);
+    }
+
+    // nobody to send money to, however sure the user is
+    let response = test::call_service(&app, add(&token, json!({
+        "nickname": "Nobody",
+        "name": "Nobody",
+        "identifier": { "username": common::username("nobody") },
+        "accept_name_mismatch": true,
+    }))).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body: Value = test::read_body_json(response).await;
+    assert_eq!(body, json!([{"invalid_name": "no_match"}]));
+
+    // close enough once the user accepts it
+    let response = test::call_service(&app, add(&token, json!({
+        "nickname": "Jane",
+        "name": "J. Doe",
+        "identifier": { "username": payee.to_uppercase() },
+        "accept_name_mismatch": true,
+    }))).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let added: Value = test::read_body_json(response).await;
+    assert_eq!(added["name_check"], "close_match");
+    assert_eq!(added["identifier"], json!({ "username": payee }));
+    assert!(added["cooling_off_until"].as_str().unwrap() > added["creation_date"].as_str().unwrap());
+
+    let response = test::call_service(&app, add(&token, json!({
+        "nickname": "Jane again",
+        "name": "Jane Doe",
+        "identifier": { "username": payee },
+    }))).await;
+    let body: Value = test::read_body_json(response).await;
+    assert_eq!(body, json!([{"invalid_identifier": "already_in_use"}]));
+
+    let response = test::call_service(&app, add(&token, json!({
+        "nickname": "Landlord",
+        "name": "Erika Mustermann",
+        "identifier": { "iban": "de89 3704 0044 0532 0130 00" },
+    }))).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let body: Value = test::read_body_json(response).await;
+    assert_eq!(body["name_check"], "unavailable");
+    assert_eq!(body["identifier"], json!({ "iban": "DE89370400440532013000" }));
+
+    let request = test::TestRequest::get()
+        .uri("/users/me/payees")
+        .insert_header(("Authorization", format!("Bearer {token}")))
+        .to_request();
+    let payees: Vec<Value> = test::call_and_read_body_json(&app, request).await;
+    let nicknames: Vec<&str> = payees.iter().map(|p| p["nickname"].as_str().unwrap()).collect();
+    assert_eq!(nicknames, ["Jane", "Landlord"]);
+
+    // users being deleted don't match anymore, however they were added
+    sqlx::query!("UPDATE users SET deletion_date = now() WHERE username = $1;", payee)
+        .execute(&pool).await.unwrap();
+    let request = test::TestRequest::get()
+        .uri("/users/me/payees")
+        .insert_header(("Authorization", format!("Bearer {token}")))
+        .to_request();
+    let payees: Vec<Value> = test::call_and_read_body_json(&app, request).await;
+    assert_eq!(payees[0]["name_check"], "no_match");
+
+    let delete = || test::TestRequest::delete()
+        .uri(&format!("/users/me/payees/{}", added["payee_id"].as_str().unwrap()))
+        .insert_header(("Authorization", format!("Bearer {token}")))
+        .to_request();
+    assert_eq!(test::call_service(&app, delete()).await.status(), StatusCode::NO_CONTENT);
+    assert_eq!(test::call_service(&app, delete()).await.status(), StatusCode::NOT_FOUND);
+
+    common::delete_users(&pool, &[&payer, &payee]).await;
+}
+
+#[actix_web::test]
+async fn test_name_check_limit() {
+    let pool = common::pool().await;
+    let app = common::app(&pool).await;
+
+    let payer = common::username("guesser");
+    common::register(&app, &payer).await;
+    common::verify_email(&pool, &payer).await;
+    let token = common::token(&app, &payer).await;
+
+    // guessing names, or whether someone has an account, only goes so far
+    let guess = |i: usize| test::TestRequest::post()
+        .uri("/users/me/payees")
+        .insert_header(("Authorization", format!("Bearer {token}")))
+        .set_json(json!({
+            "nickname": "Guess",
+            "name": format!("Guess {i}"),
+            "identifier": { "username": common::username("guessed") },
+        }))
+        .to_request();
+    for i in 0..10 {
+        let response = test::call_service(&app, guess(i)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+    let response = test::call_service(&app, guess(10)).await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(response.headers().contains_key("retry-after"));
+
+    common::delete_users(&pool, &[&payer]).await;
+}
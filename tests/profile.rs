@@ -0,0 +1,81 @@
+//! reads and changes profiles against the database from `.env`
+
+use actix_web::{test, http::StatusCode};
+use serde_json::{json, Value};
+
+mod common;
+
+#[actix_web::test]
+async fn test_get_and_update() {
+    let pool = common::pool().await;
+    let app = common::app(&pool).await;
+
+    let username = common::username("profile");
+    common::register(&app, &username).await;
+    let token = common::token(&app, &username).await;
+
+    let get = |token: &str| test::TestRequest::get()
+        .uri("/users/me")
+        .insert_header(("Authorization", format!("Bearer {token}")))
+        .to_request();
+    let update = |token: &str, body: Value| test::TestRequest::patch()
+        .uri("/users/me")
+        .insert_header(("Authorization", format!("Bearer {token}")))
+        .set_json(body)
+        .to_request();
+
+    let response = test::call_service(&app, get(&token)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let profile: Value = test::read_body_json(response).await;
+    assert_eq!(profile["username"], json!(username));
+    assert_eq!(profile["email"], json!(format!("{username}@example.com")));
+    assert_eq!(profile["display_name"], Value::Null);
+    assert_eq!(profile["timezone"], Value::Null);
+
+    let body = json!({ "display_name": "  Jane  ", "home_currency": "EUR", "locale": "de-AT", "timezone": "Europe/Vienna" });
+    let response = test::call_service(&app, update(&token, body)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let profile: Value = test::read_body_json(response).await;
+    assert_eq!(profile["display_name"], json!("Jane"));
+    assert_eq!(profile["home_currency"], json!("EUR"));
+
+    // left out stays, null unsets
+    let response = test::call_service(&app, update(&token, json!({ "locale": null }))).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let profile: Value = test::read_body_json(test::call_service(&app, get(&token)).await).await;
+    assert_eq!(profile["locale"], Value::Null);
+    assert_eq!(profile["timezone"], json!("Europe/Vienna"));
+
+    // nothing is changed if anything is invalid
+    let body = json!({ "display_name": "Joe", "home_currency": "euro", "timezone": "Mars/Olympus" });
+    let response = test::call_service(&app, update(&token, body)).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let errors: Value = test::read_body_json(response).await;
+    assert_eq!(errors.as_array().unwrap().len(), 2);
+    assert!(errors[0].get("invalid_home_currency").is_some());
+    assert!(errors[1].get("invalid_timezone").is_some());
+    let profile: Value = test::read_body_json(test::call_service(&app, get(&token)).await).await;
+    assert_eq!(profile["display_name"], json!("Jane"));
+
+    // personal access tokens with the scope can read the profile, but not change it
+    let request = test::TestRequest::post()
+        .uri("/auth/tokens")
+        .insert_header(("Authorization", format!("Bearer {token}")))
+        .set_json(json!({ "name": "profile script", "scopes": ["user_info"], "expires_in_days": 1 }))
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let created: Value = test::read_body_json(response).await;
+    let personal = created["token"].as_str().unwrap();
+    assert_eq!(test::call_service(&app, get(personal)).await.status(), StatusCode::OK);
+    let response = test::call_service(&app, update(personal, json!({ "display_name": "Mallory" }))).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let errors: Value = test::read_body_json(response).await;
+    assert_eq!(errors, json!(["not_allowed_for_token"]));
+
+    // and nobody can without a token
+    let request = test::TestRequest::get().uri("/users/me").to_request();
+    assert_eq!(test::call_service(&app, request).await.status(), StatusCode::FORBIDDEN);
+
+    common::delete_users(&pool, &[&username]).await;
+}
@@ -0,0 +1,63 @@
+//! registers the same user many times at once against the database from
+//! `.env`, only one of the registrations can succeed
+
+use actix_web::{test, http::StatusCode};
+use futures_util::future::join_all;
+
+mod common;
+
+const REGISTRATIONS: usize = 16;
+
+#[actix_web::test]
+async fn test_concurrent_registrations() {
+    let pool = common::pool().await;
+    let app = common::app(&pool).await;
+
+    let username = common::username("race");
+    let requests = (0..REGISTRATIONS).map(|i| {
+        // every other one only differs in case, which makes no difference
+        let registerer = match i % 2 {
+            0 => username.clone(),
+            _ => username.to_uppercase(),
+        };
+        let request = test::TestRequest::post()
+            .uri("/auth/register")
+            .set_json(serde_json::json!({
+                "email": format!("{username}_{i}@example.com"),
+                "username": registerer,
+                "password": common::PASSWORD,
+            }))
+            .to_request();
+        test::call_service(&app, request)
+    });
+    let responses = join_all(requests).await;
+
+    let mut created = 0;
+    for response in responses {
+        match response.status() {
+            StatusCode::CREATED => created += 1,
+            StatusCode::BAD_REQUEST => {
+                let body: serde_json::Value = test::read_body_json(response).await;
+                assert_eq!(body, serde_json::json!([{"invalid_username": "already_in_use"}]));
+            },
+            status => panic!("registration failed with {status}"),
+        }
+    }
+    assert_eq!(created, 1);
+
+    // the same goes for emails
+    let request = test::TestRequest::post()
+        .uri("/auth/register")
+        .set_json(serde_json::json!({
+            "email": format!("{}_0@EXAMPLE.com", username.to_uppercase()),
+            "username": format!("{username}_other"),
+            "password": common::PASSWORD,
+        }))
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body: serde_json::Value = test::read_body_json(response).await;
+    assert_eq!(body, serde_json::json!([{"invalid_email": "already_in_use"}]));
+
+    common::delete_users(&pool, &[&username]).await;
+}
@@ -0,0 +1,138 @@
+//! runs the same checks against every session store: the Postgres one using
+//! the database from `.env`, and the Redis one if `REDIS_URL` is set, like
+//! `redis://localhost:6379/15`
+
+use std::time::Duration as StdDuration;
+
+use chrono::{Duration, Utc};
+use cyber_bank_rs::{db, store::{PgStore, RedisStore, RefreshToken, SessionStore, StoredResponse}};
+use rand::RngCore;
+use uuid::Uuid;
+
+/// long enough for anything stored as already expired to be gone from Redis
+const EXPIRY: StdDuration = StdDuration::from_millis(50);
+
+fn random_key() -> String {
+    format!("test_{:016x}", rand::thread_rng().next_u64())
+}
+
+async fn check_revocation(store: &dyn SessionStore) {
+    let jti = Uuid::new_v4();
+    assert!(!store.is_token_revoked(jti).await.unwrap());
+    store.revoke_token(jti, Utc::now() + Duration::minutes(1)).await.unwrap();
+    assert!(store.is_token_revoked(jti).await.unwrap());
+
+    let expired = Uuid::new_v4();
+    store.revoke_token(expired, Utc::now() - Duration::seconds(1)).await.unwrap();
+    tokio::time::sleep(EXPIRY).await;
+    assert!(!store.is_token_revoked(expired).await.unwrap());
+}
+
+async fn check_refresh_tokens(store: &dyn SessionStore) {
+    let token = RefreshToken { user_id: Uuid::new_v4(), session_id: Uuid::new_v4() };
+    let hash = random_key().into_bytes();
+    store.put_refresh_token(&hash, &token, Utc::now() + Duration::minutes(1)).await.unwrap();
+    assert_eq!(store.take_refresh_token(&hash).await.unwrap(), Some(token.clone()));
+    // they can only be used once
+    assert_eq!(store.take_refresh_token(&hash).await.unwrap(), None);
+
+    let expired = random_key().into_bytes();
+    store.put_refresh_token(&expired, &token, Utc::now() - Duration::seconds(1)).await.unwrap();
+    tokio::time::sleep(EXPIRY).await;
+    assert_eq!(store.take_refresh_token(&expired).await.unwrap(), None);
+}
+
+async fn check_counters(store: &dyn SessionStore) {
+    let key = random_key();
+    let first = store.increment(&key, Duration::minutes(1)).await.unwrap();
+    assert_eq!(first.count, 1);
+    assert!(first.reset_date > Utc::now() + Duration::seconds(50));
+    let second = store.increment(&key, Duration::minutes(1)).await.unwrap();
+    assert_eq!(second.count, 2);
+    // the window starts with the first hit, later ones don't extend it
+    assert!((second.reset_date - first.reset_date).num_milliseconds().abs() < 1000);
+
+    let short = random_key();
+    store.increment(&short, Duration::milliseconds(10)).await.unwrap();
+    tokio::time::sleep(EXPIRY).await;
+    assert_eq!(store.increment(&short, Duration::milliseconds(10)).await.unwrap().count, 1);
+
+    // reset counters start over
+    store.reset_counter(&key).await.unwrap();
+    assert_eq!(store.increment(&key, Duration::minutes(1)).await.unwrap().count, 1);
+}
+
+async fn check_locks(store: &dyn SessionStore) {
+    let key = random_key();
+    assert_eq!(store.locked_until(&key).await.unwrap(), None);
+    let until = Utc::now() + Duration::minutes(1);
+    store.lock(&key, until).await.unwrap();
+    let locked = store.locked_until(&key).await.unwrap().unwrap();
+    assert!((locked - until).num_milliseconds().abs() < 1000);
+    store.unlock(&key).await.unwrap();
+    assert_eq!(store.locked_until(&key).await.unwrap(), None);
+
+    let expired = random_key();
+    store.lock(&expired, Utc::now() - Duration::seconds(1)).await.unwrap();
+    tokio::time::sleep(EXPIRY).await;
+    assert_eq!(store.locked_until(&expired).await.unwrap(), None);
+}
+
+async fn check_idempotency(store: &dyn SessionStore) {
+    let key = random_key();
+    let expiration = Utc::now() + Duration::minutes(1);
+    assert_eq!(store.start_request(&key, "first", expiration).await.unwrap(), None);
+
+    let in_progress = store.start_request(&key, "second", expiration).await.unwrap().unwrap();
+    assert_eq!(in_progress.fingerprint, "first");
+    assert_eq!(in_progress.response, None);
+
+    let response = StoredResponse {
+        status: 201,
+        content_type: Some("application/json".to_string()),
+        body: Some(r#"{"id":1}"#.to_string()),
+    };
+    store.finish_request(&key, &response).await.unwrap();
+    let done = store.start_request(&key, "first", expiration).await.unwrap().unwrap();
+    assert_eq!(done.response, Some(response));
+
+    store.abandon_request(&key).await.unwrap();
+    assert_eq!(store.start_request(&key, "first", expiration).await.unwrap(), None);
+
+    let expired = random_key();
+    assert_eq!(store.start_request(&expired, "first", Utc::now() - Duration::seconds(1)).await.unwrap(), None);
+    tokio::time::sleep(EXPIRY).await;
+    assert_eq!(store.start_request(&expired, "second", expiration).await.unwrap(), None);
+}
+
+async fn check_store(store: &dyn SessionStore) {
+    check_revocation(store).await;
+    check_refresh_tokens(store).await;
+    check_counters(store).await;
+    check_locks(store).await;
+    check_idempotency(store).await;
+}
+
+#[actix_web::test]
+async fn test_postgres_store() {
+    let pool = db::get_db_pool().await;
+    db::set_up_db_tables(&pool).await;
+    let store = PgStore::new(pool);
+    check_store(&store).await;
+
+    // nothing that's still good is cleaned up
+    let jti = Uuid::new_v4();
+    store.revoke_token(jti, Utc::now() + Duration::minutes(1)).await.unwrap();
+    store.cleanup().await.unwrap();
+    assert!(store.is_token_revoked(jti).await.unwrap());
+}
+
+#[actix_web::test]
+async fn test_redis_store() {
+    let Ok(url) = std::env::var("REDIS_URL") else {
+        eprintln!("REDIS_URL isn't set, skipping the Redis store");
+        return;
+    };
+    let store = RedisStore::connect(&url).await.unwrap();
+    check_store(&store).await;
+}
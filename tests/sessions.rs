@@ -0,0 +1,189 @@
+//! logs in, refreshes and logs out against the database from `.env`, checking
+//! which tokens keep working
+
+use actix_web::{test, http::StatusCode};
+use serde_json::{json, Value};
+
+mod common;
+
+#[actix_web::test]
+async fn test_refresh_and_logout() {
+    let pool = common::pool().await;
+    let app = common::app(&pool).await;
+
+    let username = common::username("session");
+    common::register(&app, &username).await;
+    let login = common::login(&app, &username).await;
+
+    let refresh = |refresh_token: &str| test::TestRequest::post()
+        .uri("/auth/refresh")
+        .set_json(json!({ "refresh_token": refresh_token }))
+        .to_request();
+    let me = |token: &str| test::TestRequest::get()
+        .uri("/users/me")
+        .insert_header(("Authorization", format!("Bearer {token}")))
+        .to_request();
+
+    // refreshing hands out a new refresh token every time
+    let first = login["refresh_token"].as_str().unwrap();
+    let response = test::call_service(&app, refresh(first)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let refreshed: Value = test::read_body_json(response).await;
+    let second = refreshed["refresh_token"].as_str().unwrap();
+    assert_ne!(second, first);
+    let token = refreshed["token"].as_str().unwrap();
+    assert_eq!(test::call_service(&app, me(token)).await.status(), StatusCode::OK);
+
+    // and the old one is used up
+    let response = test::call_service(&app, refresh(first)).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body: Value = test::read_body_json(response).await;
+    assert_eq!(body, json!(["invalid_refresh_token"]));
+
+    let request = test::TestRequest::post()
+        .uri("/auth/logout")
+        .insert_header(("Authorization", format!("Bearer {token}")))
+        .to_request();
+    assert_eq!(test::call_service(&app, request).await.status(), StatusCode::NO_CONTENT);
+
+    // the token stops working right away, and the session can't be refreshed
+    assert_eq!(test::call_service(&app, me(token)).await.status(), StatusCode::FORBIDDEN);
+    assert_eq!(test::call_service(&app, refresh(second)).await.status(), StatusCode::BAD_REQUEST);
+    let session_token = login["token"].as_str().unwrap();
+    assert_eq!(test::call_service(&app, me(session_token)).await.status(), StatusCode::FORBIDDEN);
+
+    common::delete_users(&pool, &[&username]).await;
+}
+
+#[actix_web::test]
+async fn test_list_and_revoke() {
+    let pool = common::pool().await;
+    let app = common::app(&pool).await;
+
+    let username = common::username("sessions");
+    let other = common::username("sessions");
+    common::register(&app, &username).await;
+    common::register(&app, &other).await;
+    let first = common::token(&app, &username).await;
+    let second = common::token(&app, &username).await;
+    let third = common::token(&app, &username).await;
+
+    let with_token = |request: test::TestRequest, token: &str| request
+        .insert_header(("Authorization", format!("Bearer {token}")))
+        .to_request();
+    let me = |token: &str| with_token(test::TestRequest::get().uri("/users/me"), token);
+    let list = |token: &str| with_token(test::TestRequest::get().uri("/auth/sessions"), token);
+    let revoke = |session_id: &str, token: &str| with_token(
+        test::TestRequest::delete().uri(&format!("/auth/sessions/{session_id}")),
+        token,
+    );
+
+    let response = test::call_service(&app, list(&first)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let sessions: Vec<Value> = test::read_body_json(response).await;
+    assert_eq!(sessions.len(), 3);
+    assert_eq!(sessions.iter().filter(|s| s["current"] == json!(true)).count(), 1);
+
+    // the session the second token belongs to
+    let sessions: Vec<Value> = test::read_body_json(test::call_service(&app, list(&second)).await).await;
+    let current = sessions.iter().find(|s| s["current"] == json!(true)).unwrap();
+    let second_session = current["session_id"].as_str().unwrap().to_string();
+
+    // other users' sessions can't be revoked, and look like they don't exist
+    let other_token = common::token(&app, &other).await;
+    let response = test::call_service(&app, revoke(&second_session, &other_token)).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(test::call_service(&app, me(&second)).await.status(), StatusCode::OK);
+
+    let response = test::call_service(&app, revoke(&second_session, &first)).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert_eq!(test::call_service(&app, me(&second)).await.status(), StatusCode::FORBIDDEN);
+    let response = test::call_service(&app, revoke(&second_session, &first)).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    // logging out everywhere else keeps the current session
+    let request = with_token(test::TestRequest::delete().uri("/auth/sessions"), &first);
+    assert_eq!(test::call_service(&app, request).await.status(), StatusCode::NO_CONTENT);
+    assert_eq!(test::call_service(&app, me(&third)).await.status(), StatusCode::FORBIDDEN);
+    assert_eq!(test::call_service(&app, me(&first)).await.status(), StatusCode::OK);
+    let sessions: Vec<Value> = test::read_body_json(test::call_service(&app, list(&first)).await).await;
+    assert_eq!(sessions.len(), 1);
+    assert_eq!(sessions[0]["current"], json!(true));
+
+    common::delete_users(&pool, &[&username, &other]).await;
+}
+
+#[actix_web::test]
+async fn test_second_factor_token_used_once() {
+    use blake2::{Blake2b512, Digest};
+
+    let pool = common::pool().await;
+    let app = common::app(&pool).await;
+
+    let username = common::username("mfa");
+    common::register(&app, &username).await;
+    let user_id: uuid::Uuid = sqlx::query_scalar("SELECT user_id FROM users WHERE username = $1;")
+        .bind(&username)
+        .fetch_one(&pool).await.unwrap();
+    sqlx::query("INSERT INTO totp_secrets (user_id, secret, creation_date, enabled_date) VALUES ($1, $2, now(), now());")
+        .bind(user_id)
+        .bind(vec![0u8; 20])
+        .execute(&pool).await.unwrap();
+    for code in ["aaaaaaaaaa", "bbbbbbbbbb"] {
+        sqlx::query("INSERT INTO recovery_codes (user_id, code_hash) VALUES ($1, $2);")
+            .bind(user_id)
+            .bind(Blake2b512::digest(code.as_bytes()).to_vec())
+            .execute(&pool).await.unwrap();
+    }
+
+    let login = common::login(&app, &username).await;
+    assert_eq!(login["mfa_required"], true);
+    let pending = login["token"].as_str().unwrap().to_string();
+    let second_factor = |code: &str| test::TestRequest::post()
+        .uri("/auth/login/mfa")
+        .insert_header(("Authorization", format!("Bearer {pending}")))
+        .set_json(json!({ "recovery_code": code }))
+        .to_request();
+    assert_eq!(test::call_service(&app, second_factor("aaaaa-aaaaa")).await.status(), StatusCode::OK);
+
+    // the token finished its login, it can't start another session even
+    // with another valid code
+    assert_eq!(test::call_service(&app, second_factor("bbbbb-bbbbb")).await.status(), StatusCode::FORBIDDEN);
+
+    // nor can a token that's only good for the second factor log out
+    let pending = common::token(&app, &username).await;
+    let request = test::TestRequest::post()
+        .uri("/auth/logout")
+        .insert_header(("Authorization", format!("Bearer {pending}")))
+        .to_request();
+    assert_eq!(test::call_service(&app, request).await.status(), StatusCode::FORBIDDEN);
+
+    common::delete_users(&pool, &[&username]).await;
+}
+
+#[actix_web::test]
+async fn test_throttling_by_address() {
+    let pool = common::pool().await;
+    let app = common::app(&pool).await;
+
+    let username = common::username("throttled");
+    common::register(&app, &username).await;
+
+    let login = |addr: &str, password: &str| test::TestRequest::post()
+        .uri("/auth/login")
+        .peer_addr(addr.parse().unwrap())
+        .set_json(json!({ "username": username, "password": password }))
+        .to_request();
+    // fresh addresses, which earlier runs didn't throttle
+    let address = || format!("[2001:db8::{:x}:{:x}]:1234", rand::random::<u16>(), rand::random::<u16>());
+    let (guesser, user) = (address(), address());
+    for _ in 0..4 {
+        assert_eq!(test::call_service(&app, login(&guesser, "wrong")).await.status(), StatusCode::FORBIDDEN);
+    }
+    assert_eq!(test::call_service(&app, login(&guesser, common::PASSWORD)).await.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    // someone guessing from elsewhere doesn't keep the user out
+    assert_eq!(test::call_service(&app, login(&user, common::PASSWORD)).await.status(), StatusCode::OK);
+
+    common::delete_users(&pool, &[&username]).await;
+}
@@ -0,0 +1,275 @@
+//! runs both WebAuthn ceremonies against a software authenticator, and
+//! starts logins against the database from `.env`
+
+use ciborium::Value;
+use cyber_bank_rs::auth::webauthn::ceremony::{
+    self, AssertionResponse, AttestationResponse, CeremonyError, PublicKeyCredential, RelyingParty,
+};
+use p256::ecdsa::{signature::Signer, Signature, SigningKey};
+use rand::RngCore;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+mod common;
+
+/// a software authenticator holding a single ES256 credential
+struct SoftwareAuthenticator {
+    key: SigningKey,
+    credential_id: Vec<u8>,
+    sign_count: u32,
+    user_verification: bool,
+}
+
+impl SoftwareAuthenticator {
+    fn new() -> Self {
+        let mut credential_id = vec![0u8; 16];
+        rand::thread_rng().fill_bytes(&mut credential_id);
+        Self {
+            key: SigningKey::random(&mut rand::rngs::OsRng),
+            credential_id,
+            sign_count: 0,
+            user_verification: true,
+        }
+    }
+
+    fn client_data(ceremony: &str, challenge: &[u8], origin: &str) -> Vec<u8> {
+        serde_json::json!({
+            "type": ceremony,
+            "challenge": ceremony::encode(challenge),
+            "origin": origin,
+            "crossOrigin": false,
+        }).to_string().into_bytes()
+    }
+
+    fn authenticator_data(&self, rp_id: &str, attested: bool) -> Vec<u8> {
+        let mut data = Sha256::digest(rp_id.as_bytes()).to_vec();
+        let mut flags = 0x01;
+        if self.user_verification {
+            flags |= 0x04;
+        }
+        if attested {
+            flags |= 0x40;
+        }
+        data.push(flags);
+        data.extend_from_slice(&self.sign_count.to_be_bytes());
+        data
+    }
+
+    fn cose_key(&self) -> Vec<u8> {
+        let point = self.key.verifying_key().to_encoded_point(false);
+        let int = |i: i64| Value::Integer(i.into());
+        let key = Value::Map(vec![
+            (int(1), int(2)),
+            (int(3), int(ceremony::ES256)),
+            (int(-1), int(1)),
+            (int(-2), Value::Bytes(point.x().unwrap().to_vec())),
+            (int(-3), Value::Bytes(point.y().unwrap().to_vec())),
+        ]);
+        let mut encoded = Vec::new();
+        ciborium::ser::into_writer(&key, &mut encoded).unwrap();
+        encoded
+    }
+
+    fn create(&self, rp_id: &str, origin: &str, challenge: &[u8]) -> PublicKeyCredential<AttestationResponse> {
+        let mut auth_data = self.authenticator_data(rp_id, true);
+        auth_data.extend_from_slice(&[0u8; 16]);
+        auth_data.extend_from_slice(&(self.credential_id.len() as u16).to_be_bytes());
+        auth_data.extend_from_slice(&self.credential_id);
+        auth_data.extend_from_slice(&self.cose_key());
+
+        let attestation = Value::Map(vec![
+            (Value::Text("fmt".into()), Value::Text("none".into())),
+            (Value::Text("attStmt".into()), Value::Map(vec![])),
+            (Value::Text("authData".into()), Value::Bytes(auth_data)),
+        ]);
+        let mut attestation_object = Vec::new();
+        ciborium::ser::into_writer(&attestation, &mut attestation_object).unwrap();
+
+        PublicKeyCredential {
+            id: ceremony::encode(&self.credential_id),
+            raw_id: ceremony::encode(&self.credential_id),
+            credential_type: "public-key".into(),
+            response: AttestationResponse {
+                client_data_json: ceremony::encode(&Self::client_data("webauthn.create", challenge, origin)),
+                attestation_object: ceremony::encode(&attestation_object),
+            },
+        }
+    }
+
+    fn get(&mut self, rp_id: &str, origin: &str, challenge: &[u8]) -> PublicKeyCredential<AssertionResponse> {
+        self.sign_count += 1;
+        let auth_data = self.authenticator_data(rp_id, false);
+        let client_data = Self::client_data("webauthn.get", challenge, origin);
+
+        let mut signed = auth_data.clone();
+        signed.extend_from_slice(&Sha256::digest(&client_data));
+        let signature: Signature = self.key.sign(&signed);
+
+        PublicKeyCredential {
+            id: ceremony::encode(&self.credential_id),
+            raw_id: ceremony::encode(&self.credential_id),
+            credential_type: "public-key".into(),
+            response: AssertionResponse {
+                client_data_json: ceremony::encode(&client_data),
+                authenticator_data: ceremony::encode(&auth_data),
+                signature: ceremony::encode(signature.to_der().as_bytes()),
+                user_handle: None,
+            },
+        }
+    }
+}
+
+fn relying_party() -> RelyingParty {
+    RelyingParty {
+        id: "localhost".into(),
+        origin: "http://localhost:8080".into(),
+    }
+}
+
+fn challenge() -> Vec<u8> {
+    let mut challenge = vec![0u8; 32];
+    rand::thread_rng().fill_bytes(&mut challenge);
+    challenge
+}
+
+#[test]
+fn test_register_and_authenticate() {
+    let rp = relying_party();
+    let mut authenticator = SoftwareAuthenticator::new();
+
+    let challenge = challenge();
+    let registered = ceremony::verify_registration(&rp, &challenge, &authenticator.create(&rp.id, &rp.origin, &challenge))
+        .unwrap();
+    assert_eq!(registered.credential_id, authenticator.credential_id);
+    assert!(registered.user_verified);
+
+    let mut sign_count = registered.sign_count;
+    for _ in 0..3 {
+        let challenge = self::challenge();
+        let assertion = authenticator.get(&rp.id, &rp.origin, &challenge);
+        let verified = ceremony::verify_authentication(&rp, &challenge, &registered.public_key, sign_count, &assertion)
+            .unwrap();
+        assert!(verified.sign_count > sign_count);
+        sign_count = verified.sign_count;
+    }
+}
+
+#[test]
+fn test_rejected_registrations() {
+    let rp = relying_party();
+    let authenticator = SoftwareAuthenticator::new();
+    let challenge = challenge();
+
+    assert_eq!(
+        ceremony::verify_registration(&rp, &self::challenge(), &authenticator.create(&rp.id, &rp.origin, &challenge)),
+        Err(CeremonyError::ChallengeMismatch)
+    );
+    assert_eq!(
+        ceremony::verify_registration(&rp, &challenge, &authenticator.create(&rp.id, "https://evil.example", &challenge)),
+        Err(CeremonyError::OriginMismatch)
+    );
+    assert_eq!(
+        ceremony::verify_registration(&rp, &challenge, &authenticator.create("evil.example", &rp.origin, &challenge)),
+        Err(CeremonyError::RelyingPartyMismatch)
+    );
+    assert_eq!(
+        ceremony::verify_registration(&rp, &challenge, &{
+            let mut credential = authenticator.create(&rp.id, &rp.origin, &challenge);
+            credential.credential_type = "password".into();
+            credential
+        }),
+        Err(CeremonyError::WrongCredentialType)
+    );
+}
+
+#[test]
+fn test_rejected_assertions() {
+    let rp = relying_party();
+    let mut authenticator = SoftwareAuthenticator::new();
+
+    let challenge = challenge();
+    let registered = ceremony::verify_registration(&rp, &challenge, &authenticator.create(&rp.id, &rp.origin, &challenge))
+        .unwrap();
+
+    // registration assertions can't be used to log in
+    assert_eq!(
+        ceremony::verify_authentication(&rp, &challenge, &registered.public_key, 0, &{
+            let mut assertion = authenticator.get(&rp.id, &rp.origin, &challenge);
+            assertion.response.client_data_json = ceremony::encode(
+                &SoftwareAuthenticator::client_data("webauthn.create", &challenge, &rp.origin)
+            );
+            assertion
+        }),
+        Err(CeremonyError::WrongCeremony)
+    );
+    assert_eq!(
+        ceremony::verify_authentication(&rp, &challenge, &registered.public_key, 0, &{
+            let mut assertion = authenticator.get(&rp.id, &rp.origin, &challenge);
+            assertion.credential_type = "otp".into();
+            assertion
+        }),
+        Err(CeremonyError::WrongCredentialType)
+    );
+
+    // a different key signed it
+    let other = SoftwareAuthenticator::new();
+    let other_key = ceremony::verify_registration(&rp, &challenge, &other.create(&rp.id, &rp.origin, &challenge))
+        .unwrap()
+        .public_key;
+    assert_eq!(
+        ceremony::verify_authentication(&rp, &challenge, &other_key, 0, &authenticator.get(&rp.id, &rp.origin, &challenge)),
+        Err(CeremonyError::InvalidSignature)
+    );
+
+    // a cloned authenticator would be behind on its counter
+    let assertion = authenticator.get(&rp.id, &rp.origin, &challenge);
+    assert_eq!(
+        ceremony::verify_authentication(&rp, &challenge, &registered.public_key, authenticator.sign_count, &assertion),
+        Err(CeremonyError::SignCountRegressed)
+    );
+}
+
+#[actix_web::test]
+async fn test_login_begin_hides_users() {
+    let pool = common::pool().await;
+    let app = common::app(&pool).await;
+
+    let with = common::username("passkey");
+    let without = common::username("password");
+    common::register(&app, &with).await;
+    common::register(&app, &without).await;
+    let credential_id = challenge();
+    sqlx::query(r"INSERT INTO webauthn_credentials (credential_id, user_id, name, public_key, sign_count, creation_date)
+        SELECT $2, user_id, 'key', $3, 0, now() FROM users WHERE username = $1;")
+        .bind(&with)
+        .bind(&credential_id)
+        .bind(SoftwareAuthenticator::new().cose_key())
+        .execute(&pool).await.unwrap();
+
+    let allowed = |username: String| {
+        let app = &app;
+        async move {
+            let request = actix_web::test::TestRequest::post()
+                .uri("/auth/webauthn/login/begin")
+                .set_json(json!({ "username": username }))
+                .to_request();
+            let options: serde_json::Value = actix_web::test::call_and_read_body_json(app, request).await;
+            options["publicKey"]["allowCredentials"].as_array().unwrap().clone()
+        }
+    };
+
+    assert_eq!(allowed(with.clone()).await, [json!({ "type": "public-key", "id": ceremony::encode(&credential_id) })]);
+
+    // users without authenticators and usernames nobody has look the same
+    // as users with some, every time
+    let nobody = common::username("nobody");
+    for username in [&without, &nobody] {
+        let decoys = allowed(username.clone()).await;
+        assert!(!decoys.is_empty());
+        assert!(decoys.iter().all(|d| d["type"] == "public-key" && d["id"].as_str().unwrap().len() == 43));
+        assert_eq!(allowed(username.to_uppercase()).await, decoys);
+    }
+    assert_ne!(allowed(without.clone()).await, allowed(nobody).await);
+
+    common::delete_users(&pool, &[&with, &without]).await;
+}